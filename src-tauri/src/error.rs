@@ -0,0 +1,110 @@
+use std::fmt;
+
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+
+/// Errors of the Tauri commands. They reach the frontend as { code, message }, so it can branch
+/// on the kind of error and show the message as is. Internal errors add a diagnostic.
+#[derive(Debug, Clone)]
+pub enum SimError {
+    /// The graph refers to nodes it doesn't have or has edges of unknown types
+    Graph(String),
+    /// A route, or something naming one, doesn't fit the graph or the other routes
+    Route(String),
+    /// An argument out of range or naming something that doesn't exist, like a stored run
+    InvalidArgument(String),
+    /// z3 failed to decide whether departures fit
+    Solver(String),
+    /// The optimizer found no schedule at all which runs without conflicts
+    InfeasibleSchedule,
+    /// The frontend asked for the command to stop
+    Cancelled,
+    /// Another heavy command is still running, so this one was turned away
+    Busy(String),
+    /// A bug, the simulation got into a state it should never be in, with what it was doing to
+    /// report with it
+    Internal(String, Box<Diagnostic>),
+}
+
+/// What a run was doing when it failed a check on its state
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub time: f64,
+    pub section: String,
+    /// Trains caught up in it
+    pub trains: Vec<String>,
+    /// Trains arriving and leaving just before, oldest first
+    pub recent_events: Vec<String>,
+}
+
+impl SimError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            SimError::Graph(_) => "graph",
+            SimError::Route(_) => "route",
+            SimError::InvalidArgument(_) => "invalid_argument",
+            SimError::Solver(_) => "solver",
+            SimError::InfeasibleSchedule => "infeasible_schedule",
+            SimError::Cancelled => "cancelled",
+            SimError::Busy(_) => "busy",
+            SimError::Internal(..) => "internal",
+        }
+    }
+
+    /// The same error, with its message prefixed by where it happened
+    pub fn in_context(self, context: &str) -> Self {
+        match self {
+            SimError::Graph(message) => SimError::Graph(format!("{context}: {message}")),
+            SimError::Route(message) => SimError::Route(format!("{context}: {message}")),
+            SimError::InvalidArgument(message) => {
+                SimError::InvalidArgument(format!("{context}: {message}"))
+            }
+            SimError::Solver(message) => SimError::Solver(format!("{context}: {message}")),
+            SimError::Internal(message, diagnostic) => {
+                SimError::Internal(format!("{context}: {message}"), diagnostic)
+            }
+            error @ (SimError::InfeasibleSchedule | SimError::Cancelled | SimError::Busy(_)) => {
+                error
+            }
+        }
+    }
+}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SimError::Graph(message) => write!(f, "invalid graph: {message}"),
+            SimError::Route(message) => write!(f, "invalid route: {message}"),
+            SimError::InvalidArgument(message)
+            | SimError::Solver(message)
+            | SimError::Busy(message) => {
+                write!(f, "{message}")
+            }
+            SimError::InfeasibleSchedule => write!(
+                f,
+                "no conflict free schedule was found, try fewer routes sharing track"
+            ),
+            SimError::Cancelled => write!(f, "cancelled"),
+            SimError::Internal(message, _) => write!(f, "internal error: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for SimError {}
+
+impl Serialize for SimError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let diagnostic = match self {
+            SimError::Internal(_, diagnostic) => Some(diagnostic),
+            _ => None,
+        };
+        let mut error =
+            serializer.serialize_struct("SimError", 2 + diagnostic.is_some() as usize)?;
+        error.serialize_field("code", self.code())?;
+        error.serialize_field("message", &self.to_string())?;
+        if let Some(diagnostic) = diagnostic {
+            error.serialize_field("diagnostic", diagnostic)?;
+        }
+        error.end()
+    }
+}
@@ -1,33 +1,87 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod error;
 mod shortest_path;
 mod simulator;
 
 use simulator::{
-    optimize, shortest_paths, Route, SimulationResults,
-    Simulator, SubwayMap, TrackStationId, SCHEDULE_PERIOD,
+    candidate_route_paths, departures_to_schedule, depot_move_parents, estimate_path_cost,
+    optimize, passenger_cost, preview_loads, reroute_windows, rerouted, route_terminal,
+    schedule_to_departures, shortest_paths, spread_peaks, unused_elements, ArrivalProcess,
+    Breakdown, Checkpoint, CostAudit, Disruption, DisruptionPlan, DivergenceEntity, HeadwayPeriod,
+    HeadwayProfile, Holding, InvariantFailure, Journey, JourneyEvent, JourneySampling,
+    OptimizeOptions, PathCost, PathSegment, PausedRun, Reroute, ReroutePlan, ReroutedPaths, Route,
+    RouteId, Schedule, SimulationConfig, SimulationResults, Simulator, Standby, StationClosure,
+    StationId, StationStatistic, SubwayMap, TimeUnit, TimedTransfer, TrackId, TrackStationId,
+    TrainId, TrainPositions, BLOCK_LENGTH, SCHEDULE_GRANULARITY, SCHEDULE_PERIOD,
+    STATION_DWELL_TIME,
 };
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use petgraph::graph::NodeIndex;
 use petgraph::visit::EdgeRef;
 use petgraph::{Direction, Graph};
 use serde::{Deserialize, Serialize};
+use tauri::Manager;
 
 use rand::rngs::StdRng;
 use rand::seq::IteratorRandom;
 use rand::{Rng, SeedableRng};
 
+use crate::error::SimError;
 use crate::simulator::{SearchMap, Trip, TripData};
 
-#[derive(Deserialize, Serialize, Clone, Hash, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+struct JsPoint {
+    x: f64,
+    y: f64,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
 struct JsNode {
     id: String,
+    #[serde(default)]
+    position: Option<JsPoint>,
+    /// Overrides the default dwell time of trains at this station
+    #[serde(default)]
+    dwell_time: Option<f64>,
+    /// Passengers the platform comfortably holds, alerting when more are waiting
+    #[serde(default)]
+    waiting_capacity: Option<usize>,
+    /// Whether the station has a bypass track express trains can pass a stopped train on
+    #[serde(default)]
+    bypass: bool,
+    /// Whether trains can cross over to reverse here, the only places they can turn back
+    #[serde(default)]
+    crossover: bool,
+    /// Whether trains running late may skip the station, see SimulationConfig::auto_express
+    #[serde(default)]
+    skippable: bool,
+    /// Measured walks between the platforms of routes here, for interchanges where the usual
+    /// transfer doesn't do
+    #[serde(default)]
+    transfer_times: Vec<JsTransferTime>,
+}
+
+/// Minutes riders take to get from the platform of one route to that of another
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+struct JsTransferTime {
+    /// route ids
+    from: String,
+    to: String,
+    time: f64,
 }
 
-#[derive(Deserialize, Serialize, Clone, Hash, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
 struct JsEdge {
     id: String,
     source: String,
@@ -35,31 +89,81 @@ struct JsEdge {
     weight: u16,
     #[serde(alias = "type")]
     r#type: String,
+    /// Optional polyline describing the drawn shape of the edge, from source to target
+    #[serde(default)]
+    geometry: Option<Vec<JsPoint>>,
+    /// Track trains may run on but riders can't use, like yard leads and relay tracks
+    #[serde(default)]
+    non_revenue: bool,
+    /// Track deliberately laid in one direction only, which needs no reverse edge
+    #[serde(default)]
+    one_way: bool,
+    /// Parallel tracks the edge has in its direction, 1 if missing
+    #[serde(default)]
+    tracks: Option<u8>,
+    /// Length of the edge's signal blocks, shorter blocks letting trains run closer together
+    #[serde(default)]
+    block_length: Option<f64>,
 }
 
 impl JsEdge {
-    fn to_edge(&self) -> Edge {
-        Edge {
+    fn to_edge(&self) -> Result<Edge, SimError> {
+        Ok(Edge {
             ty: match &*self.r#type {
                 "track" => EdgeType::Track,
                 "walk" => EdgeType::Walk,
-                _ => panic!("illegal walk type encountered"),
+                ty => {
+                    return Err(SimError::Graph(format!(
+                        "edge {} has unknown type {ty}",
+                        self.id
+                    )))
+                }
             },
             weight: self.weight,
-        }
+            revenue: !self.non_revenue,
+            tracks: self.tracks.unwrap_or(1).max(1),
+            block_length: self
+                .block_length
+                .filter(|length| *length > 0.0)
+                .unwrap_or(BLOCK_LENGTH),
+        })
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum EdgeType {
     Track,
     Walk,
 }
 
+#[derive(Debug, Clone)]
+pub struct Node {
+    dwell_time: Option<f64>,
+    waiting_capacity: Option<usize>,
+    bypass: bool,
+    crossover: bool,
+    skippable: bool,
+    /// Resolved onto the routes by js_routes_to_routes, see Route::transfer_times
+    transfer_times: Vec<JsTransferTime>,
+}
+
+impl Node {
+    // How long trains dwell here, STATION_DWELL_TIME minutes unless the station says
+    fn dwell_time(&self, time_unit: TimeUnit) -> f64 {
+        self.dwell_time
+            .unwrap_or(STATION_DWELL_TIME * time_unit.per_minute())
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Edge {
     ty: EdgeType,
     weight: u16,
+    /// Whether riders can travel along the edge
+    revenue: bool,
+    /// Parallel tracks, each carrying its own queue of trains
+    tracks: u8,
+    block_length: f64,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -70,6 +174,145 @@ struct JsRoute {
     edges: Vec<String>,
     #[serde(default)]
     offset: u64,
+    #[serde(default)]
+    combine_short_stops: bool,
+    /// runs empty trains to reposition them rather than carrying riders
+    #[serde(default)]
+    deadhead: bool,
+    /// ids of nodes on the route its trains don't stop at
+    #[serde(default)]
+    pass_through: Vec<String>,
+    /// id of the route trains continue on after reaching the end of this one
+    #[serde(default)]
+    turnaround: Option<String>,
+    /// only runs trains turning around onto it from routes ending at its first node, sharing
+    /// their fleet instead of dispatching its own
+    #[serde(default)]
+    interlined: bool,
+    /// id of the node trains pull out of and back into
+    #[serde(default)]
+    depot: Option<String>,
+    /// id of the route this one runs part of, sharing its departures in turn
+    #[serde(default)]
+    variant_of: Option<String>,
+    /// length of the route's trains, in the same units as edge weights
+    #[serde(default)]
+    train_length: f64,
+    /// cars in each of the route's trains, which then get their capacity and length from the
+    /// cars of its rolling stock instead of train_length
+    #[serde(default)]
+    cars: Option<u32>,
+    /// name of the config's rolling stock class the route's trains belong to
+    #[serde(default)]
+    rolling_stock: Option<String>,
+    /// how the route's trains are numbered in outputs, routeidx_count if missing
+    #[serde(default)]
+    numbering: Option<JsTrainNumbering>,
+    /// steps the route dispatches trains at, in place of the frequency it would be run at
+    #[serde(default)]
+    timetable: Option<Vec<i32>>,
+    /// spare trains kept at one of the route's nodes to fill gaps in service
+    #[serde(default)]
+    standby: Option<JsStandby>,
+    /// other ends the route's trains take in turn with the one it runs to itself
+    #[serde(default)]
+    branches: Vec<JsBranch>,
+    /// express service over the route, only stopping at some of its nodes
+    #[serde(default)]
+    express: Option<JsExpress>,
+    /// running time supplement as a fraction of the quickest run, like 0.05 for 5%, which
+    /// late trains can catch up by
+    #[serde(default)]
+    padding: f64,
+    /// most trains the route can have out at once, later departures waiting for one
+    #[serde(default)]
+    max_trains: Option<usize>,
+}
+
+/// Express laid over a route, running as often as it does in simulations. The optimizer
+/// starts it without trains and adds them where they help. Outputs list it under its own id.
+#[derive(Deserialize, Serialize, Clone)]
+struct JsExpress {
+    id: String,
+    name: String,
+    /// ids of the route's nodes it stops at
+    stops: Vec<String>,
+    /// the route's own if missing, which has the express leave with its trains, so set it to
+    /// fall between them
+    #[serde(default)]
+    offset: Option<u64>,
+}
+
+/// Branch of a route, running over its nodes up to the first of the branch's and on from
+/// there. Outputs list it under its own id.
+#[derive(Deserialize, Serialize, Clone)]
+struct JsBranch {
+    id: String,
+    name: String,
+    nodes: Vec<String>,
+}
+
+/// trains stored at station, one being sent out whenever the route's last train got there
+/// more than gap minutes ago
+#[derive(Deserialize, Serialize, Clone)]
+struct JsStandby {
+    station: String,
+    trains: u32,
+    gap: f64,
+}
+
+/// Agency style trip numbers, the route's nth train out being prefix followed by
+/// start + n * step
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+struct JsTrainNumbering {
+    prefix: String,
+    start: u32,
+    step: u32,
+}
+
+impl Default for JsTrainNumbering {
+    fn default() -> Self {
+        Self {
+            prefix: String::new(),
+            start: 1,
+            step: 1,
+        }
+    }
+}
+
+/// Labels of trains in outputs, by the numbering of the route they were dispatched on
+#[derive(Clone)]
+struct TrainLabels(HashMap<String, JsTrainNumbering>);
+
+impl TrainLabels {
+    fn new(js_routes: &JsRoutes) -> Result<Self, SimError> {
+        let mut numberings = HashMap::new();
+        for route in js_routes.values() {
+            let Some(numbering) = &route.numbering else {
+                continue;
+            };
+            if numbering.step == 0 {
+                return Err(SimError::Route(format!(
+                    "route {} numbers its trains with a step of 0, giving them all the same number",
+                    route.name
+                )));
+            }
+            numberings.insert(route.id.clone(), numbering.clone());
+        }
+        Ok(Self(numberings))
+    }
+
+    fn label(&self, train: TrainId, route_id_map: &[String]) -> String {
+        match self.0.get(&route_id_map[train.route_idx as usize]) {
+            Some(numbering) => format!(
+                "{}{}",
+                numbering.prefix,
+                numbering.start as u64 + train.count as u64 * numbering.step as u64
+            ),
+            None => format!("{}_{}", train.route_idx, train.count),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -80,302 +323,7300 @@ struct JsGraph {
 
 type JsRoutes = HashMap<String, JsRoute>;
 
+/// Drawn geometry of the graph, keyed by cytoscape id, used to turn simulated
+/// (section, pos) pairs into map coordinates
+#[derive(Default, Clone)]
+struct SectionGeometry {
+    node_positions: HashMap<String, JsPoint>,
+    // polyline and track length for each edge a train can be on
+    edge_polylines: HashMap<String, (Vec<JsPoint>, u16)>,
+}
+
+impl SectionGeometry {
+    fn from_js_graph(js_graph: &JsGraph) -> Self {
+        let node_positions: HashMap<_, _> = js_graph
+            .nodes
+            .iter()
+            .filter_map(|node| node.position.map(|position| (node.id.clone(), position)))
+            .collect();
+        let mut edge_polylines = HashMap::new();
+        for edge in &js_graph.edges {
+            // without a drawn shape fall back to a straight line between the endpoints
+            let polyline = match &edge.geometry {
+                Some(geometry) if geometry.len() >= 2 => geometry.clone(),
+                _ => match (
+                    node_positions.get(&edge.source),
+                    node_positions.get(&edge.target),
+                ) {
+                    (Some(source), Some(target)) => vec![*source, *target],
+                    _ => continue,
+                },
+            };
+            edge_polylines.insert(edge.id.clone(), (polyline, edge.weight));
+        }
+        SectionGeometry {
+            node_positions,
+            edge_polylines,
+        }
+    }
+
+    /// Gets the map coordinates of a train at position pos in the given section
+    fn coordinates(&self, section: &str, pos: f64) -> Option<JsPoint> {
+        if let Some(position) = self.node_positions.get(section) {
+            return Some(*position);
+        }
+        let (polyline, length) = self.edge_polylines.get(section)?;
+        let fraction = if *length == 0 {
+            1.0
+        } else {
+            (pos / *length as f64).clamp(0.0, 1.0)
+        };
+        Some(point_along_polyline(polyline, fraction))
+    }
+}
+
+/// Interpolates the point a given fraction of the way along a polyline by arc length
+fn point_along_polyline(polyline: &[JsPoint], fraction: f64) -> JsPoint {
+    let segment_length =
+        |a: &JsPoint, b: &JsPoint| ((b.x - a.x).powi(2) + (b.y - a.y).powi(2)).sqrt();
+    let total_length: f64 = polyline
+        .windows(2)
+        .map(|w| segment_length(&w[0], &w[1]))
+        .sum();
+    let mut distance_left = fraction * total_length;
+    for w in polyline.windows(2) {
+        let length = segment_length(&w[0], &w[1]);
+        if distance_left <= length && length > 0.0 {
+            let t = distance_left / length;
+            return JsPoint {
+                x: w[0].x + t * (w[1].x - w[0].x),
+                y: w[0].y + t * (w[1].y - w[0].y),
+            };
+        }
+        distance_left -= length;
+    }
+    *polyline.last().unwrap()
+}
+
 #[derive(Serialize)]
 struct ShortestPath {
     length: u16,
     path: Vec<String>,
 }
 
+// Node with the given cytoscape id
+fn station_node(
+    cytoscape_id_map: &HashMap<String, NodeIndex>,
+    id: &str,
+) -> Result<NodeIndex, SimError> {
+    cytoscape_id_map
+        .get(id)
+        .copied()
+        .ok_or_else(|| SimError::Graph(format!("unknown station {id}")))
+}
+
 // TODO: clean up HashMap return situation
-fn js_graph_to_subway_map(
-    js_graph: JsGraph,
-) -> (
+type ConvertedGraph = (
     SubwayMap,
     HashMap<String, NodeIndex>,
     HashMap<TrackStationId, String>,
-) {
+);
+
+fn js_graph_to_subway_map(js_graph: JsGraph) -> Result<ConvertedGraph, SimError> {
     let mut graph: SubwayMap = Graph::new();
     let mut cytoscape_map = HashMap::new();
     let mut petgraph_map = HashMap::new();
     for node in js_graph.nodes {
-        let node_id = graph.add_node(node.id.clone());
+        let node_id = graph.add_node(Node {
+            dwell_time: node.dwell_time,
+            waiting_capacity: node.waiting_capacity,
+            bypass: node.bypass,
+            crossover: node.crossover,
+            skippable: node.skippable,
+            transfer_times: node.transfer_times,
+        });
         cytoscape_map.insert(node.id.clone(), node_id);
         petgraph_map.insert(TrackStationId::Station(node_id), node.id);
     }
     for edge in js_graph.edges {
-        let edge_id = graph.add_edge(
-            *cytoscape_map.get(&edge.source).unwrap(),
-            *cytoscape_map.get(&edge.target).unwrap(),
-            edge.to_edge(),
-        );
+        let source = station_node(&cytoscape_map, &edge.source)?;
+        let target = station_node(&cytoscape_map, &edge.target)?;
+        let edge_id = graph.add_edge(source, target, edge.to_edge()?);
         petgraph_map.insert(TrackStationId::Track(edge_id), edge.id.clone());
 
         // walk edges are represented as one-way in JsGraph for creation convenience; they must
         // be duplicated in the other direction to represent their two-way nature
         if edge.r#type == "walk" {
-            let edge_id = graph.add_edge(
-                *cytoscape_map.get(&edge.target).unwrap(),
-                *cytoscape_map.get(&edge.source).unwrap(),
-                edge.to_edge(),
-            );
+            let edge_id = graph.add_edge(target, source, edge.to_edge()?);
             petgraph_map.insert(TrackStationId::Track(edge_id), edge.id.clone() + "_rev");
         }
     }
-    (graph, cytoscape_map, petgraph_map)
+    Ok((graph, cytoscape_map, petgraph_map))
+}
+
+/// Search maps of the networks path queries were made on, keyed by SearchMap::network_hash and
+/// also written to the app's cache directory, so only the first query on a network builds one
+/// even across restarts
+struct SearchMapCache {
+    dir: Option<PathBuf>,
+    maps: Mutex<HashMap<u64, SearchMap>>,
+}
+
+impl SearchMapCache {
+    // Reads in the search maps earlier runs of the app stored in dir
+    fn load(dir: Option<PathBuf>) -> Self {
+        let mut maps = HashMap::new();
+        let entries = dir.as_ref().and_then(|dir| fs::read_dir(dir).ok());
+        for path in entries
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+        {
+            let Some(hash) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| u64::from_str_radix(stem, 16).ok())
+            else {
+                continue;
+            };
+            // one that doesn't read back is built again on the next query
+            let search_map = fs::read(&path)
+                .ok()
+                .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+                .and_then(SearchMap::from_stored);
+            if let Some(search_map) = search_map {
+                maps.insert(hash, search_map);
+            }
+        }
+        SearchMapCache {
+            dir,
+            maps: Mutex::new(maps),
+        }
+    }
+
+    // Search map of a network, built and stored if there isn't one yet. A map that can't be
+    // written out is still used, the window being sent search_map_not_stored with why.
+    fn get(&self, window: &tauri::Window, subway_map: &SubwayMap, routes: &[Route]) -> SearchMap {
+        let hash = SearchMap::network_hash(subway_map, routes);
+        if let Some(search_map) = self.maps.lock().unwrap().get(&hash) {
+            return search_map.clone();
+        }
+        let search_map = SearchMap::generate(subway_map, routes);
+        // the next start just builds it again
+        if let Err(error) = self.store(hash, &search_map) {
+            let _ = window.emit("search_map_not_stored", error.to_string());
+        }
+        self.maps.lock().unwrap().insert(hash, search_map.clone());
+        search_map
+    }
+
+    // Writes a search map to dir for later runs of the app to load
+    fn store(&self, hash: u64, search_map: &SearchMap) -> io::Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        fs::create_dir_all(dir)?;
+        let file = File::create(dir.join(format!("{hash:016x}.json")))?;
+        serde_json::to_writer(BufWriter::new(file), &search_map.to_stored())
+            .map_err(io::Error::from)
+    }
 }
 
 #[tauri::command]
-fn shortest_path(js_graph: JsGraph, js_routes: JsRoutes, source: String, target: String) {
-    let (graph, map, _) = js_graph_to_subway_map(js_graph);
-    let (routes, _) = js_routes_to_routes(js_routes, &graph, &map);
-    let mut search_map = SearchMap::generate(&graph, &routes);
-    let start = map[&source];
-    let end = map[&target];
+fn shortest_path(
+    window: tauri::Window,
+    search_maps: tauri::State<'_, SearchMapCache>,
+    js_graph: JsGraph,
+    js_routes: JsRoutes,
+    source: String,
+    target: String,
+) -> Result<(), SimError> {
+    let (graph, map, _) = js_graph_to_subway_map(js_graph)?;
+    let (routes, _) = js_routes_to_routes(js_routes, &graph, &map)?;
+    let mut search_map = search_maps.get(&window, &graph, &routes);
+    let start = station_node(&map, &source)?;
+    let end = station_node(&map, &target)?;
     let paths = shortest_paths(start, end, &mut search_map, 3);
     println!("Shortest paths: {:?}", paths);
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct JsSegmentCost {
+    routes: Vec<String>,
+    start_station: String,
+    end_station: String,
+    in_vehicle_time: f64,
+    /// trains of the routes in the period, which give the wait
+    frequency: i64,
+    wait_time: f64,
+    walk_time: f64,
+}
+
+#[derive(Serialize)]
+struct JsPathCost {
+    segments: Vec<JsSegmentCost>,
+    in_vehicle_time: f64,
+    wait_time: f64,
+    walk_time: f64,
+    transfers: usize,
+    /// weighted cost as used by the optimizer
+    cost: f64,
+}
+
+impl JsPathCost {
+    fn from_path_cost(path_cost: PathCost, petgraph_map: &HashMap<TrackStationId, String>) -> Self {
+        JsPathCost {
+            in_vehicle_time: path_cost.segments.iter().map(|s| s.in_vehicle_time).sum(),
+            wait_time: path_cost.segments.iter().map(|s| s.wait_time).sum(),
+            walk_time: path_cost.segments.iter().map(|s| s.walk_time).sum(),
+            transfers: path_cost.segments.len().saturating_sub(1),
+            cost: path_cost.cost,
+            segments: path_cost
+                .segments
+                .into_iter()
+                .map(|s| JsSegmentCost {
+                    routes: s.routes,
+                    start_station: petgraph_map[&TrackStationId::Station(s.start_station)].clone(),
+                    end_station: petgraph_map[&TrackStationId::Station(s.end_station)].clone(),
+                    in_vehicle_time: s.in_vehicle_time,
+                    frequency: s.frequency,
+                    wait_time: s.wait_time,
+                    walk_time: s.walk_time,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Costs of one itinerary under each of the compared schedules.
+/// None if the itinerary has no service under that schedule
+#[derive(Serialize)]
+struct JsItineraryComparison {
+    baseline: Option<JsPathCost>,
+    candidate: Option<JsPathCost>,
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+fn compare_itineraries(
+    window: tauri::Window,
+    search_maps: tauri::State<'_, SearchMapCache>,
+    js_graph: JsGraph,
+    js_routes: JsRoutes,
+    source: String,
+    target: String,
+    baseline: Schedule,
+    candidate: Schedule,
+    departure_time: Option<i64>,
+    k: Option<usize>,
+) -> Result<Vec<JsItineraryComparison>, SimError> {
+    let (graph, map, petgraph_map) = js_graph_to_subway_map(js_graph)?;
+    let (routes, _) = js_routes_to_routes(js_routes, &graph, &map)?;
+    let mut search_map = search_maps.get(&window, &graph, &routes);
+    let start = station_node(&map, &source)?;
+    let end = station_node(&map, &target)?;
+    let paths = shortest_paths(start, end, &mut search_map, k.unwrap_or(3));
+    let departure_time = departure_time.unwrap_or_default();
+
+    Ok(paths
+        .iter()
+        .map(|path| {
+            let cost_under = |schedule| {
+                estimate_path_cost(&search_map, schedule, path, departure_time)
+                    .map(|path_cost| JsPathCost::from_path_cost(path_cost, &petgraph_map))
+            };
+            JsItineraryComparison {
+                baseline: cost_under(&baseline),
+                candidate: cost_under(&candidate),
+            }
+        })
+        .collect())
+}
+
+// Tracks between the given nodes, keyed by the node they leave
+fn path_tracks(
+    node_ids: &HashSet<NodeIndex>,
+    subway_map: &SubwayMap,
+) -> HashMap<StationId, TrackId> {
+    let mut station_to = HashMap::with_capacity(node_ids.len());
+    for node in node_ids {
+        // walk links between stations of the route aren't track it runs on
+        for neighbor_edge in subway_map
+            .edges_directed(*node, Direction::Outgoing)
+            .filter(|edge| edge.weight().ty != EdgeType::Walk)
+        {
+            if node_ids.contains(&neighbor_edge.target()) {
+                station_to.insert(*node, neighbor_edge.id());
+                break;
+            }
+        }
+    }
+    station_to
 }
 
 fn js_routes_to_routes(
     js_routes: JsRoutes,
     subway_map: &SubwayMap,
     cytoscape_id_map: &HashMap<String, NodeIndex>,
-) -> (Vec<Route>, Vec<String>) {
+) -> Result<(Vec<Route>, Vec<String>), SimError> {
     let mut route_id_map = Vec::new();
     let mut routes = Vec::new();
+    let mut linked_routes = Vec::new();
     for (_, route) in js_routes {
-        let mut station_to = HashMap::with_capacity(route.nodes.len());
-        let node_ids: HashSet<_> = route.nodes.iter().map(|id| cytoscape_id_map[id]).collect();
-
-        for node in &node_ids {
-            for neighbor_edge in subway_map.edges_directed(*node, Direction::Outgoing) {
-                if node_ids.contains(&neighbor_edge.target()) {
-                    station_to.insert(*node, neighbor_edge.id());
-                    break;
+        let node = |id: &String| {
+            cytoscape_id_map.get(id).copied().ok_or_else(|| {
+                SimError::Route(format!(
+                    "route {} stops at unknown station {id}",
+                    route.name
+                ))
+            })
+        };
+        let start_station =
+            node(route.nodes.first().ok_or_else(|| {
+                SimError::Route(format!("route {} has no stations", route.name))
+            })?)?;
+        let node_ids: HashSet<_> = route.nodes.iter().map(node).collect::<Result<_, _>>()?;
+        let pass_through = route
+            .pass_through
+            .iter()
+            .map(node)
+            .collect::<Result<_, _>>()?;
+        let depot = route.depot.as_ref().map(node).transpose()?;
+        let standby = route
+            .standby
+            .as_ref()
+            .map(|standby| {
+                let station = node(&standby.station)?;
+                if !node_ids.contains(&station) {
+                    return Err(SimError::Route(format!(
+                        "route {} keeps standby trains at {}, which it doesn't run through",
+                        route.name, standby.station
+                    )));
                 }
-            }
+                if !(standby.gap > 0.0 && standby.gap.is_finite()) {
+                    return Err(SimError::InvalidArgument(format!(
+                        "gap before route {} sends out a standby train must be a positive number of minutes",
+                        route.name
+                    )));
+                }
+                Ok(Standby {
+                    station,
+                    trains: standby.trains,
+                    gap: standby.gap,
+                })
+            })
+            .transpose()?;
+        // timetables only go by the departures of the route itself
+        if route.timetable.is_some()
+            && (route.interlined || route.variant_of.is_some() || depot.is_some())
+        {
+            return Err(SimError::Route(format!(
+                "route {} has a timetable but doesn't dispatch its own trains from its first station",
+                route.name
+            )));
         }
-
+        if !(route.padding >= 0.0 && route.padding.is_finite()) {
+            return Err(SimError::InvalidArgument(format!(
+                "padding of route {} must be a fraction of its running time of 0 or more",
+                route.name
+            )));
+        }
+        if route.cars == Some(0) {
+            return Err(SimError::InvalidArgument(format!(
+                "trains of route {} must have at least one car",
+                route.name
+            )));
+        }
+        if !route.branches.is_empty() && route.variant_of.is_some() {
+            return Err(SimError::Route(format!(
+                "route {} runs part of another route, so can't branch itself",
+                route.name
+            )));
+        }
+        let station_to = path_tracks(&node_ids, subway_map);
+        // branches take the route's path as far as they leave it
+        let branches = route
+            .branches
+            .iter()
+            .map(|branch| {
+                let nodes: Vec<_> = branch.nodes.iter().map(node).collect::<Result<_, _>>()?;
+                let fork = *nodes.first().ok_or_else(|| {
+                    SimError::Route(format!("branch {} has no stations", branch.name))
+                })?;
+                let mut branch_to = HashMap::new();
+                let mut at = start_station;
+                while at != fork {
+                    let track = station_to
+                        .get(&at)
+                        .filter(|_| !branch_to.contains_key(&at))
+                        .ok_or_else(|| {
+                            SimError::Route(format!(
+                                "branch {} starts at {}, which route {} doesn't run through",
+                                branch.name, branch.nodes[0], route.name
+                            ))
+                        })?;
+                    branch_to.insert(at, *track);
+                    at = subway_map.edge_endpoints(*track).unwrap().1;
+                }
+                branch_to.extend(path_tracks(&nodes.into_iter().collect(), subway_map));
+                Ok(branch_to)
+            })
+            .collect::<Result<Vec<_>, SimError>>()?;
+        let express_stops = route
+            .express
+            .as_ref()
+            .map(|express| {
+                express
+                    .stops
+                    .iter()
+                    .map(node)
+                    .collect::<Result<HashSet<_>, _>>()
+            })
+            .transpose()?;
         routes.push(Route {
             name: route.name,
-            start_station: cytoscape_id_map[&route.nodes[0]],
+            start_station,
             station_to,
             offset: route.offset,
+            combine_short_stops: route.combine_short_stops,
+            deadhead: route.deadhead,
+            pass_through,
+            turnaround: None,
+            depot,
+            parent: None,
+            dispatched: !route.interlined,
+            variant_of: None,
+            train_length: route.train_length.max(0.0),
+            cars: route.cars,
+            rolling_stock: route.rolling_stock,
+            transfer_times: HashMap::new(),
+            timetable: route.timetable,
+            standby,
+            overlay: false,
+            padding: route.padding,
+            max_trains: route.max_trains,
         });
         route_id_map.push(route.id.clone());
+        linked_routes.push((route.turnaround, route.variant_of));
+        // each branch is a variant of the route, so they take turns at its departures
+        let parent = routes.last().unwrap().clone();
+        if let (Some(express), Some(stops)) = (route.express, express_stops) {
+            if stops.len() < 2 || !stops.is_subset(&node_ids) {
+                return Err(SimError::Route(format!(
+                    "express {} must stop at two or more of route {}'s nodes, and only those",
+                    express.name, parent.name
+                )));
+            }
+            if route_id_map.contains(&express.id) {
+                return Err(SimError::Route(format!(
+                    "express {} has the id of another route",
+                    express.name
+                )));
+            }
+            routes.push(Route {
+                name: express.name,
+                pass_through: node_ids.difference(&stops).copied().collect(),
+                offset: express.offset.unwrap_or(parent.offset),
+                turnaround: None,
+                depot: None,
+                variant_of: None,
+                timetable: None,
+                standby: None,
+                overlay: true,
+                max_trains: None,
+                ..parent.clone()
+            });
+            route_id_map.push(express.id);
+            linked_routes.push((None, None));
+        }
+        for (branch, station_to) in route.branches.into_iter().zip(branches) {
+            if route_id_map.contains(&branch.id) {
+                return Err(SimError::Route(format!(
+                    "branch {} has the id of another route",
+                    branch.name
+                )));
+            }
+            routes.push(Route {
+                name: branch.name,
+                station_to,
+                turnaround: None,
+                depot: None,
+                timetable: None,
+                standby: None,
+                ..parent.clone()
+            });
+            route_id_map.push(branch.id);
+            linked_routes.push((None, Some(route.id.clone())));
+        }
+    }
+    // turnarounds and variants can refer to routes later in the map, so are resolved once all
+    // are numbered
+    let resolve = |id: Option<String>| {
+        id.and_then(|id| route_id_map.iter().position(|other| *other == id))
+            .map(|idx| RouteId(idx as u32))
+    };
+    for (route, (turnaround, variant_of)) in routes.iter_mut().zip(linked_routes) {
+        route.turnaround = resolve(turnaround);
+        route.variant_of = resolve(variant_of);
+    }
+    for station in subway_map.node_indices() {
+        for transfer in &subway_map[station].transfer_times {
+            let route = |id: &String| {
+                resolve(Some(id.clone())).ok_or_else(|| {
+                    SimError::Route(format!("transfer time names unknown route {id}"))
+                })
+            };
+            let (from, to) = (route(&transfer.from)?, route(&transfer.to)?);
+            if !(transfer.time >= 0.0 && transfer.time.is_finite()) {
+                return Err(SimError::InvalidArgument(format!(
+                    "transfer time from {} to {} must be a positive number of minutes",
+                    transfer.from, transfer.to
+                )));
+            }
+            routes[from.0 as usize]
+                .transfer_times
+                .insert((station, to), transfer.time);
+        }
+    }
+    // an interlined route gets all its trains from routes ending where it starts
+    for (idx, route) in routes.iter().enumerate() {
+        let fed = routes.iter().any(|other| {
+            other.turnaround == Some(RouteId(idx as u32))
+                && route_terminal(subway_map, other) == route.start_station
+        });
+        if !route.dispatched && !fed {
+            return Err(SimError::Route(format!(
+                "route {} is interlined, but no route turns around onto it at its first station",
+                route.name
+            )));
+        }
+    }
+    // trains and statistics of the depot moves simulations add go by the route they're for
+    for parent in depot_move_parents(subway_map, &routes) {
+        route_id_map.push(route_id_map[parent.0 as usize].clone());
+    }
+    Ok((routes, route_id_map))
+}
+
+fn train_positions_to_js(
+    positions: TrainPositions,
+    petgraph_map: &HashMap<TrackStationId, String>,
+    geometry: &SectionGeometry,
+) -> JsTrainPositions {
+    JsTrainPositions {
+        time: positions.time,
+        trains: positions
+            .trains
+            .into_iter()
+            .map(|p| {
+                let curr_section = petgraph_map[&p.curr_section].clone();
+                JsTrainPosition {
+                    id: (p.id.route_idx, p.id.count),
+                    coordinates: geometry.coordinates(&curr_section, p.pos),
+                    curr_section,
+                    pos: p.pos,
+                    distance_travelled: p.distance_travelled,
+                    load: p.load,
+                }
+            })
+            .collect(),
+    }
+}
+
+fn checkpoint_to_js(
+    checkpoint: Checkpoint,
+    petgraph_map: &HashMap<TrackStationId, String>,
+    route_id_map: &[String],
+    geometry: &SectionGeometry,
+) -> JsCheckpoint {
+    JsCheckpoint {
+        time: checkpoint.time,
+        passengers_delivered: checkpoint.passengers_delivered,
+        average_journey_time: checkpoint.average_journey_time,
+        passengers_waiting: checkpoint.passengers_waiting,
+        trains_in_service: checkpoint.trains_in_service,
+        arrivals: checkpoint
+            .arrivals
+            .into_iter()
+            .map(|(station, arrivals)| {
+                let arrivals = arrivals
+                    .into_iter()
+                    .map(|(route, times)| (route_id_map[route.0 as usize].clone(), times))
+                    .collect();
+                (
+                    petgraph_map[&TrackStationId::Station(station)].clone(),
+                    arrivals,
+                )
+            })
+            .collect(),
+        positions: train_positions_to_js(checkpoint.positions, petgraph_map, geometry),
     }
-    (routes, route_id_map)
+}
+
+// Fails with where it happened if a run stopped on a check it failed
+fn check_run(
+    failure: Option<&InvariantFailure>,
+    petgraph_map: &HashMap<TrackStationId, String>,
+    route_id_map: &[String],
+    labels: &TrainLabels,
+) -> Result<(), SimError> {
+    match failure {
+        Some(failure) => Err(failure.clone().into_error(
+            |section| petgraph_map[&section].clone(),
+            |train| labels.label(train, route_id_map),
+        )),
+        None => Ok(()),
+    }
+}
+
+// Delay trains lost held up behind broken down trains, totalled by the route they run
+fn route_delays<'a>(
+    delays: impl IntoIterator<Item = (&'a TrainId, &'a f64)>,
+    route_id_map: &[String],
+) -> HashMap<String, f64> {
+    let mut by_route: HashMap<String, f64> = HashMap::new();
+    for (train, delay) in delays {
+        *by_route
+            .entry(route_id_map[train.route_idx as usize].clone())
+            .or_default() += delay;
+    }
+    by_route
 }
 
 fn simulation_results_to_js(
     simulation_results: SimulationResults,
     petgraph_map: &HashMap<TrackStationId, String>,
     route_id_map: &[String],
+    labels: &TrainLabels,
+    geometry: &SectionGeometry,
+    run_id: u64,
 ) -> JsSimulationResults {
+    let train = |id: TrainId| labels.label(id, route_id_map);
+    let mut peak_loads = HashMap::new();
+    for positions in &simulation_results.train_positions {
+        for p in &positions.trains {
+            if let TrackStationId::Track(_) = p.curr_section {
+                let peak = peak_loads
+                    .entry(petgraph_map[&p.curr_section].clone())
+                    .or_insert(0);
+                *peak = p.load.max(*peak);
+            }
+        }
+    }
+
     let train_positions: Vec<_> = simulation_results
         .train_positions
         .into_iter()
-        .map(|t| JsTrainPositions {
-            time: t.time,
-            trains: t
-                .trains
-                .into_iter()
-                .map(|p| JsTrainPosition {
-                    id: (p.id.route_idx, p.id.count),
-                    curr_section: petgraph_map[&p.curr_section].clone(),
-                    pos: p.pos,
-                    distance_travelled: p.distance_travelled,
-                })
-                .collect(),
-        })
+        .map(|t| train_positions_to_js(t, petgraph_map, geometry))
         .collect();
 
     let train_to_route = simulation_results
         .train_to_route
         .into_iter()
-        .map(|(train, route)| {
-            (
-                format!("{}_{}", train.route_idx, train.count),
-                route_id_map[route.0 as usize].clone(),
-            )
-        })
+        .map(|(id, route)| (train(id), route_id_map[route.0 as usize].clone()))
         .collect();
 
-    let station_statistics = simulation_results
-        .station_statistics
+    let station_statistics = station_statistics_to_js(
+        &simulation_results.station_statistics,
+        &simulation_results.nominal_headways,
+        &simulation_results.terminals,
+        petgraph_map,
+        route_id_map,
+        &StatisticsOptions::default(),
+    );
+
+    let dispatch_deviations = simulation_results
+        .dispatch_deviations
+        .into_iter()
+        .map(|(r_id, deviation)| (route_id_map[r_id.0 as usize].clone(), deviation))
+        .collect();
+
+    let timetable = simulation_results
+        .departures
         .into_iter()
+        .map(|(r_id, departures)| (route_id_map[r_id.0 as usize].clone(), departures))
+        .collect();
+
+    JsSimulationResults {
+        run_id,
+        timetable,
+        frequency_plan: None,
+        profile: None,
+        cost_audit: None,
+        restarts: None,
+        seed: None,
+        snapshot: None,
+        holding: None,
+        gap_trains: None,
+        periods: None,
+        fleet_waits: simulation_results
+            .fleet_waits
+            .iter()
+            .map(|wait| JsFleetWait {
+                route: route_id_map[wait.route.0 as usize].clone(),
+                scheduled: wait.scheduled,
+                dispatched: wait.dispatched,
+            })
+            .collect(),
+        transfers: None,
+        maintenance: None,
+        closures: None,
+        train_trips: simulation_results
+            .train_trips
+            .iter()
+            .map(|trip| JsTrainTrip {
+                train: train(trip.train),
+                route: route_id_map[trip.route.0 as usize].clone(),
+                start: trip.start,
+                end: trip.end,
+            })
+            .collect(),
+        branch_headways: simulation_results
+            .branch_headways
+            .into_iter()
+            .map(|branch| {
+                let route = |id: RouteId| route_id_map[id.0 as usize].clone();
+                JsBranchHeadways {
+                    station: petgraph_map[&TrackStationId::Station(branch.station)].clone(),
+                    pattern: branch
+                        .pattern
+                        .into_iter()
+                        .map(|(time, id)| (time, route(id)))
+                        .collect(),
+                    branches: branch
+                        .branch_headways
+                        .into_iter()
+                        .map(|(id, headways)| {
+                            let mean = headways.iter().sum::<f64>() / headways.len() as f64;
+                            let max = headways.iter().copied().fold(0.0, f64::max);
+                            let headway = JsBranchHeadway {
+                                headways,
+                                mean,
+                                max,
+                            };
+                            (route(id), headway)
+                        })
+                        .collect(),
+                    irregular: branch
+                        .irregular
+                        .into_iter()
+                        .map(|irregular| JsIrregularAlternation {
+                            time: irregular.time,
+                            route: route(irregular.route),
+                            repeats: irregular.repeats,
+                        })
+                        .collect(),
+                }
+            })
+            .collect(),
+        overtakes: simulation_results
+            .overtakes
+            .iter()
+            .map(|overtake| JsOvertake {
+                station: petgraph_map[&TrackStationId::Station(overtake.station)].clone(),
+                train: train(overtake.train),
+                overtaken: train(overtake.overtaken),
+                time: overtake.time,
+            })
+            .collect(),
+        crowding_skips: simulation_results.crowding_skips,
+        crowding_detours: simulation_results.crowding_detours,
+        skipped_stops: simulation_results
+            .skipped_stops
+            .iter()
+            .map(|skipped| JsSkippedStop {
+                station: petgraph_map[&TrackStationId::Station(skipped.station)].clone(),
+                train: train(skipped.train),
+                time: skipped.time,
+                lateness: skipped.lateness,
+                passed_by: skipped.passed_by,
+            })
+            .collect(),
+        bypass_use: simulation_results
+            .bypass_use
+            .iter()
+            .map(|(station, minutes)| {
+                (
+                    petgraph_map[&TrackStationId::Station(*station)].clone(),
+                    *minutes,
+                )
+            })
+            .collect(),
+        incidents: simulation_results
+            .incidents
+            .iter()
+            .map(|incident| JsIncident {
+                train: train(incident.train),
+                section: petgraph_map[&incident.section].clone(),
+                start: incident.start,
+                end: incident.end,
+                total_delay: incident.delays.values().sum(),
+                delays: incident
+                    .delays
+                    .iter()
+                    .map(|(id, delay)| (train(*id), *delay))
+                    .collect(),
+                route_delays: route_delays(&incident.delays, route_id_map),
+                rescued: incident.rescued,
+                cleared: incident.cleared,
+            })
+            .collect(),
+        knock_on_delays: route_delays(
+            simulation_results
+                .incidents
+                .iter()
+                .flat_map(|incident| &incident.delays),
+            route_id_map,
+        ),
+        crowding_alerts: simulation_results
+            .crowding_alerts
+            .into_iter()
+            .map(|alert| JsCrowdingAlert {
+                station: petgraph_map[&TrackStationId::Station(alert.station)].clone(),
+                start: alert.start,
+                end: alert.end,
+                peak_waiting: alert.peak_waiting,
+            })
+            .collect(),
+        journeys: simulation_results
+            .journeys
+            .into_iter()
+            .map(|journey| journey_to_js(journey, petgraph_map, &train))
+            .collect(),
+        train_positions,
+        train_to_route,
+        station_statistics,
+        dispatch_deviations,
+        peak_loads,
+        passengers_delivered: simulation_results.journey_times.len(),
+        average_journey_time: mean(&simulation_results.journey_times),
+        dispatches: simulation_results.dispatches,
+        peak_trains_in_service: simulation_results.peak_trains_in_service,
+        trains_in_service: simulation_results
+            .in_service
+            .iter()
+            .map(|in_service| JsTrainsInService {
+                time: in_service.time,
+                total: in_service.by_route.values().sum(),
+                by_route: in_service
+                    .by_route
+                    .iter()
+                    .map(|(r_id, trains)| (route_id_map[r_id.0 as usize].clone(), *trains))
+                    .collect(),
+            })
+            .collect(),
+        padding: simulation_results
+            .padding
+            .iter()
+            .map(|(r_id, padding)| {
+                (
+                    route_id_map[r_id.0 as usize].clone(),
+                    JsPaddingUse {
+                        scheduled: padding.scheduled,
+                        used: padding.used,
+                        utilization: (padding.scheduled > 0.0)
+                            .then(|| padding.used / padding.scheduled),
+                    },
+                )
+            })
+            .collect(),
+        peak_trains_by_route: simulation_results.in_service.iter().fold(
+            HashMap::new(),
+            |mut peaks, in_service| {
+                for (r_id, trains) in &in_service.by_route {
+                    let peak = peaks
+                        .entry(route_id_map[r_id.0 as usize].clone())
+                        .or_insert(0);
+                    *peak = (*peak).max(*trains);
+                }
+                peaks
+            },
+        ),
+        warm_up: simulation_results.warm_up,
+        time_unit: simulation_results.time_unit,
+        disruption_statistics: JsDisruptionStatistics {
+            delayed_trains: simulation_results.disruption_delays.len(),
+            total_delay: simulation_results.disruption_delays.values().sum(),
+            max_delay: simulation_results
+                .disruption_delays
+                .values()
+                .copied()
+                .fold(0.0, f64::max),
+            short_turns: simulation_results
+                .short_turns
+                .iter()
+                .map(|(_, station)| petgraph_map[&TrackStationId::Station(*station)].clone())
+                .collect(),
+        },
+        operating_distances: simulation_results
+            .operating_distances
+            .iter()
+            .map(|(r_id, distance)| {
+                (
+                    route_id_map[r_id.0 as usize].clone(),
+                    JsOperatingDistance {
+                        revenue: distance.revenue,
+                        deadhead: distance.deadhead,
+                    },
+                )
+            })
+            .collect(),
+        bunching: simulation_results
+            .bunching
+            .iter()
+            .fold(HashMap::new(), |mut bunching, b| {
+                bunching
+                    .entry(route_id_map[b.route.0 as usize].clone())
+                    .or_insert_with(HashMap::new)
+                    .entry(petgraph_map[&TrackStationId::Station(b.station)].clone())
+                    .or_insert_with(Vec::new)
+                    .push(JsBunching {
+                        time: b.time,
+                        headway: b.headway,
+                    });
+                bunching
+            }),
+        schedule_deviations: simulation_results.schedule_deviations.iter().fold(
+            HashMap::new(),
+            |mut deviations, ((r_id, station), late)| {
+                deviations
+                    .entry(route_id_map[r_id.0 as usize].clone())
+                    .or_insert_with(HashMap::new)
+                    .insert(
+                        petgraph_map[&TrackStationId::Station(*station)].clone(),
+                        JsScheduleDeviation {
+                            mean: late.iter().sum::<f64>() / late.len() as f64,
+                            max: late.iter().copied().fold(f64::MIN, f64::max),
+                            min: late.iter().copied().fold(f64::MAX, f64::min),
+                            trains: late.len(),
+                        },
+                    );
+                deviations
+            },
+        ),
+        ridership: simulation_results
+            .boardings
+            .iter()
+            .map(|(r_id, boardings)| (route_id_map[r_id.0 as usize].clone(), *boardings))
+            .collect(),
+        route_loads: simulation_results
+            .route_loads
+            .iter()
+            .map(|(r_id, load)| {
+                let route_load = JsRouteLoad {
+                    capacity: load.capacity,
+                    cars: load.cars,
+                    average_load_factor: mean(&load.load_factors),
+                    peak_load_factor: load.load_factors.iter().copied().reduce(f64::max),
+                };
+                (route_id_map[r_id.0 as usize].clone(), route_load)
+            })
+            .collect(),
+        energy: simulation_results
+            .energy
+            .into_iter()
+            .map(|(r_id, by_hour)| {
+                (
+                    route_id_map[r_id.0 as usize].clone(),
+                    JsRouteEnergy {
+                        total: by_hour.iter().sum(),
+                        by_hour,
+                    },
+                )
+            })
+            .collect(),
+    }
+}
+
+fn station_statistics_to_js(
+    station_statistics: &HashMap<StationId, StationStatistic>,
+    nominal_headways: &HashMap<RouteId, f64>,
+    terminals: &HashMap<RouteId, StationId>,
+    petgraph_map: &HashMap<TrackStationId, String>,
+    route_id_map: &[String],
+    options: &StatisticsOptions,
+) -> HashMap<String, JsStationStatistic> {
+    let route_statistics = |arrival_times: &mut dyn Iterator<Item = (&RouteId, &Vec<f64>)>| {
+        arrival_times
+            .map(|(r_id, data)| {
+                (
+                    route_id_map[r_id.0 as usize].clone(),
+                    calculate_arrival_time_statistics(
+                        data.clone(),
+                        nominal_headways.get(r_id).copied(),
+                        options,
+                    ),
+                )
+            })
+            .collect()
+    };
+
+    station_statistics
+        .iter()
         .map(|(id, s)| {
-            let overall_arrival_times = (s.arrival_times.len() > 1).then(|| {
+            let is_terminal = |r_id: &RouteId| terminals.get(r_id) == Some(id);
+            // arrivals riders see, leaving out trains just dispatched here if asked to
+            let rider_facing: HashMap<_, _> = s
+                .arrival_times
+                .iter()
+                .filter(|(r_id, _)| !(options.exclude_terminals && is_terminal(r_id)))
+                .collect();
+            let overall_arrival_times = (rider_facing.len() > 1).then(|| {
                 let mut data = Vec::new();
-                s.arrival_times
+                rider_facing
                     .values()
-                    .for_each(|arrival_time| data.extend(arrival_time));
+                    .for_each(|arrival_time| data.extend(*arrival_time));
                 data.sort_unstable_by(f64::total_cmp);
-                calculate_arrival_time_statistics(data)
+                // routes sharing a station combine their frequencies
+                let combined_frequency: f64 = rider_facing
+                    .keys()
+                    .filter_map(|r_id| nominal_headways.get(r_id))
+                    .map(|headway| 1.0 / headway)
+                    .sum();
+                calculate_arrival_time_statistics(data, Some(1.0 / combined_frequency), options)
             });
-            let arrival_times = s
-                .arrival_times
+            let last_arrival = rider_facing
+                .values()
+                .filter_map(|arrival_times| arrival_times.last())
+                .copied()
+                .fold(0.0, f64::max);
+            let bands = options
+                .bands
+                .bands(last_arrival)
                 .into_iter()
-                .map(|(r_id, data)| {
-                    (
-                        route_id_map[r_id.0 as usize].clone(),
-                        calculate_arrival_time_statistics(data),
-                    )
+                .map(|band| {
+                    let arrival_times = rider_facing
+                        .iter()
+                        .map(|(r_id, data)| (*r_id, band_arrivals(data, &band)))
+                        .filter(|(_, data)| !data.is_empty())
+                        .map(|(r_id, data)| {
+                            (
+                                route_id_map[r_id.0 as usize].clone(),
+                                calculate_arrival_time_statistics(
+                                    data,
+                                    nominal_headways.get(r_id).copied(),
+                                    options,
+                                ),
+                            )
+                        })
+                        .collect();
+                    JsBandStatistic {
+                        band,
+                        arrival_times,
+                    }
                 })
                 .collect();
+            let arrival_times = route_statistics(&mut rider_facing.into_iter());
+            let terminal_arrival_times =
+                route_statistics(&mut s.arrival_times.iter().filter(|(r_id, _)| is_terminal(r_id)));
             (
-                petgraph_map[&TrackStationId::Station(id)].clone(),
+                petgraph_map[&TrackStationId::Station(*id)].clone(),
                 JsStationStatistic {
                     arrival_times,
+                    terminal_arrival_times,
                     overall_arrival_times,
+                    bands,
+                    average_passenger_wait: mean(&s.passenger_waits),
                 },
             )
         })
-        .collect();
+        .collect()
+}
 
-    JsSimulationResults {
-        train_positions,
-        train_to_route,
-        station_statistics,
+/// The raw output of a run kept around so statistics can be recomputed without re-simulating
+struct StoredRun {
+    station_statistics: HashMap<StationId, StationStatistic>,
+    nominal_headways: HashMap<RouteId, f64>,
+    terminals: HashMap<RouteId, StationId>,
+    petgraph_map: HashMap<TrackStationId, String>,
+    route_id_map: Vec<String>,
+}
+
+#[derive(Default)]
+struct RunStore {
+    runs: Mutex<HashMap<u64, StoredRun>>,
+    next_id: AtomicU64,
+}
+
+impl RunStore {
+    fn insert(
+        &self,
+        simulation_results: &SimulationResults,
+        petgraph_map: &HashMap<TrackStationId, String>,
+        route_id_map: &[String],
+    ) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.runs.lock().unwrap().insert(
+            id,
+            StoredRun {
+                station_statistics: simulation_results.station_statistics.clone(),
+                nominal_headways: simulation_results.nominal_headways.clone(),
+                terminals: simulation_results.terminals.clone(),
+                petgraph_map: petgraph_map.clone(),
+                route_id_map: route_id_map.to_vec(),
+            },
+        );
+        id
+    }
+
+    fn remove(&self, run_id: u64) {
+        self.runs.lock().unwrap().remove(&run_id);
+    }
+}
+
+/// How riders are assumed to arrive at stations when estimating their wait
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+enum RiderArrival {
+    /// Riders show up at random, so are more likely to arrive during long headways
+    #[default]
+    Random,
+    /// Riders wait half the mean headway
+    HalfHeadway,
+    /// Riders know the schedule and arrive this many minutes early, or wait a full headway
+    /// if it is shorter
+    Informed { buffer: f64 },
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+struct StatisticsOptions {
+    rider_arrival: RiderArrival,
+    /// headway percentiles to report, between 0 and 100
+    percentiles: Vec<f64>,
+    /// arrivals before this time are ignored
+    warm_up: f64,
+    /// leave routes out of the arrival statistics of the station their trains are dispatched
+    /// from, where back to back dispatches make headways look shorter than riders see them.
+    /// They are still reported in terminal_arrival_times.
+    exclude_terminals: bool,
+    /// time of day bands to also report arrival statistics for, like the peak and shoulders
+    bands: StatisticsBands,
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+enum StatisticsBands {
+    /// only statistics over the whole run
+    #[default]
+    None,
+    /// the SCHEDULE_GRANULARITY minute periods schedules set frequencies for
+    SchedulePeriods,
+    Custom(Vec<JsTimeBand>),
+}
+
+/// A named stretch of the run over [start, end)
+#[derive(Deserialize, Serialize, Clone)]
+struct JsTimeBand {
+    name: String,
+    start: f64,
+    end: f64,
+}
+
+impl StatisticsBands {
+    // Bands covering arrivals up to last_arrival
+    fn bands(&self, last_arrival: f64) -> Vec<JsTimeBand> {
+        match self {
+            StatisticsBands::None => Vec::new(),
+            StatisticsBands::SchedulePeriods => {
+                let periods = (last_arrival / SCHEDULE_GRANULARITY as f64).floor() as i64 + 1;
+                (0..periods.max(0))
+                    .map(|period| {
+                        let start = period * SCHEDULE_GRANULARITY;
+                        let end = start + SCHEDULE_GRANULARITY;
+                        JsTimeBand {
+                            name: format!("{start}-{end}"),
+                            start: start as f64,
+                            end: end as f64,
+                        }
+                    })
+                    .collect()
+            }
+            StatisticsBands::Custom(bands) => bands.clone(),
+        }
+    }
+}
+
+// Arrivals ending the headways which end within the band, so starting with the last one before
+// it. Arrivals must be sorted.
+fn band_arrivals(arrival_times: &[f64], band: &JsTimeBand) -> Vec<f64> {
+    let first = arrival_times.partition_point(|time| *time < band.start);
+    let end = arrival_times.partition_point(|time| *time < band.end);
+    if first == end {
+        return Vec::new();
     }
+    arrival_times[first.saturating_sub(1)..end].to_vec()
 }
 
 #[tauri::command]
-async fn run_simulation(
-    js_graph: JsGraph,
-    js_routes: JsRoutes,
-    frequency: u64,
-) -> Result<JsSimulationResults, String> {
-    let (subway_map, cytoscape_id_map, petgraph_map) = js_graph_to_subway_map(js_graph.clone());
-    let (routes, route_id_map) = js_routes_to_routes(js_routes, &subway_map, &cytoscape_id_map);
+fn recompute_statistics(
+    runs: tauri::State<'_, RunStore>,
+    run_id: u64,
+    options: StatisticsOptions,
+) -> Result<HashMap<String, JsStationStatistic>, SimError> {
+    recompute_stored_statistics(&runs, run_id, options)
+}
 
-    let simulator = Simulator::new(subway_map, routes.clone());
-    let simulation_results = simulator.run(60, frequency);
-    Ok(simulation_results_to_js(
-        simulation_results,
-        &petgraph_map,
-        &route_id_map,
+fn recompute_stored_statistics(
+    runs: &RunStore,
+    run_id: u64,
+    options: StatisticsOptions,
+) -> Result<HashMap<String, JsStationStatistic>, SimError> {
+    let runs = runs.runs.lock().unwrap();
+    let run = runs
+        .get(&run_id)
+        .ok_or_else(|| SimError::InvalidArgument(format!("no run with id {run_id}")))?;
+    Ok(station_statistics_to_js(
+        &run.station_statistics,
+        &run.nominal_headways,
+        &run.terminals,
+        &run.petgraph_map,
+        &run.route_id_map,
+        &options,
     ))
 }
 
+#[derive(Serialize)]
+struct JsStationRank {
+    station: String,
+    boardings: usize,
+    /// total time passengers who boarded here spent waiting
+    passenger_minutes: f64,
+    /// boardings times the expected wait implied by the station's headways
+    expected_passenger_minutes: f64,
+}
+
+/// Ranks the stations of a stored run by the total time passengers spent waiting there, most
+/// waiting first
 #[tauri::command]
-async fn run_optimize(
-    js_graph: JsGraph,
-    js_routes: JsRoutes,
-) -> Result<JsSimulationResults, String> {
-    let (subway_map, cytoscape_id_map, petgraph_map) = js_graph_to_subway_map(js_graph.clone());
-    let (routes, route_id_map) = js_routes_to_routes(js_routes, &subway_map, &cytoscape_id_map);
+fn station_ranking(
+    runs: tauri::State<'_, RunStore>,
+    run_id: u64,
+    options: Option<StatisticsOptions>,
+) -> Result<Vec<JsStationRank>, SimError> {
+    rank_stations(&runs, run_id, options)
+}
 
-    let mut rng = StdRng::seed_from_u64(5050);
+fn rank_stations(
+    runs: &RunStore,
+    run_id: u64,
+    options: Option<StatisticsOptions>,
+) -> Result<Vec<JsStationRank>, SimError> {
+    let runs = runs.runs.lock().unwrap();
+    let run = runs
+        .get(&run_id)
+        .ok_or_else(|| SimError::InvalidArgument(format!("no run with id {run_id}")))?;
+    let statistics = station_statistics_to_js(
+        &run.station_statistics,
+        &run.nominal_headways,
+        &run.terminals,
+        &run.petgraph_map,
+        &run.route_id_map,
+        &options.unwrap_or_default(),
+    );
 
-    let mut trip_data = TripData::new();
-    let mut num_trips = 0;
-    
-    let mut search_map = SearchMap::generate(&subway_map, &routes);
-    
-    let mut shortest_paths_cache = HashMap::new();
+    let mut ranking: Vec<_> = run
+        .station_statistics
+        .iter()
+        .map(|(id, s)| {
+            let station = run.petgraph_map[&TrackStationId::Station(*id)].clone();
+            let statistic = &statistics[&station];
+            let expected_wait = statistic
+                .overall_arrival_times
+                .as_ref()
+                .or_else(|| {
+                    // only stations of one route go without overall arrivals, but taking the
+                    // lowest route id's keeps rankings the same from call to call regardless
+                    statistic
+                        .arrival_times
+                        .iter()
+                        .min_by(|(a, _), (b, _)| a.cmp(b))
+                        .map(|(_, arrival_times)| arrival_times)
+                })
+                .map_or(0.0, |arrival_times| arrival_times.expected_wait);
+            JsStationRank {
+                station,
+                boardings: s.passenger_waits.len(),
+                passenger_minutes: s.passenger_waits.iter().sum(),
+                expected_passenger_minutes: s.passenger_waits.len() as f64 * expected_wait,
+            }
+        })
+        .collect();
+    ranking.sort_by(|a, b| b.passenger_minutes.total_cmp(&a.passenger_minutes));
+    Ok(ranking)
+}
 
-    for _ in 0..30 * SCHEDULE_PERIOD {
-        let start = subway_map.node_indices().choose(&mut rng).unwrap();
-        let end = subway_map.node_indices().choose(&mut rng).unwrap();
-        
-        let paths = shortest_paths(start, end, &mut search_map, 2);
-        
-        if !paths.is_empty() {
-            let trip = Trip { start,
-                end,
-                count: 1,
-            };
-            trip_data
-                .entry(rng.gen_range(0..SCHEDULE_PERIOD))
+/// Frees a stored run no longer needed
+#[tauri::command]
+fn discard_run(runs: tauri::State<'_, RunStore>, run_id: u64) {
+    runs.remove(run_id);
+}
+
+/// Travel demand between two stations. Riders arrive over [start_time, end_time).
+#[derive(Deserialize, Clone)]
+struct JsTrip {
+    origin: String,
+    destination: String,
+    #[serde(default)]
+    count: usize,
+    /// riders a time unit on average, giving the demand as a rate instead of a count
+    #[serde(default)]
+    rate: Option<f64>,
+    start_time: f64,
+    end_time: f64,
+    #[serde(default)]
+    arrival_process: ArrivalProcess,
+}
+
+/// Closes the edge with the given id over [start, end)
+#[derive(Deserialize, Clone)]
+struct JsDisruption {
+    edge: String,
+    start: f64,
+    end: f64,
+    /// fraction of their usual speed trains keep, like 0.5 for track work halving it. The edge
+    /// is closed if missing.
+    #[serde(default)]
+    speed: Option<f64>,
+}
+
+// Tracks by the ids of their edges
+fn edge_tracks(petgraph_map: &HashMap<TrackStationId, String>) -> HashMap<&String, TrackId> {
+    petgraph_map
+        .iter()
+        .filter_map(|(section, id)| match section {
+            TrackStationId::Track(track) => Some((id, *track)),
+            TrackStationId::Station(_) => None,
+        })
+        .collect()
+}
+
+fn js_disruptions_to_plan(
+    js_disruptions: Vec<JsDisruption>,
+    petgraph_map: &HashMap<TrackStationId, String>,
+) -> Result<DisruptionPlan, SimError> {
+    let tracks = edge_tracks(petgraph_map);
+    js_disruptions
+        .into_iter()
+        .map(|disruption| {
+            let speed = disruption.speed.unwrap_or_default();
+            if !(0.0..=1.0).contains(&speed) {
+                return Err(SimError::InvalidArgument(format!(
+                    "speed over edge {} must be between 0 and 1, got {speed}",
+                    disruption.edge
+                )));
+            }
+            Ok(Disruption {
+                track: *tracks
+                    .get(&disruption.edge)
+                    .ok_or_else(|| SimError::Graph(format!("unknown edge {}", disruption.edge)))?,
+                start: disruption.start,
+                end: disruption.end,
+                speed,
+                tracks: None,
+            })
+        })
+        .collect()
+}
+
+/// Planned engineering work on the edges with the given ids over [start, end), closing them or
+/// leaving only some of their parallel tracks open
+#[derive(Deserialize, Clone)]
+struct JsMaintenanceWindow {
+    edges: Vec<String>,
+    start: f64,
+    end: f64,
+    /// parallel tracks kept open, the edges are closed if missing or 0
+    #[serde(default)]
+    tracks: Option<u8>,
+}
+
+// The disruptions of each maintenance window, in the calendar's order
+fn js_maintenance_to_plans(
+    calendar: &[JsMaintenanceWindow],
+    petgraph_map: &HashMap<TrackStationId, String>,
+) -> Result<Vec<DisruptionPlan>, SimError> {
+    let tracks = edge_tracks(petgraph_map);
+    calendar
+        .iter()
+        .map(|window| {
+            if window.end <= window.start {
+                return Err(SimError::InvalidArgument(format!(
+                    "maintenance window from {} to {} ends before it starts",
+                    window.start, window.end
+                )));
+            }
+            let tracks_open = window.tracks.filter(|tracks| *tracks > 0);
+            window
+                .edges
+                .iter()
+                .map(|edge| {
+                    Ok(Disruption {
+                        track: *tracks
+                            .get(edge)
+                            .ok_or_else(|| SimError::Graph(format!("unknown edge {edge}")))?,
+                        start: window.start,
+                        end: window.end,
+                        speed: if tracks_open.is_some() { 1.0 } else { 0.0 },
+                        tracks: tracks_open,
+                    })
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// Trips the maintenance calendar lengthened against the same run without it, matching each
+// train's trips on a route in the order they were made
+#[allow(clippy::too_many_arguments)]
+fn maintenance_report(
+    calendar: &[JsMaintenanceWindow],
+    plans: &[DisruptionPlan],
+    routes: &[Route],
+    simulation_results: &SimulationResults,
+    baseline: &SimulationResults,
+    labels: &TrainLabels,
+    route_id_map: &[String],
+) -> JsMaintenanceReport {
+    let mut baseline_trips: HashMap<(TrainId, RouteId), VecDeque<f64>> = HashMap::new();
+    for trip in &baseline.train_trips {
+        if let Some(end) = trip.end {
+            baseline_trips
+                .entry((trip.train, trip.route))
                 .or_default()
-                .push(trip);
-            num_trips += 1;
-            
-            shortest_paths_cache.insert((start,end), paths);
+                .push_back(end - trip.start);
+        }
+    }
+    let mut delayed = Vec::new();
+    for trip in &simulation_results.train_trips {
+        let Some(end) = trip.end else {
+            continue;
+        };
+        let Some(baseline) = baseline_trips
+            .get_mut(&(trip.train, trip.route))
+            .and_then(|trips| trips.pop_front())
+        else {
+            continue;
+        };
+        let delay = end - trip.start - baseline;
+        if delay > 1e-9 {
+            delayed.push((trip, delay));
         }
     }
-    
-    println!("Using {num_trips} trips for optimization");
 
-    let (schedule, simulation_results) = optimize(subway_map, routes, &trip_data, &shortest_paths_cache);
+    let mut by_route: HashMap<String, JsMaintenanceDelay> = HashMap::new();
+    for (trip, delay) in &delayed {
+        let route = by_route
+            .entry(route_id_map[trip.route.0 as usize].clone())
+            .or_default();
+        route.delayed_trips += 1;
+        route.total_delay += delay;
+        route.max_delay = route.max_delay.max(*delay);
+        route.trips.push(JsDelayedTrip {
+            train: labels.label(trip.train, route_id_map),
+            start: trip.start,
+            delay: *delay,
+        });
+    }
+    let windows = calendar
+        .iter()
+        .zip(plans)
+        .map(|(window, plan)| {
+            // routes over the edges, counting variants and depot moves as their route
+            let affected: HashSet<_> = routes
+                .iter()
+                .enumerate()
+                .filter(|(_, route)| {
+                    plan.iter()
+                        .any(|disruption| route.station_to.values().any(|t| *t == disruption.track))
+                })
+                .map(|(i, route)| route.parent.unwrap_or(RouteId(i as u32)))
+                .collect();
+            let during: Vec<_> = delayed
+                .iter()
+                .filter(|(trip, _)| {
+                    affected.contains(&trip.route)
+                        && trip.start < window.end
+                        && trip.end.is_some_and(|end| end > window.start)
+                })
+                .collect();
+            let mut route_names: Vec<_> = affected
+                .iter()
+                .map(|route| route_id_map[route.0 as usize].clone())
+                .collect();
+            route_names.sort();
+            JsMaintenanceImpact {
+                edges: window.edges.clone(),
+                start: window.start,
+                end: window.end,
+                tracks: window.tracks.filter(|tracks| *tracks > 0),
+                routes: route_names,
+                delayed_trips: during.len(),
+                total_delay: during.iter().fold(0.0, |total, (_, delay)| total + delay),
+            }
+        })
+        .collect();
+    JsMaintenanceReport {
+        windows,
+        routes: by_route,
+        average_journey_time: mean(&simulation_results.journey_times),
+        baseline_average_journey_time: mean(&baseline.journey_times),
+    }
+}
 
-    println!("Found schedule: {:#?}\x07", schedule);
+/// Breaks down the train furthest along the edge with the given id once start comes, of the route
+/// with the given id or any if missing, standing it there for duration
+#[derive(Deserialize, Clone)]
+struct JsBreakdown {
+    edge: String,
+    #[serde(default)]
+    route: Option<String>,
+    start: f64,
+    duration: f64,
+    /// pushed out of service to the end of its route after by a rescue train instead of
+    /// recovering
+    #[serde(default)]
+    rescue: bool,
+    /// fraction of its usual speed a rescued train is pushed at, a quarter if missing
+    #[serde(default)]
+    push_speed: Option<f64>,
+}
 
-    // TODO handle error condition
-    let simulation_results = simulation_results.unwrap();
-    Ok(simulation_results_to_js(
-        simulation_results,
-        &petgraph_map,
-        &route_id_map,
-    ))
+fn js_breakdowns_to_plan(
+    js_breakdowns: Vec<JsBreakdown>,
+    petgraph_map: &HashMap<TrackStationId, String>,
+    route_id_map: &[String],
+) -> Result<Vec<Breakdown>, SimError> {
+    let tracks = edge_tracks(petgraph_map);
+    js_breakdowns
+        .into_iter()
+        .map(|breakdown| {
+            if !(breakdown.duration > 0.0 && breakdown.duration.is_finite()) {
+                return Err(SimError::InvalidArgument(format!(
+                    "breakdown on edge {} must last a positive time, got {}",
+                    breakdown.edge, breakdown.duration
+                )));
+            }
+            let push_speed = breakdown.push_speed.unwrap_or(0.25);
+            if !(push_speed > 0.0 && push_speed <= 1.0) {
+                return Err(SimError::InvalidArgument(format!(
+                    "push speed of breakdown on edge {} must be above 0 and at most 1, got {push_speed}",
+                    breakdown.edge
+                )));
+            }
+            let route = breakdown
+                .route
+                .map(|route| {
+                    route_id_map
+                        .iter()
+                        .position(|id| *id == route)
+                        .map(|i| RouteId(i as u32))
+                        .ok_or_else(|| SimError::Route(format!("unknown route {route}")))
+                })
+                .transpose()?;
+            Ok(Breakdown {
+                route,
+                track: *tracks
+                    .get(&breakdown.edge)
+                    .ok_or_else(|| SimError::Graph(format!("unknown edge {}", breakdown.edge)))?,
+                start: breakdown.start,
+                duration: breakdown.duration,
+                recovers: !breakdown.rescue,
+                push_speed,
+            })
+        })
+        .collect()
 }
 
-#[derive(Serialize)]
-struct JsTrainPosition {
-    pub id: (u32, u32),
-    pub curr_section: String,
-    pub pos: f64,
-    pub distance_travelled: f64,
+/// Shuts the node with the given id over [start, end), trains running through it and riders
+/// walking to the stations a walk away
+#[derive(Deserialize, Clone)]
+struct JsStationClosure {
+    station: String,
+    start: f64,
+    end: f64,
+}
+
+fn js_closures_to_plan(
+    js_closures: &[JsStationClosure],
+    cytoscape_id_map: &HashMap<String, NodeIndex>,
+) -> Result<Vec<StationClosure>, SimError> {
+    js_closures
+        .iter()
+        .map(|closure| {
+            Ok(StationClosure {
+                station: station_node(cytoscape_id_map, &closure.station)?,
+                start: closure.start,
+                end: closure.end,
+            })
+        })
+        .collect()
+}
+
+// Riders moved by station closures and how their trips changed, against the same run with the
+// stations open
+fn closure_report(
+    closures: &[JsStationClosure],
+    simulation_results: &SimulationResults,
+    baseline: &SimulationResults,
+) -> JsClosureReport {
+    JsClosureReport {
+        closures: closures
+            .iter()
+            .zip(&simulation_results.closure_effects)
+            .map(|(closure, effect)| JsClosureImpact {
+                station: closure.station.clone(),
+                start: closure.start,
+                end: closure.end,
+                trains_run_through: effect.trains_run_through,
+                riders_diverted: effect.riders_diverted,
+                riders_carried_past: effect.riders_carried_past,
+            })
+            .collect(),
+        passengers_delivered: simulation_results.journey_times.len(),
+        baseline_passengers_delivered: baseline.journey_times.len(),
+        average_journey_time: mean(&simulation_results.journey_times),
+        baseline_average_journey_time: mean(&baseline.journey_times),
+    }
+}
+
+/// Holds trains at the node with the given id up to max_hold minutes for feeders due at the
+/// nodes a walk away
+#[derive(Deserialize, Clone)]
+struct JsTimedTransfer {
+    station: String,
+    max_hold: f64,
+}
+
+fn js_transfers_to_plan(
+    js_transfers: Vec<JsTimedTransfer>,
+    cytoscape_id_map: &HashMap<String, NodeIndex>,
+) -> Result<Vec<TimedTransfer>, SimError> {
+    js_transfers
+        .into_iter()
+        .map(|transfer| {
+            Ok(TimedTransfer {
+                station: station_node(cytoscape_id_map, &transfer.station)?,
+                max_hold: transfer.max_hold.max(0.0),
+            })
+        })
+        .collect()
+}
+
+/// Sends the route with the given id along the path through nodes over [start, end). The nodes
+/// run from where the detour leaves the route's own path to where it rejoins it.
+#[derive(Deserialize, Clone)]
+struct JsReroute {
+    route: String,
+    start: f64,
+    end: f64,
+    nodes: Vec<String>,
+}
+
+fn js_reroutes_to_plan(
+    js_reroutes: Vec<JsReroute>,
+    subway_map: &SubwayMap,
+    cytoscape_id_map: &HashMap<String, NodeIndex>,
+    route_id_map: &[String],
+) -> Result<ReroutePlan, SimError> {
+    js_reroutes
+        .into_iter()
+        .map(|reroute| {
+            let route = route_id_map
+                .iter()
+                .position(|id| *id == reroute.route)
+                .ok_or_else(|| SimError::Route(format!("unknown route {}", reroute.route)))?;
+            let node_ids = reroute
+                .nodes
+                .iter()
+                .map(|id| {
+                    cytoscape_id_map
+                        .get(id)
+                        .copied()
+                        .ok_or_else(|| SimError::Graph(format!("unknown station {id}")))
+                })
+                .collect::<Result<_, SimError>>()?;
+            Ok(Reroute {
+                route: RouteId(route as u32),
+                start: reroute.start,
+                end: reroute.end,
+                station_to: path_tracks(&node_ids, subway_map),
+            })
+        })
+        .collect()
+}
+
+/// Headways of routes by id from start until the next period
+#[derive(Deserialize, Clone)]
+struct JsHeadwayPeriod {
+    start: i32,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    headways: HashMap<String, u64>,
+    /// the system is shut, e.g. overnight, with no trains dispatched and no riders let in
+    #[serde(default)]
+    shutdown: bool,
+}
+
+fn js_periods_to_profile(
+    mut js_periods: Vec<JsHeadwayPeriod>,
+    route_id_map: &[String],
+) -> Result<HeadwayProfile, SimError> {
+    js_periods.sort_by_key(|period| period.start);
+    let periods = js_periods
+        .into_iter()
+        .map(|period| {
+            let headways = period
+                .headways
+                .into_iter()
+                .map(|(route, headway)| {
+                    let idx = route_id_map
+                        .iter()
+                        .position(|id| *id == route)
+                        .ok_or_else(|| SimError::Route(format!("unknown route {route}")))?;
+                    Ok((RouteId(idx as u32), headway))
+                })
+                .collect::<Result<_, SimError>>()?;
+            Ok(HeadwayPeriod {
+                start: period.start,
+                name: period.name,
+                headways,
+                shutdown: period.shutdown,
+            })
+        })
+        .collect::<Result<_, SimError>>()?;
+    Ok(HeadwayProfile { periods })
+}
+
+/// Passengers to trace through the run: a random sample plus everyone between the given
+/// pairs of station ids
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+struct JsJourneySampling {
+    sample: usize,
+    od_pairs: Vec<(String, String)>,
+}
+
+fn js_sampling_to_sampling(
+    js_sampling: JsJourneySampling,
+    cytoscape_map: &HashMap<String, NodeIndex>,
+) -> Result<JourneySampling, SimError> {
+    let node = |id: &String| {
+        cytoscape_map
+            .get(id)
+            .copied()
+            .ok_or_else(|| SimError::Graph(format!("unknown station {id}")))
+    };
+    Ok(JourneySampling {
+        sample: js_sampling.sample,
+        od_pairs: js_sampling
+            .od_pairs
+            .iter()
+            .map(|(origin, destination)| Ok((node(origin)?, node(destination)?)))
+            .collect::<Result<_, SimError>>()?,
+    })
 }
 
 #[derive(Serialize)]
-struct JsTrainPositions {
-    pub time: u32,
-    pub trains: Vec<JsTrainPosition>,
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsJourneyEvent {
+    Board {
+        station: String,
+        train: String,
+        time: f64,
+        waited: f64,
+    },
+    Alight {
+        station: String,
+        train: String,
+        time: f64,
+    },
+    Walk {
+        from: String,
+        to: String,
+        time: f64,
+    },
+    Stranded {
+        station: String,
+        train: String,
+        time: f64,
+    },
 }
 
 #[derive(Serialize)]
-struct JsStationStatistic {
-    pub arrival_times: HashMap<String, JsArrivalStats>,
-    /// arrival times for all routes
-    /// None if there's only one route
-    pub overall_arrival_times: Option<JsArrivalStats>,
+struct JsJourney {
+    origin: String,
+    destination: String,
+    arrival_time: f64,
+    events: Vec<JsJourneyEvent>,
+    /// None if the passenger was still travelling when the run ended
+    completed_at: Option<f64>,
+}
+
+fn journey_to_js(
+    journey: Journey,
+    petgraph_map: &HashMap<TrackStationId, String>,
+    train: &impl Fn(TrainId) -> String,
+) -> JsJourney {
+    let station = |id: StationId| petgraph_map[&TrackStationId::Station(id)].clone();
+    JsJourney {
+        origin: station(journey.origin),
+        destination: station(journey.destination),
+        arrival_time: journey.arrival_time,
+        completed_at: journey.completed_at,
+        events: journey
+            .events
+            .into_iter()
+            .map(|event| match event {
+                JourneyEvent::Board {
+                    station: s,
+                    train: t,
+                    time,
+                    waited,
+                } => JsJourneyEvent::Board {
+                    station: station(s),
+                    train: train(t),
+                    time,
+                    waited,
+                },
+                JourneyEvent::Alight {
+                    station: s,
+                    train: t,
+                    time,
+                } => JsJourneyEvent::Alight {
+                    station: station(s),
+                    train: train(t),
+                    time,
+                },
+                JourneyEvent::Walk { from, to, time } => JsJourneyEvent::Walk {
+                    from: station(from),
+                    to: station(to),
+                    time,
+                },
+                JourneyEvent::Stranded {
+                    station: s,
+                    train: t,
+                    time,
+                } => JsJourneyEvent::Stranded {
+                    station: station(s),
+                    train: train(t),
+                    time,
+                },
+            })
+            .collect(),
+    }
+}
+
+fn js_trips_to_trip_data(
+    js_trips: Vec<JsTrip>,
+    cytoscape_map: &HashMap<String, NodeIndex>,
+    search_map: &mut SearchMap,
+    seed: u64,
+) -> Result<(TripData, ShortestPaths), SimError> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut trip_data = TripData::new();
+    let mut shortest_paths_cache = HashMap::new();
+    for js_trip in js_trips {
+        let node = |id: &String| {
+            cytoscape_map
+                .get(id)
+                .copied()
+                .ok_or_else(|| SimError::Graph(format!("unknown station {id}")))
+        };
+        let start = node(&js_trip.origin)?;
+        let end = node(&js_trip.destination)?;
+        let riders = match js_trip.rate {
+            Some(rate) if !rate.is_finite() || rate < 0.0 => {
+                return Err(SimError::InvalidArgument(format!(
+                    "trip from {} to {} has a rate of {rate} riders",
+                    js_trip.origin, js_trip.destination
+                )))
+            }
+            Some(rate) => rate * (js_trip.end_time - js_trip.start_time).max(0.0),
+            None => js_trip.count as f64,
+        };
+        let paths = shortest_paths_cache
+            .entry((start, end))
+            .or_insert_with(|| shortest_paths(start, end, search_map, 2));
+        // riders with no way to make the trip are left out
+        if paths.is_empty() {
+            continue;
+        }
+        let trip = Trip::new(
+            start,
+            end,
+            riders,
+            js_trip.start_time,
+            js_trip.end_time,
+            &js_trip.arrival_process,
+            &mut rng,
+        );
+        trip_data
+            .entry(js_trip.start_time as i64)
+            .or_default()
+            .push(trip);
+    }
+    Ok((trip_data, shortest_paths_cache))
+}
+
+/// Minutes recorded by run_simulation when no duration is given
+const SIMULATION_HORIZON: i32 = 60;
+/// Longest duration run_simulation accepts, a full day of service
+const MAX_SIMULATION_HORIZON: i32 = 24 * 60;
+/// Seed random draws are made with, unless the command or experiment run gives its own
+const DEFAULT_SEED: u64 = 5050;
+
+/// Seed of a command's random draws, a number to reproduce a run or "random" for a new one
+/// each time
+#[derive(Deserialize, Clone, Copy)]
+#[serde(untagged)]
+enum JsSeed {
+    Fixed(u64),
+    Random(RandomSeed),
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum RandomSeed {
+    Random,
+}
+
+impl JsSeed {
+    fn resolve(seed: Option<JsSeed>) -> u64 {
+        match seed {
+            None => DEFAULT_SEED,
+            Some(JsSeed::Fixed(seed)) => seed,
+            Some(JsSeed::Random(RandomSeed::Random)) => rand::random(),
+        }
+    }
+}
+
+// A run's seed also replaces those of the config's random processes
+fn reseed(config: &mut SimulationConfig, seed: u64) {
+    if let Some(noise) = &mut config.travel_time_noise {
+        noise.seed = seed;
+    }
+    if let Some(incidents) = &mut config.incidents {
+        incidents.seed = seed;
+    }
+}
+
+// Rough calibration of run costs, measured on a development machine with a debug build
+const SECONDS_PER_SECTION_STEP: f64 = 2e-7;
+const SECONDS_PER_TRAIN_STEP: f64 = 1.5e-6;
+const BYTES_PER_TRAIN_POSITION: f64 = 140.;
+const BYTES_PER_STATION_ROUTE: f64 = 120.;
+
+fn simulation_horizon(duration: Option<i32>, time_unit: TimeUnit) -> Result<i32, SimError> {
+    let in_unit = |minutes: i32| (minutes as f64 * time_unit.per_minute()) as i32;
+    let max = in_unit(MAX_SIMULATION_HORIZON);
+    match duration {
+        None => Ok(in_unit(SIMULATION_HORIZON)),
+        Some(duration) if (1..=max).contains(&duration) => Ok(duration),
+        Some(duration) => Err(SimError::InvalidArgument(format!(
+            "duration must be between 1 and {max} {}, got {duration}",
+            time_unit.name()
+        ))),
+    }
+}
+
+// Scheduling counts departures in SCHEDULE_GRANULARITY minute periods
+fn scheduling_unit(config: &SimulationConfig) -> Result<(), SimError> {
+    match config.time_unit {
+        TimeUnit::Minutes => Ok(()),
+        unit => Err(SimError::InvalidArgument(format!(
+            "schedules can only be worked out in minutes, not {}",
+            unit.name()
+        ))),
+    }
 }
 
 #[derive(Serialize)]
-struct JsArrivalStats {
-    pub min_wait: f64,
-    pub max_wait: f64,
-    pub average_wait: f64,
+struct JsRunEstimate {
+    seconds: f64,
+    result_bytes: f64,
+    steps: u64,
+    trains_in_service: f64,
 }
 
+/// Estimated load and wait of a route over one schedule period
 #[derive(Serialize)]
-struct JsSimulationResults {
-    pub train_positions: Vec<JsTrainPositions>,
-    // String of routeid_trainnum to route string
-    pub train_to_route: HashMap<String, String>,
-    pub station_statistics: HashMap<String, JsStationStatistic>,
+struct JsPeriodLoad {
+    riders: f64,
+    /// riders per train over the route's busiest track as a fraction of capacity, None if no
+    /// trains run
+    load_factor: Option<f64>,
+    wait_time: Option<f64>,
 }
 
-fn main() {
-    tauri::Builder::default()
-        .invoke_handler(tauri::generate_handler![
-            run_simulation,
-            shortest_path,
-            run_optimize
-        ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+/// Estimates each route's load factor and wait in every period of a schedule from the demand,
+/// using the optimizer's cost model instead of simulating. Cheap enough to call on every edit.
+#[tauri::command]
+fn preview_frequencies(
+    js_graph: JsGraph,
+    js_routes: JsRoutes,
+    schedule: Schedule,
+    demand: Vec<JsTrip>,
+    config: Option<SimulationConfig>,
+    cyclic: Option<bool>,
+) -> Result<HashMap<String, Vec<JsPeriodLoad>>, SimError> {
+    let config = config.unwrap_or_default();
+    scheduling_unit(&config)?;
+    let (subway_map, cytoscape_id_map, _) = js_graph_to_subway_map(js_graph)?;
+    let (routes, _) = js_routes_to_routes(js_routes, &subway_map, &cytoscape_id_map)?;
+    let mut search_map = SearchMap::generate(&subway_map, &routes);
+    let (trip_data, shortest_paths_cache) =
+        js_trips_to_trip_data(demand, &cytoscape_id_map, &mut search_map, DEFAULT_SEED)?;
+    let loads = preview_loads(
+        &search_map,
+        &subway_map,
+        &routes,
+        &config,
+        &schedule,
+        &trip_data,
+        &shortest_paths_cache,
+        cyclic.unwrap_or_default(),
+    );
+    Ok(loads
+        .into_iter()
+        .map(|(route, loads)| {
+            let loads = loads
+                .into_iter()
+                .map(|load| JsPeriodLoad {
+                    riders: load.riders,
+                    load_factor: load.load_factor,
+                    wait_time: load.wait_time,
+                })
+                .collect();
+            (route, loads)
+        })
+        .collect())
 }
 
-fn calculate_arrival_time_statistics(data: Vec<f64>) -> JsArrivalStats {
-    let mut differences = Vec::with_capacity(data.len());
-    let mut prev_time = data.first().copied().unwrap_or_default();
-    for item in data.iter().skip(1) {
-        differences.push(*item - prev_time);
-        prev_time = *item;
+/// Departures each route is planned to make, from an optimized schedule if given, otherwise
+/// from the frequency or profile and the routes' offsets and timetables as run_simulation
+/// would dispatch them. Nothing is simulated, so it's cheap enough to call on every edit.
+#[allow(clippy::too_many_arguments)]
+#[tauri::command]
+fn preview_dispatches(
+    js_graph: JsGraph,
+    js_routes: JsRoutes,
+    frequency: u64,
+    frequency_profile: Option<Vec<JsHeadwayPeriod>>,
+    schedule: Option<Schedule>,
+    config: Option<SimulationConfig>,
+    duration: Option<i32>,
+) -> Result<HashMap<String, Vec<i64>>, SimError> {
+    let config = config.unwrap_or_default();
+    if let Some(schedule) = schedule {
+        scheduling_unit(&config)?;
+        // schedules go by route name
+        let ids: HashMap<_, _> = js_routes
+            .into_values()
+            .map(|route| (route.name, (route.id, route.offset)))
+            .collect();
+        if let Some(route) = schedule.keys().find(|route| !ids.contains_key(*route)) {
+            return Err(SimError::Route(format!(
+                "schedule names unknown route {route}"
+            )));
+        }
+        let offsets = ids
+            .iter()
+            .map(|(name, (_, offset))| (name.clone(), *offset))
+            .collect();
+        return Ok(schedule_to_departures(&schedule, &offsets)
+            .into_iter()
+            .map(|(name, departures)| (ids[&name].0.clone(), departures))
+            .collect());
     }
-    JsArrivalStats {
-        min_wait: differences
+    let duration = simulation_horizon(duration, config.time_unit)?;
+    let (subway_map, cytoscape_id_map, _) = js_graph_to_subway_map(js_graph)?;
+    let (routes, route_id_map) = js_routes_to_routes(js_routes, &subway_map, &cytoscape_id_map)?;
+    let profile = match frequency_profile {
+        Some(periods) => js_periods_to_profile(periods, &route_id_map)?,
+        None => HeadwayProfile::constant((0..routes.len() as u32).map(RouteId), frequency),
+    };
+    let simulator = Simulator::new(subway_map, routes, config);
+    Ok(simulator
+        .planned_departures(duration, &profile)
+        .into_iter()
+        .map(|(r_id, departures)| {
+            (
+                route_id_map[r_id.0 as usize].clone(),
+                departures.into_iter().map(i64::from).collect(),
+            )
+        })
+        .collect())
+}
+
+/// Predicts how long run_simulation would take and how large its results would be, so
+/// expensive settings can be dialed back before running
+#[tauri::command]
+fn estimate_run(
+    js_graph: JsGraph,
+    js_routes: JsRoutes,
+    frequency: u64,
+    config: Option<SimulationConfig>,
+    duration: Option<i32>,
+) -> Result<JsRunEstimate, SimError> {
+    let config = config.unwrap_or_default();
+    let duration = simulation_horizon(duration, config.time_unit)?;
+    let (subway_map, cytoscape_id_map, _) = js_graph_to_subway_map(js_graph)?;
+    let (routes, _) = js_routes_to_routes(js_routes, &subway_map, &cytoscape_id_map)?;
+    let simulator = Simulator::new(subway_map, routes, config);
+    let workload = simulator.workload(duration, frequency);
+
+    let steps = workload.steps as f64;
+    Ok(JsRunEstimate {
+        seconds: steps
+            * (workload.sections as f64 * SECONDS_PER_SECTION_STEP
+                + workload.trains_in_service * SECONDS_PER_TRAIN_STEP),
+        result_bytes: workload.recorded_steps as f64
+            * workload.trains_in_service
+            * BYTES_PER_TRAIN_POSITION
+            + (workload.stations * workload.routes) as f64 * BYTES_PER_STATION_ROUTE,
+        steps: workload.steps,
+        trains_in_service: workload.trains_in_service,
+    })
+}
+
+/// The heavy command running, if any. Simulations and optimizations take all cores and print as
+/// they go, so only one runs at a time and any started meanwhile is rejected as busy
+#[derive(Default)]
+struct ActiveRuns {
+    active: Mutex<Option<ActiveRun>>,
+}
+
+struct ActiveRun {
+    command: &'static str,
+    started: Instant,
+}
+
+impl ActiveRuns {
+    fn start(&self, command: &'static str) -> Result<ActiveRunGuard<'_>, SimError> {
+        let mut active = self.active.lock().unwrap();
+        if let Some(run) = &*active {
+            return Err(SimError::Busy(format!(
+                "{} has been running for {:.0}s, wait for it to finish or cancel it",
+                run.command,
+                run.started.elapsed().as_secs_f64()
+            )));
+        }
+        *active = Some(ActiveRun {
+            command,
+            started: Instant::now(),
+        });
+        Ok(ActiveRunGuard { runs: self })
+    }
+}
+
+// Frees the slot once the command returns, whether it succeeded or not
+struct ActiveRunGuard<'a> {
+    runs: &'a ActiveRuns,
+}
+
+impl Drop for ActiveRunGuard<'_> {
+    fn drop(&mut self) {
+        *self.runs.active.lock().unwrap() = None;
+    }
+}
+
+#[derive(Serialize)]
+struct JsActiveRun {
+    command: &'static str,
+    /// seconds since the command started
+    elapsed: f64,
+}
+
+/// Lists the heavy commands running, for the frontend to show status and disable buttons
+#[tauri::command]
+fn get_active_runs(active_runs: tauri::State<'_, ActiveRuns>) -> Vec<JsActiveRun> {
+    active_runs
+        .active
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|run| JsActiveRun {
+            command: run.command,
+            elapsed: run.started.elapsed().as_secs_f64(),
+        })
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+#[tauri::command]
+async fn run_simulation(
+    window: tauri::Window,
+    runs: tauri::State<'_, RunStore>,
+    active_runs: tauri::State<'_, ActiveRuns>,
+    checkpoints: tauri::State<'_, CheckpointStore>,
+    snapshots: tauri::State<'_, SnapshotStore>,
+    js_graph: JsGraph,
+    js_routes: JsRoutes,
+    frequency: u64,
+    config: Option<SimulationConfig>,
+    demand: Option<Vec<JsTrip>>,
+    disruptions: Option<Vec<JsDisruption>>,
+    maintenance: Option<Vec<JsMaintenanceWindow>>,
+    trace: Option<JsJourneySampling>,
+    frequency_profile: Option<Vec<JsHeadwayPeriod>>,
+    reroutes: Option<Vec<JsReroute>>,
+    duration: Option<i32>,
+    timed_transfers: Option<Vec<JsTimedTransfer>>,
+    closures: Option<Vec<JsStationClosure>>,
+    breakdowns: Option<Vec<JsBreakdown>>,
+    seed: Option<JsSeed>,
+    feed: Option<JsFeed>,
+    pause_at: Option<i32>,
+) -> Result<JsSimulationResults, SimError> {
+    let _run = active_runs.start("run_simulation")?;
+    checkpoints.checkpoints.lock().unwrap().clear();
+    let mut config = config.unwrap_or_default();
+    let feed = feed
+        .map(|feed| ArrivalFeed::open(feed, config.time_unit))
+        .transpose()?;
+    // the feed goes out with the checkpoints, every minute unless they're set to come less often
+    if feed.is_some() && config.checkpoint_interval.is_none() {
+        config.checkpoint_interval = Some(1);
+    }
+    // without a seed the config's own seeds stay
+    let given = seed.is_some();
+    let seed = JsSeed::resolve(seed);
+    if given {
+        reseed(&mut config, seed);
+    }
+    let mut results = simulate(
+        &runs,
+        js_graph,
+        js_routes,
+        frequency,
+        simulation_horizon(duration, config.time_unit)?,
+        config,
+        demand.unwrap_or_default(),
+        disruptions.unwrap_or_default(),
+        maintenance.unwrap_or_default(),
+        reroutes.unwrap_or_default(),
+        timed_transfers.unwrap_or_default(),
+        closures.unwrap_or_default(),
+        breakdowns.unwrap_or_default(),
+        trace.unwrap_or_default(),
+        frequency_profile,
+        seed,
+        pause_at.map(|time| (&*snapshots, time)),
+        &|checkpoint| {
+            if let Some(feed) = &feed {
+                feed.send(&checkpoint);
+            }
+            checkpoints
+                .checkpoints
+                .lock()
+                .unwrap()
+                .push(checkpoint.clone());
+            // the run goes on whether or not the window can be told
+            let _ = window.emit("simulation_checkpoint", checkpoint);
+        },
+    )?;
+    results.seed = Some(seed);
+    Ok(results)
+}
+
+/// Where run_simulation streams the arrivals of its checkpoints, one JSON message per line
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum JsFeedTarget {
+    /// a file, replaced at the start of the run
+    File(String),
+    /// address of a local socket listening for the feed, like 127.0.0.1:9000
+    Socket(String),
+}
+
+#[derive(Deserialize)]
+struct JsFeed {
+    target: JsFeedTarget,
+    /// POSIX time the run starts at, the arrival times counting from it like a real feed's
+    #[serde(default)]
+    start_time: i64,
+}
+
+/// A message of the feed, shaped like a GTFS-realtime FeedMessage of trip updates
+#[derive(Serialize)]
+struct JsFeedMessage {
+    header: JsFeedHeader,
+    entity: Vec<JsFeedEntity>,
+}
+
+#[derive(Serialize)]
+struct JsFeedHeader {
+    gtfs_realtime_version: &'static str,
+    timestamp: i64,
+}
+
+#[derive(Serialize)]
+struct JsFeedEntity {
+    id: String,
+    trip_update: JsTripUpdate,
+}
+
+/// Arrivals of a route's trains since the previous message
+#[derive(Serialize)]
+struct JsTripUpdate {
+    trip: JsTripDescriptor,
+    stop_time_update: Vec<JsStopTimeUpdate>,
+}
+
+#[derive(Serialize)]
+struct JsTripDescriptor {
+    route_id: String,
+}
+
+#[derive(Serialize)]
+struct JsStopTimeUpdate {
+    stop_id: String,
+    arrival: JsStopTimeEvent,
+}
+
+#[derive(Serialize)]
+struct JsStopTimeEvent {
+    time: i64,
+}
+
+struct ArrivalFeed {
+    writer: Mutex<Box<dyn Write + Send>>,
+    start_time: i64,
+    time_unit: TimeUnit,
+}
+
+impl ArrivalFeed {
+    fn open(feed: JsFeed, time_unit: TimeUnit) -> Result<Self, SimError> {
+        let writer: Box<dyn Write + Send> = match &feed.target {
+            JsFeedTarget::File(path) => {
+                Box::new(BufWriter::new(File::create(path).map_err(|err| {
+                    SimError::InvalidArgument(format!("can't write the feed to {path}: {err}"))
+                })?))
+            }
+            JsFeedTarget::Socket(address) => {
+                Box::new(TcpStream::connect(address).map_err(|err| {
+                    SimError::InvalidArgument(format!("can't send the feed to {address}: {err}"))
+                })?)
+            }
+        };
+        Ok(Self {
+            writer: Mutex::new(writer),
+            start_time: feed.start_time,
+            time_unit,
+        })
+    }
+
+    fn timestamp(&self, time: f64) -> i64 {
+        self.start_time + (time * 60.0 / self.time_unit.per_minute()).round() as i64
+    }
+
+    // Writes the arrivals of a checkpoint as a message. A consumer going away doesn't stop the
+    // run, so failures are ignored.
+    fn send(&self, checkpoint: &JsCheckpoint) {
+        let mut updates: HashMap<&String, Vec<JsStopTimeUpdate>> = HashMap::new();
+        for (station, arrivals) in &checkpoint.arrivals {
+            for (route, times) in arrivals {
+                updates
+                    .entry(route)
+                    .or_default()
+                    .extend(times.iter().map(|&time| JsStopTimeUpdate {
+                        stop_id: station.clone(),
+                        arrival: JsStopTimeEvent {
+                            time: self.timestamp(time),
+                        },
+                    }));
+            }
+        }
+        let mut entity: Vec<_> = updates
+            .into_iter()
+            .map(|(route, mut stop_time_update)| {
+                stop_time_update.sort_by(|a, b| {
+                    (a.arrival.time, &a.stop_id).cmp(&(b.arrival.time, &b.stop_id))
+                });
+                JsFeedEntity {
+                    id: route.clone(),
+                    trip_update: JsTripUpdate {
+                        trip: JsTripDescriptor {
+                            route_id: route.clone(),
+                        },
+                        stop_time_update,
+                    },
+                }
+            })
+            .collect();
+        entity.sort_by(|a, b| a.id.cmp(&b.id));
+        let message = JsFeedMessage {
+            header: JsFeedHeader {
+                gtfs_realtime_version: "2.0",
+                timestamp: self.timestamp(checkpoint.time),
+            },
+            entity,
+        };
+        let mut writer = self.writer.lock().unwrap();
+        if serde_json::to_writer(&mut *writer, &message).is_ok() {
+            let _ = writeln!(writer);
+            let _ = writer.flush();
+        }
+    }
+}
+
+/// Checkpoints of the latest simulation, so the part simulated is still there if it fails
+#[derive(Default)]
+struct CheckpointStore {
+    checkpoints: Mutex<Vec<JsCheckpoint>>,
+}
+
+#[tauri::command]
+fn get_checkpoints(checkpoints: tauri::State<'_, CheckpointStore>) -> Vec<JsCheckpoint> {
+    checkpoints.checkpoints.lock().unwrap().clone()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn simulate(
+    runs: &RunStore,
+    js_graph: JsGraph,
+    js_routes: JsRoutes,
+    frequency: u64,
+    duration: i32,
+    config: SimulationConfig,
+    demand: Vec<JsTrip>,
+    disruptions: Vec<JsDisruption>,
+    maintenance: Vec<JsMaintenanceWindow>,
+    reroutes: Vec<JsReroute>,
+    timed_transfers: Vec<JsTimedTransfer>,
+    closures: Vec<JsStationClosure>,
+    breakdowns: Vec<JsBreakdown>,
+    trace: JsJourneySampling,
+    frequency_profile: Option<Vec<JsHeadwayPeriod>>,
+    demand_seed: u64,
+    pause: Option<(&SnapshotStore, i32)>,
+    on_checkpoint: &(dyn Fn(JsCheckpoint) + Sync),
+) -> Result<JsSimulationResults, SimError> {
+    let geometry = SectionGeometry::from_js_graph(&js_graph);
+    let (subway_map, cytoscape_id_map, petgraph_map) = js_graph_to_subway_map(js_graph)?;
+    let labels = TrainLabels::new(&js_routes)?;
+    let (routes, route_id_map) = js_routes_to_routes(js_routes, &subway_map, &cytoscape_id_map)?;
+
+    let mut search_map = SearchMap::generate(&subway_map, &routes);
+    let (trip_data, shortest_paths_cache) =
+        js_trips_to_trip_data(demand, &cytoscape_id_map, &mut search_map, demand_seed)?;
+
+    let reroutes = js_reroutes_to_plan(reroutes, &subway_map, &cytoscape_id_map, &route_id_map)?;
+    // riders starting out during a reroute plan their trip on the network as it runs then
+    let rerouted_paths: Vec<_> = reroute_windows(&reroutes)
+        .into_iter()
+        .map(|(start, end)| {
+            let mut search_map = SearchMap::generate(
+                &subway_map,
+                &rerouted(&subway_map, &routes, &reroutes, start),
+            );
+            let shortest_paths = shortest_paths_cache
+                .keys()
+                .map(|&(origin, destination)| {
+                    let paths = shortest_paths(origin, destination, &mut search_map, 2);
+                    ((origin, destination), paths)
+                })
+                .collect();
+            ReroutedPaths {
+                start,
+                end,
+                search_map,
+                shortest_paths,
+            }
+        })
+        .collect();
+
+    let holding = config.holding;
+    let mut simulator = Simulator::new(subway_map, routes.clone(), config);
+    simulator.add_rerouted_demand(
+        &search_map,
+        &trip_data,
+        &shortest_paths_cache,
+        &rerouted_paths,
+    );
+    let disruptions = js_disruptions_to_plan(disruptions, &petgraph_map)?;
+    let maintenance_plans = js_maintenance_to_plans(&maintenance, &petgraph_map)?;
+    simulator.set_disruptions(
+        disruptions
             .iter()
+            .chain(maintenance_plans.iter().flatten())
             .copied()
-            .min_by(f64::total_cmp)
-            .unwrap_or_default(),
-        max_wait: differences
+            .collect(),
+    );
+    simulator.set_reroutes(reroutes);
+    let coordinating = !timed_transfers.is_empty();
+    simulator.set_timed_transfers(js_transfers_to_plan(timed_transfers, &cytoscape_id_map)?);
+    simulator.set_station_closures(js_closures_to_plan(&closures, &cytoscape_id_map)?);
+    simulator.set_breakdowns(js_breakdowns_to_plan(
+        breakdowns,
+        &petgraph_map,
+        &route_id_map,
+    )?);
+    simulator.trace_journeys(&js_sampling_to_sampling(trace, &cytoscape_id_map)?);
+    simulator.check_engine()?;
+    let profile = frequency_profile
+        .map(|periods| js_periods_to_profile(periods, &route_id_map))
+        .transpose()?;
+    let run = |simulator: Simulator| match &profile {
+        Some(profile) => simulator.run_with_profile(duration, profile),
+        None => simulator.run(duration, frequency),
+    };
+
+    // holding is judged against the same run with trains leaving as soon as they can
+    let baseline = match holding {
+        Holding::None => None,
+        _ => {
+            let mut baseline = simulator.clone();
+            baseline.set_holding(Holding::None);
+            Some(baseline)
+        }
+    };
+    // and standby trains against the same run keeping them in storage
+    let gap_baseline = routes.iter().any(|route| route.standby.is_some()).then(|| {
+        let mut gap_baseline = simulator.clone();
+        gap_baseline.clear_standby();
+        gap_baseline
+    });
+    // and maintenance against the same run with the track all open
+    let maintenance_baseline = (!maintenance.is_empty()).then(|| {
+        let mut maintenance_baseline = simulator.clone();
+        maintenance_baseline.set_disruptions(disruptions);
+        maintenance_baseline
+    });
+    // and station closures against the same run with every station open
+    let closure_baseline = (!closures.is_empty()).then(|| {
+        let mut closure_baseline = simulator.clone();
+        closure_baseline.set_station_closures(Vec::new());
+        closure_baseline
+    });
+    // checkpoints are converted as they come in, while the run goes on
+    let (checkpoints, received) = mpsc::channel();
+    // a paused run would hold on to the sender, and the thread receiving wouldn't finish
+    match pause {
+        None => simulator.set_checkpoints(checkpoints),
+        Some(_) => drop(checkpoints),
+    }
+    let (
+        simulation_results,
+        baseline,
+        gap_baseline,
+        maintenance_baseline,
+        closure_baseline,
+        paused,
+    ) = std::thread::scope(|scope| {
+        scope.spawn(|| {
+            for checkpoint in received {
+                on_checkpoint(checkpoint_to_js(
+                    checkpoint,
+                    &petgraph_map,
+                    &route_id_map,
+                    &geometry,
+                ));
+            }
+        });
+        match pause {
+            None => (
+                run(simulator),
+                baseline.map(run),
+                gap_baseline.map(run),
+                maintenance_baseline.map(run),
+                closure_baseline.map(run),
+                None,
+            ),
+            Some((_, time)) => {
+                // the event engine can't stop partway, so paused runs always step
+                let mut paused = match &profile {
+                    Some(profile) => simulator.start_run_with_profile(duration, profile),
+                    None => simulator.start_run(duration, frequency),
+                };
+                paused.run_until(time);
+                (paused.results(), None, None, None, None, Some(paused))
+            }
+        }
+    });
+    for results in std::iter::once(&simulation_results)
+        .chain(&baseline)
+        .chain(&gap_baseline)
+        .chain(&maintenance_baseline)
+        .chain(&closure_baseline)
+    {
+        check_run(
+            results.failure.as_ref(),
+            &petgraph_map,
+            &route_id_map,
+            &labels,
+        )?;
+    }
+    let holding_report = baseline.map(|baseline| JsHoldingReport {
+        holds: simulation_results.hold_times.len(),
+        total_hold_time: simulation_results.hold_times.iter().sum(),
+        headway_deviation: mean_headway_deviation(&simulation_results),
+        baseline_headway_deviation: mean_headway_deviation(&baseline),
+    });
+    let gap_train_report = gap_baseline.map(|gap_baseline| JsGapTrainReport {
+        inserted: simulation_results
+            .gap_trains
             .iter()
-            .copied()
-            .max_by(f64::total_cmp)
-            .unwrap_or_default(),
-        average_wait: differences.iter().sum::<f64>() / differences.len() as f64,
+            .map(|gap_train| JsGapTrain {
+                train: labels.label(gap_train.train, &route_id_map),
+                route: route_id_map[gap_train.train.route_idx as usize].clone(),
+                station: petgraph_map[&TrackStationId::Station(gap_train.station)].clone(),
+                time: gap_train.time,
+                gap: gap_train.gap,
+            })
+            .collect(),
+        average_wait: mean_passenger_wait(&simulation_results),
+        baseline_average_wait: mean_passenger_wait(&gap_baseline),
+    });
+    let transfer_report = coordinating.then(|| {
+        let mut report = JsTransferReport::default();
+        for transfer in &simulation_results.coordinated_transfers {
+            let station = petgraph_map[&TrackStationId::Station(transfer.station)].clone();
+            let counts = report.by_station.entry(station.clone()).or_default();
+            if transfer.made {
+                report.made += 1;
+                counts.made += 1;
+            } else {
+                report.missed += 1;
+                counts.missed += 1;
+            }
+            let train = |id: TrainId| labels.label(id, &route_id_map);
+            report.transfers.push(JsCoordinatedTransfer {
+                station,
+                train: train(transfer.train),
+                feeder: train(transfer.feeder),
+                time: transfer.time,
+                made: transfer.made,
+            });
+        }
+        report
+    });
+    let maintenance_report = maintenance_baseline.map(|maintenance_baseline| {
+        maintenance_report(
+            &maintenance,
+            &maintenance_plans,
+            &routes,
+            &simulation_results,
+            &maintenance_baseline,
+            &labels,
+            &route_id_map,
+        )
+    });
+
+    let closure_report = closure_baseline
+        .map(|closure_baseline| closure_report(&closures, &simulation_results, &closure_baseline));
+    let period_report = profile
+        .as_ref()
+        .map(|profile| period_statistics(profile, &simulation_results, duration, &route_id_map));
+
+    let run_id = runs.insert(&simulation_results, &petgraph_map, &route_id_map);
+    let mut results = simulation_results_to_js(
+        simulation_results,
+        &petgraph_map,
+        &route_id_map,
+        &labels,
+        &geometry,
+        run_id,
+    );
+    results.periods = period_report;
+    results.holding = holding_report;
+    results.gap_trains = gap_train_report;
+    results.transfers = transfer_report;
+    results.maintenance = maintenance_report;
+    results.closures = closure_report;
+    if let (Some((snapshots, _)), Some(paused)) = (pause, paused) {
+        results.snapshot = Some(snapshots.insert(Snapshot {
+            run: paused,
+            petgraph_map,
+            route_id_map,
+            labels,
+            geometry,
+        }));
+    }
+    Ok(results)
+}
+
+/// A paused run and what's needed to turn its results into their form for the frontend
+#[derive(Clone)]
+struct Snapshot {
+    run: PausedRun,
+    petgraph_map: HashMap<TrackStationId, String>,
+    route_id_map: Vec<String>,
+    labels: TrainLabels,
+    geometry: SectionGeometry,
+}
+
+impl Snapshot {
+    // Train of the run now in service going by the label it has in outputs
+    fn train(&self, label: &str) -> Result<TrainId, SimError> {
+        self.run
+            .trains()
+            .find(|train| self.labels.label(*train, &self.route_id_map) == label)
+            .ok_or_else(|| SimError::InvalidArgument(format!("no train {label} in service")))
+    }
+}
+
+/// Runs paused partway by run_simulation, kept until discarded so they can be carried on from
+/// more than once
+#[derive(Default)]
+struct SnapshotStore {
+    snapshots: Mutex<HashMap<u64, Snapshot>>,
+    next_id: AtomicU64,
+}
+
+impl SnapshotStore {
+    fn insert(&self, snapshot: Snapshot) -> JsSnapshot {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let time = snapshot.run.time();
+        self.snapshots.lock().unwrap().insert(id, snapshot);
+        JsSnapshot { id, time }
+    }
+}
+
+#[derive(Serialize)]
+struct JsSnapshot {
+    /// id to pass resume_simulation
+    id: u64,
+    /// time the run was paused at
+    time: i32,
+}
+
+/// Carries on a paused run from its snapshot until the given time, or its end if missing. The
+/// results cover the run so far, with a new snapshot if it paused again. The snapshot resumed
+/// from stays, so it can be carried on from again.
+#[tauri::command]
+async fn resume_simulation(
+    runs: tauri::State<'_, RunStore>,
+    active_runs: tauri::State<'_, ActiveRuns>,
+    snapshots: tauri::State<'_, SnapshotStore>,
+    snapshot: u64,
+    until: Option<i32>,
+) -> Result<JsSimulationResults, SimError> {
+    let _run = active_runs.start("resume_simulation")?;
+    let Snapshot {
+        mut run,
+        petgraph_map,
+        route_id_map,
+        labels,
+        geometry,
+    } = snapshots
+        .snapshots
+        .lock()
+        .unwrap()
+        .get(&snapshot)
+        .cloned()
+        .ok_or_else(|| SimError::InvalidArgument(format!("no snapshot with id {snapshot}")))?;
+    run.run_until(until.unwrap_or(i32::MAX));
+    check_run(run.failure(), &petgraph_map, &route_id_map, &labels)?;
+    let simulation_results = run.results();
+    let run_id = runs.insert(&simulation_results, &petgraph_map, &route_id_map);
+    let mut results = simulation_results_to_js(
+        simulation_results,
+        &petgraph_map,
+        &route_id_map,
+        &labels,
+        &geometry,
+        run_id,
+    );
+    if !run.is_finished() {
+        results.snapshot = Some(snapshots.insert(Snapshot {
+            run,
+            petgraph_map,
+            route_id_map,
+            labels,
+            geometry,
+        }));
+    }
+    Ok(results)
+}
+
+/// Frees a snapshot no longer needed
+#[tauri::command]
+fn discard_snapshot(snapshots: tauri::State<'_, SnapshotStore>, snapshot: u64) {
+    snapshots.snapshots.lock().unwrap().remove(&snapshot);
+}
+
+/// A run the frontend steps through and plays as it likes, see start_session
+struct Session {
+    snapshot: Mutex<Snapshot>,
+    playing: AtomicBool,
+}
+
+impl Session {
+    // Steps the run the given minutes on, returning where it's got
+    fn step(&self, minutes: u32) -> Result<JsSessionStep, SimError> {
+        let snapshot = &mut *self.snapshot.lock().unwrap();
+        let since = snapshot.run.time();
+        snapshot
+            .run
+            .run_until(since.saturating_add_unsigned(minutes));
+        check_run(
+            snapshot.run.failure(),
+            &snapshot.petgraph_map,
+            &snapshot.route_id_map,
+            &snapshot.labels,
+        )?;
+        Ok(JsSessionStep {
+            finished: snapshot.run.is_finished(),
+            checkpoint: checkpoint_to_js(
+                snapshot.run.checkpoint(since),
+                &snapshot.petgraph_map,
+                &snapshot.route_id_map,
+                &snapshot.geometry,
+            ),
+        })
+    }
+}
+
+#[derive(Default)]
+struct SessionStore {
+    sessions: Mutex<HashMap<u64, Arc<Session>>>,
+    next_id: AtomicU64,
+}
+
+impl SessionStore {
+    fn get(&self, session: u64) -> Result<Arc<Session>, SimError> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(&session)
+            .cloned()
+            .ok_or_else(|| SimError::InvalidArgument(format!("no session with id {session}")))
+    }
+}
+
+#[derive(Serialize, Clone)]
+struct JsSessionStep {
+    /// where the run has got, with the arrivals of the step
+    checkpoint: JsCheckpoint,
+    /// whether the run has reached its end, stepping further does nothing
+    finished: bool,
+}
+
+#[derive(Serialize, Clone)]
+struct JsSessionPlayed {
+    session: u64,
+    #[serde(flatten)]
+    step: JsSessionStep,
+}
+
+/// A played session stopping on an error, emitted as session_failed
+#[derive(Serialize, Clone)]
+struct JsSessionFailed {
+    session: u64,
+    error: SimError,
+}
+
+/// Opens a session on a copy of a snapshot, so a run paused by run_simulation can be stepped
+/// through a minute at a time. Pausing at 0 starts the session at the beginning of the run.
+/// Returns the id of the session.
+#[tauri::command]
+fn start_session(
+    snapshots: tauri::State<'_, SnapshotStore>,
+    sessions: tauri::State<'_, SessionStore>,
+    snapshot: u64,
+) -> Result<u64, SimError> {
+    let snapshot = snapshots
+        .snapshots
+        .lock()
+        .unwrap()
+        .get(&snapshot)
+        .cloned()
+        .ok_or_else(|| SimError::InvalidArgument(format!("no snapshot with id {snapshot}")))?;
+    let id = sessions.next_id.fetch_add(1, Ordering::Relaxed);
+    sessions.sessions.lock().unwrap().insert(
+        id,
+        Arc::new(Session {
+            snapshot: Mutex::new(snapshot),
+            playing: AtomicBool::new(false),
+        }),
+    );
+    Ok(id)
+}
+
+/// Steps a session the given minutes on, one by default
+#[tauri::command]
+async fn step_session(
+    sessions: tauri::State<'_, SessionStore>,
+    session: u64,
+    minutes: Option<u32>,
+) -> Result<JsSessionStep, SimError> {
+    sessions.get(session)?.step(minutes.unwrap_or(1))
+}
+
+/// Plays a session a minute at a time until paused or at its end, emitting each step as
+/// session_step, or session_failed if the run fails a check. interval is the wall clock
+/// milliseconds between steps, 1000 by default.
+#[tauri::command]
+fn play_session(
+    window: tauri::Window,
+    sessions: tauri::State<'_, SessionStore>,
+    session: u64,
+    interval: Option<u64>,
+) -> Result<(), SimError> {
+    let id = session;
+    let session = sessions.get(id)?;
+    if session.playing.swap(true, Ordering::Relaxed) {
+        return Ok(());
+    }
+    let interval = Duration::from_millis(interval.unwrap_or(1000));
+    std::thread::spawn(move || {
+        while session.playing.load(Ordering::Relaxed) {
+            let step = match session.step(1) {
+                Ok(step) => step,
+                Err(error) => {
+                    let _ = window.emit("session_failed", JsSessionFailed { session: id, error });
+                    break;
+                }
+            };
+            let finished = step.finished;
+            let _ = window.emit("session_step", JsSessionPlayed { session: id, step });
+            if finished {
+                break;
+            }
+            std::thread::sleep(interval);
+        }
+        session.playing.store(false, Ordering::Relaxed);
+    });
+    Ok(())
+}
+
+/// Stops a playing session after the step it's on
+#[tauri::command]
+fn pause_session(sessions: tauri::State<'_, SessionStore>, session: u64) -> Result<(), SimError> {
+    sessions
+        .get(session)?
+        .playing
+        .store(false, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Full results of a session up to where it's got, with a snapshot to carry on from elsewhere
+#[tauri::command]
+async fn session_results(
+    runs: tauri::State<'_, RunStore>,
+    snapshots: tauri::State<'_, SnapshotStore>,
+    sessions: tauri::State<'_, SessionStore>,
+    session: u64,
+) -> Result<JsSimulationResults, SimError> {
+    let snapshot = sessions.get(session)?.snapshot.lock().unwrap().clone();
+    check_run(
+        snapshot.run.failure(),
+        &snapshot.petgraph_map,
+        &snapshot.route_id_map,
+        &snapshot.labels,
+    )?;
+    let simulation_results = snapshot.run.results();
+    let run_id = runs.insert(
+        &simulation_results,
+        &snapshot.petgraph_map,
+        &snapshot.route_id_map,
+    );
+    let mut results = simulation_results_to_js(
+        simulation_results,
+        &snapshot.petgraph_map,
+        &snapshot.route_id_map,
+        &snapshot.labels,
+        &snapshot.geometry,
+        run_id,
+    );
+    if !snapshot.run.is_finished() {
+        results.snapshot = Some(snapshots.insert(snapshot));
+    }
+    Ok(results)
+}
+
+/// Cancels a train of a session, which is taken out of service at the station it's at or the
+/// next one it gets to. Its riders get off there to wait for another.
+#[tauri::command]
+fn cancel_train(
+    sessions: tauri::State<'_, SessionStore>,
+    session: u64,
+    train: String,
+) -> Result<(), SimError> {
+    let session = sessions.get(session)?;
+    let snapshot = &mut *session.snapshot.lock().unwrap();
+    let train = snapshot.train(&train)?;
+    snapshot.run.cancel_train(train);
+    Ok(())
+}
+
+/// Runs an extra train of a route in a session, leaving its first station as soon as the
+/// platform is free
+#[tauri::command]
+fn add_train(
+    sessions: tauri::State<'_, SessionStore>,
+    session: u64,
+    route: String,
+) -> Result<(), SimError> {
+    let session = sessions.get(session)?;
+    let snapshot = &mut *session.snapshot.lock().unwrap();
+    let idx = snapshot
+        .route_id_map
+        .iter()
+        .position(|id| *id == route)
+        .ok_or_else(|| SimError::InvalidArgument(format!("no route with id {route}")))?;
+    snapshot.run.add_train(RouteId(idx as u32));
+    Ok(())
+}
+
+/// Holds a train of a session at the station it's stopped at, the given minutes longer than it
+/// would stay otherwise
+#[tauri::command]
+fn hold_train(
+    sessions: tauri::State<'_, SessionStore>,
+    session: u64,
+    train: String,
+    minutes: f64,
+) -> Result<(), SimError> {
+    if !(minutes >= 0.0 && minutes.is_finite()) {
+        return Err(SimError::InvalidArgument(format!(
+            "can't hold a train for {minutes} minutes"
+        )));
+    }
+    let session = sessions.get(session)?;
+    let snapshot = &mut *session.snapshot.lock().unwrap();
+    let id = snapshot.train(&train)?;
+    snapshot
+        .run
+        .hold_train(id, minutes)
+        .map_err(|error| error.in_context(&format!("holding train {train}")))
+}
+
+/// Stops a session and frees it
+#[tauri::command]
+fn end_session(sessions: tauri::State<'_, SessionStore>, session: u64) {
+    if let Some(session) = sessions.sessions.lock().unwrap().remove(&session) {
+        session.playing.store(false, Ordering::Relaxed);
+    }
+}
+
+// Mean headway deviation over every route at every station, the regularity holding evens out
+fn mean_headway_deviation(simulation_results: &SimulationResults) -> Option<f64> {
+    let deviations: Vec<_> = simulation_results
+        .station_statistics
+        .values()
+        .flat_map(|statistic| &statistic.arrival_times)
+        .filter_map(|(r_id, arrival_times)| {
+            calculate_arrival_time_statistics(
+                arrival_times.clone(),
+                simulation_results.nominal_headways.get(r_id).copied(),
+                &StatisticsOptions::default(),
+            )
+            .headway_deviation
+        })
+        .collect();
+    mean(&deviations)
+}
+
+// Mean wait of riders boarding at every station
+fn mean_passenger_wait(simulation_results: &SimulationResults) -> Option<f64> {
+    let waits: Vec<_> = simulation_results
+        .station_statistics
+        .values()
+        .flat_map(|statistic| statistic.passenger_waits.iter().copied())
+        .collect();
+    mean(&waits)
+}
+
+// Splits a run's statistics between the periods of its profile, the first counted from the
+// start of recording and the last lasting to the end of the run
+fn period_statistics(
+    profile: &HeadwayProfile,
+    simulation_results: &SimulationResults,
+    duration: i32,
+    route_id_map: &[String],
+) -> Vec<JsPeriodStatistics> {
+    let periods = &profile.periods;
+    periods
+        .iter()
+        .enumerate()
+        .map(|(i, period)| {
+            let start = if i == 0 { 0.0 } else { period.start as f64 };
+            let end = periods
+                .get(i + 1)
+                .map_or(duration as f64, |next| next.start as f64);
+            let within = |time: f64| start <= time && time < end;
+
+            let mut departures = HashMap::new();
+            for trip in &simulation_results.train_trips {
+                if within(trip.start) {
+                    *departures
+                        .entry(route_id_map[trip.route.0 as usize].clone())
+                        .or_default() += 1;
+                }
+            }
+            let journey_times: Vec<_> = simulation_results
+                .journey_times
+                .iter()
+                .zip(&simulation_results.journey_starts)
+                .filter(|(_, &set_out)| within(set_out))
+                .map(|(&journey_time, _)| journey_time)
+                .collect();
+            let waits: Vec<_> = simulation_results
+                .station_statistics
+                .values()
+                .flat_map(|statistic| statistic.passenger_waits.iter().zip(&statistic.boarded_at))
+                .filter(|(_, &boarded)| within(boarded))
+                .map(|(&wait, _)| wait)
+                .collect();
+            JsPeriodStatistics {
+                name: period.name.clone(),
+                start,
+                end,
+                shutdown: period.shutdown,
+                departures,
+                peak_trains_in_service: simulation_results
+                    .in_service
+                    .iter()
+                    .filter(|in_service| within(in_service.time))
+                    .map(|in_service| in_service.by_route.values().sum())
+                    .max()
+                    .unwrap_or(0),
+                passengers_delivered: journey_times.len(),
+                average_journey_time: mean(&journey_times),
+                average_passenger_wait: mean(&waits),
+                turned_away: simulation_results
+                    .turned_away
+                    .iter()
+                    .filter(|&&time| within(time))
+                    .count(),
+            }
+        })
+        .collect()
+}
+
+#[derive(Deserialize, Clone)]
+struct JsNetwork {
+    graph: JsGraph,
+    routes: JsRoutes,
+}
+
+/// Which of the bulkier parts of a run's results an experiment keeps
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(default)]
+struct JsRunOutput {
+    positions: bool,
+    journeys: bool,
+}
+
+/// One run of an experiment, with the same settings as run_simulation
+#[derive(Deserialize, Clone)]
+struct JsExperimentRun {
+    /// label the run's results are reported under
+    name: String,
+    /// key into the experiment's networks
+    network: String,
+    /// key into the experiment's demands, no riders if missing
+    #[serde(default)]
+    demand: Option<String>,
+    frequency: u64,
+    /// minutes to record, see run_simulation
+    #[serde(default)]
+    duration: Option<i32>,
+    #[serde(default)]
+    frequency_profile: Option<Vec<JsHeadwayPeriod>>,
+    #[serde(default)]
+    config: Option<SimulationConfig>,
+    #[serde(default)]
+    disruptions: Vec<JsDisruption>,
+    #[serde(default)]
+    maintenance: Vec<JsMaintenanceWindow>,
+    #[serde(default)]
+    reroutes: Vec<JsReroute>,
+    #[serde(default)]
+    timed_transfers: Vec<JsTimedTransfer>,
+    #[serde(default)]
+    closures: Vec<JsStationClosure>,
+    #[serde(default)]
+    breakdowns: Vec<JsBreakdown>,
+    #[serde(default)]
+    trace: JsJourneySampling,
+    /// the run is repeated with each seed, which draws rider arrivals and replaces the travel
+    /// time noise and incident seeds. Without any it runs once as configured.
+    #[serde(default)]
+    seeds: Vec<u64>,
+    #[serde(default)]
+    output: JsRunOutput,
+}
+
+/// A batch of runs declared up front, so a study can be saved and rerun exactly
+#[derive(Deserialize)]
+struct JsExperiment {
+    networks: HashMap<String, JsNetwork>,
+    #[serde(default)]
+    demands: HashMap<String, Vec<JsTrip>>,
+    runs: Vec<JsExperimentRun>,
+}
+
+#[derive(Serialize)]
+struct JsExperimentResult {
+    name: String,
+    seed: Option<u64>,
+    results: JsSimulationResults,
+}
+
+/// Runs every run of an experiment once per seed, returning all the results in manifest order
+#[tauri::command]
+async fn run_experiment(
+    runs: tauri::State<'_, RunStore>,
+    active_runs: tauri::State<'_, ActiveRuns>,
+    experiment: JsExperiment,
+) -> Result<Vec<JsExperimentResult>, SimError> {
+    let _run = active_runs.start("run_experiment")?;
+    experiment_results(&runs, experiment)
+}
+
+fn experiment_results(
+    runs: &RunStore,
+    experiment: JsExperiment,
+) -> Result<Vec<JsExperimentResult>, SimError> {
+    let mut results = Vec::new();
+    for run in experiment.runs {
+        let network = experiment.networks.get(&run.network).ok_or_else(|| {
+            SimError::InvalidArgument(format!(
+                "run {} uses unknown network {}",
+                run.name, run.network
+            ))
+        })?;
+        let demand = match &run.demand {
+            Some(demand) => experiment.demands.get(demand).cloned().ok_or_else(|| {
+                SimError::InvalidArgument(format!("run {} uses unknown demand {demand}", run.name))
+            })?,
+            None => Vec::new(),
+        };
+        let time_unit = run
+            .config
+            .as_ref()
+            .map_or(TimeUnit::Minutes, |config| config.time_unit);
+        let duration = simulation_horizon(run.duration, time_unit)
+            .map_err(|err| err.in_context(&format!("run {}", run.name)))?;
+        let seeds = if run.seeds.is_empty() {
+            vec![None]
+        } else {
+            run.seeds.iter().copied().map(Some).collect()
+        };
+
+        for seed in seeds {
+            let mut config = run.config.clone().unwrap_or_default();
+            if let Some(seed) = seed {
+                reseed(&mut config, seed);
+            }
+            let mut simulation_results = simulate(
+                runs,
+                network.graph.clone(),
+                network.routes.clone(),
+                run.frequency,
+                duration,
+                config,
+                demand.clone(),
+                run.disruptions.clone(),
+                run.maintenance.clone(),
+                run.reroutes.clone(),
+                run.timed_transfers.clone(),
+                run.closures.clone(),
+                run.breakdowns.clone(),
+                run.trace.clone(),
+                run.frequency_profile.clone(),
+                seed.unwrap_or(DEFAULT_SEED),
+                None,
+                &|_| {},
+            )
+            .map_err(|err| err.in_context(&format!("run {}", run.name)))?;
+            if !run.output.positions {
+                simulation_results.train_positions.clear();
+            }
+            if !run.output.journeys {
+                simulation_results.journeys.clear();
+            }
+            results.push(JsExperimentResult {
+                name: run.name.clone(),
+                seed,
+                results: simulation_results,
+            });
+        }
+    }
+    Ok(results)
+}
+
+#[derive(Serialize)]
+struct JsConnectionTime {
+    from: String,
+    to: String,
+    walk_time: u16,
+    cost: f64,
+}
+
+/// Exports the minimum walk/transfer time between stations as assumed by the router
+#[tauri::command]
+fn connection_time_matrix(
+    window: tauri::Window,
+    search_maps: tauri::State<'_, SearchMapCache>,
+    js_graph: JsGraph,
+    js_routes: JsRoutes,
+) -> Result<Vec<JsConnectionTime>, SimError> {
+    let (graph, map, petgraph_map) = js_graph_to_subway_map(js_graph)?;
+    let (routes, _) = js_routes_to_routes(js_routes, &graph, &map)?;
+    let search_map = search_maps.get(&window, &graph, &routes);
+    let mut connection_times: Vec<_> = search_map
+        .connection_times()
+        .into_iter()
+        .map(|c| JsConnectionTime {
+            from: petgraph_map[&TrackStationId::Station(c.from)].clone(),
+            to: petgraph_map[&TrackStationId::Station(c.to)].clone(),
+            walk_time: c.walk_time,
+            cost: c.cost,
+        })
+        .collect();
+    connection_times.sort_by(|a, b| (&a.from, &a.to).cmp(&(&b.from, &b.to)));
+    Ok(connection_times)
+}
+
+/// Suggests offsets for routes sharing track so their trains are evenly spread, without running
+/// the optimizer
+#[tauri::command]
+fn suggest_offsets(
+    js_graph: JsGraph,
+    js_routes: JsRoutes,
+    frequency: u64,
+) -> Result<HashMap<String, u64>, SimError> {
+    let (subway_map, cytoscape_id_map, _) = js_graph_to_subway_map(js_graph)?;
+    let (routes, route_id_map) = js_routes_to_routes(js_routes, &subway_map, &cytoscape_id_map)?;
+    let offsets = simulator::suggest_offsets(&subway_map, &routes, frequency).ok_or_else(|| {
+        SimError::Route("too many routes share track to search offsets exhaustively".to_string())
+    })?;
+    Ok(route_id_map.into_iter().zip(offsets).collect())
+}
+
+/// Counts a timetable's departures per schedule period, for editing it as frequencies
+#[tauri::command]
+fn departures_to_frequencies(departures: HashMap<String, Vec<i64>>) -> Result<Schedule, SimError> {
+    for (route, times) in &departures {
+        if let Some(time) = times
+            .iter()
+            .find(|time| !(0..SCHEDULE_PERIOD).contains(*time))
+        {
+            return Err(SimError::InvalidArgument(format!(
+                "departure of {route} at {time} is outside the schedule period"
+            )));
+        }
+    }
+    Ok(departures_to_schedule(&departures))
+}
+
+/// Turns per period frequencies back into evenly spaced departures honoring route offsets
+#[tauri::command]
+fn frequencies_to_departures(schedule: Schedule, js_routes: JsRoutes) -> HashMap<String, Vec<i64>> {
+    let offsets = js_routes
+        .into_values()
+        .map(|route| (route.name, route.offset))
+        .collect();
+    schedule_to_departures(&schedule, &offsets)
+}
+
+#[derive(Serialize)]
+struct JsTrackPairing {
+    /// ids of track edges with no track back the other way between the same stations
+    unpaired: Vec<String>,
+    /// the graph with reverse edges added for each unpaired edge, mirroring its weight and
+    /// geometry
+    graph: JsGraph,
+}
+
+#[derive(Serialize)]
+struct JsUnusedElements {
+    /// ids of nodes no route serves and no walk reaches from one that does
+    nodes: Vec<String>,
+    /// ids of edges no route or depot move runs over and no rider walks along
+    edges: Vec<String>,
+    /// the graph without them, cheaper to simulate on
+    graph: JsGraph,
+}
+
+/// A route turning its trains around at a terminal without a crossover
+#[derive(Serialize)]
+struct JsMissingCrossover {
+    route: String,
+    station: String,
+}
+
+/// Flags route patterns which turn trains back at stations with no crossover to do it on
+#[tauri::command]
+fn check_crossovers(
+    js_graph: JsGraph,
+    js_routes: JsRoutes,
+) -> Result<Vec<JsMissingCrossover>, SimError> {
+    let (subway_map, cytoscape_id_map, petgraph_map) = js_graph_to_subway_map(js_graph)?;
+    let (routes, route_id_map) = js_routes_to_routes(js_routes, &subway_map, &cytoscape_id_map)?;
+    // routes can't visit a station twice, so the only turnbacks are turnarounds at terminals
+    Ok(routes
+        .iter()
+        .enumerate()
+        .filter(|(_, route)| route.turnaround.is_some())
+        .map(|(idx, route)| (idx, route_terminal(&subway_map, route)))
+        .filter(|(_, terminal)| !subway_map[*terminal].crossover)
+        .map(|(idx, terminal)| JsMissingCrossover {
+            route: route_id_map[idx].clone(),
+            station: petgraph_map[&TrackStationId::Station(terminal)].clone(),
+        })
+        .collect())
+}
+
+/// Lists the nodes and edges left over from importing or editing a network which nothing uses,
+/// and returns the graph pruned of them
+#[tauri::command]
+fn find_unused_elements(
+    mut js_graph: JsGraph,
+    js_routes: JsRoutes,
+) -> Result<JsUnusedElements, SimError> {
+    let (subway_map, cytoscape_id_map, petgraph_map) = js_graph_to_subway_map(js_graph.clone())?;
+    let (routes, _) = js_routes_to_routes(js_routes, &subway_map, &cytoscape_id_map)?;
+    let (stations, tracks) = unused_elements(&subway_map, &routes);
+    // the reverse of a walk edge shares its fate, so only the drawn edge's id is needed
+    let unused: HashSet<_> = stations
+        .into_iter()
+        .map(TrackStationId::Station)
+        .chain(tracks.into_iter().map(TrackStationId::Track))
+        .map(|id| petgraph_map[&id].clone())
+        .collect();
+    let nodes = js_graph
+        .nodes
+        .iter()
+        .filter(|node| unused.contains(&node.id))
+        .map(|node| node.id.clone())
+        .collect();
+    let edges = js_graph
+        .edges
+        .iter()
+        .filter(|edge| unused.contains(&edge.id))
+        .map(|edge| edge.id.clone())
+        .collect();
+    js_graph.nodes.retain(|node| !unused.contains(&node.id));
+    js_graph.edges.retain(|edge| !unused.contains(&edge.id));
+    Ok(JsUnusedElements {
+        nodes,
+        edges,
+        graph: js_graph,
+    })
+}
+
+/// Finds track lacking a reverse edge, a common mistake when drawing double track which leaves
+/// return routes with nowhere to run, and builds the missing edges
+#[tauri::command]
+fn pair_tracks(mut js_graph: JsGraph) -> JsTrackPairing {
+    let tracks: HashSet<_> = js_graph
+        .edges
+        .iter()
+        .filter(|edge| edge.r#type == "track")
+        .map(|edge| (edge.source.clone(), edge.target.clone()))
+        .collect();
+    let mut ids: HashSet<_> = js_graph.edges.iter().map(|edge| edge.id.clone()).collect();
+
+    let mut unpaired = Vec::new();
+    let mut reverse_edges = Vec::new();
+    for edge in &js_graph.edges {
+        if edge.r#type != "track"
+            || edge.one_way
+            || tracks.contains(&(edge.target.clone(), edge.source.clone()))
+        {
+            continue;
+        }
+        unpaired.push(edge.id.clone());
+
+        let mut id = format!("{}_rev", edge.id);
+        while ids.contains(&id) {
+            id += "_rev";
+        }
+        ids.insert(id.clone());
+        reverse_edges.push(JsEdge {
+            id,
+            source: edge.target.clone(),
+            target: edge.source.clone(),
+            geometry: edge
+                .geometry
+                .as_ref()
+                .map(|points| points.iter().rev().copied().collect()),
+            ..edge.clone()
+        });
+    }
+    js_graph.edges.extend(reverse_edges);
+    JsTrackPairing {
+        unpaired,
+        graph: js_graph,
+    }
+}
+
+/// Proposes draft routes between two terminal stations along the shortest and alternative paths
+#[tauri::command]
+fn suggest_routes(
+    js_graph: JsGraph,
+    source: String,
+    target: String,
+    k: Option<usize>,
+) -> Result<Vec<JsRoute>, SimError> {
+    let (graph, map, petgraph_map) = js_graph_to_subway_map(js_graph)?;
+    let start = station_node(&map, &source)?;
+    let end = station_node(&map, &target)?;
+    Ok(candidate_route_paths(&graph, start, end, k.unwrap_or(3))
+        .into_iter()
+        .enumerate()
+        .map(|(i, (nodes, edges))| JsRoute {
+            name: format!("{source} to {target} ({})", i + 1),
+            id: format!("{source}_{target}_{i}"),
+            nodes: nodes
+                .into_iter()
+                .map(|node| petgraph_map[&TrackStationId::Station(node)].clone())
+                .collect(),
+            edges: edges
+                .into_iter()
+                .map(|edge| petgraph_map[&TrackStationId::Track(edge)].clone())
+                .collect(),
+            offset: 0,
+            combine_short_stops: false,
+            deadhead: false,
+            pass_through: Vec::new(),
+            turnaround: None,
+            interlined: false,
+            depot: None,
+            variant_of: None,
+            train_length: 0.0,
+            cars: None,
+            rolling_stock: None,
+            numbering: None,
+            timetable: None,
+            standby: None,
+            branches: Vec::new(),
+            express: None,
+            padding: 0.0,
+            max_trains: None,
+        })
+        .collect())
+}
+
+#[derive(Serialize)]
+struct JsDivergence {
+    time: f64,
+    /// label of the train or id of the station that differs
+    entity: String,
+    description: String,
+}
+
+/// Runs the same simulation twice and reports anywhere the results differ
+#[tauri::command]
+async fn verify_determinism(
+    active_runs: tauri::State<'_, ActiveRuns>,
+    js_graph: JsGraph,
+    js_routes: JsRoutes,
+    frequency: u64,
+    config: Option<SimulationConfig>,
+) -> Result<Vec<JsDivergence>, SimError> {
+    let _run = active_runs.start("verify_determinism")?;
+    let (subway_map, cytoscape_id_map, petgraph_map) = js_graph_to_subway_map(js_graph)?;
+    let labels = TrainLabels::new(&js_routes)?;
+    let (routes, route_id_map) = js_routes_to_routes(js_routes, &subway_map, &cytoscape_id_map)?;
+    let config = config.unwrap_or_default();
+    let duration = simulation_horizon(None, config.time_unit)?;
+    Simulator::new(subway_map.clone(), routes.clone(), config.clone()).check_engine()?;
+
+    let run = || {
+        Simulator::new(subway_map.clone(), routes.clone(), config.clone()).run(duration, frequency)
+    };
+    let first = run();
+    let second = run();
+    for results in [&first, &second] {
+        check_run(
+            results.failure.as_ref(),
+            &petgraph_map,
+            &route_id_map,
+            &labels,
+        )?;
+    }
+
+    Ok(first
+        .divergences(&second)
+        .into_iter()
+        .map(|d| JsDivergence {
+            time: d.time,
+            entity: match d.entity {
+                DivergenceEntity::Train(train) => labels.label(train, &route_id_map),
+                DivergenceEntity::Station(station) => {
+                    petgraph_map[&TrackStationId::Station(station)].clone()
+                }
+                DivergenceEntity::Run => "run".to_string(),
+            },
+            description: d.description,
+        })
+        .collect())
+}
+
+#[allow(clippy::too_many_arguments)]
+#[tauri::command]
+async fn run_optimize(
+    runs: tauri::State<'_, RunStore>,
+    active_runs: tauri::State<'_, ActiveRuns>,
+    cancellation: tauri::State<'_, OptimizeCancellation>,
+    js_graph: JsGraph,
+    js_routes: JsRoutes,
+    config: Option<SimulationConfig>,
+    options: Option<OptimizeOptions>,
+    seed: Option<JsSeed>,
+    audit: Option<Vec<(String, String)>>,
+    timed_transfers: Option<Vec<JsTimedTransfer>>,
+) -> Result<JsSimulationResults, SimError> {
+    let _run = active_runs.start("run_optimize")?;
+    cancellation.0.store(false, Ordering::Relaxed);
+    let mut options = options.unwrap_or_default();
+    options.seed = JsSeed::resolve(seed);
+    let config = config.unwrap_or_default();
+    scheduling_unit(&config)?;
+    let geometry = SectionGeometry::from_js_graph(&js_graph);
+    let (subway_map, cytoscape_id_map, petgraph_map) = js_graph_to_subway_map(js_graph)?;
+    options.audit = audit
+        .iter()
+        .flatten()
+        .map(|(origin, destination)| {
+            Ok((
+                station_node(&cytoscape_id_map, origin)?,
+                station_node(&cytoscape_id_map, destination)?,
+            ))
+        })
+        .collect::<Result<_, SimError>>()?;
+    options.timed_transfers =
+        js_transfers_to_plan(timed_transfers.unwrap_or_default(), &cytoscape_id_map)?;
+    let labels = TrainLabels::new(&js_routes)?;
+    let (routes, route_id_map) = js_routes_to_routes(js_routes, &subway_map, &cytoscape_id_map)?;
+
+    let started = Instant::now();
+    let mut search_map = SearchMap::generate(&subway_map, &routes);
+    let (trip_data, shortest_paths_cache) = sample_trips(&subway_map, &mut search_map, &options);
+    let sampling_time = started.elapsed().as_secs_f64();
+
+    // later restarts sample their own riders and route order
+    let outcomes = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..options.restarts.max(1) as u64)
+            .map(|restart| {
+                let (subway_map, routes, config) =
+                    (subway_map.clone(), routes.clone(), config.clone());
+                let mut options = options.clone();
+                options.seed = options.seed.wrapping_add(restart);
+                let (trip_data, shortest_paths_cache) = (&trip_data, &shortest_paths_cache);
+                let cancelled = &cancellation.0;
+                scope.spawn(move || {
+                    let sampled = (restart > 0).then(|| {
+                        let mut search_map = SearchMap::generate(&subway_map, &routes);
+                        sample_trips(&subway_map, &mut search_map, &options)
+                    });
+                    let (trip_data, shortest_paths_cache) = match &sampled {
+                        Some((trip_data, shortest_paths_cache)) => {
+                            (trip_data, shortest_paths_cache)
+                        }
+                        None => (trip_data, shortest_paths_cache),
+                    };
+                    let mut cost_audit = Vec::new();
+                    optimize(
+                        subway_map,
+                        routes,
+                        trip_data,
+                        shortest_paths_cache,
+                        config,
+                        &options,
+                        cancelled,
+                        &mut cost_audit,
+                    )
+                    .map(|outcome| (options.seed, outcome, cost_audit))
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Result<Vec<_>, SimError>>()
+    })?;
+
+    // every restart's schedule is priced on the first one's riders, so they compare
+    let mut restarts = Vec::new();
+    let mut best = None;
+    let mut best_cost = f64::INFINITY;
+    for (seed, (schedule, simulation_results, profile), cost_audit) in outcomes {
+        let cost = simulation_results.is_some().then(|| {
+            passenger_cost(
+                &mut search_map,
+                &schedule,
+                &routes,
+                &trip_data,
+                &shortest_paths_cache,
+                &options,
+            ) + options.operator_cost * schedule.values().flatten().sum::<i64>() as f64
+        });
+        restarts.push(JsRestart { seed, cost });
+        if let Some(cost) = cost.filter(|cost| *cost < best_cost) {
+            best_cost = cost;
+            best = Some((seed, schedule, simulation_results, profile, cost_audit));
+        }
+    }
+    let (best_seed, schedule, simulation_results, mut profile, cost_audit) =
+        best.ok_or(SimError::InfeasibleSchedule)?;
+
+    println!("Found schedule: {:#?}\x07", schedule);
+
+    let simulation_results = simulation_results.ok_or(SimError::InfeasibleSchedule)?;
+    let run_id = runs.insert(&simulation_results, &petgraph_map, &route_id_map);
+    let started = Instant::now();
+    let mut js_results = simulation_results_to_js(
+        simulation_results,
+        &petgraph_map,
+        &route_id_map,
+        &labels,
+        &geometry,
+        run_id,
+    );
+    profile.search_map += sampling_time;
+    profile.serialization = started.elapsed().as_secs_f64();
+    js_results.profile = Some(JsOptimizeProfile {
+        cost_estimation: profile.cost_estimation,
+        search_map: profile.search_map,
+        simulation: profile.simulation,
+        solving: profile.solving,
+        serialization: profile.serialization,
+    });
+    js_results.seed = Some(options.seed);
+    js_results.frequency_plan = Some(schedule);
+    if restarts.len() > 1 {
+        js_results.restarts = Some(JsRestarts::new(restarts, best_seed));
+    }
+    if !options.audit.is_empty() {
+        js_results.cost_audit = Some(
+            cost_audit
+                .into_iter()
+                .map(|audit| cost_audit_to_js(audit, &petgraph_map))
+                .collect(),
+        );
+    }
+    Ok(js_results)
+}
+
+fn cost_audit_to_js(
+    audit: CostAudit,
+    petgraph_map: &HashMap<TrackStationId, String>,
+) -> JsCostAudit {
+    let station = |id| petgraph_map[&TrackStationId::Station(id)].clone();
+    JsCostAudit {
+        route: audit.route,
+        period: audit.period,
+        cost: audit.cost,
+        accepted: audit.accepted,
+        riders: audit
+            .riders
+            .into_iter()
+            .map(|rider| JsAuditedRider {
+                origin: station(rider.origin),
+                destination: station(rider.destination),
+                arrival_time: rider.arrival_time,
+                path: rider
+                    .path
+                    .map(|path| JsPathCost::from_path_cost(path, petgraph_map)),
+            })
+            .collect(),
+    }
+}
+
+/// How an optimization's restarts came out, priced on the riders sampled with the seed of
+/// the results
+#[derive(Serialize)]
+struct JsRestarts {
+    restarts: Vec<JsRestart>,
+    /// seed of the restart whose schedule was kept
+    best_seed: u64,
+    /// spread of the costs of the restarts which found a schedule
+    min_cost: f64,
+    max_cost: f64,
+    mean_cost: f64,
+    cost_std_dev: f64,
+}
+
+impl JsRestarts {
+    fn new(restarts: Vec<JsRestart>, best_seed: u64) -> Self {
+        let costs: Vec<_> = restarts.iter().filter_map(|restart| restart.cost).collect();
+        let mean_cost = mean(&costs).unwrap_or_default();
+        JsRestarts {
+            best_seed,
+            min_cost: costs.iter().copied().fold(f64::INFINITY, f64::min),
+            max_cost: costs.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            mean_cost,
+            cost_std_dev: (costs
+                .iter()
+                .map(|cost| (cost - mean_cost).powi(2))
+                .sum::<f64>()
+                / costs.len() as f64)
+                .sqrt(),
+            restarts,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsRestart {
+    seed: u64,
+    /// cost of the schedule found, None if none ran
+    cost: Option<f64>,
+}
+
+/// How the optimizer priced the audited trips for an increment it picked, in the order it
+/// picked them
+#[derive(Serialize)]
+struct JsCostAudit {
+    /// route and schedule period given another train
+    route: String,
+    period: usize,
+    /// cost of all riders with the increment
+    cost: f64,
+    /// whether the schedule still ran, keeping the train
+    accepted: bool,
+    riders: Vec<JsAuditedRider>,
+}
+
+#[derive(Serialize)]
+struct JsAuditedRider {
+    origin: String,
+    destination: String,
+    arrival_time: f64,
+    /// path the cost model picked, None if none of the trip's paths has service
+    path: Option<JsPathCost>,
+}
+
+/// Set to stop the running optimization. Each optimizing command clears it as it starts, so a
+/// cancel sent before one starts is dropped
+#[derive(Default)]
+struct OptimizeCancellation(AtomicBool);
+
+/// Stops a running optimization, which then fails as cancelled
+#[tauri::command]
+fn cancel_optimize(cancellation: tauri::State<'_, OptimizeCancellation>) {
+    cancellation.0.store(true, Ordering::Relaxed);
+}
+
+type ShortestPaths = HashMap<(NodeIndex, NodeIndex), Vec<Vec<PathSegment>>>;
+
+// Samples random trips across the schedule period to optimize against
+fn sample_trips(
+    subway_map: &SubwayMap,
+    search_map: &mut SearchMap,
+    options: &OptimizeOptions,
+) -> (TripData, ShortestPaths) {
+    let mut rng = StdRng::seed_from_u64(options.seed);
+
+    let mut trip_data = TripData::new();
+    let mut num_trips = 0;
+
+    let mut shortest_paths_cache = HashMap::new();
+
+    for _ in 0..30 * SCHEDULE_PERIOD {
+        let start = subway_map.node_indices().choose(&mut rng).unwrap();
+        let end = subway_map.node_indices().choose(&mut rng).unwrap();
+        
+        let paths = shortest_paths(start, end, search_map, 2);
+        
+        if !paths.is_empty() {
+            let time = rng.gen_range(0..SCHEDULE_PERIOD);
+            let trip = Trip::new(
+                start,
+                end,
+                1.0,
+                time as f64,
+                (time + 1) as f64,
+                &options.arrival_process,
+                &mut rng,
+            );
+            trip_data.entry(time).or_default().push(trip);
+            num_trips += 1;
+            
+            shortest_paths_cache.insert((start,end), paths);
+        }
+    }
+    
+    println!("Using {num_trips} trips for optimization");
+    (trip_data, shortest_paths_cache)
+}
+
+/// A schedule no other found beats on both the trains it runs and its riders' cost
+#[derive(Serialize)]
+struct JsFrontierPoint {
+    /// cost per train the optimizer found the schedule with
+    operator_cost: f64,
+    /// trains the schedule runs over the schedule period
+    trains: i64,
+    /// cost of the sampled riders under the schedule
+    passenger_cost: f64,
+    schedule: Schedule,
+}
+
+/// Optimizes a schedule once for each given cost per train, returning the Pareto frontier of
+/// the schedules found by fewest trains first. Every run prices the same sampled riders, so
+/// their costs compare.
+#[allow(clippy::too_many_arguments)]
+#[tauri::command]
+async fn optimize_frontier(
+    active_runs: tauri::State<'_, ActiveRuns>,
+    cancellation: tauri::State<'_, OptimizeCancellation>,
+    js_graph: JsGraph,
+    js_routes: JsRoutes,
+    config: Option<SimulationConfig>,
+    options: Option<OptimizeOptions>,
+    seed: Option<JsSeed>,
+    operator_costs: Vec<f64>,
+) -> Result<Vec<JsFrontierPoint>, SimError> {
+    let _run = active_runs.start("optimize_frontier")?;
+    cancellation.0.store(false, Ordering::Relaxed);
+    if operator_costs.is_empty()
+        || operator_costs
+            .iter()
+            .any(|cost| !(*cost >= 0.0 && cost.is_finite()))
+    {
+        return Err(SimError::InvalidArgument(
+            "operator costs must be one or more positive numbers".to_owned(),
+        ));
+    }
+    let mut options = options.unwrap_or_default();
+    options.seed = JsSeed::resolve(seed);
+    let config = config.unwrap_or_default();
+    scheduling_unit(&config)?;
+    let (subway_map, cytoscape_id_map, _) = js_graph_to_subway_map(js_graph)?;
+    let (routes, _) = js_routes_to_routes(js_routes, &subway_map, &cytoscape_id_map)?;
+
+    let mut search_map = SearchMap::generate(&subway_map, &routes);
+    let (trip_data, shortest_paths_cache) = sample_trips(&subway_map, &mut search_map, &options);
+
+    let mut points = Vec::new();
+    for operator_cost in operator_costs {
+        options.operator_cost = operator_cost;
+        let (schedule, simulation_results, _) = optimize(
+            subway_map.clone(),
+            routes.clone(),
+            &trip_data,
+            &shortest_paths_cache,
+            config.clone(),
+            &options,
+            &cancellation.0,
+            &mut Vec::new(),
+        )?;
+        // nothing ran at this cost, so there's no schedule to offer
+        if simulation_results.is_none() {
+            continue;
+        }
+        points.push(JsFrontierPoint {
+            operator_cost,
+            trains: schedule.values().flatten().sum(),
+            passenger_cost: passenger_cost(
+                &mut search_map,
+                &schedule,
+                &routes,
+                &trip_data,
+                &shortest_paths_cache,
+                &options,
+            ),
+            schedule,
+        });
+    }
+    let frontier = pareto_frontier(points);
+    if frontier.is_empty() {
+        return Err(SimError::InfeasibleSchedule);
+    }
+    Ok(frontier)
+}
+
+// The points no other beats on both trains and passenger cost, by fewest trains first
+fn pareto_frontier(points: Vec<JsFrontierPoint>) -> Vec<JsFrontierPoint> {
+    let dominated: Vec<_> = points
+        .iter()
+        .map(|point| {
+            points.iter().any(|other| {
+                other.trains <= point.trains
+                    && other.passenger_cost <= point.passenger_cost
+                    && (other.trains < point.trains || other.passenger_cost < point.passenger_cost)
+            })
+        })
+        .collect();
+    let mut frontier: Vec<_> = points
+        .into_iter()
+        .zip(dominated)
+        .filter_map(|(point, dominated)| (!dominated).then_some(point))
+        .collect();
+    frontier.sort_by_key(|point| point.trains);
+    // costs giving the same schedule only need it once
+    frontier.dedup_by(|a, b| a.trains == b.trains && a.passenger_cost == b.passenger_cost);
+    frontier
+}
+
+#[derive(Serialize)]
+struct JsPeakSpreading {
+    riders_before: Vec<usize>,
+    riders_after: Vec<usize>,
+    /// Fraction by which the busiest period's ridership drops after spreading
+    peak_reduction: f64,
+    seed: u64,
+}
+
+/// Optimizes a schedule, then reports how riders spread out between adjacent periods in
+/// response to it
+#[allow(clippy::too_many_arguments)]
+#[tauri::command]
+async fn peak_spreading(
+    active_runs: tauri::State<'_, ActiveRuns>,
+    cancellation: tauri::State<'_, OptimizeCancellation>,
+    js_graph: JsGraph,
+    js_routes: JsRoutes,
+    config: Option<SimulationConfig>,
+    options: Option<OptimizeOptions>,
+    sensitivity: Option<f64>,
+    seed: Option<JsSeed>,
+) -> Result<JsPeakSpreading, SimError> {
+    let _run = active_runs.start("peak_spreading")?;
+    cancellation.0.store(false, Ordering::Relaxed);
+    let mut options = options.unwrap_or_default();
+    options.seed = JsSeed::resolve(seed);
+    let config = config.unwrap_or_default();
+    scheduling_unit(&config)?;
+    let (subway_map, cytoscape_id_map, _) = js_graph_to_subway_map(js_graph)?;
+    let (routes, _) = js_routes_to_routes(js_routes, &subway_map, &cytoscape_id_map)?;
+
+    let mut search_map = SearchMap::generate(&subway_map, &routes);
+    let (trip_data, shortest_paths_cache) = sample_trips(&subway_map, &mut search_map, &options);
+
+    let (schedule, _, _) = optimize(
+        subway_map,
+        routes,
+        &trip_data,
+        &shortest_paths_cache,
+        config,
+        &options,
+        &cancellation.0,
+        &mut Vec::new(),
+    )?;
+
+    let spreading = spread_peaks(
+        &search_map,
+        &schedule,
+        &trip_data,
+        &shortest_paths_cache,
+        sensitivity.unwrap_or(1.),
+        options.cyclic,
+        options.seed,
+    );
+    let peak_before = spreading
+        .riders_before
+        .iter()
+        .max()
+        .copied()
+        .unwrap_or_default();
+    let peak_after = spreading
+        .riders_after
+        .iter()
+        .max()
+        .copied()
+        .unwrap_or_default();
+    Ok(JsPeakSpreading {
+        peak_reduction: if peak_before > 0 {
+            1. - peak_after as f64 / peak_before as f64
+        } else {
+            0.
+        },
+        riders_before: spreading.riders_before,
+        riders_after: spreading.riders_after,
+        seed: options.seed,
+    })
+}
+
+#[derive(Serialize, Clone)]
+struct JsTrainPosition {
+    pub id: (u32, u32),
+    pub curr_section: String,
+    pub pos: f64,
+    pub distance_travelled: f64,
+    /// map coordinates of the train, if the section has known geometry
+    pub coordinates: Option<JsPoint>,
+    /// passengers on board
+    pub load: usize,
+}
+
+#[derive(Serialize, Clone)]
+struct JsTrainPositions {
+    pub time: f64,
+    pub trains: Vec<JsTrainPosition>,
+}
+
+/// summary of a simulation so far, emitted as simulation_checkpoint while it runs
+#[derive(Serialize, Clone)]
+struct JsCheckpoint {
+    /// simulated minutes so far, not counting warm up
+    pub time: f64,
+    pub passengers_delivered: usize,
+    pub average_journey_time: Option<f64>,
+    pub passengers_waiting: usize,
+    pub trains_in_service: usize,
+    /// arrivals since the previous checkpoint, by station then route
+    pub arrivals: HashMap<String, HashMap<String, Vec<f64>>>,
+    pub positions: JsTrainPositions,
+}
+
+#[derive(Serialize)]
+struct JsStationStatistic {
+    pub arrival_times: HashMap<String, JsArrivalStats>,
+    /// arrival times of routes dispatched from this station
+    pub terminal_arrival_times: HashMap<String, JsArrivalStats>,
+    /// arrival times for all routes
+    /// None if there's only one route
+    pub overall_arrival_times: Option<JsArrivalStats>,
+    /// arrival statistics of each of StatisticsOptions::bands, by route
+    pub bands: Vec<JsBandStatistic>,
+    /// mean time simulated passengers waited to board here, None if nobody boarded
+    pub average_passenger_wait: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct JsBandStatistic {
+    #[serde(flatten)]
+    pub band: JsTimeBand,
+    /// routes arriving during the band
+    pub arrival_times: HashMap<String, JsArrivalStats>,
+}
+
+#[derive(Serialize)]
+struct JsArrivalStats {
+    pub min_wait: f64,
+    pub max_wait: f64,
+    pub average_wait: f64,
+    /// headway riders were told to expect
+    pub nominal_headway: Option<f64>,
+    /// mean absolute difference between realized and nominal headways
+    pub headway_deviation: Option<f64>,
+    /// extra time beyond the nominal headway riders must budget to be on time 95% of the
+    /// time, as a fraction of the nominal headway
+    pub reliability_buffer_index: Option<f64>,
+    /// average wait of a rider under the chosen arrival assumption
+    pub expected_wait: f64,
+    /// (percentile, headway) pairs
+    pub headway_percentiles: Vec<(f64, f64)>,
+}
+
+#[derive(Serialize)]
+struct JsSimulationResults {
+    /// id of the stored run, for use with recompute_statistics until discard_run frees it
+    pub run_id: u64,
+    pub train_positions: Vec<JsTrainPositions>,
+    // String of routeid_trainnum to route string
+    pub train_to_route: HashMap<String, String>,
+    /// which route each physical train served when, following trains through turnarounds
+    pub train_trips: Vec<JsTrainTrip>,
+    pub station_statistics: HashMap<String, JsStationStatistic>,
+    /// mean deviation of each route's departures from even spacing, for optimized schedules
+    pub dispatch_deviations: HashMap<String, f64>,
+    /// when each route's trains left their first station, for optimized schedules
+    pub timetable: HashMap<String, Vec<i64>>,
+    /// trains of each route in every schedule period, found by run_optimize
+    pub frequency_plan: Option<Schedule>,
+    /// most passengers seen on board a train along each track
+    pub peak_loads: HashMap<String, usize>,
+    /// passengers who reached their destination
+    pub passengers_delivered: usize,
+    /// mean door to door time of delivered passengers, None if there were none
+    pub average_journey_time: Option<f64>,
+    pub operating_distances: HashMap<String, JsOperatingDistance>,
+    /// riders boarding each route's trains, counting the route of the train they actually took
+    /// where several share a trunk
+    pub ridership: HashMap<String, usize>,
+    /// capacity of each route's trains and how full they left their stops against it
+    pub route_loads: HashMap<String, JsRouteLoad>,
+    /// arrivals bunched up with the route's previous train, by route then station
+    pub bunching: HashMap<String, HashMap<String, Vec<JsBunching>>>,
+    /// how late the trains of routes run to a timetable got to each stop, by route then station
+    pub schedule_deviations: HashMap<String, HashMap<String, JsScheduleDeviation>>,
+    /// traction energy of each route's trains, empty unless the config has an energy model
+    pub energy: HashMap<String, JsRouteEnergy>,
+    pub disruption_statistics: JsDisruptionStatistics,
+    /// trains dispatched over the run, warm up included, a train turned around counting once
+    pub dispatches: usize,
+    /// most trains out at once, the physical fleet needed to run the service
+    pub peak_trains_in_service: usize,
+    /// trains out at each time unit, by route
+    pub trains_in_service: Vec<JsTrainsInService>,
+    /// most trains each route had out at once
+    pub peak_trains_by_route: HashMap<String, usize>,
+    /// padding of the padded routes and how much of it their trains used
+    pub padding: HashMap<String, JsPaddingUse>,
+    /// minutes simulated before statistics started
+    pub warm_up: u32,
+    /// unit of every time in the results, minutes unless the config said otherwise
+    pub time_unit: TimeUnit,
+    /// step by step trips of the traced passengers
+    pub journeys: Vec<JsJourney>,
+    /// where the time of an optimization run went, None for plain simulations
+    pub profile: Option<JsOptimizeProfile>,
+    /// how the optimizer priced the trips it was asked to audit, None unless it was
+    pub cost_audit: Option<Vec<JsCostAudit>>,
+    /// best schedule's competitors, when run_optimize was asked to restart
+    pub restarts: Option<JsRestarts>,
+    /// seed of the command's random draws, to reproduce a run seeded at random
+    pub seed: Option<u64>,
+    /// where a run paused partway can be resumed from, None if it ran to the end
+    pub snapshot: Option<JsSnapshot>,
+    /// times stations had more passengers waiting than their waiting capacity
+    pub crowding_alerts: Vec<JsCrowdingAlert>,
+    /// headway patterns at the stations where routes sharing a trunk branch off
+    pub branch_headways: Vec<JsBranchHeadways>,
+    /// express trains passing stopped trains on bypass tracks
+    pub overtakes: Vec<JsOvertake>,
+    /// stations late trains ran through to catch up, and the riders left on the platform
+    pub skipped_stops: Vec<JsSkippedStop>,
+    /// times riders let a crowded train go by, under the config's crowding
+    pub crowding_skips: usize,
+    /// riders who took a longer path to stay off crowded trains
+    pub crowding_detours: usize,
+    /// random and planned breakdowns and the delay they caused the trains behind
+    pub incidents: Vec<JsIncident>,
+    /// time each route's trains lost held up behind broken down trains, over all incidents
+    pub knock_on_delays: HashMap<String, f64>,
+    /// minutes each station's bypass track was occupied
+    pub bypass_use: HashMap<String, f64>,
+    /// effect of the config's holding strategy, if it has one
+    pub holding: Option<JsHoldingReport>,
+    /// standby trains sent out to fill gaps, if any route has some
+    pub gap_trains: Option<JsGapTrainReport>,
+    /// statistics of each period of the frequency profile, if the run had one
+    pub periods: Option<Vec<JsPeriodStatistics>>,
+    /// departures that waited for a train under the routes' or the config's max_trains
+    pub fleet_waits: Vec<JsFleetWait>,
+    /// connections made and missed at timed transfers, if there are any
+    pub transfers: Option<JsTransferReport>,
+    /// trips the maintenance calendar delayed, if there was one
+    pub maintenance: Option<JsMaintenanceReport>,
+    /// riders moved by station closures and the effect on trips, if any stations were closed
+    pub closures: Option<JsClosureReport>,
+}
+
+#[derive(Serialize)]
+struct JsPaddingUse {
+    /// padding of all the stops the route's trains made
+    pub scheduled: f64,
+    pub used: f64,
+    /// fraction of the padding used, None without any
+    pub utilization: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct JsTrainsInService {
+    pub time: f64,
+    pub total: usize,
+    pub by_route: HashMap<String, usize>,
+}
+
+/// How a period of a frequency profile went. Riders count in the period they set out in,
+/// waits in the one they boarded in.
+#[derive(Serialize)]
+struct JsPeriodStatistics {
+    pub name: Option<String>,
+    pub start: f64,
+    pub end: f64,
+    pub shutdown: bool,
+    /// trips trains started on each route
+    pub departures: HashMap<String, usize>,
+    pub peak_trains_in_service: usize,
+    pub passengers_delivered: usize,
+    pub average_journey_time: Option<f64>,
+    pub average_passenger_wait: Option<f64>,
+    /// riders who showed up while the system was shut
+    pub turned_away: usize,
+}
+
+#[derive(Serialize)]
+struct JsTrainTrip {
+    pub train: String,
+    pub route: String,
+    pub start: f64,
+    /// None if the train was still on the route at the end of the run
+    pub end: Option<f64>,
+}
+
+#[derive(Serialize, Default)]
+struct JsTransferReport {
+    pub made: usize,
+    pub missed: usize,
+    pub by_station: HashMap<String, JsTransferCounts>,
+    pub transfers: Vec<JsCoordinatedTransfer>,
+}
+
+#[derive(Serialize, Default)]
+struct JsTransferCounts {
+    pub made: usize,
+    pub missed: usize,
+}
+
+/// a train held at a timed transfer for a feeder, made if the feeder got in before it left
+#[derive(Serialize)]
+struct JsCoordinatedTransfer {
+    pub station: String,
+    pub train: String,
+    pub feeder: String,
+    pub time: f64,
+    pub made: bool,
+}
+
+/// How holding trains changed headway regularity, against the same run without holding
+#[derive(Serialize)]
+struct JsHoldingReport {
+    pub holds: usize,
+    pub total_hold_time: f64,
+    /// mean headway deviation over every route at every station
+    pub headway_deviation: Option<f64>,
+    pub baseline_headway_deviation: Option<f64>,
+}
+
+/// How standby trains changed riders' waits, against the same run keeping them in storage
+#[derive(Serialize)]
+struct JsGapTrainReport {
+    pub inserted: Vec<JsGapTrain>,
+    /// mean wait of riders boarding anywhere
+    pub average_wait: Option<f64>,
+    pub baseline_average_wait: Option<f64>,
+}
+
+/// Riders moved by station closures, against the same run with the stations open
+#[derive(Serialize)]
+struct JsClosureReport {
+    /// each closure, in the order given
+    pub closures: Vec<JsClosureImpact>,
+    pub passengers_delivered: usize,
+    pub baseline_passengers_delivered: usize,
+    pub average_journey_time: Option<f64>,
+    pub baseline_average_journey_time: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct JsClosureImpact {
+    pub station: String,
+    pub start: f64,
+    pub end: f64,
+    pub trains_run_through: usize,
+    /// riders who walked to another station to board
+    pub riders_diverted: usize,
+    /// riders getting off there who were carried on to the next stop
+    pub riders_carried_past: usize,
+}
+
+/// Trips made longer by maintenance, against the same run without any
+#[derive(Serialize)]
+struct JsMaintenanceReport {
+    /// each window of the calendar, in its order
+    pub windows: Vec<JsMaintenanceImpact>,
+    /// routes with trips delayed
+    pub routes: HashMap<String, JsMaintenanceDelay>,
+    pub average_journey_time: Option<f64>,
+    pub baseline_average_journey_time: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct JsMaintenanceImpact {
+    pub edges: Vec<String>,
+    pub start: f64,
+    pub end: f64,
+    /// parallel tracks left open, None if the edges were closed
+    pub tracks: Option<u8>,
+    /// routes running over the edges
+    pub routes: Vec<String>,
+    /// trips of those routes under way during the window which were delayed
+    pub delayed_trips: usize,
+    pub total_delay: f64,
+}
+
+#[derive(Serialize, Default)]
+struct JsMaintenanceDelay {
+    pub delayed_trips: usize,
+    pub total_delay: f64,
+    pub max_delay: f64,
+    pub trips: Vec<JsDelayedTrip>,
+}
+
+#[derive(Serialize)]
+struct JsDelayedTrip {
+    pub train: String,
+    pub start: f64,
+    /// time the trip took beyond the same trip without maintenance
+    pub delay: f64,
+}
+
+#[derive(Serialize)]
+struct JsFleetWait {
+    pub route: String,
+    pub scheduled: f64,
+    /// None if the train never left
+    pub dispatched: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct JsGapTrain {
+    pub train: String,
+    pub route: String,
+    pub station: String,
+    pub time: f64,
+    /// time since the route's previous train got to the station
+    pub gap: f64,
+}
+
+#[derive(Serialize)]
+struct JsBranchHeadways {
+    pub station: String,
+    /// routes of the trunk's trains in the order they arrived, with the times
+    pub pattern: Vec<(f64, String)>,
+    pub branches: HashMap<String, JsBranchHeadway>,
+    pub irregular: Vec<JsIrregularAlternation>,
+}
+
+#[derive(Serialize)]
+struct JsBranchHeadway {
+    pub headways: Vec<f64>,
+    pub mean: f64,
+    pub max: f64,
+}
+
+/// a route arriving repeats times in a row, more than its share of the trunk accounts for
+#[derive(Serialize)]
+struct JsIrregularAlternation {
+    pub time: f64,
+    pub route: String,
+    pub repeats: usize,
+}
+
+#[derive(Serialize)]
+struct JsIncident {
+    pub train: String,
+    /// id of the track or station the train broke down on
+    pub section: String,
+    pub start: f64,
+    pub end: f64,
+    /// time lost by each train held up, directly or behind another
+    pub delays: HashMap<String, f64>,
+    pub total_delay: f64,
+    /// the same delays totalled by route
+    pub route_delays: HashMap<String, f64>,
+    /// whether the train was pushed off at end instead of recovering
+    pub rescued: bool,
+    /// when a rescued train was taken out of service at the end of its route
+    pub cleared: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct JsOvertake {
+    pub station: String,
+    pub train: String,
+    pub overtaken: String,
+    pub time: f64,
+}
+
+#[derive(Serialize)]
+struct JsSkippedStop {
+    pub station: String,
+    pub train: String,
+    pub time: f64,
+    pub lateness: f64,
+    pub passed_by: usize,
+}
+
+#[derive(Serialize)]
+struct JsCrowdingAlert {
+    pub station: String,
+    pub start: f64,
+    pub end: f64,
+    pub peak_waiting: usize,
+}
+
+#[derive(Serialize)]
+struct JsBunching {
+    pub time: f64,
+    /// time since the route's previous train
+    pub headway: f64,
+}
+
+/// time trains got to a stop after they were due, early arrivals being negative
+#[derive(Serialize)]
+struct JsScheduleDeviation {
+    pub mean: f64,
+    pub max: f64,
+    pub min: f64,
+    pub trains: usize,
+}
+
+/// seconds spent on each part of an optimization run
+#[derive(Serialize)]
+struct JsOptimizeProfile {
+    pub cost_estimation: f64,
+    pub search_map: f64,
+    pub simulation: f64,
+    pub solving: f64,
+    pub serialization: f64,
+}
+
+#[derive(Serialize)]
+struct JsDisruptionStatistics {
+    /// trains held up by closed or slowed track
+    pub delayed_trains: usize,
+    pub total_delay: f64,
+    pub max_delay: f64,
+    /// station ids where trains were taken out of service ahead of a closure
+    pub short_turns: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct JsRouteEnergy {
+    pub total: f64,
+    /// energy used in each hour since recording started
+    pub by_hour: Vec<f64>,
+}
+
+#[derive(Serialize)]
+struct JsRouteLoad {
+    /// riders a train carries, from its cars if the route gives them
+    pub capacity: usize,
+    pub cars: Option<u32>,
+    /// mean and highest share of capacity trains left their stops with, None without any stops
+    pub average_load_factor: Option<f64>,
+    pub peak_load_factor: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct JsOperatingDistance {
+    pub revenue: f64,
+    pub deadhead: f64,
+}
+
+fn main() {
+    tauri::Builder::default()
+        .manage(RunStore::default())
+        .manage(OptimizeCancellation::default())
+        .manage(ActiveRuns::default())
+        .manage(CheckpointStore::default())
+        .manage(SnapshotStore::default())
+        .manage(SessionStore::default())
+        .setup(|app| {
+            let dir = app
+                .path_resolver()
+                .app_cache_dir()
+                .map(|dir| dir.join("search_maps"));
+            app.manage(SearchMapCache::load(dir));
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![
+            run_simulation,
+            get_checkpoints,
+            run_experiment,
+            shortest_path,
+            run_optimize,
+            optimize_frontier,
+            cancel_optimize,
+            get_active_runs,
+            compare_itineraries,
+            verify_determinism,
+            suggest_routes,
+            connection_time_matrix,
+            recompute_statistics,
+            peak_spreading,
+            estimate_run,
+            station_ranking,
+            discard_run,
+            suggest_offsets,
+            departures_to_frequencies,
+            frequencies_to_departures,
+            pair_tracks,
+            check_crossovers,
+            find_unused_elements,
+            resume_simulation,
+            discard_snapshot,
+            start_session,
+            step_session,
+            play_session,
+            pause_session,
+            session_results,
+            cancel_train,
+            add_train,
+            hold_train,
+            end_session,
+            preview_frequencies,
+            preview_dispatches
+        ])
+        .run(tauri::generate_context!())
+        .expect("error while running tauri application");
+}
+
+fn mean(data: &[f64]) -> Option<f64> {
+    (!data.is_empty()).then(|| data.iter().sum::<f64>() / data.len() as f64)
+}
+
+/// Gets the value at a percentile between 0 and 100 of sorted data
+fn percentile(sorted: &[f64], percentile: f64) -> f64 {
+    sorted[((sorted.len() - 1) as f64 * percentile / 100.0).round() as usize]
+}
+
+fn calculate_arrival_time_statistics(
+    mut data: Vec<f64>,
+    nominal_headway: Option<f64>,
+    options: &StatisticsOptions,
+) -> JsArrivalStats {
+    data.retain(|time| *time >= options.warm_up);
+    let mut differences = Vec::with_capacity(data.len());
+    let mut prev_time = data.first().copied().unwrap_or_default();
+    for item in data.iter().skip(1) {
+        differences.push(*item - prev_time);
+        prev_time = *item;
+    }
+
+    let nominal_headway = nominal_headway.filter(|h| h.is_finite() && *h > 0.0);
+    let (headway_deviation, reliability_buffer_index) = match nominal_headway {
+        Some(nominal) if !differences.is_empty() => {
+            let deviation = differences.iter().map(|d| (d - nominal).abs()).sum::<f64>()
+                / differences.len() as f64;
+            let mut sorted = differences.clone();
+            sorted.sort_unstable_by(f64::total_cmp);
+            let p95 = percentile(&sorted, 95.0);
+            (Some(deviation), Some((p95 - nominal) / nominal))
+        }
+        _ => (None, None),
+    };
+
+    let mut sorted = differences.clone();
+    sorted.sort_unstable_by(f64::total_cmp);
+    let headway_percentiles = if sorted.is_empty() {
+        Vec::new()
+    } else {
+        options
+            .percentiles
+            .iter()
+            .map(|p| (*p, percentile(&sorted, p.clamp(0.0, 100.0))))
+            .collect()
+    };
+
+    let total_headway: f64 = differences.iter().sum();
+    let expected_wait = match options.rider_arrival {
+        RiderArrival::Random => {
+            differences.iter().map(|h| h * h).sum::<f64>() / (2.0 * total_headway)
+        }
+        RiderArrival::HalfHeadway => total_headway / differences.len() as f64 / 2.0,
+        RiderArrival::Informed { buffer } => {
+            differences.iter().map(|h| h.min(buffer)).sum::<f64>() / differences.len() as f64
+        }
+    };
+
+    JsArrivalStats {
+        expected_wait,
+        headway_percentiles,
+        nominal_headway,
+        headway_deviation,
+        reliability_buffer_index,
+        min_wait: differences
+            .iter()
+            .copied()
+            .min_by(f64::total_cmp)
+            .unwrap_or_default(),
+        max_wait: differences
+            .iter()
+            .copied()
+            .max_by(f64::total_cmp)
+            .unwrap_or_default(),
+        average_wait: differences.iter().sum::<f64>() / differences.len() as f64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    /// Riders from origin to destination arriving over the first 20 minutes
+    fn trip(origin: &str, destination: &str, count: usize) -> Value {
+        json!({
+            "origin": origin,
+            "destination": destination,
+            "count": count,
+            "start_time": 0.0,
+            "end_time": 20.0,
+        })
+    }
+
+    /// Simulates a network given as JSON by node ids, one_way track edges "source-target" and
+    /// routes, with the rest of the scenario's inputs defaulted unless the JSON has them
+    fn run(scenario: Value) -> JsSimulationResults {
+        run_in(&RunStore::default(), scenario)
+    }
+
+    /// Graph and routes of a scenario, see run. Nodes are given by id unless they set more.
+    pub(crate) fn network(scenario: &Value) -> (JsGraph, JsRoutes) {
+        let nodes = scenario["nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|node| match node {
+                Value::String(_) => json!({ "id": node }),
+                _ => node.clone(),
+            })
+            .collect::<Vec<_>>();
+        let edges = scenario["edges"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|edge| {
+                let (source, target) = edge["id"].as_str().unwrap().split_once('-').unwrap();
+                let mut edge = edge.clone();
+                let fields = edge.as_object_mut().unwrap();
+                fields.entry("source").or_insert(json!(source));
+                fields.entry("target").or_insert(json!(target));
+                fields.entry("weight").or_insert(json!(2));
+                fields.entry("type").or_insert(json!("track"));
+                fields.entry("one_way").or_insert(json!(true));
+                edge
+            })
+            .collect::<Vec<_>>();
+        let routes = scenario["routes"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .map(|(id, route)| {
+                let mut route = route.clone();
+                let fields = route.as_object_mut().unwrap();
+                fields.entry("id").or_insert(json!(id));
+                fields.entry("name").or_insert(json!(id));
+                fields.entry("edges").or_insert(json!([]));
+                (id.clone(), route)
+            })
+            .collect::<serde_json::Map<_, _>>();
+        (
+            serde_json::from_value(json!({ "nodes": nodes, "edges": edges })).unwrap(),
+            serde_json::from_value(Value::Object(routes)).unwrap(),
+        )
+    }
+
+    /// Like run, storing the run in runs
+    fn run_in(runs: &RunStore, scenario: Value) -> JsSimulationResults {
+        run_watched(runs, scenario, &|_| {})
+    }
+
+    /// Like run_in, handing each checkpoint to on_checkpoint
+    fn run_watched(
+        runs: &RunStore,
+        scenario: Value,
+        on_checkpoint: &(dyn Fn(JsCheckpoint) + Sync),
+    ) -> JsSimulationResults {
+        run_paused(runs, scenario, None, on_checkpoint)
+    }
+
+    /// Like run_watched, pausing at the given time with a snapshot in the store
+    fn run_paused(
+        runs: &RunStore,
+        scenario: Value,
+        pause: Option<(&SnapshotStore, i32)>,
+        on_checkpoint: &(dyn Fn(JsCheckpoint) + Sync),
+    ) -> JsSimulationResults {
+        fn field<T: serde::de::DeserializeOwned>(scenario: &Value, key: &str, default: Value) -> T {
+            serde_json::from_value(scenario.get(key).cloned().unwrap_or(default)).unwrap()
+        }
+        let (js_graph, js_routes) = network(&scenario);
+        simulate(
+            runs,
+            js_graph,
+            js_routes,
+            field(&scenario, "frequency", json!(5)),
+            field(&scenario, "duration", json!(60)),
+            field(&scenario, "config", json!({})),
+            field(&scenario, "demand", json!([])),
+            field(&scenario, "disruptions", json!([])),
+            field(&scenario, "maintenance", json!([])),
+            field(&scenario, "reroutes", json!([])),
+            field(&scenario, "timed_transfers", json!([])),
+            field(&scenario, "closures", json!([])),
+            field(&scenario, "breakdowns", json!([])),
+            field(&scenario, "trace", json!({})),
+            field(&scenario, "frequency_profile", Value::Null),
+            field(&scenario, "seed", json!(0)),
+            pause,
+            on_checkpoint,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn trains_are_placed_along_the_drawn_shape_of_their_edge() {
+        let js_graph: JsGraph = serde_json::from_value(json!({
+            "nodes": [
+                { "id": "a", "position": { "x": 0.0, "y": 0.0 } },
+                { "id": "b", "position": { "x": 2.0, "y": 2.0 } },
+                { "id": "c", "position": { "x": 2.0, "y": 6.0 } },
+            ],
+            "edges": [
+                {
+                    "id": "a-b", "source": "a", "target": "b", "weight": 4, "type": "track",
+                    "geometry": [
+                        { "x": 0.0, "y": 0.0 },
+                        { "x": 2.0, "y": 0.0 },
+                        { "x": 2.0, "y": 2.0 },
+                    ],
+                },
+                { "id": "b-c", "source": "b", "target": "c", "weight": 4, "type": "track" },
+            ],
+        }))
+        .unwrap();
+        let geometry = SectionGeometry::from_js_graph(&js_graph);
+        assert_eq!(
+            geometry.coordinates("b", 3.0),
+            Some(JsPoint { x: 2.0, y: 2.0 })
+        );
+        // halfway along the bend in the drawn edge, and a straight line without one
+        assert_eq!(
+            geometry.coordinates("a-b", 2.0),
+            Some(JsPoint { x: 2.0, y: 0.0 })
+        );
+        assert_eq!(
+            geometry.coordinates("b-c", 1.0),
+            Some(JsPoint { x: 2.0, y: 3.0 })
+        );
+    }
+
+    #[test]
+    fn depot_trains_go_by_their_route() {
+        let results = run(json!({
+            "nodes": ["d", "a", "b", "c"],
+            "edges": [{ "id": "d-a" }, { "id": "a-b" }, { "id": "b-c" }, { "id": "c-d" }],
+            "routes": { "r": { "nodes": ["a", "b", "c"], "depot": "d" } },
+            "demand": [trip("a", "c", 10)],
+        }));
+        assert_eq!(results.passengers_delivered, 10);
+        assert!(!results.train_to_route.is_empty());
+        assert!(results.train_to_route.values().all(|route| route == "r"));
+        assert!(results.train_trips.iter().all(|trip| trip.route == "r"));
+    }
+
+    #[test]
+    fn trains_pass_through_stations_no_route_stops_at() {
+        let results = run(json!({
+            "nodes": ["a", "b", "c"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+            "routes": { "r": { "nodes": ["a", "b", "c"], "pass_through": ["b"] } },
+            "demand": [trip("a", "c", 10)],
+        }));
+        assert_eq!(results.passengers_delivered, 10);
+    }
+
+    #[test]
+    fn nominal_headway_is_the_frequency_run() {
+        let results = run(json!({
+            "nodes": ["a", "b", "c"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+            "routes": { "r": { "nodes": ["a", "b", "c"] } },
+            "frequency": 6,
+            "duration": 100,
+        }));
+        let arrivals = &results.station_statistics["b"].arrival_times["r"];
+        assert_eq!(arrivals.nominal_headway, Some(6.0));
+    }
+
+    #[test]
+    fn sub_minute_steps_clear_terminals_with_unused_spurs() {
+        let results = run(json!({
+            "nodes": ["a", "b", "c", "d", "y"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }, { "id": "c-d" }, { "id": "d-y" }],
+            "routes": { "r": { "nodes": ["a", "b", "c", "d"] } },
+            "frequency": 2,
+            "config": { "time_step": 0.25 },
+            "demand": [trip("a", "d", 10), trip("b", "d", 10)],
+        }));
+        assert_eq!(results.passengers_delivered, 20);
+    }
+
+    #[test]
+    fn dispatches_count_more_trains_than_are_out_at_once() {
+        let results = run(json!({
+            "nodes": ["a", "b", "c"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+            "routes": { "r": { "nodes": ["a", "b", "c"] } },
+            "frequency": 5,
+            "duration": 60,
+        }));
+        // warm up trains are dispatched too
+        assert_eq!(results.dispatches, (60 + results.warm_up as usize) / 5);
+        assert_eq!(results.peak_trains_in_service, 2);
+    }
+
+    #[test]
+    fn stations_rank_by_passenger_minutes_waited() {
+        let runs = RunStore::default();
+        let results = run_in(
+            &runs,
+            json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+                "routes": { "r": { "nodes": ["a", "b", "c"] } },
+                "frequency": 6,
+                "demand": [trip("a", "c", 10), trip("b", "c", 4)],
+            }),
+        );
+        let ranking = rank_stations(&runs, results.run_id, None).unwrap();
+        let stations: Vec<_> = ranking.iter().map(|rank| &*rank.station).collect();
+        assert_eq!(stations[..2], ["a", "b"]);
+        assert_eq!(ranking[0].boardings, 10);
+        let expected_wait = results.station_statistics["a"].arrival_times["r"].expected_wait;
+        assert_eq!(ranking[0].expected_passenger_minutes, 10.0 * expected_wait);
+    }
+
+    #[test]
+    fn riders_starting_down_the_line_ride_the_first_period() {
+        let (js_graph, js_routes) = network(&json!({
+            "nodes": ["a", "b", "c"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+            "routes": { "r": { "nodes": ["a", "b", "c"] } },
+        }));
+        let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+        let (routes, _) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+        let mut search_map = SearchMap::generate(&graph, &routes);
+        let paths = shortest_paths(map["b"], map["c"], &mut search_map, 1);
+        // trains reach b after the period starts, so a rider there at 0 gets its first trains
+        let schedule = Schedule::from([("r".to_string(), vec![4, 1])]);
+        let cost = estimate_path_cost(&search_map, &schedule, &paths[0], 0).unwrap();
+        assert_eq!(cost.segments[0].frequency, 4);
+    }
+
+    #[test]
+    fn station_event_recording_skips_steps_trains_only_run() {
+        let results = run(json!({
+            "nodes": ["a", "b", "c"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+            "routes": { "r": { "nodes": ["a", "b", "c"] } },
+            "frequency": 10,
+            "duration": 30,
+            "config": { "position_recording": "station_events" },
+        }));
+        let times: Vec<_> = results
+            .train_positions
+            .iter()
+            .map(|positions| positions.time)
+            .collect();
+        // dispatched from a, then arriving at b and c
+        assert_eq!(times, [0.0, 3.0, 5.0, 10.0, 13.0, 15.0, 20.0, 23.0, 25.0]);
+    }
+
+    #[test]
+    fn transfers_work_whichever_route_is_boarded_first() {
+        let (js_graph, js_routes) = network(&json!({
+            "nodes": ["a", "b", "c"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+            "routes": { "r": { "nodes": ["a", "b"] }, "s": { "nodes": ["b", "c"] } },
+        }));
+        let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+        let (routes, _) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+        let mut search_map = SearchMap::generate(&graph, &routes);
+        let paths = shortest_paths(map["a"], map["c"], &mut search_map, 1);
+        assert_eq!(paths.len(), 1);
+    }
+
+    #[test]
+    fn itineraries_are_costed_segment_by_segment() {
+        let (js_graph, js_routes) = network(&json!({
+            "nodes": ["a", "b", "c"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+            "routes": { "r": { "nodes": ["a", "b"] }, "s": { "nodes": ["b", "c"] } },
+        }));
+        let (graph, map, petgraph_map) = js_graph_to_subway_map(js_graph).unwrap();
+        let (routes, _) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+        let mut search_map = SearchMap::generate(&graph, &routes);
+        let paths = shortest_paths(map["a"], map["c"], &mut search_map, 1);
+        let schedule = Schedule::from([
+            ("r".to_string(), vec![6; 10]),
+            ("s".to_string(), vec![3; 10]),
+        ]);
+        let path_cost = estimate_path_cost(&search_map, &schedule, &paths[0], 0).unwrap();
+        let cost = JsPathCost::from_path_cost(path_cost, &petgraph_map);
+        assert_eq!(cost.transfers, 1);
+        let segments: Vec<_> = cost
+            .segments
+            .iter()
+            .map(|s| (&*s.start_station, &*s.end_station, s.frequency, s.wait_time))
+            .collect();
+        assert_eq!(segments, [("a", "b", 6, 2.0), ("b", "c", 3, 4.0)]);
+        assert_eq!(cost.wait_time, 6.0);
+    }
+
+    #[test]
+    fn headways_are_measured_against_the_nominal_one() {
+        let arrivals = vec![0.0, 4.0, 10.0, 14.0, 20.0];
+        let options = StatisticsOptions::default();
+        let stats = calculate_arrival_time_statistics(arrivals.clone(), Some(5.0), &options);
+        assert_eq!(stats.headway_deviation, Some(1.0));
+        // the 95th percentile headway of 6 is a fifth over the nominal 5
+        assert_eq!(stats.reliability_buffer_index, Some(0.2));
+        let stats = calculate_arrival_time_statistics(arrivals, None, &options);
+        assert_eq!(stats.headway_deviation, None);
+        assert_eq!(stats.reliability_buffer_index, None);
+    }
+
+    #[test]
+    fn repeated_runs_diverge_only_where_they_differ() {
+        let (js_graph, js_routes) = network(&json!({
+            "nodes": ["a", "b", "c"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+            "routes": { "r": { "nodes": ["a", "b", "c"] } },
+        }));
+        let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+        let (routes, _) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+        let run = || {
+            Simulator::new(graph.clone(), routes.clone(), SimulationConfig::default()).run(60, 5)
+        };
+        let first = run();
+        let mut second = run();
+        assert!(first.divergences(&second).is_empty());
+
+        let step = second
+            .train_positions
+            .iter_mut()
+            .find(|step| !step.trains.is_empty())
+            .unwrap();
+        step.trains[0].pos += 0.5;
+        let (time, train) = (step.time, step.trains[0].id);
+        let divergences = first.divergences(&second);
+        assert_eq!(divergences.len(), 1);
+        assert_eq!(divergences[0].time, time);
+        assert!(matches!(divergences[0].entity, DivergenceEntity::Train(id) if id == train));
+    }
+
+    #[test]
+    fn routes_are_suggested_shortest_first() {
+        let (js_graph, _) = network(&json!({
+            "nodes": ["a", "b", "c", "d"],
+            "edges": [
+                { "id": "a-b" },
+                { "id": "b-d" },
+                { "id": "a-c", "weight": 3 },
+                { "id": "c-d" },
+            ],
+            "routes": {},
+        }));
+        let routes = suggest_routes(js_graph, "a".to_string(), "d".to_string(), None).unwrap();
+        let paths: Vec<_> = routes
+            .iter()
+            .map(|route| (route.nodes.join(" "), route.edges.join(" ")))
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                ("a b d".to_string(), "a-b b-d".to_string()),
+                ("a c d".to_string(), "a-c c-d".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn connection_times_go_by_measured_transfers() {
+        let (js_graph, js_routes) = network(&json!({
+            "nodes": [
+                "a",
+                { "id": "b", "transfer_times": [{ "from": "r", "to": "s", "time": 3.5 }] },
+                "c",
+            ],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+            "routes": { "r": { "nodes": ["a", "b"] }, "s": { "nodes": ["b", "c"] } },
+        }));
+        let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+        let (routes, _) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+        let times: Vec<_> = SearchMap::generate(&graph, &routes)
+            .connection_times()
+            .into_iter()
+            .map(|c| (c.from, c.to, c.walk_time, c.cost))
+            .collect();
+        // transfers are rounded up to whole minutes, the way back takes as long, and walking
+        // costs riders two and a half times the minutes it takes
+        assert_eq!(times, [(map["b"], map["b"], 4, 10.0)]);
+    }
+
+    #[test]
+    fn stored_runs_recompute_statistics_until_discarded() {
+        let runs = RunStore::default();
+        let results = run_in(
+            &runs,
+            json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+                "routes": { "r": { "nodes": ["a", "b", "c"] } },
+            }),
+        );
+        let recompute = |options: Value| {
+            let options = serde_json::from_value(options).unwrap();
+            recompute_stored_statistics(&runs, results.run_id, options)
+        };
+        let statistics = recompute(json!({ "percentiles": [50.0] })).unwrap();
+        let stats = &statistics["b"].arrival_times["r"];
+        assert_eq!(stats.headway_percentiles, [(50.0, 5.0)]);
+        let statistics = recompute(json!({ "warm_up": 1e6 })).unwrap();
+        assert!(statistics["b"].arrival_times["r"]
+            .headway_percentiles
+            .is_empty());
+
+        runs.remove(results.run_id);
+        assert!(recompute(json!({})).is_err());
+    }
+
+    #[test]
+    fn closely_spaced_stops_shorten_the_dwell_of_routes_combining_them() {
+        let arrivals = |combine_short_stops: bool| {
+            let (js_graph, js_routes) = network(&json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b", "weight": 1 }, { "id": "b-c" }],
+                "routes": {
+                    "r": { "nodes": ["a", "b", "c"], "combine_short_stops": combine_short_stops },
+                },
+            }));
+            let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+            let (routes, _) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+            let config = SimulationConfig {
+                short_stop_threshold: 2.0,
+                ..SimulationConfig::default()
+            };
+            let results = Simulator::new(graph, routes, config).run(60, 5);
+            results.station_statistics[&map["c"]].arrival_times[&RouteId(0)].clone()
+        };
+        // half the usual half minute dwell at b, the track to it being half the threshold
+        let separate: Vec<_> = arrivals(false).iter().map(|time| time - 0.25).collect();
+        assert_eq!(arrivals(true), separate);
+    }
+
+    #[test]
+    fn trains_dwell_as_long_as_their_station_says() {
+        let arrivals = |dwell_time: Value| {
+            let (js_graph, js_routes) = network(&json!({
+                "nodes": ["a", { "id": "b", "dwell_time": dwell_time }, "c"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+                "routes": { "r": { "nodes": ["a", "b", "c"] } },
+            }));
+            let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+            let (routes, _) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+            let results = Simulator::new(graph, routes, SimulationConfig::default()).run(60, 5);
+            results.station_statistics[&map["c"]].arrival_times[&RouteId(0)].clone()
+        };
+        // two minutes at b instead of the default half minute
+        let slower = arrivals(json!(2.0));
+        assert!(!slower.is_empty());
+        for (slow, quick) in slower.iter().zip(arrivals(Value::Null)) {
+            assert_eq!(*slow, quick + 1.5);
+        }
+    }
+
+    #[test]
+    fn riders_spread_to_a_busier_neighbouring_period() {
+        let (js_graph, js_routes) = network(&json!({
+            "nodes": ["a", "b"],
+            "edges": [{ "id": "a-b" }],
+            "routes": { "r": { "nodes": ["a", "b"] } },
+        }));
+        let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+        let (routes, _) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+        let mut search_map = SearchMap::generate(&graph, &routes);
+        let (a, b) = (map["a"], map["b"]);
+        let paths = HashMap::from([((a, b), shortest_paths(a, b, &mut search_map, 1))]);
+        let trip_data = TripData::from([(
+            0,
+            vec![Trip {
+                start: a,
+                end: b,
+                arrival_times: vec![2.0; 10],
+            }],
+        )]);
+        let mut frequencies = vec![1; 10];
+        frequencies[1] = 12;
+        let schedule = Schedule::from([("r".to_string(), frequencies)]);
+        let spread = |sensitivity| {
+            spread_peaks(
+                &search_map,
+                &schedule,
+                &trip_data,
+                &paths,
+                sensitivity,
+                false,
+                0,
+            )
+        };
+        let spreading = spread(0.0);
+        assert_eq!(spreading.riders_before[..2], [10, 0]);
+        assert_eq!(spreading.riders_after, spreading.riders_before);
+        // sensitive enough that every rider who'd save takes the train a period later
+        assert_eq!(spread(1e9).riders_after[..2], [0, 10]);
+    }
+
+    #[test]
+    fn trains_carry_no_more_than_their_capacity() {
+        let peak_load = |config: Value| {
+            let results = run(json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+                "routes": { "r": { "nodes": ["a", "b", "c"] } },
+                "demand": [trip("a", "c", 40)],
+                "config": config,
+            }));
+            results
+                .train_positions
+                .iter()
+                .flat_map(|step| &step.trains)
+                .map(|train| train.load)
+                .max()
+                .unwrap()
+        };
+        assert!(peak_load(json!({})) > 3);
+        assert_eq!(peak_load(json!({ "train_capacity": 3 })), 3);
+    }
+
+    #[test]
+    fn run_estimates_scale_with_the_horizon() {
+        let estimate = |duration| {
+            let (js_graph, js_routes) = network(&json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+                "routes": { "r": { "nodes": ["a", "b", "c"] } },
+            }));
+            estimate_run(js_graph, js_routes, 5, None, Some(duration)).unwrap()
+        };
+        let (short, long) = (estimate(60), estimate(120));
+        // the 120 step warm up runs before the horizon
+        assert_eq!((short.steps, long.steps), (180, 240));
+        // trains take three half minute dwells and two 2 minute tracks to run the route, and
+        // one leaves every 5 minutes
+        assert_eq!(short.trains_in_service, 5.5 / 5.0);
+        assert!(long.seconds > short.seconds);
+        assert!(long.result_bytes > short.result_bytes);
+    }
+
+    #[test]
+    fn demand_given_to_a_simulation_rides_it() {
+        let results = run(json!({
+            "nodes": ["a", "b", "c"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+            "routes": { "r": { "nodes": ["a", "b", "c"] } },
+            "demand": [trip("a", "c", 10), trip("b", "c", 4)],
+        }));
+        assert_eq!(results.passengers_delivered, 14);
+        assert_eq!(results.ridership["r"], 14);
+        assert!(results.average_journey_time.is_some());
+    }
+
+    #[test]
+    fn travel_time_noise_is_reproducible_by_seed() {
+        let arrivals = |config: Value| {
+            let (js_graph, js_routes) = network(&json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+                "routes": { "r": { "nodes": ["a", "b", "c"] } },
+            }));
+            let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+            let (routes, _) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+            let config = serde_json::from_value(config).unwrap();
+            let results = Simulator::new(graph, routes, config).run(60, 5);
+            results.station_statistics[&map["c"]].arrival_times[&RouteId(0)].clone()
+        };
+        let noisy = |seed| {
+            arrivals(json!({
+                "travel_time_noise": {
+                    "distribution": { "uniform": { "spread": 0.5 } },
+                    "seed": seed,
+                },
+            }))
+        };
+        assert_eq!(noisy(1), noisy(1));
+        assert_ne!(noisy(1), noisy(2));
+        assert_ne!(noisy(1), arrivals(json!({})));
+    }
+
+    #[test]
+    fn deadhead_distance_is_kept_apart_from_revenue_distance() {
+        let results = run(json!({
+            "nodes": ["a", "b", "c"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c", "non_revenue": true }],
+            "routes": {
+                "r": { "nodes": ["a", "b", "c"] },
+                "d": { "nodes": ["a", "b"], "deadhead": true, "offset": 2 },
+            },
+            "demand": [trip("a", "b", 5)],
+        }));
+        let distances = &results.operating_distances;
+        // r runs as far over revenue track as over the non-revenue track past it
+        assert!(distances["r"].revenue > 0.0);
+        assert_eq!(distances["r"].revenue, distances["r"].deadhead);
+        assert_eq!(distances["d"].revenue, 0.0);
+        assert!(distances["d"].deadhead > 0.0);
+        // nobody rides the empty trains
+        assert_eq!(results.ridership.get("d"), None);
+        assert_eq!(results.ridership["r"], 5);
+    }
+
+    #[test]
+    fn trains_wait_out_track_outages() {
+        let statistics = |disruptions: Value| {
+            run(json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+                "routes": { "r": { "nodes": ["a", "b", "c"] } },
+                "disruptions": disruptions,
+            }))
+            .disruption_statistics
+        };
+        assert_eq!(statistics(json!([])).delayed_trains, 0);
+        let outage = statistics(json!([{ "edge": "b-c", "start": 10.0, "end": 20.0 }]));
+        // trains due over b-c while it is closed hold until it reopens, none longer than that
+        assert_eq!(outage.delayed_trains, 2);
+        assert_eq!(outage.max_delay, 10.0);
+        assert_eq!(outage.total_delay, 16.0);
+        assert!(outage.short_turns.is_empty());
+    }
+
+    #[test]
+    fn routes_sharing_track_are_offset_half_a_headway_apart() {
+        let (js_graph, js_routes) = network(&json!({
+            "nodes": ["a", "b", "c", "d", "e", "f"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }, { "id": "b-d" }, { "id": "e-f" }],
+            "routes": {
+                "r": { "nodes": ["a", "b", "c"] },
+                "s": { "nodes": ["a", "b", "d"] },
+                "t": { "nodes": ["e", "f"], "offset": 2 },
+            },
+        }));
+        let offsets = suggest_offsets(js_graph, js_routes, 6).unwrap();
+        assert_eq!((offsets["r"] as i64 - offsets["s"] as i64).rem_euclid(6), 3);
+        // sharing no track, t keeps its offset
+        assert_eq!(offsets["t"], 2);
+    }
+
+    #[test]
+    fn frequencies_and_departures_convert_back_and_forth() {
+        let (_, js_routes) = network(&json!({
+            "nodes": ["a", "b"],
+            "edges": [{ "id": "a-b" }],
+            "routes": { "r": { "nodes": ["a", "b"], "offset": 1 } },
+        }));
+        let mut frequencies = vec![0; 10];
+        frequencies[..2].copy_from_slice(&[2, 3]);
+        let schedule = Schedule::from([("r".to_string(), frequencies)]);
+        let departures = frequencies_to_departures(schedule.clone(), js_routes);
+        assert_eq!(departures["r"], [1, 7, 13, 17, 21]);
+        assert_eq!(departures_to_frequencies(departures).unwrap(), schedule);
+        let late = HashMap::from([("r".to_string(), vec![SCHEDULE_PERIOD])]);
+        assert!(departures_to_frequencies(late).is_err());
+    }
+
+    #[test]
+    fn short_turns_take_every_other_departure_of_their_parent() {
+        let results = run(json!({
+            "nodes": ["a", "b", "c"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+            "routes": {
+                "r": { "nodes": ["a", "b", "c"] },
+                "s": { "nodes": ["a", "b"], "variant_of": "r" },
+            },
+        }));
+        let stats = &results.station_statistics;
+        let headways = |station: &str, route: &str| {
+            let stats = &stats[station].arrival_times[route];
+            (stats.min_wait, stats.max_wait)
+        };
+        // the two take turns at r's five minute departures, so b sees a train every five
+        assert_eq!(headways("b", "r"), (10.0, 10.0));
+        assert_eq!(headways("b", "s"), (10.0, 10.0));
+        let overall = stats["b"].overall_arrival_times.as_ref().unwrap();
+        assert_eq!((overall.min_wait, overall.max_wait), (5.0, 5.0));
+        assert_eq!(headways("c", "r"), (10.0, 10.0));
+    }
+
+    #[test]
+    fn unpaired_track_gets_a_mirrored_reverse_edge() {
+        let (js_graph, _) = network(&json!({
+            "nodes": ["a", "b", "c", "d"],
+            "edges": [
+                {
+                    "id": "a-b",
+                    "weight": 3,
+                    "one_way": false,
+                    "geometry": [{ "x": 0.0, "y": 0.0 }, { "x": 1.0, "y": 2.0 }],
+                },
+                { "id": "b-c", "one_way": false },
+                { "id": "c-b", "one_way": false },
+                { "id": "c-d" },
+                { "id": "a-b_rev", "source": "a", "target": "d", "type": "walk" },
+            ],
+            "routes": {},
+        }));
+        let pairing = pair_tracks(js_graph);
+        // b-c and c-b pair each other, and c-d is meant to be one way
+        assert_eq!(pairing.unpaired, ["a-b"]);
+        let reverse = pairing.graph.edges.last().unwrap();
+        assert_eq!(reverse.id, "a-b_rev_rev");
+        assert_eq!((&*reverse.source, &*reverse.target), ("b", "a"));
+        assert_eq!(reverse.weight, 3);
+        let geometry = reverse.geometry.as_ref().unwrap();
+        assert_eq!(geometry[0], JsPoint { x: 1.0, y: 2.0 });
+    }
+
+    #[test]
+    fn parallel_tracks_keep_trains_from_blocking_each_other() {
+        let headways = |tracks: u8| {
+            let results = run(json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [
+                    { "id": "a-b", "weight": 10, "block_length": 10.0, "tracks": tracks },
+                    { "id": "b-c" },
+                ],
+                "routes": { "r": { "nodes": ["a", "b", "c"] } },
+            }));
+            let stats = &results.station_statistics["c"].arrival_times["r"];
+            (stats.min_wait, stats.max_wait)
+        };
+        // a train can't follow onto a single track until the one ahead clears its one block,
+        // while a second track takes the next train straight away
+        assert!(headways(1).0 > 5.0);
+        assert_eq!(headways(2), (5.0, 5.0));
+    }
+
+    #[test]
+    fn passengers_between_traced_stations_are_followed_through_their_trip() {
+        let results = run(json!({
+            "nodes": ["a", "b", "c"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+            "routes": { "r": { "nodes": ["a", "b", "c"] } },
+            "demand": [trip("a", "c", 10), trip("b", "c", 3)],
+            "trace": { "od_pairs": [["b", "c"]] },
+        }));
+        assert_eq!(results.journeys.len(), 3);
+        for journey in &results.journeys {
+            assert_eq!((&*journey.origin, &*journey.destination), ("b", "c"));
+            let events = &journey.events;
+            assert_eq!(events.len(), 2);
+            assert!(matches!(&events[0], JsJourneyEvent::Board { station, .. } if station == "b"));
+            let JsJourneyEvent::Alight { station, time, .. } = &events[1] else {
+                panic!("journey ends with something other than getting off");
+            };
+            assert_eq!(station, "c");
+            assert_eq!(journey.completed_at, Some(*time));
+        }
+    }
+
+    #[test]
+    fn long_trains_keep_followers_further_back() {
+        let headways = |train_length: f64| {
+            let results = run(json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b", "weight": 10, "block_length": 2.0 }, { "id": "b-c" }],
+                "routes": { "r": { "nodes": ["a", "b", "c"], "train_length": train_length } },
+                "frequency": 3,
+            }));
+            let stats = &results.station_statistics["c"].arrival_times["r"];
+            (stats.min_wait, stats.max_wait)
+        };
+        // followers wait for the tail of the train ahead to clear a block, which takes longer
+        // the longer the train
+        assert_eq!(headways(0.0), (3.0, 3.0));
+        assert_eq!(headways(2.0), (4.0, 4.0));
+        assert_eq!(headways(3.0), (5.0, 5.0));
+    }
+
+    #[test]
+    fn rolling_stock_sets_the_pace_of_its_routes() {
+        let results = run(json!({
+            "nodes": ["a", "b", "c", "d", "e", "f"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }, { "id": "d-e" }, { "id": "e-f" }],
+            "routes": {
+                "new": { "nodes": ["a", "b", "c"], "rolling_stock": "fast" },
+                "old": { "nodes": ["d", "e", "f"], "rolling_stock": "slow" },
+                "std": { "nodes": ["d", "e", "f"], "offset": 2 },
+            },
+            "config": {
+                "rolling_stock": {
+                    "fast": { "speed": 2.0 },
+                    "slow": { "dwell_penalty": 1.0 },
+                },
+            },
+        }));
+        let run_time = |route: &str| {
+            let trip = results
+                .train_trips
+                .iter()
+                .find(|trip| trip.route == route && trip.end.is_some())
+                .unwrap();
+            trip.end.unwrap() - trip.start
+        };
+        assert!(run_time("new") < run_time("std"));
+        // a minute longer at both stations the route's trains leave
+        assert_eq!(run_time("old"), run_time("std") + 2.0);
+    }
+
+    #[test]
+    fn stations_alert_when_more_wait_than_they_hold() {
+        let alerts = |waiting_capacity: usize| {
+            run(json!({
+                "nodes": [{ "id": "a", "waiting_capacity": waiting_capacity }, "b", "c"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+                "routes": { "r": { "nodes": ["a", "b", "c"] } },
+                "demand": [trip("a", "c", 40), trip("b", "c", 40)],
+            }))
+            .crowding_alerts
+        };
+        let crowded = alerts(3);
+        assert!(!crowded.is_empty());
+        // b is as busy but declares no capacity
+        for alert in &crowded {
+            assert_eq!(alert.station, "a");
+            assert!(alert.peak_waiting > 3);
+            assert!(alert.start < alert.end);
+        }
+        assert!(alerts(100).is_empty());
+    }
+
+    #[test]
+    fn terminal_dispatches_can_be_left_out_of_rider_statistics() {
+        let runs = RunStore::default();
+        let results = run_in(
+            &runs,
+            json!({
+                "nodes": ["d", "a", "b"],
+                "edges": [{ "id": "d-a" }, { "id": "a-b" }],
+                "routes": {
+                    "r": { "nodes": ["a", "b"] },
+                    "s": { "nodes": ["d", "a", "b"], "offset": 1 },
+                },
+            }),
+        );
+        let statistics = |options: Value| {
+            let options = serde_json::from_value(options).unwrap();
+            let mut statistics =
+                recompute_stored_statistics(&runs, results.run_id, options).unwrap();
+            statistics.remove("a").unwrap()
+        };
+        let all = statistics(json!({}));
+        let mut routes: Vec<_> = all.arrival_times.keys().collect();
+        routes.sort();
+        assert_eq!(routes, ["r", "s"]);
+        assert!(all.overall_arrival_times.is_some());
+        let rider_facing = statistics(json!({ "exclude_terminals": true }));
+        assert_eq!(rider_facing.arrival_times.keys().collect::<Vec<_>>(), ["s"]);
+        assert!(rider_facing.overall_arrival_times.is_none());
+        // r's dispatches are still reported, apart from what riders see
+        assert!(rider_facing.terminal_arrival_times.contains_key("r"));
+    }
+
+    #[test]
+    fn both_engines_run_trains_on_the_same_times() {
+        let arrivals = |engine: &str| {
+            let (js_graph, js_routes) = network(&json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c", "weight": 3 }],
+                "routes": { "r": { "nodes": ["a", "b", "c"] } },
+            }));
+            let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+            let (routes, _) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+            let config = serde_json::from_value(json!({ "engine": engine })).unwrap();
+            let results = Simulator::new(graph, routes, config).run(60, 5);
+            let stations = results.station_statistics;
+            (
+                stations[&map["b"]].arrival_times[&RouteId(0)].clone(),
+                stations[&map["c"]].arrival_times[&RouteId(0)].clone(),
+            )
+        };
+        assert_eq!(arrivals("events"), arrivals("time_step"));
+    }
+
+    #[test]
+    fn sub_minute_steps_record_positions_at_their_own_interval() {
+        let run_with = |config: serde_json::Value| {
+            let (js_graph, js_routes) = network(&json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c", "weight": 3 }],
+                "routes": { "r": { "nodes": ["a", "b", "c"] } },
+            }));
+            let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+            let (routes, _) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+            let config = serde_json::from_value(config).unwrap();
+            let results = Simulator::new(graph, routes, config).run(10, 5);
+            let times: Vec<f64> = results.train_positions.iter().map(|p| p.time).collect();
+            let arrivals = results.station_statistics[&map["c"]].arrival_times[&RouteId(0)].clone();
+            (times, arrivals)
+        };
+        let (minutes, minute_arrivals) = run_with(json!({}));
+        let (quarters, quarter_arrivals) = run_with(json!({ "time_step": 0.25 }));
+        assert_eq!(minutes, (0..10).map(f64::from).collect::<Vec<_>>());
+        assert_eq!(
+            quarters,
+            (0..40).map(|step| step as f64 * 0.25).collect::<Vec<_>>()
+        );
+        // a dispatched train sets off a step later, which is a smaller wait with finer steps
+        assert_eq!(minute_arrivals, vec![2.0, 7.0]);
+        assert_eq!(quarter_arrivals, vec![1.25, 6.25]);
+
+        let (recorded, _) = run_with(json!({
+            "time_step": 0.25,
+            "position_recording": { "interval": 1.0 },
+        }));
+        assert_eq!(recorded, minutes);
+    }
+
+    #[test]
+    fn experiments_run_once_per_seed_in_manifest_order() {
+        let (graph, routes) = network(&json!({
+            "nodes": ["a", "b"],
+            "edges": [{ "id": "a-b" }],
+            "routes": { "r": { "nodes": ["a", "b"] } },
+        }));
+        let experiment = |runs: Value| {
+            serde_json::from_value::<JsExperiment>(json!({
+                "networks": { "line": { "graph": graph, "routes": routes } },
+                "runs": runs,
+            }))
+            .unwrap()
+        };
+        let results = experiment_results(
+            &RunStore::default(),
+            experiment(json!([
+                { "name": "base", "network": "line", "frequency": 5 },
+                {
+                    "name": "seeded", "network": "line", "frequency": 5, "seeds": [1, 2],
+                    "output": { "positions": true },
+                },
+            ])),
+        )
+        .unwrap();
+        let runs: Vec<_> = results
+            .iter()
+            .map(|run| (run.name.as_str(), run.seed))
+            .collect();
+        assert_eq!(
+            runs,
+            [("base", None), ("seeded", Some(1)), ("seeded", Some(2))]
+        );
+        // positions are only kept when a run's output asks for them
+        assert!(results[0].results.train_positions.is_empty());
+        assert!(!results[1].results.train_positions.is_empty());
+
+        let unknown = experiment(json!([{ "name": "x", "network": "loop", "frequency": 5 }]));
+        assert!(experiment_results(&RunStore::default(), unknown).is_err());
+    }
+
+    #[test]
+    fn warm_ups_last_as_configured_or_until_service_levels_off() {
+        let warm_up = |warm_up: Value| {
+            let results = run(json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b", "weight": 10 }, { "id": "b-c", "weight": 10 }],
+                "routes": { "r": { "nodes": ["a", "b", "c"] } },
+                "config": { "warm_up": warm_up },
+            }));
+            (results.warm_up, results.train_positions[0].trains.len())
+        };
+        // without a warm up only the first train is out when statistics start
+        assert_eq!(warm_up(json!({ "fixed": 0 })), (0, 1));
+        let (fixed, full) = warm_up(json!({ "fixed": 30 }));
+        assert_eq!(fixed, 30);
+        assert!(full > 1);
+        // trains take over 20 minutes end to end, so service can't level off before then
+        let (steady, trains) = warm_up(json!({ "steady_state": { "max": 200 } }));
+        assert!((20..200).contains(&steady), "{steady}");
+        assert_eq!(trains, full);
+        assert_eq!(warm_up(json!({ "steady_state": { "max": 15 } })).0, 15);
+    }
+
+    #[test]
+    fn reroutes_send_trains_the_other_way_during_their_window() {
+        let (js_graph, js_routes) = network(&json!({
+            "nodes": ["a", "b", "x", "c"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }, { "id": "a-x" }, { "id": "x-c" }],
+            "routes": { "r": { "nodes": ["a", "b", "c"] } },
+        }));
+        let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+        let (routes, route_ids) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+        let reroutes = serde_json::from_value(json!([
+            { "route": "r", "start": 0.0, "end": 30.0, "nodes": ["a", "x", "c"] },
+        ]))
+        .unwrap();
+        let reroutes = js_reroutes_to_plan(reroutes, &graph, &map, &route_ids).unwrap();
+        let mut simulator = Simulator::new(graph, routes, SimulationConfig::default());
+        simulator.set_reroutes(reroutes);
+        let stations = simulator.run(60, 5).station_statistics;
+        let arrivals = |station: &str| stations[&map[station]].arrival_times[&RouteId(0)].clone();
+        // trains leaving a before the reroute ends go via x, later ones via b
+        assert!(arrivals("x").iter().all(|&time| time < 30.0));
+        assert!(arrivals("b").iter().all(|&time| time > 30.0));
+        assert_eq!(
+            arrivals("x").len() + arrivals("b").len(),
+            arrivals("c").len()
+        );
+    }
+
+    #[test]
+    fn simulations_last_the_duration_asked_for_up_to_a_day() {
+        assert_eq!(simulation_horizon(None, TimeUnit::Minutes).unwrap(), 60);
+        assert_eq!(
+            simulation_horizon(Some(180), TimeUnit::Minutes).unwrap(),
+            180
+        );
+        assert_eq!(simulation_horizon(None, TimeUnit::Seconds).unwrap(), 3600);
+        assert!(simulation_horizon(Some(0), TimeUnit::Minutes).is_err());
+        assert!(simulation_horizon(Some(24 * 60 + 1), TimeUnit::Minutes).is_err());
+        assert!(simulation_horizon(Some(24 * 60 + 1), TimeUnit::Seconds).is_ok());
+
+        let results = run(json!({
+            "nodes": ["a", "b"],
+            "edges": [{ "id": "a-b" }],
+            "routes": { "r": { "nodes": ["a", "b"] } },
+            "duration": 180,
+        }));
+        assert_eq!(results.train_positions.len(), 180);
+    }
+
+    #[test]
+    fn holding_evens_out_headways_against_an_unheld_run() {
+        let holding_report = |holding: Value| {
+            run(json!({
+                "nodes": ["a", "b", "c", "d", "e"],
+                "edges": [
+                    { "id": "a-b", "weight": 4 }, { "id": "b-c", "weight": 4 },
+                    { "id": "c-d", "weight": 4 }, { "id": "d-e", "weight": 4 },
+                ],
+                "routes": { "r": { "nodes": ["a", "b", "c", "d", "e"] } },
+                "config": {
+                    "holding": holding,
+                    "travel_time_noise": {
+                        "distribution": { "uniform": { "spread": 0.8 } },
+                        "seed": 3,
+                    },
+                },
+            }))
+            .holding
+        };
+        assert!(holding_report(json!("none")).is_none());
+        for holding in [
+            json!({ "headway_ahead": { "min_headway": 5.0, "max_hold": 2.0 } }),
+            json!({ "gap_behind": { "threshold": 5.0, "max_hold": 2.0 } }),
+        ] {
+            let report = holding_report(holding).unwrap();
+            assert!(report.holds > 0);
+            assert!(report.total_hold_time <= 2.0 * report.holds as f64);
+            assert!(report.headway_deviation.unwrap() < report.baseline_headway_deviation.unwrap());
+        }
+    }
+
+    #[test]
+    fn errors_reach_the_frontend_with_a_code_to_branch_on() {
+        let js_graph = serde_json::from_value(json!({
+            "nodes": [{ "id": "a" }],
+            "edges": [{ "id": "a-b", "source": "a", "target": "b", "weight": 1, "type": "track" }],
+        }))
+        .unwrap();
+        let Err(error) = js_graph_to_subway_map(js_graph) else {
+            panic!("edge to a missing station converted");
+        };
+        assert_eq!(
+            serde_json::to_value(error.in_context("run x")).unwrap(),
+            json!({ "code": "graph", "message": "invalid graph: run x: unknown station b" }),
+        );
+        assert_eq!(
+            serde_json::to_value(SimError::Cancelled.in_context("run x")).unwrap(),
+            json!({ "code": "cancelled", "message": "cancelled" }),
+        );
+    }
+
+    #[test]
+    fn heavy_commands_run_one_at_a_time() {
+        let active_runs = ActiveRuns::default();
+        let active = || {
+            active_runs
+                .active
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map(|run| run.command)
+        };
+        let run = active_runs.start("run_optimize").unwrap();
+        assert_eq!(active(), Some("run_optimize"));
+        let Err(busy) = active_runs.start("run_simulation") else {
+            panic!("a second heavy command started alongside the first");
+        };
+        assert_eq!(busy.code(), "busy");
+
+        drop(run);
+        assert_eq!(active(), None);
+        assert!(active_runs.start("run_simulation").is_ok());
+    }
+
+    #[test]
+    fn merge_priority_decides_who_goes_first_at_a_junction() {
+        let arrivals = |merge_priority: Value| {
+            let (js_graph, js_routes) = network(&json!({
+                "nodes": ["a", "b", "m", "c"],
+                "edges": [
+                    { "id": "a-m", "weight": 3 }, { "id": "b-m", "weight": 3 }, { "id": "m-c" },
+                ],
+                "routes": {
+                    "p": { "nodes": ["a", "m", "c"] },
+                    "q": { "nodes": ["b", "m", "c"] },
+                },
+            }));
+            let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+            let (routes, route_ids) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+            let config = json!({ "merge_priority": merge_priority });
+            let config = serde_json::from_value(config).unwrap();
+            let stations = Simulator::new(graph, routes, config)
+                .run(30, 10)
+                .station_statistics;
+            let route =
+                |name: &str| RouteId(route_ids.iter().position(|id| id == name).unwrap() as u32);
+            let at_c = &stations[&map["c"]].arrival_times;
+            (at_c[&route("p")].clone(), at_c[&route("q")].clone())
+        };
+        // both reach the junction together, so whichever gives way gets to c a minute later
+        let first = vec![7.0, 17.0, 27.0];
+        let second = vec![8.0, 18.0, 28.0];
+        assert_eq!(
+            arrivals(json!({ "route_priority": ["p"] })),
+            (first.clone(), second.clone())
+        );
+        assert_eq!(
+            arrivals(json!({ "route_priority": ["q"] })),
+            (second, first)
+        );
+    }
+
+    #[test]
+    fn interlined_routes_run_the_trains_of_the_route_feeding_them() {
+        let scenario = |turnaround: Value| {
+            json!({
+                "nodes": ["a", "b", "c", "d"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }, { "id": "c-d" }, { "id": "d-a" }],
+                "routes": {
+                    "p": { "nodes": ["a", "b", "c"], "turnaround": turnaround },
+                    "q": { "nodes": ["c", "d", "a"], "interlined": true },
+                },
+            })
+        };
+        let results = run(scenario(json!("q")));
+        let trips = |route: &str| {
+            results
+                .train_trips
+                .iter()
+                .filter(|trip| trip.route == route)
+                .map(|trip| (trip.train.clone(), (trip.start, trip.end)))
+                .collect::<HashMap<_, _>>()
+        };
+        let (p, q) = (trips("p"), trips("q"));
+        assert!(p.len() > 1);
+        // every train on q came off p at c, taking over as q the moment it finished p
+        for (train, (_, p_end)) in &p {
+            match p_end {
+                Some(end) => assert_eq!(q[train].0, *end),
+                None => assert!(!q.contains_key(train)),
+            }
+        }
+        assert!(q
+            .keys()
+            .all(|train| p.contains_key(train) || q[train].0 < 0.0));
+
+        let (js_graph, js_routes) = network(&scenario(Value::Null));
+        let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+        assert!(js_routes_to_routes(js_routes, &graph, &map).is_err());
+    }
+
+    #[test]
+    fn checkpoints_report_the_run_so_far_at_their_interval() {
+        let checkpoints = Mutex::new(Vec::new());
+        run_watched(
+            &RunStore::default(),
+            json!({
+                "nodes": ["a", "b"],
+                "edges": [{ "id": "a-b", "weight": 12 }],
+                "routes": { "r": { "nodes": ["a", "b"] } },
+                "config": { "checkpoint_interval": 20 },
+            }),
+            &|checkpoint| checkpoints.lock().unwrap().push(checkpoint),
+        );
+        let checkpoints = checkpoints.into_inner().unwrap();
+        let times: Vec<_> = checkpoints
+            .iter()
+            .map(|checkpoint| checkpoint.time)
+            .collect();
+        assert_eq!(times, [20.0, 40.0, 60.0]);
+        let mut previous = 0.0;
+        for checkpoint in &checkpoints {
+            assert_eq!(checkpoint.positions.time, checkpoint.time);
+            assert_eq!(
+                checkpoint.trains_in_service,
+                checkpoint.positions.trains.len()
+            );
+            assert!(checkpoint.trains_in_service > 0);
+            // each only has the arrivals since the one before
+            let arrivals = &checkpoint.arrivals["b"]["r"];
+            assert!(arrivals
+                .iter()
+                .all(|&time| previous <= time && time < checkpoint.time));
+            previous = checkpoint.time;
+        }
+        // together they cover every arrival of the run, a train every 5 minutes
+        let arrivals: usize = checkpoints.iter().map(|c| c.arrivals["b"]["r"].len()).sum();
+        assert_eq!(arrivals, 12);
+    }
+
+    #[test]
+    fn branch_points_flag_routes_coming_more_often_than_their_share() {
+        let branch_headways = |c_headways: [u64; 2]| {
+            let results = run(json!({
+                "nodes": ["a", "b", "m", "x", "y"],
+                "edges": [{ "id": "a-b" }, { "id": "b-m" }, { "id": "m-x" }, { "id": "m-y" }],
+                "routes": {
+                    "A": { "nodes": ["a", "b", "m", "x"] },
+                    "C": { "nodes": ["a", "b", "m", "y"], "offset": 5 },
+                },
+                "frequency_profile": [
+                    { "start": 0, "headways": { "A": 10, "C": c_headways[0] } },
+                    { "start": 30, "headways": { "A": 10, "C": c_headways[1] } },
+                ],
+            }));
+            results.branch_headways
+        };
+        let even = branch_headways([10, 10]);
+        assert_eq!(even.len(), 1);
+        assert_eq!(even[0].station, "m");
+        let routes: Vec<_> = even[0]
+            .pattern
+            .iter()
+            .map(|(_, route)| route.as_str())
+            .collect();
+        assert_eq!(routes, ["C", "A"].repeat(6));
+        assert!(even[0].irregular.is_empty());
+
+        // C waiting 30 minutes at first leaves three A's in a row
+        let uneven = &branch_headways([30, 10])[0];
+        let irregular: Vec<_> = uneven
+            .irregular
+            .iter()
+            .map(|irregular| (irregular.time, irregular.route.as_str(), irregular.repeats))
+            .collect();
+        assert_eq!(irregular, [(16.0, "A", 3)]);
+        assert_eq!(uneven.branches["A"].max, 10.0);
+        assert_eq!(uneven.branches["C"].headways, [30.0, 10.0]);
+    }
+
+    #[test]
+    fn timed_transfers_hold_trains_for_feeders_due_a_walk_away() {
+        let results = |timed_transfers: Value, disruptions: Value| {
+            run(json!({
+                "nodes": ["a", "t", "b", "c", "s", "d"],
+                "edges": [
+                    { "id": "a-t" }, { "id": "t-b" }, { "id": "c-s", "weight": 4 }, { "id": "s-d" },
+                    { "id": "s-t", "weight": 1, "type": "walk" },
+                ],
+                "routes": {
+                    "L": { "nodes": ["a", "t", "b"] },
+                    "F": { "nodes": ["c", "s", "d"] },
+                },
+                "frequency": 10,
+                "timed_transfers": timed_transfers,
+                "disruptions": disruptions,
+            }))
+        };
+        let report = |transfers: Value, disruptions: Value| {
+            let report = results(transfers, disruptions).transfers.unwrap();
+            let transfers: Vec<_> = report.transfers.iter().map(|t| (t.time, t.made)).collect();
+            (report.made, report.missed, transfers)
+        };
+        let timed = json!([{ "station": "t", "max_hold": 5.0 }]);
+        assert!(results(json!([]), json!([])).transfers.is_none());
+        // each L train waits for the F train due at s a few minutes after it
+        let (made, missed, transfers) = report(timed.clone(), json!([]));
+        assert_eq!((made, missed), (6, 0));
+        let departures: Vec<_> = transfers.iter().map(|(time, _)| *time).collect();
+        assert_eq!(departures, [7.0, 17.0, 27.0, 37.0, 47.0, 57.0]);
+        // feeders due after the longest hold aren't waited for
+        let short = json!([{ "station": "t", "max_hold": 1.0 }]);
+        assert_eq!(report(short, json!([])), (0, 0, Vec::new()));
+        // one held up after the train started waiting misses it
+        let closure = json!([{ "edge": "c-s", "start": 13.0, "end": 20.0 }]);
+        let (made, missed, transfers) = report(timed, closure);
+        assert_eq!((made, missed), (5, 1));
+        assert_eq!(transfers[1], (17.0, false));
+    }
+
+    #[test]
+    fn express_trains_overtake_on_bypass_tracks() {
+        let simulator = |bypass: bool, engine: &str| {
+            let (js_graph, js_routes) = network(&json!({
+                "nodes": ["a", "b", { "id": "c", "bypass": bypass, "dwell_time": 6.0 }, "d"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }, { "id": "c-d" }],
+                "routes": {
+                    "L": { "nodes": ["a", "b", "c", "d"] },
+                    "E": { "nodes": ["a", "b", "c", "d"], "pass_through": ["c"], "offset": 2 },
+                },
+            }));
+            let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+            let (routes, route_ids) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+            let config = serde_json::from_value(json!({ "engine": engine })).unwrap();
+            (Simulator::new(graph, routes, config), map, route_ids)
+        };
+        let results = |bypass: bool| {
+            let (simulator, map, route_ids) = simulator(bypass, "time_step");
+            assert!(simulator.check_engine().is_ok());
+            let results = simulator.run(30, 10);
+            let route =
+                |name: &str| RouteId(route_ids.iter().position(|id| id == name).unwrap() as u32);
+            let at_d = &results.station_statistics[&map["d"]].arrival_times;
+            (
+                results.overtakes.len(),
+                at_d[&route("L")].clone(),
+                at_d[&route("E")].clone(),
+            )
+        };
+        // L leaving a at 0 dwells at c until 12 and gets to d at 14. E leaving at 2 is stuck
+        // behind it without the bypass, and passes it with one.
+        assert_eq!(
+            results(false),
+            (0, vec![4.0, 14.0, 24.0], vec![5.0, 15.0, 25.0])
+        );
+        assert_eq!(
+            results(true),
+            (3, vec![4.0, 14.0, 24.0], vec![10.0, 20.0, 30.0])
+        );
+        // which the events engine doesn't model
+        assert!(simulator(false, "events").0.check_engine().is_ok());
+        assert!(simulator(true, "events").0.check_engine().is_err());
+    }
+
+    #[test]
+    fn trains_only_turn_back_where_there_is_a_crossover() {
+        let scenario = |crossovers: [bool; 2]| {
+            json!({
+                "nodes": [
+                    "a", { "id": "b", "crossover": crossovers[0] },
+                    { "id": "c", "crossover": crossovers[1] }, "d",
+                ],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }, { "id": "c-d" }, { "id": "d-a" }],
+                "routes": {
+                    "p": { "nodes": ["a", "b", "c"], "turnaround": "q" },
+                    "q": { "nodes": ["c", "d", "a"] },
+                },
+                "disruptions": [{ "edge": "b-c", "start": 10.0, "end": 30.0 }],
+                "config": { "disruption_response": "short_turn" },
+            })
+        };
+        let missing = |crossovers| {
+            let (js_graph, js_routes) = network(&scenario(crossovers));
+            let missing = check_crossovers(js_graph, js_routes).unwrap();
+            missing
+                .into_iter()
+                .map(|missing| (missing.route, missing.station))
+                .collect::<Vec<_>>()
+        };
+        // p turns around onto q at c
+        assert_eq!(
+            missing([false, false]),
+            [("p".to_string(), "c".to_string())]
+        );
+        assert!(missing([false, true]).is_empty());
+
+        // ahead of the closure trains are taken out of service at b if they can turn back
+        // there, and wait for it to reopen if not
+        let held = run(scenario([false, true])).disruption_statistics;
+        assert!(held.short_turns.is_empty());
+        assert_eq!(held.delayed_trains, 1);
+        let turned = run(scenario([true, true])).disruption_statistics;
+        assert_eq!(turned.short_turns, ["b"]);
+        assert_eq!(turned.delayed_trains, 0);
+    }
+
+    #[test]
+    fn frequency_previews_estimate_load_and_wait_by_period() {
+        let (js_graph, js_routes) = network(&json!({
+            "nodes": ["a", "b"],
+            "edges": [{ "id": "a-b" }],
+            "routes": { "r": { "nodes": ["a", "b"] } },
+        }));
+        let demand = serde_json::from_value(json!([
+            { "origin": "a", "destination": "b", "count": 24, "start_time": 0.0, "end_time": 36.0 },
+        ]))
+        .unwrap();
+        let schedule = HashMap::from([("r".to_string(), vec![1, 4, 0])]);
+        let loads = preview_frequencies(js_graph, js_routes, schedule, demand, None, None).unwrap();
+        let [one, four, none] = &loads["r"][..3] else {
+            panic!("a load for fewer than three periods");
+        };
+        assert!(one.riders > 0.0 && four.riders > 0.0);
+        // four times the trains share the riders four ways and come four times as often
+        let per_rider = |load: &JsPeriodLoad| load.load_factor.unwrap() / load.riders;
+        assert!((per_rider(one) - 4.0 * per_rider(four)).abs() < 1e-9);
+        assert_eq!(one.wait_time.unwrap(), 4.0 * four.wait_time.unwrap());
+        assert_eq!((none.load_factor, none.wait_time), (None, None));
+    }
+
+    #[test]
+    fn runs_in_seconds_scale_the_defaults_sized_in_minutes() {
+        let arrivals = |weight: u16, frequency: u64, config: Value| {
+            let (js_graph, js_routes) = network(&json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b", "weight": weight }, { "id": "b-c", "weight": weight }],
+                "routes": { "r": { "nodes": ["a", "b", "c"] } },
+            }));
+            let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+            let (routes, _) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+            let config: SimulationConfig = serde_json::from_value(config).unwrap();
+            let duration = simulation_horizon(None, config.time_unit).unwrap();
+            let results = Simulator::new(graph, routes, config).run(duration, frequency);
+            let at_c = &results.station_statistics[&map["c"]].arrival_times;
+            (results.time_unit, at_c[&RouteId(0)].clone())
+        };
+        let (unit, minutes) = arrivals(2, 5, json!({}));
+        assert_eq!(unit, TimeUnit::Minutes);
+        let (unit, seconds) = arrivals(120, 300, json!({ "time_unit": "seconds" }));
+        assert_eq!(unit, TimeUnit::Seconds);
+        // the default hour and dwells scale too, only the step stays one unit long
+        assert_eq!(seconds.len(), minutes.len());
+        for (minute, second) in minutes.iter().zip(&seconds) {
+            assert_eq!(minute * 60.0 - 59.0, *second);
+        }
+    }
+
+    #[test]
+    fn arrival_statistics_split_into_time_of_day_bands() {
+        let runs = RunStore::default();
+        let results = run_in(
+            &runs,
+            json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+                "routes": { "r": { "nodes": ["a", "b", "c"] } },
+                "frequency_profile": [
+                    { "start": 0, "headways": { "r": 5 } },
+                    { "start": 30, "headways": { "r": 15 } },
+                ],
+            }),
+        );
+        let bands = |bands: Value| {
+            let options = serde_json::from_value(json!({ "bands": bands })).unwrap();
+            let statistics = recompute_stored_statistics(&runs, results.run_id, options).unwrap();
+            statistics["b"]
+                .bands
+                .iter()
+                .map(|band| {
+                    (
+                        band.band.name.clone(),
+                        band.arrival_times.get("r").map(|stats| stats.max_wait),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let band = |name: &str, max_wait: Option<f64>| (name.to_string(), max_wait);
+        let custom = json!({ "custom": [
+            { "name": "peak", "start": 0.0, "end": 30.0 },
+            { "name": "shoulder", "start": 30.0, "end": 60.0 },
+        ] });
+        assert_eq!(
+            bands(custom),
+            [band("peak", Some(5.0)), band("shoulder", Some(15.0))]
+        );
+        // a headway counts in the band it ends in, so none does in 36-48
+        assert_eq!(
+            bands(json!("schedule_periods")),
+            [
+                band("0-12", Some(5.0)),
+                band("12-24", Some(5.0)),
+                band("24-36", Some(5.0)),
+                band("36-48", None),
+                band("48-60", Some(15.0)),
+            ]
+        );
+        assert!(bands(json!("none")).is_empty());
+    }
+
+    #[test]
+    fn random_breakdowns_hold_up_the_trains_behind() {
+        let incidents = |incidents: Value| {
+            run(json!({
+                "nodes": ["a", "b", "c", "d"],
+                "edges": [
+                    { "id": "a-b", "weight": 6 }, { "id": "b-c", "weight": 6 },
+                    { "id": "c-d", "weight": 6 },
+                ],
+                "routes": { "r": { "nodes": ["a", "b", "c", "d"] } },
+                "frequency": 3,
+                "config": { "incidents": incidents },
+            }))
+            .incidents
+        };
+        let seeded = |seed: u64| {
+            incidents(json!({
+                "probability": 0.01, "min_duration": 5.0, "max_duration": 10.0, "seed": seed,
+            }))
+        };
+        let log = |incidents: Vec<JsIncident>| {
+            incidents
+                .into_iter()
+                .map(|incident| (incident.train, incident.start, incident.end))
+                .collect::<Vec<_>>()
+        };
+        assert!(incidents(Value::Null).is_empty());
+        assert_eq!(log(seeded(1)), log(seeded(1)));
+        assert_ne!(log(seeded(1)), log(seeded(2)));
+
+        let breakdowns = seeded(1);
+        assert!(!breakdowns.is_empty());
+        for incident in &breakdowns {
+            assert!((5.0..=10.0).contains(&(incident.end - incident.start)));
+            // on a single line with a train every 3 minutes, a breakdown holds up those behind
+            assert!(!incident.delays.is_empty());
+            assert!(!incident.delays.contains_key(&incident.train));
+            assert_eq!(incident.delays.values().sum::<f64>(), incident.total_delay);
+        }
+
+        let (js_graph, js_routes) = network(&json!({
+            "nodes": ["a", "b"],
+            "edges": [{ "id": "a-b" }],
+            "routes": { "r": { "nodes": ["a", "b"] } },
+        }));
+        let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+        let (routes, _) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+        let engine = |engine: &str| {
+            let config = serde_json::from_value(json!({
+                "engine": engine,
+                "incidents": { "probability": 0.01, "min_duration": 5.0, "max_duration": 10.0 },
+            }))
+            .unwrap();
+            Simulator::new(graph.clone(), routes.clone(), config).check_engine()
+        };
+        // the events engine would leave them out
+        assert!(engine("time_step").is_ok());
+        assert!(engine("events").is_err());
+    }
+
+    #[test]
+    fn elements_nothing_reaches_are_found_and_pruned() {
+        let (js_graph, js_routes) = network(&json!({
+            "nodes": ["a", "b", "w", "x", "y"],
+            "edges": [
+                { "id": "a-b" }, { "id": "b-w", "type": "walk" }, { "id": "x-y" },
+            ],
+            "routes": { "r": { "nodes": ["a", "b"] } },
+        }));
+        let mut unused = find_unused_elements(js_graph, js_routes).unwrap();
+        unused.nodes.sort();
+        assert_eq!(unused.nodes, ["x", "y"]);
+        assert_eq!(unused.edges, ["x-y"]);
+        // w is a walk away from b, so it stays with the walk to it
+        let nodes: Vec<_> = unused
+            .graph
+            .nodes
+            .iter()
+            .map(|node| node.id.as_str())
+            .collect();
+        assert_eq!(nodes, ["a", "b", "w"]);
+        let edges: Vec<_> = unused
+            .graph
+            .edges
+            .iter()
+            .map(|edge| edge.id.as_str())
+            .collect();
+        assert_eq!(edges, ["a-b", "b-w"]);
+    }
+
+    #[test]
+    fn seeds_are_fixed_given_or_drawn_at_random() {
+        let seed = |seed: Value| JsSeed::resolve(serde_json::from_value(seed).unwrap());
+        assert_eq!(seed(Value::Null), DEFAULT_SEED);
+        assert_eq!(seed(json!(7)), 7);
+        assert_ne!(seed(json!("random")), seed(json!("random")));
+
+        // a run's seed replaces those of the config's random processes
+        let mut config: SimulationConfig = serde_json::from_value(json!({
+            "travel_time_noise": { "distribution": { "uniform": { "spread": 0.5 } }, "seed": 1 },
+            "incidents": { "probability": 0.1, "min_duration": 1.0, "max_duration": 2.0 },
+        }))
+        .unwrap();
+        reseed(&mut config, 7);
+        assert_eq!(config.travel_time_noise.unwrap().seed, 7);
+        assert_eq!(config.incidents.unwrap().seed, 7);
+    }
+
+    #[test]
+    fn slow_zones_slow_trains_while_in_effect() {
+        let arrivals = |engine: &str, disruptions: Value| {
+            let (js_graph, js_routes) = network(&json!({
+                "nodes": ["a", "b"],
+                "edges": [{ "id": "a-b", "weight": 10 }],
+                "routes": { "r": { "nodes": ["a", "b"] } },
+            }));
+            let (graph, map, petgraph_map) = js_graph_to_subway_map(js_graph).unwrap();
+            let (routes, _) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+            let config = serde_json::from_value(json!({ "engine": engine })).unwrap();
+            let mut simulator = Simulator::new(graph, routes, config);
+            let disruptions = serde_json::from_value(disruptions).unwrap();
+            simulator.set_disruptions(js_disruptions_to_plan(disruptions, &petgraph_map).unwrap());
+            let results = simulator.run(60, 10);
+            let delay: f64 = results.disruption_delays.values().sum();
+            let at_b = &results.station_statistics[&map["b"]].arrival_times;
+            (at_b[&RouteId(0)].clone(), delay)
+        };
+        let slow = json!([{ "edge": "a-b", "start": 20.0, "end": 40.0, "speed": 0.5 }]);
+        assert_eq!(
+            arrivals("time_step", json!([])),
+            (vec![1.5, 11.5, 21.5, 31.5, 41.5, 51.5], 0.0)
+        );
+        // trains take twice as long over the part of their run inside the window
+        let (stepped, stepped_delay) = arrivals("time_step", slow.clone());
+        assert_eq!(stepped, [1.5, 11.5, 23.0, 40.5, 45.5, 51.5]);
+        assert_eq!(stepped_delay, 1.5 + 9.0 + 4.0);
+        // the events engine changes speed at the window's edges rather than on the next step
+        let (events, events_delay) = arrivals("events", slow);
+        for (event, step) in events.iter().zip(&stepped) {
+            assert!((event - step).abs() <= 0.5, "{events:?}");
+        }
+        assert!((events_delay - stepped_delay).abs() <= 0.5);
+    }
+
+    #[test]
+    fn energy_goes_with_distance_and_stops_by_route_and_hour() {
+        let energy = |per_stop: f64, pass_through: Value| {
+            let results = run(json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+                "routes": { "r": { "nodes": ["a", "b", "c"], "pass_through": pass_through } },
+                "frequency": 10,
+                "duration": 120,
+                "config": { "energy": { "per_distance": 1.0, "per_stop": per_stop } },
+            }));
+            let energy = &results.energy["r"];
+            (energy.total, energy.by_hour.clone())
+        };
+        // six trains an hour each run two edges of length 2 and stop twice along the way
+        assert_eq!(energy(0.0, json!([])), (48.0, vec![24.0, 24.0]));
+        assert_eq!(energy(10.0, json!([])), (288.0, vec![144.0, 144.0]));
+        // running through b saves a stop without saving distance
+        assert_eq!(energy(10.0, json!(["b"])), (168.0, vec![84.0, 84.0]));
+    }
+
+    #[test]
+    fn the_arrival_feed_streams_a_message_per_checkpoint() {
+        let path = std::env::temp_dir().join("subway_sim_arrival_feed.ndjson");
+        let feed: JsFeed = serde_json::from_value(json!({
+            "target": { "file": path.to_str().unwrap() },
+            "start_time": 1_700_000_000,
+        }))
+        .unwrap();
+        let feed = ArrivalFeed::open(feed, TimeUnit::Minutes).unwrap();
+        run_watched(
+            &RunStore::default(),
+            json!({
+                "nodes": ["a", "b"],
+                "edges": [{ "id": "a-b" }],
+                "routes": { "r": { "nodes": ["a", "b"] } },
+                "frequency": 10,
+                "duration": 30,
+                "config": { "checkpoint_interval": 10 },
+            }),
+            &|checkpoint| feed.send(&checkpoint),
+        );
+        drop(feed);
+        let messages: Vec<Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        // a message every ten minutes, its arrivals counting in seconds from the start time
+        let timestamps: Vec<_> = messages.iter().map(|m| &m["header"]["timestamp"]).collect();
+        assert_eq!(timestamps, [1_700_000_600, 1_700_001_200, 1_700_001_800]);
+        assert_eq!(messages[0]["header"]["gtfs_realtime_version"], "2.0");
+        assert_eq!(
+            messages[1]["entity"],
+            json!([{
+                "id": "r",
+                "trip_update": {
+                    "trip": { "route_id": "r" },
+                    "stop_time_update": [
+                        { "stop_id": "a", "arrival": { "time": 1_700_000_600 } },
+                        { "stop_id": "b", "arrival": { "time": 1_700_000_810 } },
+                    ],
+                },
+            }])
+        );
+    }
+
+    #[test]
+    fn ridership_goes_to_the_route_of_the_train_boarded() {
+        let results = run(json!({
+            "nodes": ["a", "b", "c", "d"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }, { "id": "b-d" }],
+            "routes": {
+                "x": { "nodes": ["a", "b", "c"] },
+                "y": { "nodes": ["a", "b", "d"], "offset": 2 },
+            },
+            "demand": [trip("a", "c", 10), trip("a", "d", 6), trip("a", "b", 12)],
+        }));
+        // riders for the branches take their own route, riders for b whichever train comes first
+        assert_eq!(
+            results.ridership,
+            HashMap::from([("x".into(), 19), ("y".into(), 9)])
+        );
+    }
+
+    #[test]
+    fn paused_runs_carry_on_as_if_never_stopped() {
+        let (graph, map, routes) = {
+            let (js_graph, js_routes) = network(&json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+                "routes": { "r": { "nodes": ["a", "b", "c"] } },
+            }));
+            let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+            let (routes, _) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+            (graph, map, routes)
+        };
+        let simulator =
+            || Simulator::new(graph.clone(), routes.clone(), SimulationConfig::default());
+        let arrivals = |results: SimulationResults| {
+            results.station_statistics[&map["c"]].arrival_times.clone()
+        };
+        let full = arrivals(simulator().run(60, 10));
+        assert_eq!(full.values().next().unwrap().len(), 6);
+
+        let mut paused = simulator().start_run(60, 10);
+        paused.run_until(25);
+        assert_eq!((paused.time(), paused.is_finished()), (25, false));
+        // the results so far only have the trains in by then
+        assert_eq!(
+            arrivals(paused.results()).values().next().unwrap(),
+            &full.values().next().unwrap()[..2]
+        );
+        // a snapshot taken at the pause can be carried on from as well as the run itself
+        let snapshot = paused.clone();
+        assert_eq!(arrivals(paused.finish()), full);
+        assert_eq!(arrivals(snapshot.finish()), full);
+    }
+
+    #[test]
+    fn sessions_step_on_from_their_snapshot_to_the_end() {
+        let snapshots = SnapshotStore::default();
+        let results = run_paused(
+            &RunStore::default(),
+            json!({
+                "nodes": ["a", "b"],
+                "edges": [{ "id": "a-b" }],
+                "routes": { "r": { "nodes": ["a", "b"] } },
+                "frequency": 10,
+                "duration": 30,
+            }),
+            Some((&snapshots, 0)),
+            &|_| {},
+        );
+        let snapshot = results.snapshot.unwrap();
+        assert_eq!(snapshot.time, 0);
+        let session = Session {
+            snapshot: Mutex::new(snapshots.snapshots.lock().unwrap()[&snapshot.id].clone()),
+            playing: AtomicBool::new(false),
+        };
+        let step = |minutes| {
+            let step = session.step(minutes).unwrap();
+            let at_b = step
+                .checkpoint
+                .arrivals
+                .get("b")
+                .map(|at_b| at_b["r"].clone());
+            (step.checkpoint.time, at_b, step.finished)
+        };
+        // each step has the arrivals since the one before
+        assert_eq!(step(10), (10.0, Some(vec![3.5]), false));
+        assert_eq!(step(10), (20.0, Some(vec![13.5]), false));
+        // stepping past the end stops there, and further steps do nothing
+        assert_eq!(step(15), (30.0, Some(vec![23.5]), true));
+        assert_eq!(step(1), (30.0, None, true));
+    }
+
+    #[test]
+    fn trains_held_up_behind_a_breakdown_are_logged_bunched() {
+        let bunching = |config: Value| {
+            run(json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b", "weight": 6 }, { "id": "b-c" }],
+                "routes": { "r": { "nodes": ["a", "b", "c"] } },
+                "breakdowns": [{ "edge": "a-b", "route": "r", "start": 10.0, "duration": 10.0 }],
+                "config": config,
+            }))
+            .bunching
+        };
+        let logged = bunching(json!({ "bunching_threshold": 3.0 }));
+        let at = |station: &str| {
+            logged["r"][station]
+                .iter()
+                .map(|b| (b.time, b.headway))
+                .collect::<Vec<_>>()
+        };
+        // trains leaving every five minutes queue behind the broken down one, then come in two
+        // minutes apart once it clears
+        assert_eq!(at("b"), [(24.0, 1.5), (26.0, 2.0), (28.0, 2.0)]);
+        assert_eq!(at("c"), [(26.5, 1.5), (28.5, 2.0), (30.5, 2.0)]);
+        assert_eq!(logged["r"].len(), 2);
+        // nothing is logged without a threshold
+        assert!(bunching(json!({})).is_empty());
+    }
+
+    #[test]
+    fn timetabled_routes_dispatch_on_time_and_report_lateness() {
+        let scenario = |route: Value, breakdowns: Value| {
+            json!({
+                "nodes": ["a", "b", "c", "d"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }, { "id": "d-a" }],
+                "routes": { "r": route },
+                "breakdowns": breakdowns,
+            })
+        };
+        let timetabled = json!({ "nodes": ["a", "b", "c"], "timetable": [0, 13, 40] });
+        let deviations = |breakdowns: Value| {
+            let results = run(scenario(timetabled.clone(), breakdowns));
+            let stats = &results.station_statistics["a"].arrival_times["r"];
+            assert_eq!((stats.min_wait, stats.max_wait), (13.0, 27.0));
+            let at_c = &results.schedule_deviations["r"]["c"];
+            (at_c.trains, at_c.min, at_c.max)
+        };
+        // trains leave at the times given, and get in when due unless something holds them up
+        assert_eq!(deviations(json!([])), (3, 0.0, 0.0));
+        let breakdown = json!([{ "edge": "a-b", "route": "r", "start": 14.0, "duration": 5.0 }]);
+        assert_eq!(deviations(breakdown), (3, 0.0, 5.0));
+
+        // trains from a depot aren't dispatched from the route's first station
+        let from_depot = json!({ "nodes": ["a", "b", "c"], "timetable": [0], "depot": "d" });
+        let (js_graph, js_routes) = network(&scenario(from_depot, json!([])));
+        let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+        assert!(js_routes_to_routes(js_routes, &graph, &map).is_err());
+    }
+
+    #[test]
+    fn dispatchers_cancel_add_and_hold_trains_mid_run() {
+        let (graph, map, routes) = {
+            let (js_graph, js_routes) = network(&json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+                "routes": { "r": { "nodes": ["a", "b", "c"] } },
+            }));
+            let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+            let (routes, _) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+            (graph, map, routes)
+        };
+        // arrivals at c of a run paused at the given time for the dispatcher to step in
+        let arrivals = |at: i32, intervene: &dyn Fn(&mut PausedRun)| {
+            let simulator =
+                Simulator::new(graph.clone(), routes.clone(), SimulationConfig::default());
+            let mut run = simulator.start_run(30, 10);
+            run.run_until(at);
+            intervene(&mut run);
+            let results = run.finish();
+            results.station_statistics[&map["c"]].arrival_times[&RouteId(0)].clone()
+        };
+        assert_eq!(arrivals(0, &|_| {}), [6.0, 16.0, 26.0]);
+        // an extra train leaves as soon as it's asked for
+        assert_eq!(
+            arrivals(5, &|run| run.add_train(RouteId(0))),
+            [6.0, 11.0, 16.0, 26.0]
+        );
+        // the first train, still at a a minute in, goes out of service there or is held there
+        let first = |run: &PausedRun| run.trains().next().unwrap();
+        assert_eq!(
+            arrivals(1, &|run| run.cancel_train(first(run))),
+            [16.0, 26.0]
+        );
+        assert_eq!(
+            arrivals(1, &|run| run.hold_train(first(run), 5.0).unwrap()),
+            [11.0, 16.0, 26.0]
+        );
+        // trains between stations can't be held
+        assert_eq!(
+            arrivals(3, &|run| assert!(run.hold_train(first(run), 5.0).is_err())),
+            [6.0, 16.0, 26.0]
+        );
+    }
+
+    #[test]
+    fn the_frontier_keeps_schedules_nothing_beats_on_both_costs() {
+        let point = |operator_cost, trains, passenger_cost| JsFrontierPoint {
+            operator_cost,
+            trains,
+            passenger_cost,
+            schedule: Schedule::new(),
+        };
+        let frontier = pareto_frontier(vec![
+            point(0.0, 12, 5.0),
+            point(1.0, 10, 5.0),
+            point(2.0, 9, 8.0),
+            point(3.0, 8, 7.0),
+            point(4.0, 8, 7.0),
+        ]);
+        // more trains for no saving, or more of both, fall off, as do repeats
+        let kept: Vec<_> = frontier
+            .iter()
+            .map(|point| (point.operator_cost, point.trains, point.passenger_cost))
+            .collect();
+        assert_eq!(kept, [(3.0, 8, 7.0), (1.0, 10, 5.0)]);
+    }
+
+    #[test]
+    fn standby_trains_fill_gaps_in_service() {
+        let results = run(json!({
+            "nodes": ["a", "b", "c"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+            "routes": {
+                "r": {
+                    "nodes": ["a", "b", "c"],
+                    "standby": { "station": "b", "trains": 1, "gap": 12.0 },
+                },
+            },
+            "frequency": 20,
+            "demand": [trip("b", "c", 20)],
+        }));
+        let report = results.gap_trains.unwrap();
+        // trains every twenty minutes get to b at 3.5, so the first step more than twelve
+        // minutes on sends out the only standby train
+        let inserted: Vec<_> = report
+            .inserted
+            .iter()
+            .map(|train| {
+                (
+                    train.route.as_str(),
+                    train.station.as_str(),
+                    train.time,
+                    train.gap,
+                )
+            })
+            .collect();
+        assert_eq!(inserted, [("r", "b", 16.0, 12.5)]);
+        assert!(report.average_wait.unwrap() < report.baseline_average_wait.unwrap());
+    }
+
+    #[test]
+    fn dispatch_previews_list_departures_without_simulating() {
+        let preview = |schedule: Option<Schedule>| {
+            let (js_graph, js_routes) = network(&json!({
+                "nodes": ["a", "b"],
+                "edges": [{ "id": "a-b" }],
+                "routes": {
+                    "r": { "nodes": ["a", "b"], "offset": 5 },
+                    "s": { "nodes": ["a", "b"], "timetable": [3, 20] },
+                },
+            }));
+            preview_dispatches(js_graph, js_routes, 15, None, schedule, None, Some(60)).unwrap()
+        };
+        // every fifteen minutes from the offset, or at the times of the timetable
+        assert_eq!(
+            preview(None),
+            HashMap::from([("r".into(), vec![5, 20, 35, 50]), ("s".into(), vec![3, 20])])
+        );
+        // a schedule spreads each period's trains over it, still from the offset
+        let schedule = Schedule::from([("r".to_string(), vec![2, 0, 1])]);
+        assert_eq!(
+            preview(Some(schedule)),
+            HashMap::from([("r".into(), vec![5, 11, 29])])
+        );
+    }
+
+    #[test]
+    fn branching_routes_send_their_trains_down_each_branch_in_turn() {
+        let scenario = |branch: Value| {
+            json!({
+                "nodes": ["a", "b", "c", "d"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }, { "id": "b-d" }],
+                "routes": {
+                    "r": {
+                        "nodes": ["a", "b", "c"],
+                        "branches": [{ "id": "r-d", "name": "r-d", "nodes": branch }],
+                    },
+                },
+            })
+        };
+        let (js_graph, js_routes) = network(&scenario(json!(["b", "d"])));
+        let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+        let (routes, route_ids) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+        let results = Simulator::new(graph, routes, SimulationConfig::default()).run(60, 10);
+        let arrivals = |station: &str, route: &str| {
+            let route = RouteId(route_ids.iter().position(|id| id == route).unwrap() as u32);
+            results.station_statistics[&map[station]]
+                .arrival_times
+                .get(&route)
+                .cloned()
+                .unwrap_or_default()
+        };
+        // trains every ten minutes take turns, sharing a-b and splitting at b
+        assert_eq!(arrivals("b", "r"), [3.5, 23.5, 43.5]);
+        assert_eq!(arrivals("b", "r-d"), [13.5, 33.5, 53.5]);
+        assert_eq!(arrivals("c", "r"), [6.0, 26.0, 46.0]);
+        assert_eq!(arrivals("d", "r-d"), [16.0, 36.0, 56.0]);
+        assert!(arrivals("d", "r").is_empty());
+
+        // branches have to leave from somewhere the route runs through
+        let (js_graph, js_routes) = network(&scenario(json!(["d"])));
+        let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+        assert!(js_routes_to_routes(js_routes, &graph, &map).is_err());
+    }
+
+    #[test]
+    fn restart_spreads_leave_out_restarts_finding_nothing() {
+        let restarts = JsRestarts::new(
+            vec![
+                JsRestart {
+                    seed: 7,
+                    cost: Some(10.0),
+                },
+                JsRestart {
+                    seed: 8,
+                    cost: None,
+                },
+                JsRestart {
+                    seed: 9,
+                    cost: Some(14.0),
+                },
+            ],
+            7,
+        );
+        assert_eq!(restarts.restarts.len(), 3);
+        assert_eq!(
+            (
+                restarts.min_cost,
+                restarts.max_cost,
+                restarts.mean_cost,
+                restarts.cost_std_dev
+            ),
+            (10.0, 14.0, 12.0, 2.0)
+        );
+    }
+
+    #[test]
+    fn express_overlays_only_stop_at_their_stops() {
+        let scenario = |stops: Value| {
+            network(&json!({
+                "nodes": ["a", "b", "c", "d"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }, { "id": "c-d" }],
+                "routes": {
+                    "r": {
+                        "nodes": ["a", "b", "c", "d"],
+                        "express": { "id": "rx", "name": "rx", "stops": stops, "offset": 5 },
+                    },
+                },
+            }))
+        };
+        let (js_graph, js_routes) = scenario(json!(["a", "d"]));
+        let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+        let (routes, route_ids) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+        assert_eq!(route_ids, ["r", "rx"]);
+        let express = &routes[1];
+        assert!(express.overlay && !routes[0].overlay);
+        assert_eq!(express.offset, 5);
+        assert_eq!(express.pass_through, HashSet::from([map["b"], map["c"]]));
+        let results = Simulator::new(graph, routes, SimulationConfig::default()).run(30, 10);
+        let at = |station: &str| &results.station_statistics[&map[station]].arrival_times;
+        assert!(!at("b").contains_key(&RouteId(1)) && !at("c").contains_key(&RouteId(1)));
+        // leaving five minutes after the locals, the express gets to d a minute quicker for
+        // the two stops it skips
+        assert_eq!(at("d")[&RouteId(0)], [8.5, 18.5, 28.5]);
+        assert_eq!(at("d")[&RouteId(1)], [2.5, 12.5, 22.5]);
+
+        let (js_graph, js_routes) = scenario(json!(["d"]));
+        let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+        assert!(js_routes_to_routes(js_routes, &graph, &map).is_err());
+    }
+
+    #[test]
+    fn stored_search_maps_load_back_for_the_same_network() {
+        let network = |weight: u32| {
+            let (js_graph, js_routes) = network(&json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b", "weight": weight }, { "id": "b-c" }],
+                "routes": { "r": { "nodes": ["a", "b", "c"] } },
+            }));
+            let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+            let (routes, _) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+            (graph, map, routes)
+        };
+        let (graph, map, routes) = network(2);
+        let hash = SearchMap::network_hash(&graph, &routes);
+        assert_eq!(hash, SearchMap::network_hash(&network(2).0, &network(2).2));
+        assert_ne!(hash, SearchMap::network_hash(&network(3).0, &network(3).2));
+
+        let dir = std::env::temp_dir().join("subway_sim_search_maps");
+        let _ = fs::remove_dir_all(&dir);
+        let mut search_map = SearchMap::generate(&graph, &routes);
+        SearchMapCache::load(Some(dir.clone()))
+            .store(hash, &search_map)
+            .unwrap();
+        let cache = SearchMapCache::load(Some(dir));
+        let mut loaded = cache.maps.lock().unwrap().remove(&hash).unwrap();
+        let paths = |search_map: &mut SearchMap| {
+            let paths = shortest_paths(map["a"], map["c"], search_map, 2);
+            assert!(!paths.is_empty());
+            format!("{paths:?}")
+        };
+        // paths found on the map read back are the ones found on the map built
+        assert_eq!(paths(&mut loaded), paths(&mut search_map));
+    }
+
+    #[test]
+    fn trains_in_service_are_counted_by_route_each_minute() {
+        let results = |engine: &str| {
+            run(json!({
+                "nodes": ["a", "b", "c", "d", "e"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }, { "id": "d-e", "weight": 12 }],
+                "routes": {
+                    "r": { "nodes": ["a", "b", "c"] },
+                    "s": { "nodes": ["d", "e"] },
+                },
+                "frequency": 5,
+                "duration": 20,
+                "config": { "engine": engine },
+            }))
+        };
+        let counts = |results: &JsSimulationResults, minute: usize| {
+            let in_service = &results.trains_in_service;
+            assert_eq!(in_service.len(), 20);
+            let at = &in_service[minute];
+            assert_eq!(at.time, minute as f64);
+            (at.total, at.by_route["r"], at.by_route["s"])
+        };
+        let peaks = HashMap::from([("r".into(), 2), ("s".into(), 3)]);
+        // both dispatch every five minutes, and s's trains take over ten minutes to get to the
+        // end, so more of them are out at once
+        let stepped = results("time_step");
+        assert_eq!(counts(&stepped, 5), (5, 2, 3));
+        assert_eq!(counts(&stepped, 8), (3, 1, 2));
+        assert_eq!(stepped.peak_trains_by_route, peaks);
+
+        // the events engine ends trips partway through a minute rather than on the step they end
+        // in, so trains leave its counts a minute later
+        let events = results("events");
+        assert_eq!(counts(&events, 5), (5, 2, 3));
+        assert_eq!(counts(&events, 8), (4, 1, 3));
+        assert_eq!(counts(&events, 9), (3, 1, 2));
+        assert_eq!(events.peak_trains_by_route, peaks);
+    }
+
+    #[test]
+    fn maintenance_delays_the_trips_over_the_closed_edges() {
+        let results = run(json!({
+            "nodes": ["a", "b", "c", "d", "e"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }, { "id": "d-e" }],
+            "routes": {
+                "r": { "nodes": ["a", "b", "c"] },
+                "s": { "nodes": ["d", "e"] },
+            },
+            "frequency": 10,
+            "maintenance": [{ "edges": ["a-b"], "start": 12.0, "end": 20.0 }],
+        }));
+        let report = results.maintenance.unwrap();
+        // the train leaving at 10 is still on a-b when it closes and waits out the work there
+        let window = &report.windows[0];
+        assert_eq!(window.routes, ["r"]);
+        assert_eq!((window.delayed_trips, window.total_delay), (1, 8.0));
+        assert_eq!(report.routes.keys().collect::<Vec<_>>(), ["r"]);
+        let delayed: Vec<_> = report.routes["r"]
+            .trips
+            .iter()
+            .map(|trip| (trip.start, trip.delay))
+            .collect();
+        assert_eq!(delayed, [(10.0, 8.0)]);
+    }
+
+    #[test]
+    fn padded_routes_hold_early_trains_and_let_late_ones_catch_up() {
+        let results = |breakdowns: Value| {
+            run(json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+                "routes": { "r": { "nodes": ["a", "b", "c"], "padding": 0.5 } },
+                "frequency": 10,
+                "duration": 30,
+                "breakdowns": breakdowns,
+            }))
+        };
+        let padding = |breakdowns: Value| {
+            let padding = &results(breakdowns).padding["r"];
+            (padding.scheduled, padding.used, padding.utilization)
+        };
+        // each of the three trains has a minute of padding on the two minute run to b, which
+        // trains on time wait away there
+        assert_eq!(padding(json!([])), (3.0, 0.0, Some(0.0)));
+        // the first train held up a minute on a-b makes it back out of its padding
+        let breakdown = json!([{ "edge": "a-b", "route": "r", "start": 2.0, "duration": 1.0 }]);
+        assert_eq!(padding(breakdown), (3.0, 1.0, Some(1.0 / 3.0)));
+    }
+
+    #[test]
+    fn late_trains_skip_skippable_stops_to_catch_up() {
+        let skipped = |threshold: f64, breakdowns: Value| {
+            run(json!({
+                "nodes": ["a", "b", { "id": "c", "skippable": true }, "d"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }, { "id": "c-d" }],
+                "routes": { "r": { "nodes": ["a", "b", "c", "d"] } },
+                "frequency": 10,
+                "duration": 30,
+                "config": { "auto_express": { "threshold": threshold } },
+                "demand": [trip("c", "d", 5)],
+                "breakdowns": breakdowns,
+            }))
+            .skipped_stops
+            .iter()
+            .map(|stop| {
+                (
+                    stop.station.clone(),
+                    stop.time,
+                    stop.lateness,
+                    stop.passed_by,
+                )
+            })
+            .collect::<Vec<_>>()
+        };
+        // trains on time stop everywhere
+        assert!(skipped(0.5, json!([])).is_empty());
+        // the train leaving at 10 is held three minutes on a-b, so runs through c, leaving the
+        // riders who came since the train before on the platform
+        let breakdown = json!([{ "edge": "a-b", "route": "r", "start": 12.0, "duration": 3.0 }]);
+        assert_eq!(
+            skipped(2.0, breakdown.clone()),
+            [("c".to_string(), 19.0, 3.0, 4)]
+        );
+        assert!(skipped(3.0, breakdown).is_empty());
+    }
+
+    #[test]
+    fn departures_wait_for_a_train_under_a_fleet_cap() {
+        let waits = |max_trains: Value, config: Value| {
+            run(json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+                "routes": { "r": { "nodes": ["a", "b", "c"], "max_trains": max_trains } },
+                "frequency": 2,
+                "duration": 12,
+                "config": config,
+            }))
+            .fleet_waits
+            .iter()
+            .map(|wait| (wait.route.clone(), wait.scheduled, wait.dispatched))
+            .collect::<Vec<_>>()
+        };
+        assert!(waits(Value::Null, json!({})).is_empty());
+        // trains every two minutes are out for six, so with two allowed out departures wait for
+        // one to come in, and those due while another is waiting don't run at all
+        let capped = [
+            ("r".to_string(), 0.0, Some(2.0)),
+            ("r".to_string(), 2.0, Some(6.0)),
+            ("r".to_string(), 4.0, None),
+            ("r".to_string(), 6.0, Some(8.0)),
+            ("r".to_string(), 8.0, None),
+            ("r".to_string(), 10.0, None),
+        ];
+        assert_eq!(waits(json!(2), json!({})), capped);
+        // a cap on the whole system does the same with only the one route
+        assert_eq!(waits(Value::Null, json!({ "max_trains": 2 })), capped);
+    }
+
+    #[test]
+    fn shut_periods_dispatch_nothing_and_turn_riders_away() {
+        let results = run(json!({
+            "nodes": ["a", "b"],
+            "edges": [{ "id": "a-b" }],
+            "routes": { "r": { "nodes": ["a", "b"] } },
+            "demand": [
+                trip("a", "b", 4),
+                {
+                    "origin": "a",
+                    "destination": "b",
+                    "count": 3,
+                    "start_time": 40.0,
+                    "end_time": 50.0,
+                },
+            ],
+            "frequency_profile": [
+                { "start": 0, "name": "day", "headways": { "r": 10 } },
+                { "start": 30, "name": "night", "headways": { "r": 10 }, "shutdown": true },
+            ],
+        }));
+        let periods = results.periods.unwrap();
+        let day = &periods[0];
+        assert_eq!(
+            (day.name.as_deref(), day.start, day.end),
+            (Some("day"), 0.0, 30.0)
+        );
+        assert_eq!(day.departures["r"], 3);
+        assert_eq!(day.peak_trains_in_service, 1);
+        assert_eq!((day.passengers_delivered, day.turned_away), (4, 0));
+
+        // the night's headway is ignored and its riders never get in
+        let night = &periods[1];
+        assert_eq!(
+            (night.name.as_deref(), night.start, night.end),
+            (Some("night"), 30.0, 60.0)
+        );
+        assert!(night.shutdown && night.departures.is_empty());
+        assert_eq!(night.peak_trains_in_service, 0);
+        assert_eq!((night.passengers_delivered, night.turned_away), (0, 3));
+        assert_eq!(night.average_passenger_wait, None);
+    }
+
+    #[test]
+    fn transferring_riders_take_the_walk_between_stations() {
+        // times given in minutes, or in seconds when there are 60 to the unit
+        let journey = |walk: u32, per_minute: u32| {
+            let time_unit = if per_minute == 1 {
+                "minutes"
+            } else {
+                "seconds"
+            };
+            let results = run(json!({
+                "nodes": ["a", "t", "s", "d"],
+                "edges": [
+                    { "id": "a-t", "weight": 2 * per_minute },
+                    { "id": "s-d", "weight": 2 * per_minute },
+                    { "id": "t-s", "weight": walk * per_minute, "type": "walk" },
+                ],
+                "routes": {
+                    "L": { "nodes": ["a", "t"] },
+                    "F": { "nodes": ["s", "d"] },
+                },
+                "frequency": per_minute,
+                "duration": 60 * per_minute,
+                "config": { "time_unit": time_unit },
+                "demand": [{
+                    "origin": "a",
+                    "destination": "d",
+                    "count": 1,
+                    "start_time": 0.0,
+                    "end_time": per_minute,
+                }],
+            }));
+            assert_eq!(results.passengers_delivered, 1);
+            let wait_at_s = results.station_statistics["s"].average_passenger_wait;
+            (results.average_journey_time.unwrap(), wait_at_s.unwrap())
+        };
+        // a minute's walk makes the next F at s, waiting there counted from getting there
+        let (short, short_wait) = journey(1, 1);
+        assert_eq!(short_wait, 0.0);
+        // five minutes more leaves a minute to wait for the next
+        let (long, long_wait) = journey(6, 1);
+        assert_eq!(long_wait, 1.0);
+        assert_eq!(long - short, 6.0);
+
+        // walks given in seconds take that many seconds, five minutes more adding 300
+        let (short, short_wait) = journey(1, 60);
+        let (long, long_wait) = journey(6, 60);
+        assert_eq!(long_wait, short_wait);
+        assert_eq!(long - short, 300.0);
+    }
+
+    #[test]
+    fn riders_weighing_crowding_let_full_trains_go_by() {
+        let results = |crowding: Value| {
+            run(json!({
+                "nodes": ["a", "b", "c"],
+                "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+                "routes": { "r": { "nodes": ["a", "b", "c"] } },
+                "config": { "train_capacity": 4, "crowding": crowding },
+                "demand": [trip("a", "c", 8), trip("b", "c", 4)],
+            }))
+        };
+        let boarding = results(Value::Null);
+        assert_eq!(boarding.crowding_skips, 0);
+        let wait = boarding.station_statistics["b"]
+            .average_passenger_wait
+            .unwrap();
+
+        // riders at b let trains half full from a go by for up to ten minutes
+        let crowding = json!({ "threshold": 0.5, "disutility": 2.0, "max_skip_wait": 10.0 });
+        let skipping = results(crowding);
+        assert_eq!(skipping.crowding_skips, 7);
+        assert_eq!(skipping.passengers_delivered, 12);
+        assert!(
+            skipping.station_statistics["b"]
+                .average_passenger_wait
+                .unwrap()
+                > wait + 5.0
+        );
+    }
+
+    #[test]
+    fn riders_weighing_crowding_go_round_crowded_trains() {
+        let results = |crowding: Value| {
+            run(json!({
+                "nodes": ["a", "b", "c", "d"],
+                "edges": [
+                    { "id": "a-b" }, { "id": "b-c" },
+                    { "id": "a-d", "weight": 3 }, { "id": "d-c", "weight": 3 },
+                ],
+                "routes": {
+                    "X": { "nodes": ["a", "b", "c"] },
+                    "Y": { "nodes": ["a", "d"], "offset": 2 },
+                    "Z": { "nodes": ["d", "c"] },
+                },
+                "config": { "train_capacity": 4, "crowding": crowding },
+                "demand": [trip("a", "b", 20), trip("a", "c", 10)],
+            }))
+        };
+        // everyone takes X, the quickest way to c
+        let quickest = results(Value::Null);
+        assert_eq!(quickest.crowding_detours, 0);
+        assert_eq!(quickest.ridership, HashMap::from([("X".to_string(), 30)]));
+
+        // riders to c change at d when X last left a more than half full
+        let detouring = results(json!({ "threshold": 0.5, "disutility": 5.0 }));
+        assert_eq!(detouring.crowding_detours, 7);
+        assert_eq!(detouring.ridership["X"], 23);
+        assert_eq!((detouring.ridership["Y"], detouring.ridership["Z"]), (7, 7));
+        assert_eq!(detouring.passengers_delivered, 30);
+    }
+
+    #[test]
+    fn trip_rates_draw_poisson_arrivals_averaging_them() {
+        let (js_graph, js_routes) = network(&json!({
+            "nodes": ["a", "b"],
+            "edges": [{ "id": "a-b" }],
+            "routes": { "r": { "nodes": ["a", "b"] } },
+        }));
+        let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+        let (routes, _) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+        let mut search_map = SearchMap::generate(&graph, &routes);
+        let mut arrivals = |rate: f64, seed: u64| {
+            let trip = json!({
+                "origin": "a",
+                "destination": "b",
+                "rate": rate,
+                "start_time": 0.0,
+                "end_time": 100.0,
+                "arrival_process": "poisson",
+            });
+            let trips = vec![serde_json::from_value(trip).unwrap()];
+            js_trips_to_trip_data(trips, &map, &mut search_map, seed)
+                .map(|(trip_data, _)| trip_data[&0][0].arrival_times.clone())
+        };
+        // two riders a minute over 100 minutes, how many turning up varying from seed to seed
+        let counts: Vec<_> = (0..200)
+            .map(|seed| arrivals(2.0, seed).unwrap().len())
+            .collect();
+        let mean = counts.iter().sum::<usize>() as f64 / counts.len() as f64;
+        assert!((195.0..205.0).contains(&mean));
+        assert!(counts.iter().any(|&count| count != counts[0]));
+
+        let times = arrivals(2.0, 0).unwrap();
+        assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(times.iter().all(|time| (0.0..100.0).contains(time)));
+        // the same seed draws the same riders
+        assert_eq!(times, arrivals(2.0, 0).unwrap());
+        assert!(arrivals(-1.0, 0).is_err());
+    }
+
+    #[test]
+    fn closed_stations_are_run_through_and_their_riders_walk_round() {
+        let results = |closures: Value| {
+            run(json!({
+                "nodes": ["a", "b", "c", "d"],
+                "edges": [
+                    { "id": "a-b" }, { "id": "b-c" }, { "id": "c-d" },
+                    { "id": "c-b", "weight": 3, "type": "walk", "one_way": false },
+                ],
+                "routes": { "r": { "nodes": ["a", "b", "c", "d"] } },
+                "demand": [trip("c", "d", 5), trip("a", "c", 4)],
+                "closures": closures,
+            }))
+        };
+        assert!(results(json!([])).closures.is_none());
+
+        let closed = json!([{ "station": "c", "start": 0.0, "end": 60.0 }]);
+        let report = results(closed).closures.unwrap();
+        let closure = &report.closures[0];
+        // every train of the hour runs through, those getting off at c going on to d and
+        // those boarding at c walking back to b
+        assert_eq!(closure.trains_run_through, 12);
+        assert_eq!(closure.riders_carried_past, 4);
+        assert_eq!(closure.riders_diverted, 5);
+        assert_eq!(
+            report.passengers_delivered,
+            report.baseline_passengers_delivered
+        );
+        let slower =
+            report.average_journey_time.unwrap() - report.baseline_average_journey_time.unwrap();
+        assert!(slower > 3.0);
+    }
+
+    #[test]
+    fn route_cars_size_their_trains_and_load_factors() {
+        let results = run(json!({
+            "nodes": ["a", "b", "c", "d", "e", "f"],
+            "edges": [{ "id": "a-b" }, { "id": "c-d" }, { "id": "e-f" }],
+            "routes": {
+                "long": { "nodes": ["a", "b"], "rolling_stock": "cars", "cars": 4 },
+                "short": { "nodes": ["c", "d"], "rolling_stock": "cars", "cars": 2 },
+                "plain": { "nodes": ["e", "f"] },
+            },
+            "config": {
+                "train_capacity": 10,
+                "rolling_stock": { "cars": { "car_capacity": 2, "car_length": 0.1 } },
+            },
+            "demand": [trip("a", "b", 20), trip("c", "d", 20), trip("e", "f", 20)],
+        }));
+        let load = |route: &str| {
+            let load = &results.route_loads[route];
+            (load.capacity, load.cars, load.peak_load_factor)
+        };
+        assert_eq!(load("long"), (8, Some(4), Some(0.875)));
+        // half the cars fill up
+        assert_eq!(load("short"), (4, Some(2), Some(1.0)));
+        assert_eq!(load("plain"), (10, None, Some(0.6)));
+        // the same 20 riders over the hour's 12 trains, against each one's capacity
+        let average = |route: &str| results.route_loads[route].average_load_factor.unwrap();
+        assert_eq!(average("long"), 20.0 / 8.0 / 12.0);
+        assert_eq!(average("short"), 20.0 / 4.0 / 12.0);
+
+        let (js_graph, js_routes) = network(&json!({
+            "nodes": ["a", "b"],
+            "edges": [{ "id": "a-b" }],
+            "routes": { "r": { "nodes": ["a", "b"], "cars": 0 } },
+        }));
+        let (graph, map, _) = js_graph_to_subway_map(js_graph).unwrap();
+        assert!(js_routes_to_routes(js_routes, &graph, &map).is_err());
+    }
+
+    #[test]
+    fn breakdown_delays_go_by_the_route_of_depot_trains() {
+        let results = run(json!({
+            "nodes": ["d", "a", "b", "c"],
+            "edges": [
+                { "id": "d-a" },
+                { "id": "a-b", "weight": 6 },
+                { "id": "b-c" },
+                { "id": "c-d" },
+            ],
+            "routes": { "r": { "nodes": ["a", "b", "c"], "depot": "d" } },
+            "frequency": 3,
+            "breakdowns": [{ "edge": "a-b", "route": "r", "start": 10.0, "duration": 10.0 }],
+        }));
+        assert_eq!(results.incidents.len(), 1);
+        let delays = &results.incidents[0].route_delays;
+        assert_eq!(delays.keys().collect::<Vec<_>>(), ["r"]);
+        assert!(delays["r"] > 0.0);
+        assert_eq!(results.knock_on_delays, *delays);
+    }
+
+    #[test]
+    fn planned_breakdowns_need_the_stepping_engine() {
+        let (js_graph, js_routes) = network(&json!({
+            "nodes": ["a", "b"],
+            "edges": [{ "id": "a-b" }],
+            "routes": { "r": { "nodes": ["a", "b"] } },
+        }));
+        let (graph, map, petgraph_map) = js_graph_to_subway_map(js_graph).unwrap();
+        let (routes, route_id_map) = js_routes_to_routes(js_routes, &graph, &map).unwrap();
+        let engine = |engine: &str| {
+            let config = serde_json::from_value(json!({ "engine": engine })).unwrap();
+            let mut simulator = Simulator::new(graph.clone(), routes.clone(), config);
+            let breakdowns = json!([{ "edge": "a-b", "start": 10.0, "duration": 5.0 }]);
+            let breakdowns = serde_json::from_value(breakdowns).unwrap();
+            let plan = js_breakdowns_to_plan(breakdowns, &petgraph_map, &route_id_map).unwrap();
+            simulator.set_breakdowns(plan);
+            simulator.check_engine()
+        };
+        assert!(engine("time_step").is_ok());
+        assert!(engine("events").is_err());
     }
 }
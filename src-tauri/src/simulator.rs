@@ -1,21 +1,30 @@
 use std::cell::Cell;
 use std::cmp::min;
 use std::collections::{HashMap, HashSet, VecDeque};
-use std::hash::Hash;
-
-use petgraph::graph::{EdgeIndex, NodeIndex};
-use petgraph::visit::EdgeRef;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use petgraph::algo::astar;
+use petgraph::graph::{EdgeIndex, GraphIndex, NodeIndex};
+use petgraph::visit::{EdgeFiltered, EdgeRef};
 use petgraph::Direction;
 use petgraph::Graph;
 use rand::rngs::StdRng;
 use rand::prelude::SliceRandom;
-use rand::SeedableRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
 use z3::ast::Ast;
 
+use crate::error::{Diagnostic, SimError};
 use crate::shortest_path::{dijkstra, Terminated};
-use crate::{Edge, EdgeType};
+use crate::{Edge, EdgeType, Node};
+
+mod events;
 
-pub type SubwayMap = Graph<String, Edge>;
+pub type SubwayMap = Graph<Node, Edge>;
 pub type StationId = NodeIndex<u32>;
 pub type TrackId = EdgeIndex;
 
@@ -42,13 +51,112 @@ pub struct Train {
     pub distance_travelled: f64,
     /// The current route the train is on
     pub route: RouteId,
+    /// Passengers currently on board
+    pub passengers: Vec<Passenger>,
+    /// Distance covered per step on the current track, varied by travel time noise
+    pub speed: f64,
+    /// How far the train reaches back from its position
+    pub length: f64,
+}
+
+impl Train {
+    /// Position of the back of the train, negative while it's still leaving a station
+    fn tail(&self) -> f64 {
+        self.pos - self.length
+    }
+}
+
+/// One ride of a passenger's journey, boarding any of a set of routes
+#[derive(Debug, Clone)]
+pub struct Leg {
+    pub routes: HashSet<RouteId>,
+    pub board: StationId,
+    pub alight: StationId,
+}
+
+#[derive(Debug, Clone)]
+pub struct Passenger {
+    /// When the passenger arrives at their origin
+    pub arrival_time: f64,
+    /// When the passenger started waiting for their current leg
+    pub waiting_since: f64,
+    /// The remaining legs of their journey, starting with the current one
+    pub legs: VecDeque<Leg>,
+    /// Everything that happens to the passenger, if they are traced
+    pub journey: Option<Box<Journey>>,
+    /// Route the passenger got off to change trains at the station they're at, and when
+    pub transferring: Option<(RouteId, f64)>,
+    /// Paths to pick between by crowding when they arrive, quickest first, for passengers who
+    /// have more than one and SimulationConfig::crowding is set
+    pub choices: Option<Arc<Vec<RouteChoice>>>,
+}
+
+/// One of the paths a passenger can take, see SimulationConfig::crowding
+#[derive(Debug)]
+pub struct RouteChoice {
+    pub legs: VecDeque<Leg>,
+    /// Minutes riding and walking it, as the router costs it
+    pub cost: f64,
+    /// Minutes riding each leg
+    pub ride_times: Vec<f64>,
+}
+
+/// Passengers to trace in detail, see Simulator::trace_journeys
+#[derive(Debug, Clone, Default)]
+pub struct JourneySampling {
+    /// Number of passengers picked at random
+    pub sample: usize,
+    /// Origin and destination pairs all of whose passengers are traced
+    pub od_pairs: Vec<(StationId, StationId)>,
+}
+
+#[derive(Debug, Clone)]
+pub enum JourneyEvent {
+    Board {
+        station: StationId,
+        train: TrainId,
+        time: f64,
+        /// Time spent on the platform before boarding
+        waited: f64,
+    },
+    Alight {
+        station: StationId,
+        train: TrainId,
+        time: f64,
+    },
+    /// Walked to another station to board the next leg there
+    Walk {
+        from: StationId,
+        to: StationId,
+        time: f64,
+    },
+    /// Put off a train taken out of service, to wait for another
+    Stranded {
+        station: StationId,
+        train: TrainId,
+        time: f64,
+    },
+}
+
+/// The trace of one passenger's trip
+#[derive(Debug, Clone)]
+pub struct Journey {
+    pub origin: StationId,
+    pub destination: StationId,
+    pub arrival_time: f64,
+    pub events: Vec<JourneyEvent>,
+    /// When they reached their destination, None if they were still travelling at the end
+    pub completed_at: Option<f64>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Track {
     pub id: TrackId,
     pub length: u16,
-    pub trains: VecDeque<TrainId>,
+    /// Trains on each of the edge's parallel tracks, front first
+    pub lanes: Vec<VecDeque<TrainId>>,
+    /// Length of the signal blocks the track is divided into, each holding at most one train
+    pub block_length: f64,
 }
 
 #[derive(Debug, Clone)]
@@ -56,6 +164,85 @@ pub struct Station {
     pub id: StationId,
     pub train: Option<TrainId>,
     pub arrival_times: HashMap<RouteId, Vec<f64>>,
+    /// How long trains wait here before departing
+    pub dwell_time: f64,
+    /// Passengers waiting to board, in order of arrival
+    pub waiting: VecDeque<Passenger>,
+    /// How long each passenger who boarded here waited
+    pub passenger_waits: Vec<f64>,
+    /// When each of them boarded, in the same order
+    pub boarded_at: Vec<f64>,
+    /// Most passengers who can wait here comfortably, if the station declares it
+    pub waiting_capacity: Option<usize>,
+    /// Whether trains running through can pass the one at the platform. Only the stepping
+    /// engine and scheduling model the bypass, the events engine rejects runs with one.
+    pub has_bypass: bool,
+    /// Train running through on the bypass track
+    pub bypass: Option<TrainId>,
+}
+
+/// An express train passing a train stopped at a station on its bypass track
+#[derive(Debug, Clone, Copy)]
+pub struct Overtake {
+    pub station: StationId,
+    pub train: TrainId,
+    pub overtaken: TrainId,
+    pub time: f64,
+}
+
+/// A station a train running late ran through without stopping, see
+/// SimulationConfig::auto_express
+#[derive(Debug, Clone, Copy)]
+pub struct SkippedStop {
+    pub station: StationId,
+    pub train: TrainId,
+    pub time: f64,
+    /// How late the train was due there when it was told to skip it
+    pub lateness: f64,
+    /// Riders waiting there for the train's route as it went through
+    pub passed_by: usize,
+}
+
+/// A stretch of time more passengers waited at a station than it comfortably holds
+#[derive(Debug, Clone)]
+pub struct CrowdingAlert {
+    pub station: StationId,
+    pub start: f64,
+    pub end: f64,
+    /// Most passengers waiting at once during the alert
+    pub peak_waiting: usize,
+}
+
+/// A train of a route reaching a station too soon after the route's previous one, see
+/// SimulationConfig::bunching_threshold
+#[derive(Debug, Clone, Copy)]
+pub struct Bunching {
+    pub route: RouteId,
+    pub station: StationId,
+    pub time: f64,
+    /// Time since the previous train of the route got there
+    pub headway: f64,
+}
+
+/// How trains of routes sharing a trunk arrived at the station where they branch off
+#[derive(Debug, Clone)]
+pub struct BranchHeadways {
+    pub station: StationId,
+    /// Arrivals of the trunk's routes in order, the pattern the trunk hands to the branches
+    pub pattern: Vec<(f64, RouteId)>,
+    /// Gaps between each route's own trains, the headway riders of its branch get
+    pub branch_headways: HashMap<RouteId, Vec<f64>>,
+    pub irregular: Vec<IrregularAlternation>,
+}
+
+/// A route's trains arriving more times in a row than its share of the trunk explains, e.g. two
+/// A's then a long gap before a C when they run equally often
+#[derive(Debug, Clone, Copy)]
+pub struct IrregularAlternation {
+    /// Arrival of the first train of the run
+    pub time: f64,
+    pub route: RouteId,
+    pub repeats: usize,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
@@ -73,6 +260,141 @@ pub struct Route {
     pub start_station: StationId,
     pub station_to: HashMap<StationId, TrackId>,
     pub offset: u64,
+    /// Shorten dwells at closely spaced stations, see SimulationConfig::short_stop_threshold
+    pub combine_short_stops: bool,
+    /// Trains of the route run empty to reposition, and are hidden from riders
+    pub deadhead: bool,
+    /// Stations on the route's path its trains run through without stopping
+    pub pass_through: HashSet<StationId>,
+    /// Route trains re-enter service on after laying over at the end of this one, instead of
+    /// leaving service. Trains only turn around at the route's terminal, see route_terminal.
+    pub turnaround: Option<RouteId>,
+    /// Depot trains pull out of before entering service and pull back into at the end of it.
+    /// Only run dispatches from it, scheduling still dispatches at the start station.
+    pub depot: Option<StationId>,
+    /// Route a depot move was generated for
+    pub parent: Option<RouteId>,
+    /// Whether trains are dispatched onto the route, rather than only arriving by turnaround
+    pub dispatched: bool,
+    /// Length of the trains dispatched onto the route, which keep it through turnarounds
+    pub train_length: f64,
+    /// Cars in each of the route's trains, which then take their capacity and length from the
+    /// cars of their rolling stock, see RollingStock::car_capacity
+    pub cars: Option<u32>,
+    /// Name of the route's class in SimulationConfig::rolling_stock, unknown names and None
+    /// getting the default stock
+    pub rolling_stock: Option<String>,
+    /// Route this one runs part of, taking its turn at the parent's departures instead of
+    /// being dispatched on its own. Only run interleaves them, scheduling keeps them separate.
+    pub variant_of: Option<RouteId>,
+    /// Measured minutes to transfer from this route to another at a station, instead of the
+    /// usual minute's walk between platforms
+    pub transfer_times: HashMap<(StationId, RouteId), f64>,
+    /// Steps the route dispatches trains at, instead of going by the headways it's run at
+    pub timetable: Option<Vec<i32>>,
+    /// Spare trains kept along the route, put into service when its trains leave a gap
+    pub standby: Option<Standby>,
+    /// Express service laid over another route, which the optimizer starts without trains and
+    /// only runs where it helps
+    pub overlay: bool,
+    /// Running time supplement as a fraction of the quickest run, trains ahead of the padded
+    /// schedule waiting at stops and late ones catching up by running at full speed
+    pub padding: f64,
+    /// Most trains the route has out at once, counting its branches and depot moves. Departures
+    /// wait for one of them to leave service once it has that many.
+    pub max_trains: Option<usize>,
+}
+
+/// Padding of a route's stops and the part of it its trains ran into, not having to wait it
+/// away at the stop
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PaddingUse {
+    pub scheduled: f64,
+    pub used: f64,
+}
+
+/// Trains stored at a station of a route, ready to fill in for late or missing ones
+#[derive(Debug, Clone, Copy)]
+pub struct Standby {
+    pub station: StationId,
+    pub trains: u32,
+    /// Minutes since the route's last train got to the station before one is sent out
+    pub gap: f64,
+}
+
+/// A standby train put into service, see Route::standby
+#[derive(Debug, Clone, Copy)]
+pub struct GapTrain {
+    pub train: TrainId,
+    pub station: StationId,
+    pub time: f64,
+    /// Time since the route's previous train got to the station
+    pub gap: f64,
+}
+
+/// A departure held back because the route or the system had all the trains it's allowed out,
+/// see Route::max_trains
+#[derive(Debug, Clone, Copy)]
+pub struct FleetWait {
+    pub route: RouteId,
+    /// When the train was due to leave
+    pub scheduled: f64,
+    /// When it left, None if it never did
+    pub dispatched: Option<f64>,
+}
+
+/// Last station of a route, where its trains turn around or leave service. A loop's is the
+/// station before the one it closes on.
+pub fn route_terminal(subway_map: &SubwayMap, route: &Route) -> StationId {
+    let mut at = route.start_station;
+    let mut visited = HashSet::new();
+    while visited.insert(at) {
+        match route.station_to.get(&at) {
+            Some(track) if !visited.contains(&subway_map.edge_endpoints(*track).unwrap().1) => {
+                at = subway_map.edge_endpoints(*track).unwrap().1;
+            }
+            _ => break,
+        }
+    }
+    at
+}
+
+/// Station a loop route's path comes back around to, where its trains start each lap. None for
+/// a route which runs out to a terminal.
+pub fn loop_station(subway_map: &SubwayMap, route: &Route) -> Option<StationId> {
+    let track = route.station_to.get(&route_terminal(subway_map, route))?;
+    Some(subway_map.edge_endpoints(*track).unwrap().1)
+}
+
+/// Trains out at a whole time unit, by the route they're serving then
+#[derive(Debug, Clone)]
+pub struct InService {
+    pub time: f64,
+    pub by_route: HashMap<RouteId, usize>,
+}
+
+/// Summary of a run so far and where its trains are, sent while it's running so what was
+/// simulated isn't lost if it never finishes
+pub struct Checkpoint {
+    pub time: f64,
+    pub passengers_delivered: usize,
+    pub average_journey_time: Option<f64>,
+    pub passengers_waiting: usize,
+    pub trains_in_service: usize,
+    /// Arrivals at each station since the previous checkpoint, by route
+    pub arrivals: HashMap<StationId, HashMap<RouteId, Vec<f64>>>,
+    pub positions: TrainPositions,
+}
+
+/// One stint of a physical train in service on a route, from being dispatched or turned onto
+/// it until it turned onto another route or left service
+#[derive(Debug, Clone, Copy)]
+pub struct TrainTrip {
+    pub train: TrainId,
+    pub route: RouteId,
+    pub start: f64,
+    /// None if the train was still on the route when the run ended
+    pub end: Option<f64>,
 }
 
 #[derive(Debug, Clone)]
@@ -84,372 +406,4118 @@ pub struct Simulator {
     stations: HashMap<StationId, Station>,
     tracks: HashMap<TrackId, Track>,
     traversal_order: Vec<TrackStationId>,
+    /// dwell times that differ from the station's own for a route at a station
+    dwell_times: HashMap<(RouteId, StationId), f64>,
+    /// Variants of each route with any, in the order they take departures after it
+    variants: HashMap<RouteId, Vec<RouteId>>,
+    rolling_stock: HashMap<RouteId, RollingStock>,
+    config: SimulationConfig,
+    /// Passengers to release into stations, sorted by arrival time. Shared so that the
+    /// snapshots taken while scheduling don't copy it.
+    demand: Arc<Vec<Passenger>>,
+    next_passenger: usize,
+    /// Time from arriving at their origin to reaching their destination of each passenger
+    journey_times: Vec<f64>,
+    /// When each of them arrived at their origin, in the same order
+    journey_starts: Vec<f64>,
+    /// Windows of the profile's shutdown periods, see HeadwayPeriod::shutdown
+    shutdowns: Vec<(f64, f64)>,
+    /// When riders turned away by a shutdown showed up, once recording started
+    turned_away: Vec<f64>,
+    /// Traced passengers who finished their trip
+    journeys: Vec<Journey>,
+    crowding_alerts: Vec<CrowdingAlert>,
+    /// Alerts of stations which are still crowded
+    open_crowding_alerts: HashMap<StationId, CrowdingAlert>,
+    /// Source of travel time noise, only seeded by run so scheduling stays deterministic
+    noise_rng: Option<StdRng>,
+    /// Source of incidents, seeded by run like noise_rng
+    incident_rng: Option<StdRng>,
+    incidents: Vec<Incident>,
+    /// Broken down trains, by index into incidents
+    stalled: HashMap<TrainId, usize>,
+    /// Incident each train held up this step is waiting on, directly or behind another train
+    incident_blocks: HashMap<TrainId, usize>,
+    breakdowns: Vec<Breakdown>,
+    /// Broken down trains which won't recover, with the fraction of their speed they're pushed
+    /// off at once the rescue train gets to them
+    rescues: HashMap<TrainId, f64>,
+    /// Trains being pushed out of service to the end of their route, with their incident and the
+    /// fraction of their speed they go at
+    pushed: HashMap<TrainId, (usize, f64)>,
+    operating_distances: HashMap<RouteId, OperatingDistance>,
+    energy: HashMap<RouteId, Vec<f64>>,
+    boardings: HashMap<RouteId, usize>,
+    /// Share of its capacity each train left each stop with once recording started, by route
+    load_factors: HashMap<RouteId, Vec<f64>>,
+    bunching: Vec<Bunching>,
+    /// When trains dispatched to a timetable are due at each stop, with the route timetabled
+    timetabled: HashMap<(TrainId, StationId), (RouteId, f64)>,
+    /// When trains of padded routes are scheduled to leave each of their next stops, and the
+    /// padding of the run there
+    padded: HashMap<(TrainId, StationId), (f64, f64)>,
+    padding_use: HashMap<RouteId, PaddingUse>,
+    schedule_deviations: HashMap<(RouteId, StationId), Vec<f64>>,
+    recorder: FlightRecorder,
+    disruptions: DisruptionPlan,
+    reroutes: ReroutePlan,
+    /// Time each train has spent held up by closed or slowed track
+    disruption_delays: HashMap<TrainId, f64>,
+    /// Trains taken out of service before a closure, and the station they were at
+    short_turns: Vec<(TrainId, StationId)>,
+    /// Extra dwell the holding strategy, or a dispatcher, gave each train at the station it's
+    /// stopped at
+    holds: HashMap<(TrainId, StationId), f64>,
+    /// Routes a dispatcher ran an extra train on which haven't been dispatched yet
+    extra_departures: Vec<RouteId>,
+    /// Trains a dispatcher cancelled, taken out of service at the station they're at or next
+    /// get to
+    cancellations: HashSet<TrainId>,
+    gap_trains: Vec<GapTrain>,
+    /// Departures waiting for a train to be free, one a route, with where they're noted in
+    /// fleet_waits if they were due once recording started
+    fleet_queue: Vec<(RouteId, Option<usize>)>,
+    fleet_waits: Vec<FleetWait>,
+    /// When each route's last train left each station, for headway based holding
+    last_departures: HashMap<(RouteId, StationId), f64>,
+    hold_times: Vec<f64>,
+    /// Longest hold at each timed transfer station
+    timed_transfers: HashMap<StationId, f64>,
+    station_closures: Vec<StationClosure>,
+    /// What each of station_closures did, in the same order
+    closure_effects: Vec<ClosureEffect>,
+    /// Feeders each held train is waiting on, and the stations they're due at
+    transfer_waits: HashMap<(TrainId, StationId), Vec<(TrainId, StationId)>>,
+    coordinated_transfers: Vec<CoordinatedTransfer>,
+    overtakes: Vec<Overtake>,
+    /// When trains dispatched are due at each of their stops, for telling how late they run
+    express_due: HashMap<(TrainId, StationId), f64>,
+    /// Stops trains have been told to skip, with how late they were due there
+    skipping: HashMap<(TrainId, StationId), f64>,
+    skipped_stops: Vec<SkippedStop>,
+    /// Share of its capacity the last train of each route left each station with
+    departure_loads: HashMap<(RouteId, StationId), f64>,
+    crowding_skips: usize,
+    crowding_detours: usize,
+    /// Minutes each station's bypass track was occupied once recording started
+    bypass_use: HashMap<StationId, f64>,
+    checkpoints: Option<Sender<Checkpoint>>,
+    /// Route trains continue on from the end of each route at its terminal, see Route::turnaround
+    terminal_links: HashMap<(RouteId, StationId), RouteId>,
+    /// Station each loop route's trains start their laps at
+    loop_stations: HashMap<RouteId, StationId>,
+    /// Quickest walk between stations linked by walk edges, taken by riders whose next leg
+    /// starts at another station
+    walk_times: HashMap<(StationId, StationId), f64>,
+    /// Trains which left the start of their loop, held there once back until their route's
+    /// next departure sends them round again instead of a new train
+    circling: HashSet<TrainId>,
+    train_trips: Vec<TrainTrip>,
+    /// Index into train_trips of each train's current trip
+    open_trips: HashMap<TrainId, usize>,
+    /// When each train waiting at the end of a track for its station got there
+    merge_waits: HashMap<TrainId, f64>,
+    /// Track the last train to enter each station came in on, for alternating merges
+    merge_from: HashMap<StationId, TrackId>,
+    /// Time of the current step
+    current_time: f64,
+    /// Minutes per step, see SimulationConfig::time_step
+    time_step: f64,
+    steps_per_unit: i32,
+    /// Most trains in service at once
+    peak_trains: usize,
+    in_service: Vec<InService>,
 }
 
-pub struct TrainPositions {
-    pub time: u32,
-    pub trains: Vec<TrainPosition>,
+/// A train breaking down on a track, the one furthest along it of those of route, or any route
+/// if None, out on it once start comes. It stands there for duration, blocking the track behind
+/// it, then runs on if it recovers. Otherwise a rescue train pushes it out of service to the end
+/// of its route at push_speed, running through the stops on the way after putting its riders
+/// down at the first. Only the stepping engine has breakdowns.
+#[derive(Debug, Clone, Copy)]
+pub struct Breakdown {
+    pub route: Option<RouteId>,
+    pub track: TrackId,
+    pub start: f64,
+    pub duration: f64,
+    pub recovers: bool,
+    /// Fraction of its usual speed the train keeps while pushed
+    pub push_speed: f64,
 }
 
-pub struct TrainPosition {
-    pub id: TrainId,
-    pub curr_section: TrackStationId,
-    pub pos: f64,
-    pub distance_travelled: f64,
+/// A track closed to trains over [start, end), or only slowing them down like track work
+#[derive(Debug, Clone, Copy)]
+pub struct Disruption {
+    pub track: TrackId,
+    pub start: f64,
+    pub end: f64,
+    /// Fraction of their usual speed trains keep over the track, 0 closing it
+    pub speed: f64,
+    /// Parallel tracks left open to trains going onto it, all of them if None
+    pub tracks: Option<u8>,
 }
 
-pub struct StationStatistic {
-    pub arrival_times: HashMap<RouteId, Vec<f64>>,
-}
+pub type DisruptionPlan = Vec<Disruption>;
 
-pub struct SimulationResults {
-    pub train_positions: Vec<TrainPositions>,
-    pub train_to_route: HashMap<TrainId, RouteId>,
-    pub station_statistics: HashMap<StationId, StationStatistic>,
+/// A station whose trains wait up to max_hold minutes for riders off feeder trains due at the
+/// stations a walk away
+#[derive(Debug, Clone, Copy)]
+pub struct TimedTransfer {
+    pub station: StationId,
+    pub max_hold: f64,
 }
 
-const STATION_DWELL_TIME: f64 = 0.5;
-const MIN_TRAIN_DISTANCE: f64 = 2.0;
-const TIME_STEP: f64 = 1.0;
+/// A station shut over [start, end). Trains run through without stopping, except at the ends of
+/// their routes, and riders waiting there walk to the nearest open station they can still board
+/// one of their routes at.
+#[derive(Debug, Clone, Copy)]
+pub struct StationClosure {
+    pub station: StationId,
+    pub start: f64,
+    pub end: f64,
+}
 
-fn f64_min(a: f64, b: f64) -> f64 {
-    a.min(b)
+/// What a station closure did once recording started
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClosureEffect {
+    pub trains_run_through: usize,
+    /// Riders who walked to another station to board
+    pub riders_diverted: usize,
+    /// Riders getting off at the station who were carried on to the next stop
+    pub riders_carried_past: usize,
 }
 
-fn f64_max(a: f64, b: f64) -> f64 {
-    a.max(b)
+/// A train held for a feeder at a timed transfer, and whether the feeder made it before the
+/// train left
+#[derive(Debug, Clone, Copy)]
+pub struct CoordinatedTransfer {
+    pub station: StationId,
+    pub train: TrainId,
+    pub feeder: TrainId,
+    /// When the held train left
+    pub time: f64,
+    pub made: bool,
 }
 
-impl Simulator {
-    pub fn new(subway_map: SubwayMap, routes: Vec<Route>) -> Self {
-        let mut stations = HashMap::with_capacity(subway_map.node_count());
-        let mut tracks = HashMap::with_capacity(subway_map.edge_count());
+/// A route following a different path over [start, end), like running via the express track
+/// during construction
+#[derive(Debug, Clone)]
+pub struct Reroute {
+    pub route: RouteId,
+    pub start: f64,
+    pub end: f64,
+    /// Tracks taken out of the stations the detour covers, the route's own apply at the rest
+    pub station_to: HashMap<StationId, TrackId>,
+}
 
-        for node in subway_map.node_indices() {
-            stations.insert(
-                node,
-                Station {
-                    id: node,
-                    train: None,
-                    arrival_times: HashMap::new(),
-                },
-            );
-        }
+pub type ReroutePlan = Vec<Reroute>;
+
+// Track the route with the given id takes out of a station at time, following any reroute then
+fn route_track(
+    route: &Route,
+    id: RouteId,
+    reroutes: &[Reroute],
+    station: StationId,
+    time: f64,
+) -> Option<TrackId> {
+    reroutes
+        .iter()
+        .filter(|reroute| reroute.route == id && reroute.start <= time && time < reroute.end)
+        .find_map(|reroute| reroute.station_to.get(&station))
+        .or_else(|| route.station_to.get(&station))
+        .copied()
+}
 
-        for edge in subway_map.edge_references() {
-            tracks.insert(
-                edge.id(),
-                Track {
-                    id: edge.id(),
-                    length: edge.weight().weight,
-                    trains: VecDeque::new(),
-                },
-            );
-        }
+// Index of the closure shutting a station at time, if it's shut
+fn closure_at(closures: &[StationClosure], station: StationId, time: f64) -> Option<usize> {
+    closures.iter().position(|closure| {
+        closure.station == station && closure.start <= time && time < closure.end
+    })
+}
 
-        let terminal_nodes = terminal_nodes(&subway_map);
-        let mut queue: VecDeque<TrackStationId> = terminal_nodes
-            .into_iter()
-            .map(TrackStationId::Station)
-            .collect();
-        let mut traversal_order: Vec<TrackStationId> = Vec::new();
-        let mut visited = HashSet::new();
+// Whether a route's trains run through a station for it being closed at time. They still stop
+// at the end of the route, having nowhere to go on to.
+fn closed_through(
+    closures: &[StationClosure],
+    route: &Route,
+    id: RouteId,
+    reroutes: &[Reroute],
+    station: StationId,
+    time: f64,
+) -> bool {
+    closure_at(closures, station, time).is_some()
+        && route_track(route, id, reroutes, station, time).is_some()
+}
 
-        let mut all_route_edges = HashSet::new();
+/// Stretches of time over which the same reroutes are in effect, leaving out those without any
+pub fn reroute_windows(reroutes: &[Reroute]) -> Vec<(f64, f64)> {
+    let mut bounds: Vec<_> = reroutes
+        .iter()
+        .flat_map(|reroute| [reroute.start, reroute.end])
+        .collect();
+    bounds.sort_by(f64::total_cmp);
+    bounds.dedup();
+    bounds
+        .windows(2)
+        .map(|bounds| (bounds[0], bounds[1]))
+        .filter(|(start, _)| {
+            reroutes
+                .iter()
+                .any(|reroute| reroute.start <= *start && *start < reroute.end)
+        })
+        .collect()
+}
 
-        for route in &routes {
-            for edge in route.station_to.values() {
-                all_route_edges.insert(*edge);
+/// The routes as they run at time, following their reroutes then from the start station on.
+/// Riders starting their trip during a reroute are routed over these.
+pub fn rerouted(
+    subway_map: &SubwayMap,
+    routes: &[Route],
+    reroutes: &[Reroute],
+    time: f64,
+) -> Vec<Route> {
+    routes
+        .iter()
+        .enumerate()
+        .map(|(i, route)| {
+            let mut station_to = HashMap::new();
+            let mut station = route.start_station;
+            while let Some(track) = route_track(route, RouteId(i as u32), reroutes, station, time) {
+                if station_to.insert(station, track).is_some() {
+                    break;
+                }
+                station = subway_map.edge_endpoints(track).unwrap().1;
             }
-        }
+            Route {
+                station_to,
+                ..route.clone()
+            }
+        })
+        .collect()
+}
 
-        'bfs: while let Some(track_station) = queue.pop_front() {
-            if visited.contains(&track_station) {
-                continue;
-            };
+/// Paths riders starting their trip over [start, end) take, on the network as rerouted then
+pub struct ReroutedPaths {
+    pub start: f64,
+    pub end: f64,
+    pub search_map: SearchMap,
+    pub shortest_paths: HashMap<(NodeIndex, NodeIndex), Vec<Vec<PathSegment>>>,
+}
 
-            // If we get to a station where some of the edges it feeds into haven't been processed yet,
-            // skip processing now, with the assumption that we'll be returning later. Note that this
-            // requires all meaningful tracks to be assigned to a route.
-            if let TrackStationId::Station(station) = track_station {
-                for edge in subway_map
-                    .edges_directed(station, Direction::Outgoing)
-                    .filter(|e| all_route_edges.contains(&e.id()))
-                {
-                    if !visited.contains(&TrackStationId::Track(edge.id())) {
-                        continue 'bfs;
-                    }
-                }
+fn track_closed(disruptions: &[Disruption], track: TrackId, time: f64) -> bool {
+    disruptions.iter().any(|disruption| {
+        disruption.track == track
+            && disruption.speed <= 0.0
+            && disruption.start <= time
+            && time < disruption.end
+    })
+}
+
+// Fraction of their usual speed trains keep over the track at time, the slowest of the slow
+// zones in effect
+fn speed_factor(disruptions: &[Disruption], track: TrackId, time: f64) -> f64 {
+    disruptions
+        .iter()
+        .filter(|disruption| {
+            disruption.track == track && disruption.start <= time && time < disruption.end
+        })
+        .map(|disruption| disruption.speed)
+        .fold(1.0, f64_min)
+}
+
+// Parallel tracks of the track trains may go onto at time, the fewest any disruption in effect
+// leaves open. None if they all are.
+fn open_tracks(disruptions: &[Disruption], track: TrackId, time: f64) -> Option<usize> {
+    disruptions
+        .iter()
+        .filter(|disruption| {
+            disruption.track == track && disruption.start <= time && time < disruption.end
+        })
+        .filter_map(|disruption| disruption.tracks)
+        .min()
+        .map(usize::from)
+}
+
+/// What trains do when the next track on their route is closed
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisruptionResponse {
+    /// Wait at the station until the track reopens
+    #[default]
+    Hold,
+    /// Leave service at the station if it has a crossover, otherwise wait like Hold. Turning
+    /// back isn't modeled, the train just disappears.
+    ShortTurn,
+}
+
+/// How dispatchers hold trains ready to leave a station, to even out headways
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Holding {
+    /// Trains leave as soon as their dwell is up
+    #[default]
+    None,
+    /// Hold a train while the next one on its route is more than threshold minutes behind,
+    /// letting it catch up, for at most max_hold minutes
+    GapBehind { threshold: f64, max_hold: f64 },
+    /// Hold a train until the one ahead on its route left at least min_headway minutes before,
+    /// for at most max_hold minutes
+    HeadwayAhead { min_headway: f64, max_hold: f64 },
+}
+
+/// Which train goes first when trains on different tracks converge on the same station
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergePriority {
+    /// The train which got to the junction first
+    #[default]
+    FirstCome,
+    /// Trains take turns between the tracks, the one not used last going first
+    Alternate,
+    /// Routes earlier in the list go first, routes missing from it last, and trains of the same
+    /// priority in the order they got there
+    RoutePriority(Vec<String>),
+}
+
+/// Route headways for a stretch of a run, starting at start and lasting until the next period
+#[derive(Debug, Clone)]
+pub struct HeadwayPeriod {
+    pub start: i32,
+    /// What to call it in reports, like am peak
+    pub name: Option<String>,
+    /// Steps between departures of each route, routes missing from it don't run
+    pub headways: HashMap<RouteId, u64>,
+    /// Whether the system is shut, like overnight. Nothing is dispatched, whatever the
+    /// headways and timetables, and riders showing up are turned away. Trains out finish their
+    /// trips.
+    pub shutdown: bool,
+}
+
+/// How dispatching changes over a run, like peaks and the midday lull of a service day
+#[derive(Debug, Clone)]
+pub struct HeadwayProfile {
+    /// Periods by start time. The first also covers the steps before it, including warm up.
+    pub periods: Vec<HeadwayPeriod>,
+}
+
+impl HeadwayProfile {
+    pub fn constant(routes: impl Iterator<Item = RouteId>, headway: u64) -> Self {
+        HeadwayProfile {
+            periods: vec![HeadwayPeriod {
+                start: 0,
+                name: None,
+                headways: routes.map(|route| (route, headway)).collect(),
+                shutdown: false,
+            }],
+        }
+    }
+
+    /// Whether a route dispatches a train at step t. Departures are counted from the start of
+    /// each period, shifted by the route's offset.
+    fn departs(&self, route: RouteId, offset: u64, t: i32) -> bool {
+        let Some(period) = self
+            .periods
+            .iter()
+            .rev()
+            .find(|period| period.start <= t)
+            .or(self.periods.first())
+        else {
+            return false;
+        };
+        match period.headways.get(&route) {
+            Some(&headway) if headway > 0 && !period.shutdown => {
+                (t - period.start - offset as i32) % headway as i32 == 0
             }
+            _ => false,
+        }
+    }
 
-            visited.insert(track_station);
-            traversal_order.push(track_station);
-            match track_station {
-                TrackStationId::Track(track) => {
-                    let (source, _) = subway_map.edge_endpoints(track).unwrap();
-                    queue.push_back(TrackStationId::Station(source));
+    /// Headway a route is run at over the steps before end, the headways of the periods it runs
+    /// in weighted by how long they're in force. None if it doesn't run.
+    pub fn nominal_headway(&self, route: RouteId, end: i32) -> Option<f64> {
+        let mut steps = 0.0;
+        let mut weighted = 0.0;
+        for (i, period) in self.periods.iter().enumerate() {
+            let start = if i == 0 { 0 } else { period.start.max(0) };
+            let stop = self
+                .periods
+                .get(i + 1)
+                .map_or(end, |next| next.start)
+                .min(end);
+            match period.headways.get(&route) {
+                Some(&headway) if headway > 0 && !period.shutdown && stop > start => {
+                    steps += (stop - start) as f64;
+                    weighted += (stop - start) as f64 * headway as f64;
                 }
-                TrackStationId::Station(station) => subway_map
-                    .edges_directed(station, Direction::Incoming)
-                    .filter(|edge| all_route_edges.contains(&edge.id()))
-                    .for_each(|track| queue.push_back(TrackStationId::Track(track.id()))),
+                _ => {}
             }
         }
+        (steps > 0.0).then(|| weighted / steps)
+    }
 
-        let routes: HashMap<_, _> = routes
-            .into_iter()
+    /// Times the shutdown periods cover, from their start to the next period's
+    pub fn shutdowns(&self) -> Vec<(f64, f64)> {
+        self.periods
+            .iter()
             .enumerate()
-            .map(|(i, route)| (RouteId(i as u32), route))
-            .collect();
+            .filter(|(_, period)| period.shutdown)
+            .map(|(i, period)| {
+                let start = match i {
+                    0 => f64::NEG_INFINITY,
+                    _ => period.start as f64,
+                };
+                let end = self
+                    .periods
+                    .get(i + 1)
+                    .map_or(f64::INFINITY, |next| next.start as f64);
+                (start, end)
+            })
+            .collect()
+    }
+}
 
-        Self {
-            subway_map,
-            curr_train_counts: vec![0; routes.len()],
-            routes,
-            trains: HashMap::new(),
-            stations,
-            tracks,
-            traversal_order,
+/// Distance run by a route's trains, split by whether they were in passenger service
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OperatingDistance {
+    pub revenue: f64,
+    /// Distance run empty by deadhead routes or over non-revenue track
+    pub deadhead: f64,
+}
+
+impl OperatingDistance {
+    fn add(&mut self, distance: f64, in_service: bool) {
+        if in_service {
+            self.revenue += distance;
+        } else {
+            self.deadhead += distance;
         }
     }
+}
 
-    fn reset(&mut self) {
-        self.trains.clear();
-        self.curr_train_counts = vec![0; self.routes.len()];
-        for station in self.stations.values_mut() {
-            station.arrival_times = HashMap::new();
-            station.train = None;
-        }
-        for track in self.tracks.values_mut() {
-            track.trains.clear();
+/// Which simulation steps the positions of trains are recorded at
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PositionRecording {
+    /// Record every n steps
+    EverySteps(u32),
+    /// Record every this many minutes, whatever the time step
+    Interval(f64),
+    /// Record only steps where a train arrives at a station or is dispatched from its first one,
+    /// not those where trains only leave stations
+    StationEvents,
+}
+
+impl PositionRecording {
+    fn records(self, step: u32, time: f64, station_event: bool) -> bool {
+        match self {
+            PositionRecording::EverySteps(n) => step.is_multiple_of(n.max(1)),
+            PositionRecording::Interval(interval) if interval > 0.0 => {
+                let intervals = time / interval;
+                (intervals - intervals.round()).abs() < 1e-6
+            }
+            PositionRecording::Interval(_) => true,
+            PositionRecording::StationEvents => station_event,
         }
     }
+}
 
-    fn station_to_track(&mut self, station: StationId, mut time_left: f64) {
-        if let Some(train) = &self.stations[&station].train {
-            let train = *train;
-            let train_mut = self.trains.get_mut(&train).unwrap();
-            let distance_travelled = f64_max(f64_min(STATION_DWELL_TIME - train_mut.pos, time_left), 0.0);
-            train_mut.pos += distance_travelled;
-            time_left -= distance_travelled;
+/// How long run simulates before statistics start, to fill the network with trains
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarmUp {
+    /// Simulate this many minutes first
+    Fixed(u32),
+    /// Simulate until the number of trains in service levels off, for at most max minutes
+    SteadyState { max: u32 },
+}
 
-            if train_mut.pos < STATION_DWELL_TIME {
-                return;
-            }
+/// What one unit of the simulation clock is. Every time given to a run, from track weights and
+/// headways to dwells and demand, is in it, and so is every time the run reports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeUnit {
+    #[default]
+    Minutes,
+    /// For networks like people movers, with headways and runs between stations under a minute
+    Seconds,
+}
 
-            let route_id = train_mut.route;
-            let next_track_id = self.routes[&route_id].station_to.get(&station);
-            let next_track_id = match next_track_id {
-                Some(next_track_id) => next_track_id,
-                None => {
-                    self.trains.remove(&train);
-                    self.stations.get_mut(&station).unwrap().train = None;
-                    return;
-                }
-            };
-            let next_track: &mut Track = self.tracks.get_mut(next_track_id).unwrap();
-            let last_train = next_track.trains.back();
-            if let Some(last_train) = last_train {
-                let last_train_pos = self.trains[last_train].pos;
-                // only move the train off the station if there's space on the next track
-                if last_train_pos >= MIN_TRAIN_DISTANCE {
-                    self.stations.get_mut(&station).unwrap().train = None;
-                    next_track.trains.push_back(train);
-                    let pos_move = f64_min(time_left, last_train_pos - MIN_TRAIN_DISTANCE).max(0.0);
-                    let train_mut = self.trains.get_mut(&train).unwrap();
-                    train_mut.pos = pos_move;
-                    train_mut.distance_travelled += STATION_DWELL_TIME;
-                    train_mut.curr_section = TrackStationId::Track(*next_track_id);
-                }
-            } else {
-                self.stations.get_mut(&station).unwrap().train = None;
-                train_mut.curr_section = TrackStationId::Track(*next_track_id);
-                self.trains.get_mut(&train).unwrap().pos = time_left;
-                next_track.trains.push_back(train);
-            }
+impl TimeUnit {
+    /// Units in a minute, scaling the defaults which are all sized in minutes
+    pub fn per_minute(self) -> f64 {
+        match self {
+            TimeUnit::Minutes => 1.0,
+            TimeUnit::Seconds => 60.0,
         }
     }
 
-    pub fn run(mut self, iterations: i32, frequency: u64) -> SimulationResults {
-        let mut train_to_route = HashMap::new();
-        let traversal_order = self.traversal_order.clone();
-        println!("{:?}", traversal_order);
-        for route in self.routes.values() {
-            println!("{:?}", route.start_station);
+    pub fn name(self) -> &'static str {
+        match self {
+            TimeUnit::Minutes => "minutes",
+            TimeUnit::Seconds => "seconds",
         }
+    }
+}
 
-        let mut train_positions = Vec::new();
+/// How run advances time
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Engine {
+    /// Move every train a TIME_STEP at a time
+    #[default]
+    TimeStep,
+    /// Jump from one train arrival or departure to the next, see simulator/events.rs
+    Events,
+}
 
-        let mut t = -120;
+/// Per run settings for the simulation. Statistics are always computed at full resolution,
+/// regardless of what is recorded for playback.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SimulationConfig {
+    pub position_recording: PositionRecording,
+    /// Tracks shorter than this reduce the dwell at the following station, for routes with
+    /// combine_short_stops set
+    pub short_stop_threshold: f64,
+    /// Most passengers a train can carry
+    pub train_capacity: usize,
+    /// Randomly vary how long trains take to traverse each track in run
+    pub travel_time_noise: Option<TravelTimeNoise>,
+    /// Randomly break trains down in run. Only the stepping engine has incidents.
+    pub incidents: Option<IncidentConfig>,
+    /// Estimate the traction energy trains use, results have none if missing
+    pub energy: Option<EnergyModel>,
+    /// How long trains wait at a terminal before turning around onto another route, LAYOVER_TIME
+    /// minutes if missing
+    pub layover_time: Option<f64>,
+    pub disruption_response: DisruptionResponse,
+    pub holding: Holding,
+    /// Let trains running late skip stops to catch up, never if missing
+    pub auto_express: Option<AutoExpress>,
+    pub merge_priority: MergePriority,
+    /// Most trains out at once over all routes, departures waiting for a free one past it. No
+    /// limit if missing.
+    pub max_trains: Option<usize>,
+    /// Rolling stock classes by name, which routes pick with Route::rolling_stock
+    pub rolling_stock: HashMap<String, RollingStock>,
+    /// Engine run uses. Scheduling always steps.
+    pub engine: Engine,
+    /// Time units simulated per step, rounded so a whole number of steps make up a unit. Trains
+    /// are still only dispatched on whole units.
+    pub time_step: f64,
+    /// Unit of every time in and out of the run, the minute unless set. Scheduling only works in
+    /// minutes.
+    pub time_unit: TimeUnit,
+    /// Warm up of run, WARM_UP_STEPS minutes if missing. Scheduling has none, see
+    /// OptimizeOptions::cyclic.
+    pub warm_up: Option<WarmUp>,
+    /// Minutes between the checkpoints run sends out while it goes, see Simulator::set_checkpoints
+    pub checkpoint_interval: Option<u32>,
+    /// Trains of a route arriving at a station within this long of the one before, in the
+    /// config's time unit, count as bunched. Nothing is logged if missing.
+    pub bunching_threshold: Option<f64>,
+    /// Let riders avoid crowded trains, passing them up and picking less crowded paths. They
+    /// take the first path to their destination and board any train with room if missing.
+    pub crowding: Option<CrowdingChoice>,
+}
 
-        while t < iterations {
-            for track_station in &traversal_order {
-                match *track_station {
-                    TrackStationId::Station(station) => {
-                        self.station_to_track(station, TIME_STEP);
-                    }
-                    TrackStationId::Track(track) => {
-                        let mut i = 0;
-                        let mut last_train_pos = f64::INFINITY;
-                        let next_station_id = self.subway_map.edge_endpoints(track).unwrap().1;
-                        while i < self.tracks.get_mut(&track).unwrap().trains.len() {
-                            let track_mut = self.tracks.get_mut(&track).unwrap();
-                            if self.stations[&next_station_id].train.is_some() {
-                                last_train_pos = f64_min(
-                                    f64_max(track_mut.length as f64 - MIN_TRAIN_DISTANCE, 0.0),
-                                    last_train_pos,
-                                );
-                            }
-                            let curr_train_id = track_mut.trains[i];
-                            let curr_train_mut = self.trains.get_mut(&curr_train_id).unwrap();
-                            let mut time_left = TIME_STEP;
-                            let travel_distance = f64_min(
-                                f64_min(
-                                    time_left,
-                                    f64_max(track_mut.length as f64 - curr_train_mut.pos, 0.0),
-                                ),
-                                f64_max(
-                                    if curr_train_mut.pos + MIN_TRAIN_DISTANCE >= last_train_pos {
-                                        last_train_pos - MIN_TRAIN_DISTANCE - curr_train_mut.pos
-                                    } else {
-                                        last_train_pos - curr_train_mut.pos
-                                    },
-                                    0.0,
-                                ),
-                            );
-                            curr_train_mut.pos += travel_distance;
-                            time_left -= travel_distance;
-                            // we're done with the current track, and need to move into the station
-                            if curr_train_mut.pos >= track_mut.length as f64
-                                && self.stations[&next_station_id].train.is_none()
-                            {
-                                debug_assert_eq!(i, 0);
-                                track_mut.trains.pop_front();
-                                debug_assert!(
-                                    self.stations
-                                        .get_mut(&next_station_id)
-                                        .unwrap()
-                                        .train
-                                        .is_none(),
-                                    "travel distance is {travel_distance}"
-                                );
-                                let next_station_mut =
-                                    self.stations.get_mut(&next_station_id).unwrap();
-                                next_station_mut.train = Some(curr_train_id);
-                                if t >= 0 {
-                                    next_station_mut
-                                        .arrival_times
-                                        .entry(curr_train_mut.route)
-                                        .or_default()
-                                        .push(t as f64 + travel_distance);
-                                }
+/// A class of trains, like older cars on some lines and new ones on others
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct RollingStock {
+    /// Multiplies how far trains get each step, the top speed they accelerate to
+    pub speed: f64,
+    /// Rate trains pick up speed leaving a stop, in length units per time unit squared. Without
+    /// one they're at speed straight away.
+    pub acceleration: Option<f64>,
+    /// Rate trains brake for a stop, in the same units as acceleration
+    pub deceleration: Option<f64>,
+    /// Added to the dwell at every station the route stops at
+    pub dwell_penalty: f64,
+    /// Most passengers a train can carry, SimulationConfig::train_capacity if missing
+    pub capacity: Option<usize>,
+    /// Passengers each car carries, trains of routes giving their cars carrying that many
+    /// times it. They carry capacity however many cars they have if missing.
+    pub car_capacity: Option<usize>,
+    /// Length of each car, making up the trains of routes giving their cars
+    pub car_length: f64,
+}
 
-                                curr_train_mut.distance_travelled +=
-                                    self.tracks[&track].length as f64;
+impl Default for RollingStock {
+    fn default() -> Self {
+        RollingStock {
+            speed: 1.0,
+            dwell_penalty: 0.0,
+            capacity: None,
+            car_capacity: None,
+            car_length: 0.0,
+            acceleration: None,
+            deceleration: None,
+        }
+    }
+}
+
+impl RollingStock {
+    /// Most passengers a train of the stock carries with the given cars, default_capacity
+    /// without a capacity of its own
+    pub fn train_capacity(&self, cars: Option<u32>, default_capacity: usize) -> usize {
+        match (cars, self.car_capacity) {
+            (Some(cars), Some(car_capacity)) => cars as usize * car_capacity,
+            _ => self.capacity.unwrap_or(default_capacity),
+        }
+    }
+
+    /// Time to run length with a top speed of speed, accelerating from rest first when starting
+    /// from a stop and braking to one at the end when stopping
+    pub fn run_time(&self, length: f64, speed: f64, starting: bool, stopping: bool) -> f64 {
+        let per_speed = |rate: Option<f64>, applies: bool| {
+            rate.filter(|rate| applies && *rate > 0.0)
+                .map_or(0.0, |rate| 1.0 / rate)
+        };
+        // time spent speeding up and slowing down for each unit of speed
+        let ramps = per_speed(self.acceleration, starting) + per_speed(self.deceleration, stopping);
+        // on a short run the train starts braking before it gets to top speed
+        let top = f64_min(speed, (2.0 * length / ramps).sqrt());
+        (length - top * top * ramps / 2.0) / top + top * ramps
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoiseDistribution {
+    /// Travel times scaled by a factor uniformly within 1 +- spread
+    Uniform { spread: f64 },
+    /// Travel times scaled by a normally distributed factor with mean 1
+    Normal { std_dev: f64 },
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct TravelTimeNoise {
+    pub distribution: NoiseDistribution,
+    #[serde(default)]
+    pub seed: u64,
+}
+
+/// Trains running late skipping the skippable stations on their way, as long as no one on board
+/// is getting off there, until they're back within the threshold
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct AutoExpress {
+    /// How late a train has to be due at a station to skip it, in the config's time unit
+    pub threshold: f64,
+}
+
+/// How riders weigh crowding, when choosing their path on arriving and when a train comes in
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct CrowdingChoice {
+    /// Share of a train's capacity past which riders find it crowded
+    pub threshold: f64,
+    /// Extra cost of a minute's ride for every share of capacity the train is loaded past the
+    /// threshold, so 2 makes a minute on a full train feel like 1.4 with a threshold of 0.8.
+    /// Riders go by how loaded the last train of each leg's routes left its boarding station.
+    pub disutility: f64,
+    /// Longest riders wait letting crowded trains go by before boarding whatever has room, in
+    /// the config's time unit. They never let one go by if missing.
+    #[serde(default)]
+    pub max_skip_wait: f64,
+}
+
+/// Trains breaking down where they are, blocking the track behind them until they recover
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct IncidentConfig {
+    /// Chance of each train breaking down in every minute it's out, once recording started
+    pub probability: f64,
+    /// Shortest and longest a breakdown lasts in the config's time unit, drawn uniformly between
+    /// them
+    pub min_duration: f64,
+    pub max_duration: f64,
+    #[serde(default)]
+    pub seed: u64,
+}
+
+/// Traction energy of trains, from how far they run and how often they speed up from a stop
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct EnergyModel {
+    /// Energy to run a unit of distance, in whatever unit of energy the results should have
+    pub per_distance: f64,
+    /// Energy to get a train back up to a speed of 1 after stopping. It goes with the square of
+    /// the train's speed, like its kinetic energy.
+    pub per_stop: f64,
+}
+
+// Adds the energy of a train running distance into a station at time to its route's hour
+fn add_energy(
+    energy: &mut HashMap<RouteId, Vec<f64>>,
+    config: &SimulationConfig,
+    route: RouteId,
+    time: f64,
+    distance: f64,
+    stops: bool,
+    speed: f64,
+) {
+    let Some(model) = &config.energy else {
+        return;
+    };
+    let hour = (time / (60.0 * config.time_unit.per_minute())) as usize;
+    let hours = energy.entry(route).or_default();
+    if hours.len() <= hour {
+        hours.resize(hour + 1, 0.0);
+    }
+    hours[hour] += model.per_distance * distance;
+    if stops {
+        hours[hour] += model.per_stop * speed * speed;
+    }
+}
+
+// Records a route's train arriving at a station at time, logging it if it's bunched up with the
+// previous one
+fn record_arrival(
+    arrival_times: &mut HashMap<RouteId, Vec<f64>>,
+    bunching: &mut Vec<Bunching>,
+    config: &SimulationConfig,
+    station: StationId,
+    route: RouteId,
+    time: f64,
+) {
+    let arrivals = arrival_times.entry(route).or_default();
+    if let (Some(threshold), Some(&previous)) = (config.bunching_threshold, arrivals.last()) {
+        let headway = time - previous;
+        if headway < threshold {
+            bunching.push(Bunching {
+                route,
+                station,
+                time,
+                headway,
+            });
+        }
+    }
+    arrivals.push(time);
+}
+
+/// A train arriving at or leaving a station, kept for the last few to diagnose failed checks
+#[derive(Debug, Clone, Copy)]
+pub struct Movement {
+    pub time: f64,
+    pub train: TrainId,
+    pub station: StationId,
+    pub arrived: bool,
+}
+
+/// A run getting into a state it should never be in, which stops it instead of panicking
+#[derive(Debug, Clone)]
+pub struct InvariantFailure {
+    pub message: String,
+    pub time: f64,
+    pub section: TrackStationId,
+    pub trains: Vec<TrainId>,
+    /// Movements leading up to it, oldest first
+    pub recent: Vec<Movement>,
+}
+
+impl InvariantFailure {
+    /// The failure as an error for the frontend, naming sections and trains with the given
+    /// functions
+    pub fn into_error(
+        self,
+        section: impl Fn(TrackStationId) -> String,
+        train: impl Fn(TrainId) -> String,
+    ) -> SimError {
+        let recent_events = self
+            .recent
+            .iter()
+            .map(|movement| {
+                let verb = if movement.arrived {
+                    "arrived at"
+                } else {
+                    "left"
+                };
+                let station = section(TrackStationId::Station(movement.station));
+                format!(
+                    "{}: {} {verb} {station}",
+                    movement.time,
+                    train(movement.train)
+                )
+            })
+            .collect();
+        SimError::Internal(
+            self.message,
+            Box::new(Diagnostic {
+                time: self.time,
+                section: section(self.section),
+                trains: self.trains.into_iter().map(train).collect(),
+                recent_events,
+            }),
+        )
+    }
+
+    // As an error, for scheduling where the frontend's names aren't to hand
+    fn into_unnamed_error(self) -> SimError {
+        self.into_error(
+            |section| format!("{section:?}"),
+            |train| format!("{}_{}", train.route_idx, train.count),
+        )
+    }
+}
+
+/// Records the last few movements of a run, and the first check it failed
+#[derive(Debug, Clone, Default)]
+struct FlightRecorder {
+    recent: VecDeque<Movement>,
+    failure: Option<InvariantFailure>,
+}
+
+impl FlightRecorder {
+    fn moved(&mut self, time: f64, train: TrainId, station: StationId, arrived: bool) {
+        if self.recent.len() == RECENT_MOVEMENTS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(Movement {
+            time,
+            train,
+            station,
+            arrived,
+        });
+    }
+
+    // Notes the run got into a state it shouldn't have, keeping the first such state
+    fn fail(&mut self, message: &str, time: f64, section: TrackStationId, trains: Vec<TrainId>) {
+        if self.failure.is_none() {
+            self.failure = Some(InvariantFailure {
+                message: message.to_string(),
+                time,
+                section,
+                trains,
+                recent: self.recent.iter().copied().collect(),
+            });
+        }
+    }
+}
+
+/// A train broken down over [start, end), and the time it cost each train held up behind it
+#[derive(Debug, Clone)]
+pub struct Incident {
+    pub train: TrainId,
+    pub section: TrackStationId,
+    pub start: f64,
+    pub end: f64,
+    pub delays: HashMap<TrainId, f64>,
+    /// Index into the planned breakdowns it was, None for a random one
+    pub breakdown: Option<usize>,
+    /// Whether the train didn't recover, being pushed off from end on
+    pub rescued: bool,
+    /// When a rescued train was taken out of service, clearing the line
+    pub cleared: Option<f64>,
+}
+
+impl TravelTimeNoise {
+    // Draws the speed of a train over a track, the inverse of its travel time factor
+    fn sample_speed(&self, rng: &mut StdRng) -> f64 {
+        let factor = match self.distribution {
+            NoiseDistribution::Uniform { spread } => {
+                1.0 + rng.gen_range(-spread.abs()..=spread.abs())
+            }
+            NoiseDistribution::Normal { std_dev } => {
+                // Box-Muller transform
+                let u1: f64 = 1.0 - rng.gen::<f64>();
+                let u2: f64 = rng.gen();
+                1.0 + std_dev * (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos()
+            }
+        };
+        // keep trains from stopping or going infinitely fast
+        1.0 / factor.max(0.1)
+    }
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        SimulationConfig {
+            position_recording: PositionRecording::EverySteps(1),
+            short_stop_threshold: 0.0,
+            train_capacity: 1000,
+            travel_time_noise: None,
+            incidents: None,
+            energy: None,
+            layover_time: None,
+            disruption_response: DisruptionResponse::Hold,
+            holding: Holding::None,
+            auto_express: None,
+            merge_priority: MergePriority::FirstCome,
+            max_trains: None,
+            rolling_stock: HashMap::new(),
+            engine: Engine::TimeStep,
+            time_step: TIME_STEP,
+            time_unit: TimeUnit::Minutes,
+            warm_up: None,
+            checkpoint_interval: None,
+            bunching_threshold: None,
+            crowding: None,
+        }
+    }
+}
+
+impl SimulationConfig {
+    fn layover_time(&self) -> f64 {
+        self.layover_time
+            .unwrap_or(LAYOVER_TIME * self.time_unit.per_minute())
+    }
+
+    fn warm_up(&self) -> WarmUp {
+        self.warm_up.unwrap_or(WarmUp::Fixed(
+            (WARM_UP_STEPS as f64 * self.time_unit.per_minute()) as u32,
+        ))
+    }
+}
+
+/// A stepping run stopped partway, to look at through results and carry on with run_until.
+/// Cloning it keeps a snapshot which can be resumed from again, like a what-if from mid-run.
+#[derive(Clone)]
+pub struct PausedRun {
+    simulator: Simulator,
+    progress: RunProgress,
+}
+
+#[derive(Clone)]
+struct RunProgress {
+    profile: HeadwayProfile,
+    iterations: i32,
+    warm_up: u32,
+    /// Next time unit to step, negative while warming up
+    t: i32,
+    train_positions: Vec<TrainPositions>,
+    train_to_route: HashMap<TrainId, RouteId>,
+}
+
+impl PausedRun {
+    /// Time the run has got to
+    pub fn time(&self) -> i32 {
+        self.progress.t
+    }
+
+    /// Whether the run got to its end, or failed a check and can't go on
+    pub fn is_finished(&self) -> bool {
+        self.progress.t >= self.progress.iterations || self.simulator.recorder.failure.is_some()
+    }
+
+    pub fn failure(&self) -> Option<&InvariantFailure> {
+        self.simulator.recorder.failure.as_ref()
+    }
+
+    /// Runs on until time, or the end of the run if that's sooner
+    pub fn run_until(&mut self, time: i32) {
+        self.simulator.step_run(&mut self.progress, time);
+    }
+
+    /// Results of the run up to where it's got
+    pub fn results(&self) -> SimulationResults {
+        self.clone().into_results()
+    }
+
+    /// Summary of the run now, with the arrivals since the given time
+    pub fn checkpoint(&self, since: i32) -> Checkpoint {
+        self.simulator.summary(since, self.progress.t)
+    }
+
+    /// Trains running now
+    pub fn trains(&self) -> impl Iterator<Item = TrainId> + '_ {
+        self.simulator.trains.keys().copied()
+    }
+
+    /// Takes a train out of service at the station it's at, or the next one it gets to,
+    /// leaving its riders there
+    pub fn cancel_train(&mut self, train: TrainId) {
+        self.simulator.cancellations.insert(train);
+    }
+
+    /// Runs an extra train on a route, dispatched as soon as its first platform is free
+    pub fn add_train(&mut self, route: RouteId) {
+        self.simulator.extra_departures.push(route);
+    }
+
+    /// Holds a train where it's stopped for the given minutes past when it'd leave
+    pub fn hold_train(&mut self, train: TrainId, minutes: f64) -> Result<(), SimError> {
+        let simulator = &mut self.simulator;
+        let TrackStationId::Station(station) = simulator.trains[&train].curr_section else {
+            return Err(SimError::InvalidArgument(
+                "only trains stopped at a station can be held".to_owned(),
+            ));
+        };
+        *simulator.holds.entry((train, station)).or_default() +=
+            minutes * simulator.config.time_unit.per_minute();
+        Ok(())
+    }
+
+    /// Runs to the end
+    pub fn finish(mut self) -> SimulationResults {
+        self.run_until(self.progress.iterations);
+        self.into_results()
+    }
+
+    fn into_results(self) -> SimulationResults {
+        let progress = self.progress;
+        self.simulator.into_results(
+            progress.t.clamp(0, progress.iterations),
+            &progress.profile,
+            progress.train_positions,
+            progress.train_to_route,
+            progress.warm_up,
+        )
+    }
+}
+
+/// The approximate size of a simulation run, see Simulator::workload
+pub struct RunWorkload {
+    /// Steps simulated, including warm up
+    pub steps: u64,
+    /// Tracks and stations visited every step
+    pub sections: usize,
+    /// Trains running at once once the network has filled
+    pub trains_in_service: f64,
+    /// Steps whose train positions are kept for playback
+    pub recorded_steps: u64,
+    pub stations: usize,
+    pub routes: usize,
+}
+
+#[derive(Clone)]
+pub struct TrainPositions {
+    pub time: f64,
+    pub trains: Vec<TrainPosition>,
+}
+
+#[derive(Clone)]
+pub struct TrainPosition {
+    pub id: TrainId,
+    pub curr_section: TrackStationId,
+    pub pos: f64,
+    pub distance_travelled: f64,
+    /// Passengers on board
+    pub load: usize,
+}
+
+/// Riders a route's trains carry, and how full they left their stops against that
+#[derive(Clone)]
+pub struct RouteLoad {
+    pub capacity: usize,
+    pub cars: Option<u32>,
+    /// Share of capacity trains left each stop with once recording started
+    pub load_factors: Vec<f64>,
+}
+
+#[derive(Clone)]
+pub struct StationStatistic {
+    pub arrival_times: HashMap<RouteId, Vec<f64>>,
+    pub passenger_waits: Vec<f64>,
+    /// When each of passenger_waits boarded, in the same order
+    pub boarded_at: Vec<f64>,
+}
+
+pub struct SimulationResults {
+    pub train_positions: Vec<TrainPositions>,
+    pub train_to_route: HashMap<TrainId, RouteId>,
+    pub station_statistics: HashMap<StationId, StationStatistic>,
+    /// The advertised headway of each route implied by the requested frequencies
+    pub nominal_headways: HashMap<RouteId, f64>,
+    /// Mean absolute difference between each route's departures and evenly spaced ones
+    pub dispatch_deviations: HashMap<RouteId, f64>,
+    /// When each route's trains left their first station, in order, for optimized schedules
+    pub departures: HashMap<RouteId, Vec<i64>>,
+    /// Door to door times of passengers who reached their destination
+    pub journey_times: Vec<f64>,
+    /// When each of them set out, in the same order
+    pub journey_starts: Vec<f64>,
+    /// When riders showed up during a shutdown period and were turned away, once recording
+    /// started
+    pub turned_away: Vec<f64>,
+    /// Traces of the passengers picked by trace_journeys, by arrival
+    pub journeys: Vec<Journey>,
+    /// Times stations declaring a waiting capacity had more waiting, by start
+    pub crowding_alerts: Vec<CrowdingAlert>,
+    /// Station each route's trains are dispatched from, where they can leave back to back
+    pub terminals: HashMap<RouteId, StationId>,
+    pub operating_distances: HashMap<RouteId, OperatingDistance>,
+    /// Traction energy of each route's trains in every hour once recording started, by
+    /// SimulationConfig::energy
+    pub energy: HashMap<RouteId, Vec<f64>>,
+    /// Riders boarding each route's trains once recording started
+    pub boardings: HashMap<RouteId, usize>,
+    /// Capacity and loads of each route's trains, leaving out depot moves
+    pub route_loads: HashMap<RouteId, RouteLoad>,
+    /// Bunched arrivals in the order they happened, see SimulationConfig::bunching_threshold
+    pub bunching: Vec<Bunching>,
+    /// How late timetabled trains got to each stop of their route, by route and station
+    pub schedule_deviations: HashMap<(RouteId, StationId), Vec<f64>>,
+    /// Standby trains put into service, in the order they were
+    pub gap_trains: Vec<GapTrain>,
+    /// Departures delayed by Route::max_trains or SimulationConfig::max_trains once recording
+    /// started, in the order they were due
+    pub fleet_waits: Vec<FleetWait>,
+    /// Check the run failed, stopping it early
+    pub failure: Option<InvariantFailure>,
+    /// Time each train delayed by a disruption was held up
+    pub disruption_delays: HashMap<TrainId, f64>,
+    pub short_turns: Vec<(TrainId, StationId)>,
+    /// Length of each hold the holding strategy gave a train once recording started
+    pub hold_times: Vec<f64>,
+    /// Trains held for feeders at timed transfers once recording started
+    pub coordinated_transfers: Vec<CoordinatedTransfer>,
+    /// Express trains passing stopped ones on bypass tracks once recording started
+    pub overtakes: Vec<Overtake>,
+    /// Stops late trains skipped by SimulationConfig::auto_express once recording started
+    pub skipped_stops: Vec<SkippedStop>,
+    /// Times riders let a crowded train go by under SimulationConfig::crowding, once recording
+    /// started
+    pub crowding_skips: usize,
+    /// Riders who took a path other than their quickest to avoid crowding, once recording
+    /// started
+    pub crowding_detours: usize,
+    /// What each station closure did, in the order they were set
+    pub closure_effects: Vec<ClosureEffect>,
+    /// Breakdowns of SimulationConfig::incidents, in the order they happened
+    pub incidents: Vec<Incident>,
+    /// Minutes each station's bypass track was occupied once recording started
+    pub bypass_use: HashMap<StationId, f64>,
+    /// Which route each physical train served when, for trips not over before recording
+    pub train_trips: Vec<TrainTrip>,
+    /// Headway patterns where routes sharing track branch off, by station
+    pub branch_headways: Vec<BranchHeadways>,
+    /// Trains dispatched over the whole run, warm up included, counting each turned around
+    /// train once. It isn't the fleet, as trains are out at different times.
+    pub dispatches: usize,
+    /// Most trains out at once, the physical fleet needed when trains pulling into a depot are
+    /// sent back out
+    pub peak_trains_in_service: usize,
+    /// Trains out on each route at every time unit once recording started, which with
+    /// congestion lengthening trips can need more than the headways suggest
+    pub in_service: Vec<InService>,
+    /// How much of their padding each padded route's trains used
+    pub padding: HashMap<RouteId, PaddingUse>,
+    /// Minutes simulated before recording started
+    pub warm_up: u32,
+    /// Unit of every time in the results, see SimulationConfig::time_unit
+    pub time_unit: TimeUnit,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum DivergenceEntity {
+    Train(TrainId),
+    Station(StationId),
+    /// The runs as a whole, e.g. when they recorded different steps
+    Run,
+}
+
+/// A point where two simulation results that should have been identical differ
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    pub time: f64,
+    pub entity: DivergenceEntity,
+    pub description: String,
+}
+
+impl SimulationResults {
+    /// Finds where these results first differ from another run of the same scenario: the first
+    /// differing train positions, and the first differing arrival at each station. Sorted by time.
+    pub fn divergences(&self, other: &SimulationResults) -> Vec<Divergence> {
+        let mut divergences = Vec::new();
+
+        fn sorted_trains(positions: &TrainPositions) -> Vec<&TrainPosition> {
+            let mut trains: Vec<_> = positions.trains.iter().collect();
+            trains.sort_by_key(|p| (p.id.route_idx, p.id.count));
+            trains
+        }
+        for (a, b) in self.train_positions.iter().zip(&other.train_positions) {
+            if a.time != b.time {
+                divergences.push(Divergence {
+                    time: a.time.min(b.time),
+                    entity: DivergenceEntity::Run,
+                    description: "runs recorded positions at different steps".to_string(),
+                });
+                break;
+            }
+            let a_trains = sorted_trains(a);
+            let b_trains = sorted_trains(b);
+            let a_ids: HashSet<_> = a_trains.iter().map(|p| p.id).collect();
+            let b_ids: HashSet<_> = b_trains.iter().map(|p| p.id).collect();
+            for id in a_ids.symmetric_difference(&b_ids) {
+                divergences.push(Divergence {
+                    time: a.time,
+                    entity: DivergenceEntity::Train(*id),
+                    description: "train only present in one run".to_string(),
+                });
+            }
+            for (p, q) in a_trains.iter().zip(&b_trains) {
+                if p.id == q.id
+                    && (p.curr_section != q.curr_section
+                        || p.pos != q.pos
+                        || p.distance_travelled != q.distance_travelled
+                        || p.load != q.load)
+                {
+                    divergences.push(Divergence {
+                        time: a.time,
+                        entity: DivergenceEntity::Train(p.id),
+                        description: format!(
+                            "train at {:?} pos {} in one run and {:?} pos {} in the other",
+                            p.curr_section, p.pos, q.curr_section, q.pos
+                        ),
+                    });
+                }
+            }
+            if !divergences.is_empty() {
+                break;
+            }
+        }
+
+        for (id, statistic) in &self.station_statistics {
+            let other_arrival_times = other.station_statistics.get(id).map(|s| &s.arrival_times);
+            for (route, arrivals) in &statistic.arrival_times {
+                let other_arrivals = other_arrival_times
+                    .and_then(|arrival_times| arrival_times.get(route))
+                    .map(Vec::as_slice)
+                    .unwrap_or_default();
+                let first_difference = arrivals
+                    .iter()
+                    .zip(other_arrivals)
+                    .position(|(a, b)| a != b)
+                    .or((arrivals.len() != other_arrivals.len())
+                        .then(|| min(arrivals.len(), other_arrivals.len())));
+                if let Some(i) = first_difference {
+                    let a = arrivals.get(i).copied().unwrap_or(f64::INFINITY);
+                    let b = other_arrivals.get(i).copied().unwrap_or(f64::INFINITY);
+                    divergences.push(Divergence {
+                        time: f64_min(a, b),
+                        entity: DivergenceEntity::Station(*id),
+                        description: format!(
+                            "arrival {i} of route {} at {a} in one run and {b} in the other",
+                            route.0
+                        ),
+                    });
+                }
+            }
+        }
+
+        divergences.sort_by(|a, b| a.time.total_cmp(&b.time));
+        divergences
+    }
+}
+
+/// Dwell time at stations which don't specify their own
+pub const STATION_DWELL_TIME: f64 = 0.5;
+/// Length of the signal blocks on track which doesn't specify its own
+pub const BLOCK_LENGTH: f64 = 2.0;
+const TIME_STEP: f64 = 1.0;
+/// Steps run before recording starts, to fill the network with trains
+const WARM_UP_STEPS: i32 = 120;
+/// Minutes trains lay over at a terminal before turning around, unless the config says
+const LAYOVER_TIME: f64 = 3.0;
+/// Shortest stretch of minutes whose train counts are compared to detect a steady state
+const STEADY_STATE_WINDOW: usize = 10;
+/// Movements kept to diagnose a failed check
+const RECENT_MOVEMENTS: usize = 32;
+
+fn f64_min(a: f64, b: f64) -> f64 {
+    a.min(b)
+}
+
+fn f64_max(a: f64, b: f64) -> f64 {
+    a.max(b)
+}
+
+impl Simulator {
+    pub fn new(subway_map: SubwayMap, routes: Vec<Route>, config: SimulationConfig) -> Self {
+        let steps_per_unit = (1.0 / config.time_step).round().max(1.0) as i32;
+        let routes = add_depot_moves(&subway_map, routes);
+        let mut stations = HashMap::with_capacity(subway_map.node_count());
+        let mut tracks = HashMap::with_capacity(subway_map.edge_count());
+
+        for node in subway_map.node_indices() {
+            stations.insert(
+                node,
+                Station {
+                    id: node,
+                    train: None,
+                    arrival_times: HashMap::new(),
+                    dwell_time: subway_map[node].dwell_time(config.time_unit),
+                    waiting: VecDeque::new(),
+                    passenger_waits: Vec::new(),
+                    boarded_at: Vec::new(),
+                    waiting_capacity: subway_map[node].waiting_capacity,
+                    has_bypass: subway_map[node].bypass,
+                    bypass: None,
+                },
+            );
+        }
+
+        for edge in subway_map.edge_references() {
+            tracks.insert(
+                edge.id(),
+                Track {
+                    id: edge.id(),
+                    length: edge.weight().weight,
+                    lanes: vec![VecDeque::new(); edge.weight().tracks.max(1) as usize],
+                    block_length: edge.weight().block_length,
+                },
+            );
+        }
+
+        let all_route_edges = routes
+            .iter()
+            .flat_map(|route| route.station_to.values())
+            .copied()
+            .collect();
+        let traversal_order = traversal_order(&subway_map, &all_route_edges);
+
+        let routes: HashMap<_, _> = routes
+            .into_iter()
+            .enumerate()
+            .map(|(i, route)| (RouteId(i as u32), route))
+            .collect();
+        let terminal_links = routes
+            .iter()
+            .filter_map(|(id, route)| {
+                let next_route = route.turnaround?;
+                Some(((*id, route_terminal(&subway_map, route)), next_route))
+            })
+            .collect();
+        let loop_stations = routes
+            .iter()
+            .filter_map(|(id, route)| Some((*id, loop_station(&subway_map, route)?)))
+            .collect();
+        let walks = EdgeFiltered::from_fn(&subway_map, |edge| edge.weight().ty == EdgeType::Walk);
+        let walk_times = subway_map
+            .node_indices()
+            .flat_map(|from| {
+                petgraph::algo::dijkstra(&walks, from, None, |e| e.weight().weight as u32)
+                    .into_iter()
+                    .filter(move |(to, _)| *to != from)
+                    .map(move |(to, walk)| ((from, to), walk as f64))
+            })
+            .collect();
+
+        // Stations closely following the previous stop get a proportionally shorter dwell, on
+        // routes which opt in, since trains barely get up to speed between them
+        let mut dwell_times = HashMap::new();
+        for (id, route) in &routes {
+            if !route.combine_short_stops {
+                continue;
+            }
+            for track in route.station_to.values() {
+                let length = subway_map[*track].weight as f64;
+                if length < config.short_stop_threshold {
+                    let (_, station) = subway_map.edge_endpoints(*track).unwrap();
+                    dwell_times.insert(
+                        (*id, station),
+                        subway_map[station].dwell_time(config.time_unit) * length
+                            / config.short_stop_threshold,
+                    );
+                }
+            }
+        }
+        let rolling_stock: HashMap<_, _> = routes
+            .iter()
+            .map(|(id, route)| {
+                let stock = route
+                    .rolling_stock
+                    .as_ref()
+                    .and_then(|name| config.rolling_stock.get(name))
+                    .copied()
+                    // trains that can't move would never leave their first station
+                    .filter(|stock| stock.speed > 0.0)
+                    .unwrap_or_default();
+                let capacity = stock.train_capacity(route.cars, config.train_capacity);
+                (
+                    *id,
+                    RollingStock {
+                        capacity: Some(capacity),
+                        ..stock
+                    },
+                )
+            })
+            .collect();
+        // trains made up of cars are as long as them
+        let routes: HashMap<_, _> = routes
+            .into_iter()
+            .map(|(id, route)| {
+                let train_length = match route.cars {
+                    Some(cars) => cars as f64 * rolling_stock[&id].car_length,
+                    None => route.train_length,
+                };
+                (
+                    id,
+                    Route {
+                        train_length,
+                        ..route
+                    },
+                )
+            })
+            .collect();
+        for (id, route) in &routes {
+            let penalty = rolling_stock[id].dwell_penalty;
+            if penalty != 0.0 {
+                let stations = route
+                    .station_to
+                    .values()
+                    .map(|track| subway_map.edge_endpoints(*track).unwrap().1)
+                    .chain([route.start_station]);
+                for station in stations {
+                    *dwell_times
+                        .entry((*id, station))
+                        .or_insert(subway_map[station].dwell_time(config.time_unit)) += penalty;
+                }
+            }
+            for station in &route.pass_through {
+                dwell_times.insert((*id, *station), 0.0);
+            }
+        }
+
+        let mut variants: HashMap<RouteId, Vec<RouteId>> = HashMap::new();
+        for (id, route) in &routes {
+            if let Some(parent) = route.variant_of {
+                variants.entry(parent).or_default().push(*id);
+            }
+        }
+        for variants in variants.values_mut() {
+            variants.sort_by_key(|id| id.0);
+        }
+
+        Self {
+            subway_map,
+            curr_train_counts: vec![0; routes.len()],
+            routes,
+            trains: HashMap::new(),
+            stations,
+            tracks,
+            traversal_order,
+            dwell_times,
+            variants,
+            rolling_stock,
+            time_step: 1.0 / steps_per_unit as f64,
+            steps_per_unit,
+            config,
+            demand: Arc::new(Vec::new()),
+            next_passenger: 0,
+            journey_times: Vec::new(),
+            journey_starts: Vec::new(),
+            shutdowns: Vec::new(),
+            turned_away: Vec::new(),
+            journeys: Vec::new(),
+            crowding_alerts: Vec::new(),
+            open_crowding_alerts: HashMap::new(),
+            noise_rng: None,
+            incident_rng: None,
+            incidents: Vec::new(),
+            stalled: HashMap::new(),
+            incident_blocks: HashMap::new(),
+            breakdowns: Vec::new(),
+            rescues: HashMap::new(),
+            pushed: HashMap::new(),
+            operating_distances: HashMap::new(),
+            energy: HashMap::new(),
+            boardings: HashMap::new(),
+            load_factors: HashMap::new(),
+            bunching: Vec::new(),
+            timetabled: HashMap::new(),
+            padded: HashMap::new(),
+            padding_use: HashMap::new(),
+            schedule_deviations: HashMap::new(),
+            recorder: FlightRecorder::default(),
+            disruptions: Vec::new(),
+            reroutes: Vec::new(),
+            disruption_delays: HashMap::new(),
+            short_turns: Vec::new(),
+            holds: HashMap::new(),
+            extra_departures: Vec::new(),
+            cancellations: HashSet::new(),
+            gap_trains: Vec::new(),
+            fleet_queue: Vec::new(),
+            fleet_waits: Vec::new(),
+            last_departures: HashMap::new(),
+            hold_times: Vec::new(),
+            timed_transfers: HashMap::new(),
+            station_closures: Vec::new(),
+            closure_effects: Vec::new(),
+            transfer_waits: HashMap::new(),
+            coordinated_transfers: Vec::new(),
+            overtakes: Vec::new(),
+            express_due: HashMap::new(),
+            skipping: HashMap::new(),
+            skipped_stops: Vec::new(),
+            departure_loads: HashMap::new(),
+            crowding_skips: 0,
+            crowding_detours: 0,
+            bypass_use: HashMap::new(),
+            checkpoints: None,
+            terminal_links,
+            loop_stations,
+            walk_times,
+            circling: HashSet::new(),
+            train_trips: Vec::new(),
+            open_trips: HashMap::new(),
+            merge_waits: HashMap::new(),
+            merge_from: HashMap::new(),
+            current_time: 0.0,
+            peak_trains: 0,
+            in_service: Vec::new(),
+        }
+    }
+
+    /// Estimates how much work run would do, without running the simulation
+    pub fn workload(&self, iterations: i32, frequency: u64) -> RunWorkload {
+        let warm_up = match self.config.warm_up() {
+            WarmUp::Fixed(steps) => steps as i32,
+            // the trial run to find the steady state costs up to max steps too
+            WarmUp::SteadyState { max } => 2 * max as i32,
+        };
+        let steps = ((iterations + warm_up) * self.steps_per_unit).max(0) as u64;
+
+        // each route has a train dispatched every frequency steps, which stays in service for
+        // the time it takes to run the route
+        let mut trains_in_service = 0.;
+        for (id, route) in self
+            .routes
+            .iter()
+            .filter(|(_, route)| route.parent.is_none())
+        {
+            let mut trip_time = 0.;
+            let mut station = route.start_station;
+            let mut visited = HashSet::new();
+            while visited.insert(station) {
+                trip_time += self.dwell_time(*id, station);
+                let Some(track) = route.station_to.get(&station) else {
+                    break;
+                };
+                trip_time += self.tracks[track].length as f64;
+                station = self.subway_map.edge_endpoints(*track).unwrap().1;
+            }
+            // a route and its variants share one route's departures
+            let group = route.variant_of.unwrap_or(*id);
+            let group_size = self
+                .variants
+                .get(&group)
+                .map_or(1, |variants| variants.len() + 1);
+            trains_in_service += trip_time / (frequency.max(1) as usize * group_size) as f64;
+        }
+
+        let recorded_steps = match self.config.position_recording {
+            PositionRecording::EverySteps(n) => {
+                (iterations * self.steps_per_unit).max(0) as u64 / n.max(1) as u64 + 1
+            }
+            PositionRecording::Interval(interval) => {
+                (iterations.max(0) as f64 / interval.max(self.time_step)) as u64 + 1
+            }
+            // at most every step has a station event
+            PositionRecording::StationEvents => (iterations * self.steps_per_unit).max(0) as u64,
+        };
+
+        RunWorkload {
+            steps,
+            sections: self.traversal_order.len(),
+            trains_in_service,
+            recorded_steps,
+            stations: self.stations.len(),
+            routes: self.routes.len(),
+        }
+    }
+
+    /// Keeps every route's standby trains in storage, like for a baseline without them
+    pub fn clear_standby(&mut self) {
+        for route in self.routes.values_mut() {
+            route.standby = None;
+        }
+    }
+
+    /// Replaces the holding strategy of SimulationConfig, like for an uncontrolled baseline
+    pub fn set_holding(&mut self, holding: Holding) {
+        self.config.holding = holding;
+    }
+
+    /// Sets the track closures to simulate
+    pub fn set_disruptions(&mut self, disruptions: DisruptionPlan) {
+        self.disruptions = disruptions;
+    }
+
+    /// Sets the planned breakdowns to simulate. Scheduling doesn't see them.
+    pub fn set_breakdowns(&mut self, breakdowns: Vec<Breakdown>) {
+        self.breakdowns = breakdowns;
+    }
+
+    /// Errors if the run asks for something its engine doesn't simulate. The events engine has
+    /// no breakdowns, random or planned, and no bypass tracks.
+    pub fn check_engine(&self) -> Result<(), SimError> {
+        if let Engine::Events = self.config.engine {
+            if self.config.incidents.is_some() {
+                return Err(SimError::InvalidArgument(
+                    "the events engine can't simulate incidents, run them with time_step"
+                        .to_owned(),
+                ));
+            }
+            if !self.breakdowns.is_empty() {
+                return Err(SimError::InvalidArgument(
+                    "the events engine can't simulate breakdowns, run them with time_step"
+                        .to_owned(),
+                ));
+            }
+            if self.stations.values().any(|station| station.has_bypass) {
+                return Err(SimError::InvalidArgument(
+                    "the events engine can't simulate bypass tracks, run them with time_step"
+                        .to_owned(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the station closures to simulate. Scheduling doesn't see them.
+    pub fn set_station_closures(&mut self, closures: Vec<StationClosure>) {
+        self.closure_effects = vec![ClosureEffect::default(); closures.len()];
+        self.station_closures = closures;
+    }
+
+    /// Sets the timed transfers to simulate, trains at each station waiting for riders off
+    /// feeders due a walk away no later than max_hold from when they'd leave, in the config's
+    /// time unit. Scheduling doesn't see them.
+    pub fn set_timed_transfers(&mut self, timed_transfers: Vec<TimedTransfer>) {
+        self.timed_transfers = timed_transfers
+            .into_iter()
+            .map(|transfer| (transfer.station, transfer.max_hold))
+            .collect();
+    }
+
+    /// Sends a checkpoint every SimulationConfig::checkpoint_interval recorded minutes of run
+    pub fn set_checkpoints(&mut self, checkpoints: Sender<Checkpoint>) {
+        self.checkpoints = Some(checkpoints);
+    }
+
+    /// Sets the planned reroutes to simulate. Scheduling doesn't see them.
+    pub fn set_reroutes(&mut self, reroutes: ReroutePlan) {
+        // trains on detour track have to be moved too
+        let all_route_edges = self
+            .routes
+            .values()
+            .map(|route| &route.station_to)
+            .chain(reroutes.iter().map(|reroute| &reroute.station_to))
+            .flat_map(|station_to| station_to.values())
+            .copied()
+            .collect();
+        self.traversal_order = traversal_order(&self.subway_map, &all_route_edges);
+        self.reroutes = reroutes;
+    }
+
+    /// Sets the passengers to simulate: each rider of a trip takes the first of its paths
+    pub fn add_demand(
+        &mut self,
+        search_map: &SearchMap,
+        trip_data: &TripData,
+        shortest_paths: &HashMap<(NodeIndex, NodeIndex), Vec<Vec<PathSegment>>>,
+    ) {
+        self.add_rerouted_demand(search_map, trip_data, shortest_paths, &[]);
+    }
+
+    /// Like add_demand, except riders arriving during a reroute take the first of their paths
+    /// on the network as rerouted then, if they have any
+    pub fn add_rerouted_demand(
+        &mut self,
+        search_map: &SearchMap,
+        trip_data: &TripData,
+        shortest_paths: &HashMap<(NodeIndex, NodeIndex), Vec<Vec<PathSegment>>>,
+        rerouted_paths: &[ReroutedPaths],
+    ) {
+        let route_ids: HashMap<_, _> = self
+            .routes
+            .iter()
+            .map(|(id, route)| (route.name.as_str(), *id))
+            .collect();
+        let legs = |search_map: &SearchMap, path: &Vec<PathSegment>| -> VecDeque<_> {
+            path.iter()
+                .map(|segment| Leg {
+                    routes: segment
+                        .routes
+                        .iter()
+                        .filter_map(|route| route_ids.get(route.as_str()).copied())
+                        .collect(),
+                    board: search_map.map[segment.start_node].old_node,
+                    alight: search_map.map[segment.end_node].old_node,
+                })
+                .collect()
+        };
+        let mut demand = Vec::new();
+        for trip in trip_data.values().flatten() {
+            let Some(paths) = shortest_paths.get(&(trip.start, trip.end)) else {
+                continue;
+            };
+            let Some(path) = paths.first() else {
+                continue;
+            };
+            let nominal_legs = legs(search_map, path);
+            if nominal_legs.is_empty() {
+                continue;
+            }
+            let choices = (self.config.crowding.is_some() && paths.len() > 1).then(|| {
+                let choices = paths
+                    .iter()
+                    .map(|path| {
+                        let walk_time = |segment: &PathSegment| {
+                            segment
+                                .edge_to_next
+                                .and_then(|edge| search_map.map.edge_weight(edge))
+                                .map_or(0.0, |edge| edge.weight as f64)
+                        };
+                        RouteChoice {
+                            legs: legs(search_map, path),
+                            cost: path
+                                .iter()
+                                .map(|segment| segment.cost as f64 + walk_time(segment))
+                                .sum(),
+                            ride_times: path.iter().map(|segment| segment.cost as f64).collect(),
+                        }
+                    })
+                    .filter(|choice| !choice.legs.is_empty())
+                    .collect();
+                Arc::new(choices)
+            });
+            let rerouted_legs: Vec<_> = rerouted_paths
+                .iter()
+                .filter_map(|paths| {
+                    let path = paths
+                        .shortest_paths
+                        .get(&(trip.start, trip.end))
+                        .and_then(|paths| paths.first())?;
+                    let legs = legs(&paths.search_map, path);
+                    (!legs.is_empty()).then_some((paths.start, paths.end, legs))
+                })
+                .collect();
+            for &arrival_time in &trip.arrival_times {
+                let rerouted = rerouted_legs
+                    .iter()
+                    .find(|(start, end, _)| *start <= arrival_time && arrival_time < *end);
+                demand.push(Passenger {
+                    arrival_time,
+                    waiting_since: arrival_time,
+                    legs: rerouted.map_or(&nominal_legs, |(_, _, legs)| legs).clone(),
+                    journey: None,
+                    transferring: None,
+                    // riders set out on the network as rerouted only know the one way round
+                    choices: rerouted.is_none().then(|| choices.clone()).flatten(),
+                });
+            }
+        }
+        demand.sort_by(|a, b| a.arrival_time.total_cmp(&b.arrival_time));
+        self.demand = Arc::new(demand);
+        self.next_passenger = 0;
+    }
+
+    /// Traces every move of a sample of the passengers added by add_demand, as well as all
+    /// those travelling between the given pairs of stations
+    pub fn trace_journeys(&mut self, sampling: &JourneySampling) {
+        let demand = Arc::make_mut(&mut self.demand);
+        let mut rng = StdRng::seed_from_u64(5050);
+        let sampled: HashSet<_> =
+            rand::seq::index::sample(&mut rng, demand.len(), sampling.sample.min(demand.len()))
+                .into_iter()
+                .collect();
+        for (i, passenger) in demand.iter_mut().enumerate() {
+            let origin = passenger.legs[0].board;
+            let destination = passenger.legs.back().unwrap().alight;
+            if sampled.contains(&i) || sampling.od_pairs.contains(&(origin, destination)) {
+                passenger.journey = Some(Box::new(Journey {
+                    origin,
+                    destination,
+                    arrival_time: passenger.arrival_time,
+                    events: Vec::new(),
+                    completed_at: None,
+                }));
+            }
+        }
+    }
+
+    // Traced journeys, including those of passengers still waiting or on board
+    fn traced_journeys(&self) -> Vec<Journey> {
+        let travelling = self
+            .stations
+            .values()
+            .flat_map(|station| station.waiting.iter())
+            .chain(
+                self.trains
+                    .values()
+                    .flat_map(|train| train.passengers.iter()),
+            )
+            .filter_map(|passenger| passenger.journey.as_deref());
+        let mut journeys: Vec<_> = self.journeys.iter().chain(travelling).cloned().collect();
+        journeys.sort_by(|a, b| a.arrival_time.total_cmp(&b.arrival_time));
+        journeys
+    }
+
+    fn recorded_trips(&self) -> Vec<TrainTrip> {
+        self.train_trips
+            .iter()
+            .filter(|trip| trip.end.is_none_or(|end| end >= 0.0))
+            .copied()
+            .collect()
+    }
+
+    // Stations where revenue routes coming in on the same track leave on different ones, with
+    // how their trains were spread out there
+    fn branch_headways(&self) -> Vec<BranchHeadways> {
+        let mut trunks: HashMap<(StationId, TrackId), Vec<RouteId>> = HashMap::new();
+        for (id, route) in &self.routes {
+            if route.parent.is_some() || route.deadhead {
+                continue;
+            }
+            for track in route.station_to.values() {
+                let to = self.subway_map.edge_endpoints(*track).unwrap().1;
+                if route.station_to.contains_key(&to) {
+                    trunks.entry((to, *track)).or_default().push(*id);
+                }
+            }
+        }
+        let mut branch_headways = Vec::new();
+        for ((station, _), routes) in trunks {
+            let leaving: HashSet<_> = routes
+                .iter()
+                .map(|route| self.routes[route].station_to[&station])
+                .collect();
+            if leaving.len() < 2 {
+                continue;
+            }
+            let arrivals = &self.stations[&station].arrival_times;
+            let mut pattern: Vec<_> = routes
+                .iter()
+                .flat_map(|route| {
+                    let times = arrivals.get(route).map(Vec::as_slice).unwrap_or_default();
+                    times.iter().map(|time| (*time, *route))
+                })
+                .collect();
+            pattern.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+            let mut branch: HashMap<RouteId, Vec<f64>> = HashMap::new();
+            let mut last = HashMap::new();
+            for (time, route) in &pattern {
+                if let Some(previous) = last.insert(*route, *time) {
+                    branch.entry(*route).or_default().push(time - previous);
+                }
+            }
+
+            // a route running n times as often as the others together may come n in a row
+            let mut counts: HashMap<RouteId, usize> = HashMap::new();
+            for (_, route) in &pattern {
+                *counts.entry(*route).or_default() += 1;
+            }
+            let allowed = |route: RouteId| {
+                let others = pattern.len() - counts[&route];
+                counts[&route].div_ceil(others.max(1))
+            };
+            let mut irregular = Vec::new();
+            let mut start = 0;
+            for end in 1..=pattern.len() {
+                if end < pattern.len() && pattern[end].1 == pattern[start].1 {
+                    continue;
+                }
+                let (time, route) = pattern[start];
+                let repeats = end - start;
+                if counts.len() > 1 && repeats > allowed(route) {
+                    irregular.push(IrregularAlternation {
+                        time,
+                        route,
+                        repeats,
+                    });
+                }
+                start = end;
+            }
+            branch_headways.push(BranchHeadways {
+                station,
+                pattern,
+                branch_headways: branch,
+                irregular,
+            });
+        }
+        branch_headways.sort_by_key(|branch| branch.station);
+        branch_headways
+    }
+
+    fn terminals(&self) -> HashMap<RouteId, StationId> {
+        self.routes
+            .iter()
+            .filter(|(_, route)| route.dispatched && !route.deadhead)
+            .map(|(id, route)| (*id, route.start_station))
+            .collect()
+    }
+
+    // Counts the trains out at time by route
+    fn record_in_service(&mut self, time: f64) {
+        let mut by_route = HashMap::new();
+        for train in self.trains.values() {
+            let route = self.routes[&train.route].parent.unwrap_or(train.route);
+            *by_route.entry(route).or_default() += 1;
+        }
+        self.in_service.push(InService { time, by_route });
+    }
+
+    // Opens or extends an alert for each station with more waiting than it comfortably holds,
+    // and closes those of stations which have emptied out
+    fn record_crowding(&mut self, time: f64) {
+        for station in self.stations.values() {
+            let Some(capacity) = station.waiting_capacity else {
+                continue;
+            };
+            // riders still walking over from another station aren't on the platform yet
+            let waiting = station
+                .waiting
+                .iter()
+                .filter(|passenger| passenger.waiting_since <= time)
+                .count();
+            if waiting > capacity {
+                let alert = self
+                    .open_crowding_alerts
+                    .entry(station.id)
+                    .or_insert(CrowdingAlert {
+                        station: station.id,
+                        start: time,
+                        end: time,
+                        peak_waiting: waiting,
+                    });
+                alert.end = time + self.time_step;
+                alert.peak_waiting = alert.peak_waiting.max(waiting);
+            } else if let Some(alert) = self.open_crowding_alerts.remove(&station.id) {
+                self.crowding_alerts.push(alert);
+            }
+        }
+    }
+
+    // Brings back trains whose breakdown is over, pushing off those which didn't recover, then
+    // breaks down others as planned and at random
+    fn inject_incidents(&mut self, time: f64) {
+        let incidents = &self.incidents;
+        let (over, stalled) = std::mem::take(&mut self.stalled)
+            .into_iter()
+            .partition::<HashMap<_, _>, _>(|(_, incident)| incidents[*incident].end <= time);
+        self.stalled = stalled;
+        for (train, incident) in over {
+            if let Some(push_speed) = self.rescues.remove(&train) {
+                self.trains.get_mut(&train).unwrap().speed *= push_speed;
+                self.pushed.insert(train, (incident, push_speed));
+            }
+        }
+        self.incident_blocks.clone_from(&self.stalled);
+        self.incident_blocks.extend(
+            self.pushed
+                .iter()
+                .map(|(train, (incident, _))| (*train, *incident)),
+        );
+        self.break_down_planned(time);
+        let (Some(rng), Some(config)) = (&mut self.incident_rng, &self.config.incidents) else {
+            return;
+        };
+        if time < 0.0 {
+            return;
+        }
+        // go through trains in a fixed order so the random draws are reproducible
+        let mut trains: Vec<_> = self
+            .trains
+            .keys()
+            .filter(|train| !self.stalled.contains_key(train) && !self.pushed.contains_key(train))
+            .copied()
+            .collect();
+        trains.sort_by_key(|train| (train.route_idx, train.count));
+        for train in trains {
+            let chance = config.probability * self.time_step / self.config.time_unit.per_minute();
+            if rng.gen::<f64>() >= chance {
+                continue;
+            }
+            let duration =
+                rng.gen_range(config.min_duration.min(config.max_duration)..=config.max_duration);
+            self.stalled.insert(train, self.incidents.len());
+            self.incident_blocks.insert(train, self.incidents.len());
+            self.incidents.push(Incident {
+                train,
+                section: self.trains[&train].curr_section,
+                start: time,
+                end: time + duration,
+                delays: HashMap::new(),
+                breakdown: None,
+                rescued: false,
+                cleared: None,
+            });
+        }
+    }
+
+    // Breaks down the train furthest along the track of each planned breakdown now due
+    fn break_down_planned(&mut self, time: f64) {
+        for (i, breakdown) in self.breakdowns.clone().into_iter().enumerate() {
+            if time < breakdown.start
+                || self
+                    .incidents
+                    .iter()
+                    .any(|incident| incident.breakdown == Some(i))
+            {
+                continue;
+            }
+            let train = self.tracks[&breakdown.track]
+                .lanes
+                .iter()
+                .flatten()
+                .filter(|train| {
+                    let route = self.trains[train].route;
+                    breakdown
+                        .route
+                        .is_none_or(|id| self.routes[&route].parent.unwrap_or(route) == id)
+                })
+                .filter(|train| {
+                    !self.stalled.contains_key(train) && !self.pushed.contains_key(train)
+                })
+                .max_by(|a, b| self.trains[a].pos.total_cmp(&self.trains[b].pos))
+                .copied();
+            let Some(train) = train else {
+                continue;
+            };
+            self.stalled.insert(train, self.incidents.len());
+            self.incident_blocks.insert(train, self.incidents.len());
+            if !breakdown.recovers {
+                self.rescues.insert(train, breakdown.push_speed);
+            }
+            self.incidents.push(Incident {
+                train,
+                section: TrackStationId::Track(breakdown.track),
+                start: time,
+                end: time + breakdown.duration,
+                delays: HashMap::new(),
+                breakdown: Some(i),
+                rescued: !breakdown.recovers,
+                cleared: None,
+            });
+        }
+    }
+
+    // Puts the time a train lost waiting on blocker down to the incident holding blocker up, if
+    // there is one
+    fn blocked_by(&mut self, train: TrainId, blocker: Option<TrainId>, lost: f64) {
+        let Some(&incident) = blocker.and_then(|blocker| self.incident_blocks.get(&blocker)) else {
+            return;
+        };
+        if self.stalled.contains_key(&train) || self.pushed.contains_key(&train) {
+            return;
+        }
+        self.incident_blocks.insert(train, incident);
+        if lost > 0.0 {
+            *self.incidents[incident].delays.entry(train).or_default() += lost;
+        }
+    }
+
+    fn record_bypass_use(&mut self) {
+        for station in self.stations.values() {
+            if station.bypass.is_some() {
+                *self.bypass_use.entry(station.id).or_default() += self.time_step;
+            }
+        }
+    }
+
+    // Whether a train at the end of its track can run past the train at the platform on the
+    // station's bypass, only trains running through it without stopping can
+    fn can_bypass(&self, station: StationId, train: TrainId) -> bool {
+        let station_ref = &self.stations[&station];
+        let route_id = self.trains[&train].route;
+        let route = &self.routes[&route_id];
+        station_ref.has_bypass
+            && station_ref.bypass.is_none()
+            && route.pass_through.contains(&station)
+            && route_track(route, route_id, &self.reroutes, station, self.current_time).is_some()
+    }
+
+    // Moves the train on a station's bypass onto its next track once the first block is clear
+    fn bypass_to_track(&mut self, station: StationId, time_left: f64) {
+        let Some(train) = self.stations[&station].bypass else {
+            return;
+        };
+        if self.stalled.contains_key(&train) {
+            return;
+        }
+        let route_id = self.trains[&train].route;
+        let Some(next_track_id) = route_track(
+            &self.routes[&route_id],
+            route_id,
+            &self.reroutes,
+            station,
+            self.current_time,
+        ) else {
+            return;
+        };
+        if track_closed(&self.disruptions, next_track_id, self.current_time) {
+            *self.disruption_delays.entry(train).or_default() += time_left;
+            return;
+        }
+        let next_track = &self.tracks[&next_track_id];
+        let open = open_tracks(&self.disruptions, next_track_id, self.current_time);
+        let lane = roomiest_lane(next_track, &self.trains, open);
+        let limit = next_track.lanes[lane]
+            .back()
+            .map_or(f64::INFINITY, |last_train| {
+                signal_behind(self.trains[last_train].tail(), next_track.block_length)
+            });
+        if limit <= 0.0 {
+            return;
+        }
+        self.stations.get_mut(&station).unwrap().bypass = None;
+        self.tracks.get_mut(&next_track_id).unwrap().lanes[lane].push_back(train);
+        let speed = self.run_speed(route_id, next_track_id, self.rolling_stock[&route_id].speed);
+        let train_mut = self.trains.get_mut(&train).unwrap();
+        train_mut.curr_section = TrackStationId::Track(next_track_id);
+        train_mut.speed = speed;
+        train_mut.pos = f64_min(time_left * speed, limit);
+    }
+
+    fn crowding_alerts(&self) -> Vec<CrowdingAlert> {
+        let mut alerts: Vec<_> = self
+            .crowding_alerts
+            .iter()
+            .chain(self.open_crowding_alerts.values())
+            .cloned()
+            .collect();
+        alerts.sort_by(|a, b| {
+            a.start
+                .total_cmp(&b.start)
+                .then(a.station.index().cmp(&b.station.index()))
+        });
+        alerts
+    }
+
+    // Moves passengers who have arrived by time into their origin stations, and those waiting at
+    // closed stations on to others
+    fn release_passengers(&mut self, time: f64) {
+        while let Some(passenger) = self.demand.get(self.next_passenger) {
+            if passenger.arrival_time > time {
+                break;
+            }
+            if self.shut_down(passenger.arrival_time) {
+                if passenger.arrival_time >= 0.0 {
+                    self.turned_away.push(passenger.arrival_time);
+                }
+                self.next_passenger += 1;
+                continue;
+            }
+            let mut passenger = passenger.clone();
+            self.next_passenger += 1;
+            // riders weighing crowding set out on whichever of their paths looks best now
+            if let (Some(crowding), Some(choices)) = (self.config.crowding, &passenger.choices) {
+                let cost = |choice: &RouteChoice| self.crowded_cost(choice, crowding);
+                let best = (0..choices.len())
+                    .min_by(|&a, &b| cost(&choices[a]).total_cmp(&cost(&choices[b])))
+                    .unwrap_or_default();
+                if best > 0 {
+                    passenger.legs = choices[best].legs.clone();
+                    if passenger.arrival_time >= 0.0 {
+                        self.crowding_detours += 1;
+                    }
+                }
+            }
+            let origin = passenger.legs[0].board;
+            self.stations
+                .get_mut(&origin)
+                .unwrap()
+                .waiting
+                .push_back(passenger);
+        }
+        self.divert_from_closures(time);
+    }
+
+    // Cost of a path to a rider weighing up crowding, going by how loaded trains last left the
+    // start of each of its legs
+    fn crowded_cost(&self, choice: &RouteChoice, crowding: CrowdingChoice) -> f64 {
+        let crowded: f64 = choice
+            .legs
+            .iter()
+            .zip(&choice.ride_times)
+            .map(|(leg, ride_time)| {
+                let load = leg
+                    .routes
+                    .iter()
+                    .filter_map(|route| self.departure_loads.get(&(*route, leg.board)))
+                    .copied()
+                    .reduce(f64::min)
+                    .unwrap_or_default();
+                ride_time * crowding.disutility * (load - crowding.threshold).max(0.0)
+            })
+            .sum();
+        choice.cost + crowded
+    }
+
+    // Whether time falls in one of the profile's shutdown periods
+    fn shut_down(&self, time: f64) -> bool {
+        self.shutdowns
+            .iter()
+            .any(|&(start, end)| start <= time && time < end)
+    }
+
+    // Lets passengers off a train stopped at a station, then boards waiting passengers for its
+    // route while there's room
+    fn exchange_passengers(&mut self, station_id: StationId, train_id: TrainId, time: f64) {
+        if let Some((route, due)) = self.timetabled.remove(&(train_id, station_id)) {
+            if due >= 0.0 {
+                self.schedule_deviations
+                    .entry((route, station_id))
+                    .or_default()
+                    .push(time - due);
+            }
+        }
+        self.express_due.remove(&(train_id, station_id));
+        let route_id = self.trains[&train_id].route;
+        if let Some(lateness) = self.skipping.get(&(train_id, station_id)).copied() {
+            if time >= 0.0 {
+                let passed_by = self.stations[&station_id]
+                    .waiting
+                    .iter()
+                    .filter(|passenger| passenger.legs[0].routes.contains(&route_id))
+                    .count();
+                self.skipped_stops.push(SkippedStop {
+                    station: station_id,
+                    train: train_id,
+                    time,
+                    lateness,
+                    passed_by,
+                });
+            }
+            return;
+        }
+        let route = &self.routes[&route_id];
+        if route.pass_through.contains(&station_id) {
+            return;
+        }
+        if closed_through(
+            &self.station_closures,
+            route,
+            route_id,
+            &self.reroutes,
+            station_id,
+            time,
+        ) {
+            self.run_through_closure(station_id, train_id, time);
+            return;
+        }
+        // riders of a train being pushed off get off at its next stop to wait for another train,
+        // and nobody boards
+        let pushed = self.pushed.contains_key(&train_id);
+        if pushed {
+            self.set_down(station_id, train_id, time);
+        }
+        let route = &self.routes[&route_id];
+        let train = self.trains.get_mut(&train_id).unwrap();
+        let (alighting, staying): (Vec<_>, Vec<_>) = train
+            .passengers
+            .drain(..)
+            .partition(|p| p.legs[0].alight == station_id);
+        train.passengers = staying;
+
+        let capacity = self.rolling_stock[&train.route]
+            .capacity
+            .unwrap_or(self.config.train_capacity);
+        let station = self.stations.get_mut(&station_id).unwrap();
+        // while rerouted, riders only board trains which still get them where they're going
+        let serves = |alight: StationId| {
+            let mut station = station_id;
+            let mut visited = HashSet::new();
+            while visited.insert(station) {
+                match route_track(route, train.route, &self.reroutes, station, time) {
+                    Some(track) => station = self.subway_map.edge_endpoints(track).unwrap().1,
+                    None => break,
+                }
+                if station == alight {
+                    return true;
+                }
+            }
+            false
+        };
+        let rerouted = self.reroutes.iter().any(|reroute| {
+            reroute.route == train.route && reroute.start <= time && time < reroute.end
+        });
+        // riders changing trains can't board until they've made it to this route's platform
+        let per_minute = self.config.time_unit.per_minute();
+        let at_platform = |passenger: &Passenger| match passenger.transferring {
+            Some((from, since)) => self.routes[&from]
+                .transfer_times
+                .get(&(station_id, train.route))
+                .is_none_or(|walk| time >= since + walk * per_minute),
+            // riders walking over from another station start waiting once they get here
+            None => passenger.waiting_since <= time,
+        };
+        // riders who can still wait let a crowded train go by for a roomier one
+        let crowding = self.config.crowding;
+        let lets_go_by = |passenger: &Passenger, load: usize| {
+            crowding.is_some_and(|crowding| {
+                load as f64 >= crowding.threshold * capacity as f64
+                    && time - passenger.waiting_since < crowding.max_skip_wait
+            })
+        };
+        let mut i = 0;
+        let mut boarded = 0;
+        while !pushed && train.passengers.len() < capacity && i < station.waiting.len() {
+            let leg = &station.waiting[i].legs[0];
+            if leg.routes.contains(&train.route)
+                && (!rerouted || serves(leg.alight))
+                && at_platform(&station.waiting[i])
+            {
+                if lets_go_by(&station.waiting[i], train.passengers.len()) {
+                    if time >= 0.0 {
+                        self.crowding_skips += 1;
+                    }
+                    i += 1;
+                    continue;
+                }
+                boarded += 1;
+                let mut passenger = station.waiting.remove(i).unwrap();
+                passenger.transferring = None;
+                station.passenger_waits.push(time - passenger.waiting_since);
+                station.boarded_at.push(time);
+                if let Some(journey) = &mut passenger.journey {
+                    journey.events.push(JourneyEvent::Board {
+                        station: station_id,
+                        train: train_id,
+                        time,
+                        waited: time - passenger.waiting_since,
+                    });
+                }
+                train.passengers.push(passenger);
+            } else {
+                i += 1;
+            }
+        }
+        if !pushed {
+            let load_factor = train.passengers.len() as f64 / capacity as f64;
+            self.departure_loads
+                .insert((train.route, station_id), load_factor);
+            // trains don't leave the end of their route with riders
+            let leaves =
+                route_track(route, train.route, &self.reroutes, station_id, time).is_some();
+            if time >= 0.0 && !route.deadhead && leaves {
+                self.load_factors
+                    .entry(route.parent.unwrap_or(train.route))
+                    .or_default()
+                    .push(load_factor);
+            }
+        }
+        // riders are put down to the train they actually boarded, whichever of the routes
+        // sharing the track it was on
+        if time >= 0.0 && boarded > 0 {
+            *self
+                .boardings
+                .entry(route.parent.unwrap_or(train.route))
+                .or_default() += boarded;
+        }
+
+        // passengers with further legs transfer to the station those start at
+        let from_route = train.route;
+        for mut passenger in alighting {
+            passenger.legs.pop_front();
+            let next_board = passenger.legs.front().map(|leg| leg.board);
+            if let Some(journey) = &mut passenger.journey {
+                journey.events.push(JourneyEvent::Alight {
+                    station: station_id,
+                    train: train_id,
+                    time,
+                });
+                match next_board {
+                    Some(board) if board != station_id => journey.events.push(JourneyEvent::Walk {
+                        from: station_id,
+                        to: board,
+                        time,
+                    }),
+                    Some(_) => {}
+                    None => journey.completed_at = Some(time),
+                }
+            }
+            if let Some(board) = next_board {
+                let walk = self.walk_times.get(&(station_id, board));
+                passenger.waiting_since = time + walk.copied().unwrap_or_default();
+                passenger.transferring = (board == station_id).then_some((from_route, time));
+                self.stations
+                    .get_mut(&board)
+                    .unwrap()
+                    .waiting
+                    .push_back(passenger);
+            } else {
+                self.journey_times.push(time - passenger.arrival_time);
+                self.journey_starts.push(passenger.arrival_time);
+                if let Some(journey) = passenger.journey {
+                    self.journeys.push(*journey);
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.trains.clear();
+        self.closure_effects = vec![ClosureEffect::default(); self.station_closures.len()];
+        self.curr_train_counts = vec![0; self.routes.len()];
+        for station in self.stations.values_mut() {
+            station.arrival_times = HashMap::new();
+            station.train = None;
+            station.bypass = None;
+            station.waiting.clear();
+            station.passenger_waits.clear();
+            station.boarded_at.clear();
+        }
+        self.next_passenger = 0;
+        self.journey_times.clear();
+        self.journey_starts.clear();
+        self.turned_away.clear();
+        self.journeys.clear();
+        self.crowding_alerts.clear();
+        self.open_crowding_alerts.clear();
+        self.operating_distances.clear();
+        self.energy.clear();
+        self.boardings.clear();
+        self.load_factors.clear();
+        self.bunching.clear();
+        self.timetabled.clear();
+        self.padded.clear();
+        self.padding_use.clear();
+        self.schedule_deviations.clear();
+        self.recorder = FlightRecorder::default();
+        self.disruption_delays.clear();
+        self.short_turns.clear();
+        self.holds.clear();
+        self.extra_departures.clear();
+        self.cancellations.clear();
+        self.circling.clear();
+        self.gap_trains.clear();
+        self.fleet_queue.clear();
+        self.fleet_waits.clear();
+        self.last_departures.clear();
+        self.hold_times.clear();
+        self.train_trips.clear();
+        self.open_trips.clear();
+        self.transfer_waits.clear();
+        self.coordinated_transfers.clear();
+        self.overtakes.clear();
+        self.express_due.clear();
+        self.skipping.clear();
+        self.skipped_stops.clear();
+        self.departure_loads.clear();
+        self.crowding_skips = 0;
+        self.crowding_detours = 0;
+        self.bypass_use.clear();
+        self.incidents.clear();
+        self.stalled.clear();
+        self.incident_blocks.clear();
+        self.rescues.clear();
+        self.pushed.clear();
+        self.merge_waits.clear();
+        self.merge_from.clear();
+        self.peak_trains = 0;
+        self.in_service.clear();
+        for track in self.tracks.values_mut() {
+            for lane in &mut track.lanes {
+                lane.clear();
+            }
+        }
+    }
+
+    // Puts a train which finished its route at a terminal into service on next_route, once the
+    // station that route starts at is free
+    fn turn_around(&mut self, train_id: TrainId, terminal: StationId, next_route: RouteId) {
+        let start_station = self.routes[&next_route].start_station;
+        if start_station != terminal {
+            if self.stations[&start_station].train.is_some() {
+                return;
+            }
+            self.stations.get_mut(&terminal).unwrap().train = None;
+            self.stations.get_mut(&start_station).unwrap().train = Some(train_id);
+        }
+
+        let train = self.trains.get_mut(&train_id).unwrap();
+        train.route = next_route;
+        train.curr_section = TrackStationId::Station(start_station);
+        // progress through the dwell starts negative to account for the layover
+        train.pos = -self.config.layover_time();
+        if self.current_time >= 0.0 && !self.routes[&next_route].deadhead {
+            record_arrival(
+                &mut self.stations.get_mut(&start_station).unwrap().arrival_times,
+                &mut self.bunching,
+                &self.config,
+                start_station,
+                next_route,
+                self.current_time,
+            );
+        }
+        self.start_trip(train_id);
+        self.exchange_passengers(start_station, train_id, self.current_time);
+    }
+
+    // Starts the trip of a train on the route it's now on. Depot moves carry on the trip of the
+    // route they were made for.
+    fn start_trip(&mut self, train: TrainId) {
+        let route = self.trains[&train].route;
+        let route = self.routes[&route].parent.unwrap_or(route);
+        if let Some(trip) = self.open_trips.get(&train) {
+            if self.train_trips[*trip].route == route {
+                return;
+            }
+        }
+        self.end_trip(train);
+        self.open_trips.insert(train, self.train_trips.len());
+        self.train_trips.push(TrainTrip {
+            train,
+            route,
+            start: self.current_time,
+            end: None,
+        });
+    }
+
+    fn end_trip(&mut self, train: TrainId) {
+        if let Some(trip) = self.open_trips.remove(&train) {
+            self.train_trips[trip].end = Some(self.current_time);
+        }
+    }
+
+    fn dwell_time(&self, route: RouteId, station: StationId) -> f64 {
+        self.dwell_times
+            .get(&(route, station))
+            .copied()
+            .unwrap_or(self.stations[&station].dwell_time)
+    }
+
+    // How long a train dwells at a station, not at all if it's skipping the stop or the station
+    // is closed
+    fn stop_dwell(&self, train: TrainId, station: StationId) -> f64 {
+        if self.runs_through(train, station, self.current_time) {
+            0.0
+        } else {
+            self.dwell_time(self.trains[&train].route, station)
+        }
+    }
+
+    // Whether a train runs through a station it would stop at, skipping it, being pushed off or
+    // the station being closed
+    fn runs_through(&self, train: TrainId, station: StationId, time: f64) -> bool {
+        let route = self.trains[&train].route;
+        self.skipping.contains_key(&(train, station))
+            || self.pushed.contains_key(&train)
+            || closed_through(
+                &self.station_closures,
+                &self.routes[&route],
+                route,
+                &self.reroutes,
+                station,
+                time,
+            )
+    }
+
+    // Next station after this one a route's trains stop at, following closures at time
+    fn next_stop(&self, id: RouteId, station: StationId, time: f64) -> Option<StationId> {
+        let route = &self.routes[&id];
+        let mut at = station;
+        let mut visited = HashSet::new();
+        while visited.insert(at) {
+            let track = route_track(route, id, &self.reroutes, at, time)?;
+            at = self.subway_map.edge_endpoints(track).unwrap().1;
+            if !route.pass_through.contains(&at)
+                && !closed_through(&self.station_closures, route, id, &self.reroutes, at, time)
+            {
+                return Some(at);
+            }
+        }
+        None
+    }
+
+    // Whether a route's trains stop at from and ride on to to
+    fn rides(&self, id: RouteId, from: StationId, to: StationId, time: f64) -> bool {
+        let route = &self.routes[&id];
+        if route.pass_through.contains(&from) {
+            return false;
+        }
+        let mut at = from;
+        let mut visited = HashSet::new();
+        while visited.insert(at) {
+            let Some(track) = route_track(route, id, &self.reroutes, at, time) else {
+                return false;
+            };
+            at = self.subway_map.edge_endpoints(track).unwrap().1;
+            if at == to {
+                return true;
+            }
+        }
+        false
+    }
+
+    // Sends riders waiting at closed stations to the nearest open station a walk away they can
+    // board one of their routes at, going on to where they're getting off. Those with nowhere
+    // to go wait for the station to reopen.
+    fn divert_from_closures(&mut self, time: f64) {
+        for (i, closure) in self.station_closures.clone().iter().enumerate() {
+            if time < closure.start || time >= closure.end {
+                continue;
+            }
+            let station = closure.station;
+            let waiting = std::mem::take(&mut self.stations.get_mut(&station).unwrap().waiting);
+            let mut staying = VecDeque::new();
+            for mut passenger in waiting {
+                let leg = &passenger.legs[0];
+                let nearest = self
+                    .walk_times
+                    .iter()
+                    .filter(|((from, to), _)| {
+                        *from == station
+                            && closure_at(&self.station_closures, *to, time).is_none()
+                            && leg
+                                .routes
+                                .iter()
+                                .any(|route| self.rides(*route, *to, leg.alight, time))
+                    })
+                    .min_by(|((_, a), a_walk), ((_, b), b_walk)| {
+                        a_walk.total_cmp(b_walk).then(a.cmp(b))
+                    })
+                    .map(|((_, to), walk)| (*to, *walk));
+                let Some((to, walk)) = nearest else {
+                    staying.push_back(passenger);
+                    continue;
+                };
+                passenger.legs[0].board = to;
+                passenger.transferring = None;
+                passenger.waiting_since = passenger.waiting_since.max(time) + walk;
+                if let Some(journey) = &mut passenger.journey {
+                    journey.events.push(JourneyEvent::Walk {
+                        from: station,
+                        to,
+                        time,
+                    });
+                }
+                self.stations
+                    .get_mut(&to)
+                    .unwrap()
+                    .waiting
+                    .push_back(passenger);
+                if time >= 0.0 {
+                    self.closure_effects[i].riders_diverted += 1;
+                }
+            }
+            self.stations.get_mut(&station).unwrap().waiting = staying;
+        }
+    }
+
+    // Runs a train through a closed station, riders getting off there staying on to the next
+    // stop
+    fn run_through_closure(&mut self, station: StationId, train: TrainId, time: f64) {
+        let route = self.trains[&train].route;
+        let next = self.next_stop(route, station, time);
+        let mut carried_past = 0;
+        for passenger in &mut self.trains.get_mut(&train).unwrap().passengers {
+            if let (Some(next), true) = (next, passenger.legs[0].alight == station) {
+                passenger.legs[0].alight = next;
+                carried_past += 1;
+            }
+        }
+        if time >= 0.0 {
+            if let Some(i) = closure_at(&self.station_closures, station, time) {
+                let effect = &mut self.closure_effects[i];
+                effect.trains_run_through += 1;
+                effect.riders_carried_past += carried_past;
+            }
+        }
+    }
+
+    // Has a train leaving a station skip the next one if it'll get there late enough, it's a
+    // station trains may skip and no one on board is getting off there. The end of the line and
+    // the start of a loop are never skipped.
+    fn plan_skip(&mut self, train: TrainId, station: StationId) {
+        let Some(express) = self.config.auto_express else {
+            return;
+        };
+        let train_ref = &self.trains[&train];
+        let route = &self.routes[&train_ref.route];
+        let next_station = |at| {
+            route_track(
+                route,
+                train_ref.route,
+                &self.reroutes,
+                at,
+                self.current_time,
+            )
+            .map(|track| self.subway_map.edge_endpoints(track).unwrap().1)
+        };
+        let Some(next) = next_station(station) else {
+            return;
+        };
+        if !self.subway_map[next].skippable
+            || route.pass_through.contains(&next)
+            || next_station(next).is_none()
+            || self.loop_stations.get(&train_ref.route) == Some(&next)
+            || train_ref
+                .passengers
+                .iter()
+                .any(|p| p.legs[0].alight == next)
+        {
+            return;
+        }
+        let (Some(due), Some(left)) = (
+            self.express_due.get(&(train, next)),
+            self.time_to_reach(train_ref, next),
+        ) else {
+            return;
+        };
+        let lateness = self.current_time + left - due;
+        if lateness > express.threshold {
+            self.skipping.insert((train, next), lateness);
+        }
+    }
+
+    // Takes a train out of service at a station, leaving its riders there to wait for service
+    // to resume
+    fn short_turn(&mut self, train: TrainId, station: StationId) {
+        self.take_out_of_service(train, station);
+        self.short_turns.push((train, station));
+    }
+
+    // Puts the riders of a train down at a station to wait there for another train of their leg,
+    // leaving aboard those getting off there anyway
+    fn set_down(&mut self, station: StationId, train: TrainId, time: f64) {
+        let train_mut = self.trains.get_mut(&train).unwrap();
+        let (set_down, staying): (Vec<_>, Vec<_>) = train_mut
+            .passengers
+            .drain(..)
+            .partition(|passenger| passenger.legs[0].alight != station);
+        train_mut.passengers = staying;
+        let station_mut = self.stations.get_mut(&station).unwrap();
+        for mut passenger in set_down {
+            passenger.legs[0].board = station;
+            passenger.waiting_since = time;
+            passenger.transferring = None;
+            if let Some(journey) = &mut passenger.journey {
+                journey.events.push(JourneyEvent::Stranded {
+                    station,
+                    train,
+                    time,
+                });
+            }
+            station_mut.waiting.push_back(passenger);
+        }
+    }
+
+    fn take_out_of_service(&mut self, train: TrainId, station: StationId) {
+        self.end_trip(train);
+        let passengers = self.trains.remove(&train).unwrap().passengers;
+        let station_mut = self.stations.get_mut(&station).unwrap();
+        station_mut.train = None;
+        for mut passenger in passengers {
+            passenger.waiting_since = self.current_time;
+            if let Some(journey) = &mut passenger.journey {
+                journey.events.push(JourneyEvent::Stranded {
+                    station,
+                    train,
+                    time: self.current_time,
+                });
+            }
+            station_mut.waiting.push_back(passenger);
+        }
+    }
+
+    fn held(&self, train: TrainId, station: StationId) -> f64 {
+        self.holds
+            .get(&(train, station))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // Decides how much longer a train whose dwell was up at ready is held at a station, only the
+    // first time it's asked at each stop, returning 0 after
+    fn hold(&mut self, train: TrainId, station: StationId, ready: f64) -> f64 {
+        if self.holds.contains_key(&(train, station))
+            || self.runs_through(train, station, self.current_time)
+        {
+            return 0.0;
+        }
+        let route = self.trains[&train].route;
+        let hold = match self.config.holding {
+            Holding::None => 0.0,
+            Holding::GapBehind {
+                threshold,
+                max_hold,
+            } => {
+                let behind = self
+                    .trains
+                    .values()
+                    .filter(|other| other.route == route && other.id != train)
+                    .filter_map(|other| self.time_to_reach(other, station))
+                    .min_by(f64::total_cmp);
+                behind.map_or(0.0, |behind| (behind - threshold).clamp(0.0, max_hold))
+            }
+            Holding::HeadwayAhead {
+                min_headway,
+                max_hold,
+            } => self
+                .last_departures
+                .get(&(route, station))
+                .map_or(0.0, |departed| {
+                    (min_headway - (self.current_time - departed)).clamp(0.0, max_hold)
+                }),
+        };
+        if hold > 0.0 && self.current_time >= 0.0 {
+            self.hold_times.push(hold);
+        }
+        let hold = f64_max(hold, self.transfer_hold(train, station));
+        let hold = f64_max(hold, self.padding_hold(train, station, ready));
+        self.holds.insert((train, station), hold);
+        hold
+    }
+
+    // Wait of a train ahead of its padded schedule until it's due to leave, noting how much of
+    // the padding up to the stop it ran into
+    fn padding_hold(&mut self, train: TrainId, station: StationId, ready: f64) -> f64 {
+        let Some((departs, padding)) = self.padded.remove(&(train, station)) else {
+            return 0.0;
+        };
+        let wait = f64_max(departs - ready, 0.0);
+        if self.current_time >= 0.0 {
+            let route = self.trains[&train].route;
+            let route = self.routes[&route].parent.unwrap_or(route);
+            let padding_use = self.padding_use.entry(route).or_default();
+            padding_use.scheduled += padding;
+            padding_use.used += padding - f64_min(wait, padding);
+        }
+        wait
+    }
+
+    // Hold at a timed transfer for the riders of feeders due a walk away within the window,
+    // noting the feeders to see if the connection was made once the train leaves
+    fn transfer_hold(&mut self, train: TrainId, station: StationId) -> f64 {
+        let Some(max_hold) = self.timed_transfers.get(&station).copied() else {
+            return 0.0;
+        };
+        let route = self.trains[&train].route;
+        let mut hold = 0.0;
+        let mut feeders = Vec::new();
+        for walk in self
+            .subway_map
+            .edges_directed(station, Direction::Incoming)
+            .filter(|edge| edge.weight().ty == EdgeType::Walk)
+        {
+            let at = walk.source();
+            for feeder in self.trains.values() {
+                let feeder_route = &self.routes[&feeder.route];
+                if feeder.route == route
+                    || feeder_route.deadhead
+                    || feeder_route.pass_through.contains(&at)
+                {
+                    continue;
+                }
+                let Some(due) = self.time_to_reach(feeder, at) else {
+                    continue;
+                };
+                let ready = due + walk.weight().weight as f64;
+                if ready <= max_hold {
+                    hold = f64_max(hold, ready);
+                    feeders.push((feeder.id, at));
+                }
+            }
+        }
+        if !feeders.is_empty() {
+            self.transfer_waits.insert((train, station), feeders);
+        }
+        hold
+    }
+
+    // Average speed of a route's train over a track with a top speed of speed. It accelerates
+    // and brakes by its rolling stock for the stops either side, but keeps to the one speed
+    // along the track, so the time that takes is spread evenly over it.
+    fn run_speed(&self, route: RouteId, track: TrackId, speed: f64) -> f64 {
+        let length = self.tracks[&track].length as f64;
+        if length <= 0.0 {
+            return speed;
+        }
+        let (from, to) = self.subway_map.edge_endpoints(track).unwrap();
+        let stops = |station| !self.routes[&route].pass_through.contains(&station);
+        length / self.rolling_stock[&route].run_time(length, speed, stops(from), stops(to))
+    }
+
+    // Time a train would take to get to a station further along its route at full speed,
+    // None if it doesn't pass it
+    fn time_to_reach(&self, train: &Train, station: StationId) -> Option<f64> {
+        let speed = self.rolling_stock[&train.route].speed;
+        let (mut time, mut at) = match train.curr_section {
+            TrackStationId::Station(at) => (
+                f64_max(self.dwell_time(train.route, at) - train.pos, 0.0),
+                at,
+            ),
+            TrackStationId::Track(track) => {
+                let (_, at) = self.subway_map.edge_endpoints(track).unwrap();
+                let left = (self.tracks[&track].length as f64 - train.pos) / train.speed.max(speed);
+                if at == station {
+                    return Some(left);
+                }
+                (left + self.dwell_time(train.route, at), at)
+            }
+        };
+        let mut visited = HashSet::new();
+        while visited.insert(at) {
+            let track = route_track(
+                &self.routes[&train.route],
+                train.route,
+                &self.reroutes,
+                at,
+                self.current_time,
+            )?;
+            time += self.tracks[&track].length as f64 / self.run_speed(train.route, track, speed);
+            at = self.subway_map.edge_endpoints(track).unwrap().1;
+            if at == station {
+                return Some(time);
+            }
+            time += self.dwell_time(train.route, at);
+        }
+        None
+    }
+
+    // Whether a train at the end of track should let one on another of the station's approaches
+    // in first, going by the merge priority. Other trains count once they wait at the end of
+    // their track, or when they'd get there within lookahead.
+    fn gives_way(
+        &self,
+        station: StationId,
+        track: TrackId,
+        train: TrainId,
+        lookahead: f64,
+    ) -> bool {
+        let arrival = |train: TrainId, track: TrackId| {
+            self.merge_waits.get(&train).copied().unwrap_or_else(|| {
+                let train = &self.trains[&train];
+                let left = f64_max(self.tracks[&track].length as f64 - train.pos, 0.0);
+                self.current_time + left / train.speed
+            })
+        };
+        let rank = |train: TrainId, track: TrackId| {
+            let class = match &self.config.merge_priority {
+                MergePriority::FirstCome => 0,
+                MergePriority::Alternate => {
+                    usize::from(self.merge_from.get(&station) == Some(&track))
+                }
+                MergePriority::RoutePriority(order) => {
+                    let route = self.trains[&train].route;
+                    let route = self.routes[&route].parent.unwrap_or(route);
+                    order
+                        .iter()
+                        .position(|name| *name == self.routes[&route].name)
+                        .unwrap_or(order.len())
+                }
+            };
+            (class, arrival(train, track), (train.route_idx, train.count))
+        };
+        let own = rank(train, track);
+        self.subway_map
+            .edges_directed(station, Direction::Incoming)
+            .filter(|edge| {
+                edge.id() != track && !track_closed(&self.disruptions, edge.id(), self.current_time)
+            })
+            .filter_map(|edge| self.tracks.get(&edge.id()).map(|other| (edge.id(), other)))
+            .flat_map(|(id, other)| {
+                other
+                    .lanes
+                    .iter()
+                    .filter_map(move |lane| lane.front().map(|front| (*front, id)))
+            })
+            .filter(|(other, other_track)| {
+                self.merge_waits.contains_key(other)
+                    || lookahead > 0.0
+                        && arrival(*other, *other_track) <= self.current_time + lookahead
+            })
+            .any(|(other, other_track)| rank(other, other_track) < own)
+    }
+
+    // Bookkeeping for a train pulling out of a station onto its next track
+    fn departed(&mut self, train: TrainId, station: StationId) {
+        self.recorder
+            .moved(self.current_time, train, station, false);
+        self.holds.remove(&(train, station));
+        self.skipping.remove(&(train, station));
+        self.plan_skip(train, station);
+        if self.loop_stations.get(&self.trains[&train].route) == Some(&station) {
+            self.circling.insert(train);
+        }
+        // a feeder still on its way missed the connection
+        for (feeder, at) in self
+            .transfer_waits
+            .remove(&(train, station))
+            .unwrap_or_default()
+        {
+            let missed = self.trains.get(&feeder).is_some_and(|feeder| {
+                feeder.curr_section != TrackStationId::Station(at)
+                    && self.time_to_reach(feeder, at).is_some()
+            });
+            if self.current_time >= 0.0 {
+                self.coordinated_transfers.push(CoordinatedTransfer {
+                    station,
+                    train,
+                    feeder,
+                    time: self.current_time,
+                    made: !missed,
+                });
+            }
+        }
+        let route = self.trains[&train].route;
+        self.last_departures
+            .insert((route, station), self.current_time);
+    }
+
+    fn station_to_track(&mut self, station: StationId, mut time_left: f64) {
+        self.bypass_to_track(station, time_left);
+        if let Some(train) = &self.stations[&station].train {
+            let train = *train;
+            if self.cancellations.remove(&train) {
+                self.take_out_of_service(train, station);
+                return;
+            }
+            // a broken down train goes nowhere
+            if self.stalled.contains_key(&train) {
+                return;
+            }
+            let dwell_time = self.stop_dwell(train, station) + self.held(train, station);
+            let train_mut = self.trains.get_mut(&train).unwrap();
+            let distance_travelled = f64_max(f64_min(dwell_time - train_mut.pos, time_left), 0.0);
+            train_mut.pos += distance_travelled;
+            time_left -= distance_travelled;
+
+            if train_mut.pos < dwell_time {
+                return;
+            }
+
+            let route_id = train_mut.route;
+            let next_track_id = route_track(
+                &self.routes[&route_id],
+                route_id,
+                &self.reroutes,
+                station,
+                self.current_time,
+            );
+            // a train pushed off leaves service at the end of its route, or the start of its loop
+            if next_track_id.is_none() || self.loop_stations.get(&route_id) == Some(&station) {
+                if let Some((incident, _)) = self.pushed.remove(&train) {
+                    self.incidents[incident].cleared = Some(self.current_time);
+                    self.take_out_of_service(train, station);
+                    return;
+                }
+            }
+            let next_track_id = match next_track_id {
+                Some(next_track_id) => next_track_id,
+                None => {
+                    match self.terminal_links.get(&(route_id, station)).copied() {
+                        Some(next_route) => self.turn_around(train, station, next_route),
+                        None => {
+                            self.end_trip(train);
+                            self.trains.remove(&train);
+                            self.stations.get_mut(&station).unwrap().train = None;
+                        }
+                    }
+                    return;
+                }
+            };
+            // a train back at the start of its loop waits for its next departure
+            if self.circling.contains(&train) && self.loop_stations.get(&route_id) == Some(&station)
+            {
+                return;
+            }
+            // a held train spends the rest of the step waiting
+            let ready = self.current_time + self.time_step - time_left;
+            if self.hold(train, station, ready) > 0.0 {
+                return;
+            }
+            if track_closed(&self.disruptions, next_track_id, self.current_time) {
+                match self.config.disruption_response {
+                    DisruptionResponse::ShortTurn if self.subway_map[station].crossover => {
+                        self.short_turn(train, station)
+                    }
+                    DisruptionResponse::ShortTurn | DisruptionResponse::Hold => {
+                        *self.disruption_delays.entry(train).or_default() += time_left;
+                    }
+                }
+                return;
+            }
+            let noise = match (&mut self.noise_rng, &self.config.travel_time_noise) {
+                (Some(rng), Some(noise)) => noise.sample_speed(rng),
+                _ => 1.0,
+            };
+            let push_speed = self.pushed.get(&train).map_or(1.0, |(_, speed)| *speed);
+            let speed = self.run_speed(
+                route_id,
+                next_track_id,
+                noise * self.rolling_stock[&route_id].speed,
+            ) * push_speed;
+            let open = open_tracks(&self.disruptions, next_track_id, self.current_time);
+            let next_track: &mut Track = self.tracks.get_mut(&next_track_id).unwrap();
+            let lane = roomiest_lane(next_track, &self.trains, open);
+            let next_lane = &mut next_track.lanes[lane];
+            let last_train = next_lane.back();
+            if let Some(last_train) = last_train {
+                let limit = signal_behind(self.trains[last_train].tail(), next_track.block_length);
+                // only move the train off the station once the first block is clear
+                if limit > 0.0 {
+                    self.stations.get_mut(&station).unwrap().train = None;
+                    next_lane.push_back(train);
+                    self.departed(train, station);
+                    let pos_move = f64_min(time_left * speed, limit);
+                    let train_mut = self.trains.get_mut(&train).unwrap();
+                    train_mut.pos = pos_move;
+                    train_mut.speed = speed;
+                    train_mut.distance_travelled += dwell_time;
+                    train_mut.curr_section = TrackStationId::Track(next_track_id);
+                } else {
+                    let last_train = *last_train;
+                    self.blocked_by(train, Some(last_train), time_left);
+                }
+            } else {
+                self.stations.get_mut(&station).unwrap().train = None;
+                let train_mut = self.trains.get_mut(&train).unwrap();
+                train_mut.curr_section = TrackStationId::Track(next_track_id);
+                train_mut.speed = speed;
+                train_mut.pos = time_left * speed;
+                next_lane.push_back(train);
+                self.departed(train, station);
+            }
+        }
+    }
+
+    // Whether a route dispatches a train at step t, by its timetable if it has one
+    fn departs(&self, profile: &HeadwayProfile, route: RouteId, t: i32) -> bool {
+        let route_ref = &self.routes[&route];
+        match &route_ref.timetable {
+            _ if self.shut_down(t as f64) => false,
+            Some(timetable) => timetable.contains(&t),
+            None => profile.departs(route, route_ref.offset, t),
+        }
+    }
+
+    // Notes when a train just dispatched onto a timetabled route is due at each of its stops,
+    // running at full speed with the usual dwells
+    fn timetable_stops(&mut self, train_id: TrainId, time: f64) {
+        let train = &self.trains[&train_id];
+        let route = &self.routes[&train.route];
+        let timetabled = route.parent.unwrap_or(train.route);
+        if self.routes[&timetabled].timetable.is_none() {
+            return;
+        }
+        // the train first moves on the step after it's dispatched
+        let start = time + self.time_step;
+        let due: Vec<_> = route
+            .station_to
+            .values()
+            .map(|track| self.subway_map.edge_endpoints(*track).unwrap().1)
+            .filter(|station| {
+                *station != route.start_station && !route.pass_through.contains(station)
+            })
+            .filter_map(|station| {
+                self.time_to_reach(train, station)
+                    .map(|left| (station, start + left))
+            })
+            .collect();
+        for (station, at) in due {
+            self.timetabled
+                .insert((train_id, station), (timetabled, at));
+        }
+    }
+
+    // Notes when a train just dispatched is due at each station along its route, for telling
+    // how late it runs when auto expressing. Padded runs count as taking their padding.
+    fn express_stops(&mut self, train_id: TrainId, time: f64) {
+        if self.config.auto_express.is_none() {
+            return;
+        }
+        let train = &self.trains[&train_id];
+        let route = &self.routes[&train.route];
+        let padding = self.routes[&route.parent.unwrap_or(train.route)].padding;
+        let due: Vec<_> = route
+            .station_to
+            .values()
+            .map(|track| self.subway_map.edge_endpoints(*track).unwrap().1)
+            .filter_map(|station| {
+                self.time_to_reach(train, station)
+                    .map(|left| (station, time + left * (1.0 + padding)))
+            })
+            .collect();
+        for (station, at) in due {
+            self.express_due.insert((train_id, station), at);
+        }
+    }
+
+    // Notes when a train just dispatched onto a padded route is due to leave each of its stops,
+    // its runs there stretched by the route's padding
+    fn padded_stops(&mut self, train_id: TrainId, time: f64) {
+        let train = &self.trains[&train_id];
+        let route = &self.routes[&train.route];
+        let padding = self.routes[&route.parent.unwrap_or(train.route)].padding;
+        if padding <= 0.0 {
+            return;
+        }
+        let speed = self.rolling_stock[&train.route].speed;
+        let mut at = route.start_station;
+        // the train first moves on the step after it's dispatched
+        let mut departs = time + self.time_step + self.dwell_time(train.route, at);
+        // padding of the runs through stations the train doesn't stop at adds up to the next stop
+        let mut run_padding = 0.0;
+        let mut due = Vec::new();
+        let mut visited = HashSet::new();
+        while visited.insert(at) {
+            let Some(track) = route_track(route, train.route, &self.reroutes, at, time) else {
+                break;
+            };
+            let run = self.tracks[&track].length as f64 / self.run_speed(train.route, track, speed);
+            at = self.subway_map.edge_endpoints(track).unwrap().1;
+            run_padding += run * padding;
+            departs += run * (1.0 + padding) + self.dwell_time(train.route, at);
+            if at != route.start_station && !route.pass_through.contains(&at) {
+                due.push((at, departs, run_padding));
+                run_padding = 0.0;
+            }
+        }
+        for (station, departs, padding) in due {
+            self.padded.insert((train_id, station), (departs, padding));
+        }
+    }
+
+    // Dispatches the trains departing at step t, returning them with the station they start at
+    fn dispatch(
+        &mut self,
+        t: i32,
+        profile: &HeadwayProfile,
+        train_to_route: &mut HashMap<TrainId, RouteId>,
+    ) -> Vec<(StationId, TrainId)> {
+        let departing: Vec<_> = self
+            .routes
+            .iter()
+            .filter(|(id, route)| {
+                route.dispatched
+                    && route.variant_of.is_none()
+                    && self.departs(profile, route.parent.unwrap_or(**id), t)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        let mut dispatched = Vec::new();
+        // departures left waiting when the system shuts are dropped with the rest of the service
+        if self.shut_down(t as f64) {
+            self.fleet_queue.clear();
+        }
+        // departures still waiting for a train go first, in the order they were due
+        for (id, wait) in std::mem::take(&mut self.fleet_queue) {
+            if self.fleet_full(id) {
+                self.fleet_queue.push((id, wait));
+                continue;
+            }
+            let start_station = self.routes[&id].start_station;
+            let train = self.dispatch_train(id, start_station, t, train_to_route);
+            if let (Some(wait), Some(_)) = (wait, train) {
+                self.fleet_waits[wait].dispatched = Some(t as f64);
+            }
+            dispatched.extend(train);
+        }
+        for id in departing {
+            let id = match self.variants.get(&id) {
+                Some(variants) => dispatch_turn(id, variants, &self.curr_train_counts),
+                None => id,
+            };
+            // a loop sends round a train which finished its lap rather than a new one
+            if let Some(train) = self.lapped_train(id) {
+                self.circling.remove(&train);
+                dispatched.push((self.loop_stations[&id], train));
+                continue;
+            }
+            let fleet_route = self.fleet_route(id);
+            let waiting = self
+                .fleet_queue
+                .iter()
+                .any(|(waiting, _)| self.fleet_route(*waiting) == fleet_route);
+            if waiting || self.fleet_full(id) {
+                let wait = (t >= 0).then(|| {
+                    self.fleet_waits.push(FleetWait {
+                        route: fleet_route,
+                        scheduled: t as f64,
+                        dispatched: None,
+                    });
+                    self.fleet_waits.len() - 1
+                });
+                // the first train free takes the departure already waiting, dropping this one
+                if !waiting {
+                    self.fleet_queue.push((id, wait));
+                }
+                continue;
+            }
+            // TODO: do I need to handle the case where the start platform is taken?
+            let start_station = self.routes[&id].start_station;
+            dispatched.extend(self.dispatch_train(id, start_station, t, train_to_route));
+        }
+        for (_, train) in &dispatched {
+            self.start_trip(*train);
+            self.timetable_stops(*train, t as f64);
+            self.padded_stops(*train, t as f64);
+            self.express_stops(*train, t as f64);
+        }
+        // extra trains wait for their first platform to be free
+        for id in std::mem::take(&mut self.extra_departures) {
+            if self.fleet_full(id) {
+                self.extra_departures.push(id);
+                continue;
+            }
+            let start_station = self.routes[&id].start_station;
+            match self.dispatch_train(id, start_station, t, train_to_route) {
+                Some((station, train)) => {
+                    self.start_trip(train);
+                    dispatched.push((station, train));
+                }
+                None => self.extra_departures.push(id),
+            }
+        }
+        for (station, train) in self.insert_gap_trains(t, train_to_route) {
+            self.start_trip(train);
+            dispatched.push((station, train));
+        }
+        dispatched
+    }
+
+    // Route whose trains a route's count against Route::max_trains
+    fn fleet_route(&self, id: RouteId) -> RouteId {
+        let route = &self.routes[&id];
+        route.variant_of.or(route.parent).unwrap_or(id)
+    }
+
+    // Whether a route, or the whole system, has as many trains out as it's allowed
+    fn fleet_full(&self, id: RouteId) -> bool {
+        let fleet_route = self.fleet_route(id);
+        let out = || {
+            self.trains
+                .values()
+                .filter(|train| self.fleet_route(train.route) == fleet_route)
+                .count()
+        };
+        self.routes[&fleet_route]
+            .max_trains
+            .is_some_and(|max_trains| out() >= max_trains)
+            || self
+                .config
+                .max_trains
+                .is_some_and(|max_trains| self.trains.len() >= max_trains)
+    }
+
+    // Train of a loop route waiting at the start of its loop to go round again
+    fn lapped_train(&self, route: RouteId) -> Option<TrainId> {
+        let train = self.stations[self.loop_stations.get(&route)?].train?;
+        (self.circling.contains(&train) && self.trains[&train].route == route).then_some(train)
+    }
+
+    // Sends out a standby train on each route whose last train got to where they're kept longer
+    // ago than the route allows, while it has any left
+    fn insert_gap_trains(
+        &mut self,
+        t: i32,
+        train_to_route: &mut HashMap<TrainId, RouteId>,
+    ) -> Vec<(StationId, TrainId)> {
+        let per_minute = self.config.time_unit.per_minute();
+        let due: Vec<_> = self
+            .routes
+            .iter()
+            .filter_map(|(id, route)| {
+                let standby = route.standby?;
+                if self.fleet_full(*id) {
+                    return None;
+                }
+                let sent = self
+                    .gap_trains
+                    .iter()
+                    .filter(|gap_train| RouteId(gap_train.train.route_idx) == *id);
+                if sent.count() >= standby.trains as usize {
+                    return None;
+                }
+                let last = *self.stations[&standby.station]
+                    .arrival_times
+                    .get(id)?
+                    .last()?;
+                let gap = t as f64 - last;
+                (gap > standby.gap * per_minute).then_some((*id, standby.station, gap))
+            })
+            .collect();
+        let mut inserted = Vec::new();
+        for (id, station, gap) in due {
+            if let Some((station, train)) = self.dispatch_train(id, station, t, train_to_route) {
+                self.gap_trains.push(GapTrain {
+                    train,
+                    station,
+                    time: t as f64,
+                    gap,
+                });
+                inserted.push((station, train));
+            }
+        }
+        inserted
+    }
+
+    // Puts a new train of a route at a station along it, unless the platform is taken
+    fn dispatch_train(
+        &mut self,
+        id: RouteId,
+        station: StationId,
+        t: i32,
+        train_to_route: &mut HashMap<TrainId, RouteId>,
+    ) -> Option<(StationId, TrainId)> {
+        let route = &self.routes[&id];
+        let start_station_mut = self.stations.get_mut(&station).unwrap();
+        if start_station_mut.train.is_some() {
+            return None;
+        }
+        let curr_train_id = TrainId {
+            route_idx: id.0,
+            count: self.curr_train_counts[id.0 as usize],
+        };
+        let train = Train {
+            id: curr_train_id,
+            curr_section: TrackStationId::Station(start_station_mut.id),
+            pos: 0.0,
+            distance_travelled: 0.0,
+            route: id,
+            passengers: Vec::new(),
+            speed: 1.0,
+            length: route.train_length,
+        };
+
+        start_station_mut.train = Some(curr_train_id);
+        if t >= 0 && !route.deadhead {
+            record_arrival(
+                &mut start_station_mut.arrival_times,
+                &mut self.bunching,
+                &self.config,
+                station,
+                id,
+                t as f64,
+            );
+        }
+        self.trains.insert(curr_train_id, train);
+        train_to_route.insert(curr_train_id, route.parent.unwrap_or(id));
+        self.curr_train_counts[id.0 as usize] += 1;
+        Some((station, curr_train_id))
+    }
+
+    fn checkpoint_due(&self, time: i32) -> bool {
+        match (&self.checkpoints, self.config.checkpoint_interval) {
+            (Some(_), Some(every)) if every > 0 => time > 0 && time % every as i32 == 0,
+            _ => false,
+        }
+    }
+
+    // Sends a checkpoint of the run up to time, a whole minute
+    fn checkpoint(&self, time: i32) {
+        let (Some(checkpoints), Some(every)) = (&self.checkpoints, self.config.checkpoint_interval)
+        else {
+            return;
+        };
+        // the receiving end going away just means nobody is listening
+        let _ = checkpoints.send(self.summary(time - every as i32, time));
+    }
+
+    // Summary of the run up to time, with the arrivals from since
+    fn summary(&self, since: i32, time: i32) -> Checkpoint {
+        let since = since as f64..time as f64;
+        let arrivals = self
+            .stations
+            .iter()
+            .map(|(id, station)| {
+                let arrivals: HashMap<_, Vec<_>> = station
+                    .arrival_times
+                    .iter()
+                    .map(|(route, times)| {
+                        let times = times.iter().copied().filter(|t| since.contains(t));
+                        (*route, times.collect::<Vec<_>>())
+                    })
+                    .filter(|(_, times)| !times.is_empty())
+                    .collect();
+                (*id, arrivals)
+            })
+            .filter(|(_, arrivals)| !arrivals.is_empty())
+            .collect();
+        let delivered = self.journey_times.len();
+        Checkpoint {
+            time: time as f64,
+            passengers_delivered: delivered,
+            average_journey_time: (delivered > 0)
+                .then(|| self.journey_times.iter().sum::<f64>() / delivered as f64),
+            passengers_waiting: self.stations.values().map(|s| s.waiting.len()).sum(),
+            trains_in_service: self.trains.len(),
+            arrivals,
+            positions: self.positions(time as f64),
+        }
+    }
+
+    fn route_loads(&self) -> HashMap<RouteId, RouteLoad> {
+        self.routes
+            .iter()
+            .filter(|(_, route)| route.parent.is_none())
+            .map(|(id, route)| {
+                let load = RouteLoad {
+                    capacity: self.rolling_stock[id]
+                        .capacity
+                        .unwrap_or(self.config.train_capacity),
+                    cars: route.cars,
+                    load_factors: self.load_factors.get(id).cloned().unwrap_or_default(),
+                };
+                (*id, load)
+            })
+            .collect()
+    }
+
+    fn positions(&self, time: f64) -> TrainPositions {
+        TrainPositions {
+            time,
+            trains: self
+                .trains
+                .iter()
+                .map(|(id, train)| TrainPosition {
+                    id: *id,
+                    curr_section: train.curr_section,
+                    pos: train.pos,
+                    distance_travelled: train.distance_travelled,
+                    load: train.passengers.len(),
+                })
+                .collect(),
+        }
+    }
+
+    fn into_results(
+        self,
+        iterations: i32,
+        profile: &HeadwayProfile,
+        train_positions: Vec<TrainPositions>,
+        train_to_route: HashMap<TrainId, RouteId>,
+        warm_up: u32,
+    ) -> SimulationResults {
+        // timetabled routes have no headway of their own, so go by how often they ran
+        let nominal_headways = self
+            .routes
+            .iter()
+            .filter_map(|(id, route)| match route.timetable {
+                Some(_) => {
+                    let departures = (0..iterations)
+                        .filter(|t| self.departs(profile, *id, *t))
+                        .count();
+                    (departures > 0).then(|| (*id, iterations as f64 / departures as f64))
+                }
+                None => profile
+                    .nominal_headway(*id, iterations)
+                    .map(|headway| (*id, headway)),
+            })
+            .collect();
+        let route_loads = self.route_loads();
+        SimulationResults {
+            train_positions,
+            train_to_route,
+            dispatch_deviations: HashMap::new(),
+            departures: HashMap::new(),
+            journeys: self.traced_journeys(),
+            crowding_alerts: self.crowding_alerts(),
+            terminals: self.terminals(),
+            train_trips: self.recorded_trips(),
+            branch_headways: self.branch_headways(),
+            time_unit: self.config.time_unit,
+            journey_times: self.journey_times,
+            journey_starts: self.journey_starts,
+            turned_away: self.turned_away,
+            operating_distances: self.operating_distances,
+            energy: self.energy,
+            route_loads,
+            boardings: self.boardings,
+            bunching: self.bunching,
+            schedule_deviations: self.schedule_deviations,
+            gap_trains: self.gap_trains,
+            fleet_waits: self.fleet_waits,
+            failure: self.recorder.failure,
+            disruption_delays: self.disruption_delays,
+            short_turns: self.short_turns,
+            hold_times: self.hold_times,
+            coordinated_transfers: self.coordinated_transfers,
+            overtakes: self.overtakes,
+            skipped_stops: self.skipped_stops,
+            crowding_skips: self.crowding_skips,
+            crowding_detours: self.crowding_detours,
+            closure_effects: self.closure_effects,
+            incidents: self.incidents,
+            bypass_use: self.bypass_use,
+            dispatches: self.curr_train_counts.iter().sum::<u32>() as usize,
+            peak_trains_in_service: self.peak_trains,
+            in_service: self.in_service,
+            padding: self.padding_use,
+            warm_up,
+            nominal_headways,
+            station_statistics: self
+                .stations
+                .into_iter()
+                .map(|(id, s)| {
+                    (
+                        id,
+                        StationStatistic {
+                            arrival_times: s.arrival_times,
+                            passenger_waits: s.passenger_waits,
+                            boarded_at: s.boarded_at,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    // Minutes to warm up for. Finding a steady state takes a trial run from an empty network,
+    // with the warm up lasting until its train count levels off.
+    fn warm_up_steps(&self, profile: &HeadwayProfile) -> i32 {
+        let max = match self.config.warm_up() {
+            WarmUp::Fixed(steps) => return steps as i32,
+            WarmUp::SteadyState { max } => max as usize,
+        };
+        let mut trial = self.clone();
+        trial.config.warm_up = Some(WarmUp::Fixed(0));
+        trial.config.engine = Engine::TimeStep;
+        trial.config.position_recording = PositionRecording::Interval(1.0);
+        let counts: Vec<_> = trial
+            .run_with_profile(max as i32, profile)
+            .train_positions
+            .iter()
+            .map(|positions| positions.trains.len() as f64)
+            .collect();
+
+        // compare windows at least as long as the longest headway, so dispatches even out
+        let longest_headway = profile
+            .periods
+            .first()
+            .and_then(|period| period.headways.values().max())
+            .copied()
+            .unwrap_or_default() as usize;
+        let window = ((STEADY_STATE_WINDOW as f64 * self.config.time_unit.per_minute()) as usize)
+            .max(longest_headway);
+        let mean = |counts: &[f64]| counts.iter().sum::<f64>() / counts.len() as f64;
+        (2 * window..=counts.len())
+            .find(|&end| {
+                let previous = mean(&counts[end - 2 * window..end - window]);
+                let last = mean(&counts[end - window..end]);
+                (last - previous).abs() <= f64_max(0.5, 0.02 * previous)
+            })
+            .unwrap_or(max) as i32
+    }
+
+    pub fn run(self, iterations: i32, frequency: u64) -> SimulationResults {
+        let profile = HeadwayProfile::constant(self.routes.keys().copied(), frequency);
+        self.run_with_profile(iterations, &profile)
+    }
+
+    /// Steps each route is due to dispatch a train at over a run, going by the profile and any
+    /// timetables without moving any trains. Variants take their turns from the first
+    /// departure, and departures of depot moves count for the route they pull out onto.
+    pub fn planned_departures(
+        &self,
+        iterations: i32,
+        profile: &HeadwayProfile,
+    ) -> HashMap<RouteId, Vec<i32>> {
+        let mut counts = vec![0; self.curr_train_counts.len()];
+        let mut departures: HashMap<RouteId, Vec<i32>> = HashMap::new();
+        for t in 0..iterations {
+            for (id, route) in &self.routes {
+                if !route.dispatched
+                    || route.variant_of.is_some()
+                    || !self.departs(profile, route.parent.unwrap_or(*id), t)
+                {
+                    continue;
+                }
+                let id = match self.variants.get(id) {
+                    Some(variants) => dispatch_turn(*id, variants, &counts),
+                    None => *id,
+                };
+                counts[id.0 as usize] += 1;
+                departures
+                    .entry(self.routes[&id].parent.unwrap_or(id))
+                    .or_default()
+                    .push(t);
+            }
+        }
+        departures
+    }
+
+    /// Runs with route headways changing over the run as given by the profile
+    pub fn run_with_profile(
+        mut self,
+        iterations: i32,
+        profile: &HeadwayProfile,
+    ) -> SimulationResults {
+        self.shutdowns = profile.shutdowns();
+        if let Engine::Events = self.config.engine {
+            let warm_up = self.warm_up_steps(profile);
+            return self.run_events(iterations, profile, warm_up);
+        }
+        self.start_run_with_profile(iterations, profile).finish()
+    }
+
+    /// Starts a run which can be stopped partway with PausedRun::run_until, then looked at and
+    /// carried on. Always steps, whatever the engine.
+    pub fn start_run(self, iterations: i32, frequency: u64) -> PausedRun {
+        let profile = HeadwayProfile::constant(self.routes.keys().copied(), frequency);
+        self.start_run_with_profile(iterations, &profile)
+    }
+
+    pub fn start_run_with_profile(
+        mut self,
+        iterations: i32,
+        profile: &HeadwayProfile,
+    ) -> PausedRun {
+        self.shutdowns = profile.shutdowns();
+        let warm_up = self.warm_up_steps(profile);
+        self.noise_rng = self
+            .config
+            .travel_time_noise
+            .map(|noise| StdRng::seed_from_u64(noise.seed));
+        self.incident_rng = self
+            .config
+            .incidents
+            .map(|incidents| StdRng::seed_from_u64(incidents.seed));
+        println!("{:?}", self.traversal_order);
+        for route in self.routes.values() {
+            println!("{:?}", route.start_station);
+        }
+        PausedRun {
+            simulator: self,
+            progress: RunProgress {
+                profile: profile.clone(),
+                iterations,
+                warm_up: warm_up as u32,
+                t: -warm_up,
+                train_positions: Vec::new(),
+                train_to_route: HashMap::new(),
+            },
+        }
+    }
+
+    // Steps a started run on until the time unit until or the end of the run
+    fn step_run(&mut self, run: &mut RunProgress, until: i32) {
+        let profile = &run.profile;
+        let mut train_to_route = std::mem::take(&mut run.train_to_route);
+        let mut train_positions = std::mem::take(&mut run.train_positions);
+        let traversal_order = self.traversal_order.clone();
+        let time_step = self.time_step;
+        let mut t = run.t;
+
+        while t < until.min(run.iterations) && self.recorder.failure.is_none() {
+            for sub_step in 0..self.steps_per_unit {
+                let time = t as f64 + sub_step as f64 * time_step;
+                self.current_time = time;
+                self.release_passengers(time);
+                self.inject_incidents(time);
+                let mut station_event = false;
+                for track_station in &traversal_order {
+                    match *track_station {
+                        TrackStationId::Station(station) => {
+                            self.station_to_track(station, time_step);
+                        }
+                        TrackStationId::Track(track) => {
+                            // trains caught on a closed track stop where they are
+                            if track_closed(&self.disruptions, track, self.current_time) {
+                                for train in self.tracks[&track].lanes.iter().flatten() {
+                                    *self.disruption_delays.entry(*train).or_default() += time_step;
+                                }
+                                continue;
+                            }
+                            let next_station_id = self.subway_map.edge_endpoints(track).unwrap().1;
+                            // the platform is taken until the last train to leave it has cleared it
+                            let platform_tail = train_leaving(
+                                &self.subway_map,
+                                &self.tracks,
+                                &self.trains,
+                                next_station_id,
+                            );
+                            // trains on each of the parallel tracks only follow those ahead on the
+                            // same one
+                            for lane in 0..self.tracks[&track].lanes.len() {
+                                let mut i = 0;
+                                // furthest the next train may go before an occupied block
+                                let mut limit = f64::INFINITY;
+                                while i < self.tracks[&track].lanes[lane].len() {
+                                    // so does one giving way to a train on another track
+                                    let giving_way = i == 0
+                                        && self.gives_way(
+                                            next_station_id,
+                                            track,
+                                            self.tracks[&track].lanes[lane][0],
+                                            time_step,
+                                        );
+                                    let stalled = self
+                                        .stalled
+                                        .contains_key(&self.tracks[&track].lanes[lane][i]);
+                                    let platform_taken =
+                                        self.stations[&next_station_id].train.is_some()
+                                            || platform_tail.is_some();
+                                    // unless it runs through on the bypass
+                                    let bypassing = i == 0
+                                        && platform_taken
+                                        && !giving_way
+                                        && !stalled
+                                        && self.can_bypass(
+                                            next_station_id,
+                                            self.tracks[&track].lanes[lane][0],
+                                        );
+                                    let track_mut = self.tracks.get_mut(&track).unwrap();
+                                    // an occupied station holds trains at the end of the track
+                                    if platform_taken && !bypassing || giving_way {
+                                        limit = f64_min(track_mut.length as f64, limit);
+                                    }
+                                    let curr_train_id = track_mut.lanes[lane][i];
+                                    let curr_train_mut =
+                                        self.trains.get_mut(&curr_train_id).unwrap();
+                                    let mut time_left = time_step;
+                                    // a broken down train stays where it is
+                                    if stalled {
+                                        limit = f64_min(limit, curr_train_mut.pos);
+                                    }
+                                    let speed = curr_train_mut.speed
+                                        * speed_factor(&self.disruptions, track, time);
+                                    let travel_distance = f64_min(
+                                        f64_min(
+                                            time_left * speed,
+                                            f64_max(
+                                                track_mut.length as f64 - curr_train_mut.pos,
+                                                0.0,
+                                            ),
+                                        ),
+                                        f64_max(limit - curr_train_mut.pos, 0.0),
+                                    );
+                                    curr_train_mut.pos += travel_distance;
+                                    time_left -= travel_distance / speed;
+                                    // time lost crawling through a slow zone
+                                    if speed < curr_train_mut.speed {
+                                        *self
+                                            .disruption_delays
+                                            .entry(curr_train_id)
+                                            .or_default() += travel_distance / speed
+                                            - travel_distance / curr_train_mut.speed;
+                                    }
+                                    // we're done with the current track, and need to move into the station
+                                    if curr_train_mut.pos >= track_mut.length as f64
+                                        && self.stations[&next_station_id].train.is_none()
+                                        && platform_tail.is_none()
+                                        && !giving_way
+                                        && !stalled
+                                    {
+                                        let arrival = time + time_step - time_left;
+                                        if i != 0 {
+                                            self.recorder.fail(
+                                                "a train reached the end of a track before the one ahead of it",
+                                                arrival,
+                                                TrackStationId::Track(track),
+                                                vec![curr_train_id, track_mut.lanes[lane][0]],
+                                            );
+                                        }
+                                        self.merge_waits.remove(&curr_train_id);
+                                        self.merge_from.insert(next_station_id, track);
+                                        track_mut.lanes[lane].pop_front();
+                                        let next_station_mut =
+                                            self.stations.get_mut(&next_station_id).unwrap();
+                                        if let Some(occupant) = next_station_mut.train {
+                                            self.recorder.fail(
+                                                "a train arrived at an occupied station",
+                                                arrival,
+                                                TrackStationId::Station(next_station_id),
+                                                vec![curr_train_id, occupant],
+                                            );
+                                        }
+                                        self.recorder.moved(
+                                            arrival,
+                                            curr_train_id,
+                                            next_station_id,
+                                            true,
+                                        );
+                                        next_station_mut.train = Some(curr_train_id);
+                                        station_event = true;
+                                        let in_service = self.subway_map[track].revenue
+                                            && !self.routes[&curr_train_mut.route].deadhead;
+                                        let curr_route = curr_train_mut.route;
+                                        let stops = !self.routes[&curr_route]
+                                            .pass_through
+                                            .contains(&next_station_id)
+                                            && !self
+                                                .skipping
+                                                .contains_key(&(curr_train_id, next_station_id))
+                                            && !self.pushed.contains_key(&curr_train_id)
+                                            && !closed_through(
+                                                &self.station_closures,
+                                                &self.routes[&curr_route],
+                                                curr_route,
+                                                &self.reroutes,
+                                                next_station_id,
+                                                time + time_step - time_left,
+                                            );
+                                        if time >= 0.0 && in_service && stops {
+                                            record_arrival(
+                                                &mut next_station_mut.arrival_times,
+                                                &mut self.bunching,
+                                                &self.config,
+                                                next_station_id,
+                                                curr_train_mut.route,
+                                                time + time_step - time_left,
+                                            );
+                                        }
 
-                                curr_train_mut.curr_section =
-                                    TrackStationId::Station(next_station_id);
-                                curr_train_mut.pos = 0.0;
+                                        curr_train_mut.distance_travelled +=
+                                            self.tracks[&track].length as f64;
+                                        if time >= 0.0 {
+                                            let route = &self.routes[&curr_train_mut.route];
+                                            self.operating_distances
+                                                .entry(route.parent.unwrap_or(curr_train_mut.route))
+                                                .or_default()
+                                                .add(self.tracks[&track].length as f64, in_service);
+                                            add_energy(
+                                                &mut self.energy,
+                                                &self.config,
+                                                route.parent.unwrap_or(curr_train_mut.route),
+                                                time + time_step - time_left,
+                                                self.tracks[&track].length as f64,
+                                                stops,
+                                                curr_train_mut.speed,
+                                            );
+                                        }
+
+                                        curr_train_mut.curr_section =
+                                            TrackStationId::Station(next_station_id);
+                                        curr_train_mut.pos = 0.0;
 
-                                self.station_to_track(next_station_id, time_left);
-                                // TODO: handle the fact that some station time may be wasted when the train could keep moving
-                                // potentially some of this spaghetti code needs to get factored out into
-                                // a separate function, tbd
-                            } else {
-                                last_train_pos = curr_train_mut.pos;
-                                i += 1;
+                                        self.exchange_passengers(
+                                            next_station_id,
+                                            curr_train_id,
+                                            time + time_step - time_left,
+                                        );
+                                        self.station_to_track(next_station_id, time_left);
+                                        // TODO: handle the fact that some station time may be wasted when the train could keep moving
+                                        // potentially some of this spaghetti code needs to get factored out into
+                                        // a separate function, tbd
+                                    } else if bypassing
+                                        && curr_train_mut.pos >= track_mut.length as f64
+                                    {
+                                        track_mut.lanes[lane].remove(i);
+                                        self.merge_waits.remove(&curr_train_id);
+                                        let length = track_mut.length as f64;
+                                        curr_train_mut.distance_travelled += length;
+                                        curr_train_mut.curr_section =
+                                            TrackStationId::Station(next_station_id);
+                                        curr_train_mut.pos = 0.0;
+                                        let next_station_mut =
+                                            self.stations.get_mut(&next_station_id).unwrap();
+                                        next_station_mut.bypass = Some(curr_train_id);
+                                        if time >= 0.0 {
+                                            if let Some(overtaken) = next_station_mut.train {
+                                                self.overtakes.push(Overtake {
+                                                    station: next_station_id,
+                                                    train: curr_train_id,
+                                                    overtaken,
+                                                    time: time + time_step - time_left,
+                                                });
+                                            }
+                                            let route = &self.routes[&curr_train_mut.route];
+                                            self.operating_distances
+                                                .entry(route.parent.unwrap_or(curr_train_mut.route))
+                                                .or_default()
+                                                .add(
+                                                    length,
+                                                    self.subway_map[track].revenue
+                                                        && !route.deadhead,
+                                                );
+                                            add_energy(
+                                                &mut self.energy,
+                                                &self.config,
+                                                route.parent.unwrap_or(curr_train_mut.route),
+                                                time + time_step - time_left,
+                                                length,
+                                                false,
+                                                curr_train_mut.speed,
+                                            );
+                                        }
+                                        self.bypass_to_track(next_station_id, time_left);
+                                    } else {
+                                        // waiting at the end of the track to get into the station
+                                        if curr_train_mut.pos >= track_mut.length as f64 {
+                                            self.merge_waits
+                                                .entry(curr_train_id)
+                                                .or_insert(time + time_step - time_left);
+                                        }
+                                        limit = signal_behind(
+                                            curr_train_mut.tail(),
+                                            track_mut.block_length,
+                                        );
+                                        let blocker = if i > 0 {
+                                            Some(track_mut.lanes[lane][i - 1])
+                                        } else {
+                                            self.stations[&next_station_id].train.or(platform_tail)
+                                        };
+                                        self.blocked_by(curr_train_id, blocker, time_left);
+                                        i += 1;
+                                    }
+                                }
                             }
                         }
                     }
                 }
-            }
 
-            for (id, route) in &self.routes {
-                if (t - route.offset as i32) % (frequency as i32) != 0 {
-                    continue;
+                // trains are only dispatched on whole time units
+                if sub_step == 0 {
+                    let dispatched = self.dispatch(t, profile, &mut train_to_route);
+                    station_event |= !dispatched.is_empty();
+                    for (station, train) in dispatched {
+                        self.exchange_passengers(station, train, time);
+                    }
                 }
-                let start_station_mut = self.stations.get_mut(&route.start_station).unwrap();
-                // TODO: do I need to handle the case where this is not true?
-                let curr_train_id = TrainId {
-                    route_idx: id.0,
-                    count: self.curr_train_counts[id.0 as usize],
-                };
-                if start_station_mut.train.is_none() {
-                    let train = Train {
-                        id: curr_train_id,
-                        curr_section: TrackStationId::Station(start_station_mut.id),
-                        pos: 0.0,
-                        distance_travelled: 0.0,
-                        route: *id,
-                    };
 
-                    start_station_mut.train = Some(curr_train_id);
-                    if t >= 0 {
-                        start_station_mut
-                            .arrival_times
-                            .entry(*id)
-                            .or_default()
-                            .push(t as f64);
+                if time >= 0.0 {
+                    self.peak_trains = self.peak_trains.max(self.trains.len());
+                    if sub_step == 0 {
+                        self.record_in_service(time);
+                    }
+                    self.record_crowding(time);
+                    self.record_bypass_use();
+                    let step = (t * self.steps_per_unit + sub_step) as u32;
+                    if self
+                        .config
+                        .position_recording
+                        .records(step, time, station_event)
+                    {
+                        train_positions.push(self.positions(time));
                     }
-                    self.trains.insert(curr_train_id, train);
-                    train_to_route.insert(curr_train_id, *id);
-                    self.curr_train_counts[id.0 as usize] += 1;
                 }
             }
 
             println!("Iteration: {t}, train count: {}", self.trains.len());
-
-            let mut curr_train_positions = Vec::new();
-            for (id, train) in &self.trains {
-                curr_train_positions.push(TrainPosition {
-                    id: *id,
-                    curr_section: train.curr_section,
-                    pos: train.pos,
-                    distance_travelled: train.distance_travelled,
-                })
-            }
-            if t >= 0 {
-                train_positions.push(TrainPositions {
-                    time: t as u32,
-                    trains: curr_train_positions,
-                });
+            if self.checkpoint_due(t + 1) {
+                self.checkpoint(t + 1);
             }
 
             t += 1;
         }
 
-        SimulationResults {
-            train_positions,
-            train_to_route,
-            station_statistics: self
-                .stations
-                .into_iter()
-                .map(|(id, s)| {
-                    (
-                        id,
-                        StationStatistic {
-                            arrival_times: s.arrival_times,
-                        },
-                    )
-                })
-                .collect(),
-        }
+        run.t = t;
+        run.train_positions = train_positions;
+        run.train_to_route = train_to_route;
     }
 
     // This is mostly a copy paste of the run function right now.
     // TODO figure out how to consolidate code with run
+    // Time spent in z3 checks is added to solve_time, which outlives the states rolled back to
+    #[allow(clippy::too_many_arguments)]
     pub fn schedule_trains<'a>(
         &mut self,
         iterations: i32,
         desired_frequencies: &Frequencies,
         z3_context: &'a z3::Context,
-        conflicts: &[z3::ast::Bool]
-    ) -> Option<(SimulationResults, Vec<z3::ast::Bool<'a>>)> {
-
-        let z3_solver = z3::Solver::new(z3_context);
-        
-        let mut routes: Vec<_> = self.routes.iter().map(|(key, value)| (key.clone(), value.clone())).collect();
-        let mut rng = StdRng::seed_from_u64(5050);
+        conflicts: &[z3::ast::Bool],
+        ground_rules: &mut GroundRules<'a>,
+        options: &OptimizeOptions,
+        solve_time: &mut Duration,
+    ) -> Result<Option<(SimulationResults, Vec<z3::ast::Bool<'a>>)>, SimError> {
+        // depot moves are left to run, trains still turn around onto pull-ins though
+        let mut routes: Vec<_> = self
+            .routes
+            .iter()
+            .filter(|(_, route)| route.parent.is_none())
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        let mut rng = StdRng::seed_from_u64(options.seed);
+
+        let period_length = desired_frequencies.len() as f64 * SCHEDULE_GRANULARITY as f64;
+        let nominal_headways = self
+            .routes
+            .iter()
+            .filter(|(_, route)| route.parent.is_none())
+            .map(|(id, route)| {
+                let trains: i64 = desired_frequencies
+                    .iter()
+                    .map(|period| period[&route.name].get())
+                    .sum();
+                (*id, period_length / trains as f64)
+            })
+            .collect();
 
         let mut frequencies = Vec::with_capacity(desired_frequencies.len());
         for period in desired_frequencies {
@@ -460,55 +4528,67 @@ impl Simulator {
             frequencies.push(map);
         }
 
-        // initialize ground rules for all departure variables. Specifically,
-        // r_i+1 > r_i, and depending on frequencies set time bounds:
-        // r_0 >= 0 and r_0 < SCHEDULE_GRANULARITY must always be true
-
-        for (id, route) in self.routes.iter() {
-            let mut start_time = 0;
-            let mut curr_idx = 0;
-            for freq in &frequencies {
-                let end_time = start_time + SCHEDULE_GRANULARITY;
+        // In cyclic mode the period is simulated twice, with the second pass forced to repeat the
+        // departures of the first. The first pass fills the network with trains, so only the
+        // second is recorded, giving the steady state of a repeating timetable.
+        let (iterations, record_from) = if options.cyclic {
+            let first_pass = frequencies.clone();
+            frequencies.extend(first_pass);
+            (iterations * 2, iterations)
+        } else {
+            (iterations, 0)
+        };
 
-                for i in 0..freq[&route.name] {
-                    let curr_train = TrainId {
-                        route_idx: id.0,
-                        count: (i + curr_idx) as u32,
-                    }
-                    .to_z3_departure(&z3_context);
-                    let next_train = TrainId {
-                        route_idx: id.0,
-                        count: (i + curr_idx + 1) as u32,
-                    }
-                    .to_z3_departure(&z3_context);
-
-                    z3_solver.assert(
-                        &z3::ast::Int::add(
-                            &z3_context,
-                            &[
-                                &curr_train,
-                                &z3::ast::Int::from_u64(&z3_context, MIN_TRAIN_DISTANCE as u64),
-                            ],
-                        )
-                        .le(&next_train),
-                    );
-                    z3_solver
-                        .assert(&curr_train.ge(&z3::ast::Int::from_i64(&z3_context, start_time)));
-                    z3_solver
-                        .assert(&curr_train.lt(&z3::ast::Int::from_i64(&z3_context, end_time)));
-                }
+        // departure times if each period's trains were spread evenly across it
+        let mut ideal_departures = HashMap::new();
+        let mut guards = Vec::new();
+        for (id, route) in self
+            .routes
+            .iter()
+            .filter(|(_, route)| route.parent.is_none())
+        {
+            let route_frequencies: Vec<_> =
+                frequencies.iter().map(|freq| freq[&route.name]).collect();
+            // trains leave a block apart on the track out of the first station
+            let block_length = route
+                .station_to
+                .get(&route.start_station)
+                .map_or(BLOCK_LENGTH, |track| self.tracks[track].block_length);
+            let rules = ground_rules.for_route(
+                z3_context,
+                *id,
+                route,
+                route_frequencies,
+                block_length,
+                record_from,
+                options,
+            );
+            guards.push(rules.guard.clone());
+            ideal_departures.extend(rules.ideal_departures.iter().copied());
+        }
 
-                start_time = end_time;
-                curr_idx += freq[&route.name];
+        // the ground rules are asserted into the solver once, what holds for this call alone goes
+        // in a scope of its own which is left however the call returns
+        let z3_solver = ground_rules.solver(z3_context, options);
+        let _scope = z3_solver.scope();
+        for guard in &guards {
+            z3_solver.assert(guard);
+        }
+        if options.smooth_dispatches {
+            for (rule, weight) in
+                self.coordination_rules(z3_context, &frequencies, &ideal_departures, options)
+            {
+                z3_solver.assert_soft(&rule, weight);
             }
         }
-        
+
         for conflict in conflicts {
             z3_solver.assert(conflict);
         }
 
         let mut train_to_route = HashMap::new();
         let traversal_order = self.traversal_order.clone();
+        let time_step = self.time_step;
 
         let mut train_positions = Vec::new();
 
@@ -517,307 +4597,1326 @@ impl Simulator {
         let mut train_scheduled_at = HashMap::new();
         let mut states = Vec::with_capacity(iterations as usize);
 
-        let mut new_conflicts = Vec::new();
+        let mut new_conflicts = Vec::new();
+
+        'iteration: while t < iterations {
+            states.push((self.clone(), frequencies.clone()));
+            assert_eq!(states.len(), t as usize + 1);
+            z3_solver.push();
+
+            for sub_step in 0..self.steps_per_unit {
+                let time = (t - record_from) as f64 + sub_step as f64 * time_step;
+                self.current_time = time;
+                self.release_passengers(time);
+                let mut station_event = false;
+
+                for track_station in &traversal_order {
+                    match *track_station {
+                        TrackStationId::Station(station) => {
+                            self.station_to_track(station, time_step);
+                        }
+                        TrackStationId::Track(track) => {
+                            let next_station_id = self.subway_map.edge_endpoints(track).unwrap().1;
+                            // the platform is taken until the last train to leave it has cleared it
+                            let platform_tail = train_leaving(
+                                &self.subway_map,
+                                &self.tracks,
+                                &self.trains,
+                                next_station_id,
+                            );
+                            // trains on each of the parallel tracks only follow those ahead on the
+                            // same one
+                            for lane in 0..self.tracks[&track].lanes.len() {
+                                let mut i = 0;
+                                // furthest the next train may go before an occupied block
+                                let mut limit = f64::INFINITY;
+                                while i < self.tracks[&track].lanes[lane].len() {
+                                    // so does one giving way to a train on another track
+                                    let giving_way = i == 0
+                                        && self.gives_way(
+                                            next_station_id,
+                                            track,
+                                            self.tracks[&track].lanes[lane][0],
+                                            time_step,
+                                        );
+                                    let platform_taken =
+                                        self.stations[&next_station_id].train.is_some()
+                                            || platform_tail.is_some();
+                                    // unless it runs through on the bypass
+                                    let bypassing = i == 0
+                                        && platform_taken
+                                        && !giving_way
+                                        && self.can_bypass(
+                                            next_station_id,
+                                            self.tracks[&track].lanes[lane][0],
+                                        );
+                                    let track_mut = self.tracks.get_mut(&track).unwrap();
+                                    // an occupied station holds trains at the end of the track
+                                    if platform_taken && !bypassing || giving_way {
+                                        limit = f64_min(track_mut.length as f64, limit);
+                                    }
+                                    let curr_train_id = track_mut.lanes[lane][i];
+                                    let curr_train_mut =
+                                        self.trains.get_mut(&curr_train_id).unwrap();
+                                    let mut time_left = time_step;
+
+                                    // trains giving way at a junction wait there, any other
+                                    // hold up is a conflict
+                                    let waiting_at_merge = giving_way
+                                        || i == 0 && self.merge_waits.contains_key(&curr_train_id);
+                                    if limit < curr_train_mut.pos + time_left * curr_train_mut.speed
+                                        && !waiting_at_merge
+                                    {
+                                        // MERGE CONFLICT
+
+                                        let conflicting_train = if i > 0 {
+                                            track_mut.lanes[lane][i - 1]
+                                        } else {
+                                            self.stations[&next_station_id]
+                                                .train
+                                                .or(platform_tail)
+                                                .unwrap()
+                                        };
+
+                                        let scheduled_at = min(
+                                            train_scheduled_at[&curr_train_id],
+                                            train_scheduled_at[&conflicting_train],
+                                        );
+                                        t = scheduled_at;
+                                        let num_states_removed =
+                                            states.len() - scheduled_at as usize;
+                                        states.drain(scheduled_at as usize + 1..);
+                                        let prev_state = states.pop().unwrap();
+
+                                        *self = prev_state.0;
+                                        frequencies = prev_state.1;
+
+                                        train_positions.retain(|p: &TrainPositions| {
+                                            p.time + (record_from as f64) < scheduled_at as f64
+                                        });
+
+                                        // restore solver state to the iteration we're returning to
+                                        z3_solver.pop(num_states_removed as u32);
+
+                                        // TODO quadratic performance, FIXME
+                                        for assertion in &new_conflicts {
+                                            z3_solver.assert(assertion);
+                                        }
+                                        // encode conflict
+                                        let conflicting_train_scheduled_at = conflicting_train
+                                            .to_z3_departure(&z3_context)
+                                            ._eq(&z3::ast::Int::from_i64(
+                                                &z3_context,
+                                                train_scheduled_at[&conflicting_train] as i64,
+                                            ));
+                                        let assertion = conflicting_train_scheduled_at.implies(
+                                            &curr_train_id
+                                                .to_z3_departure(&z3_context)
+                                                ._eq(&z3::ast::Int::from_i64(
+                                                    &z3_context,
+                                                    train_scheduled_at[&curr_train_id] as i64,
+                                                ))
+                                                .not(),
+                                        );
+                                        z3_solver.assert(&assertion);
+                                        new_conflicts.push(assertion);
+
+                                        continue 'iteration;
+
+                                    }
+
+                                    let travel_distance = f64_min(
+                                        f64_min(
+                                            time_left * curr_train_mut.speed,
+                                            f64_max(
+                                                track_mut.length as f64 - curr_train_mut.pos,
+                                                0.0,
+                                            ),
+                                        ),
+                                        f64_max(limit - curr_train_mut.pos, 0.0),
+                                    );
+                            
+                                    curr_train_mut.pos += travel_distance;
+                                    time_left -= travel_distance / curr_train_mut.speed;
+                                    // we're done with the current track, and need to move into the station
+                                    if curr_train_mut.pos >= track_mut.length as f64
+                                        && self.stations[&next_station_id].train.is_none()
+                                        && platform_tail.is_none()
+                                        && !giving_way
+                                    {
+                                        match self.stations[&next_station_id].train {
+                                            None => {
+                                                let arrival = time + time_step - time_left;
+                                                if i != 0 {
+                                                    self.recorder.fail(
+                                                        "a train reached the end of a track before the one ahead of it",
+                                                        arrival,
+                                                        TrackStationId::Track(track),
+                                                        vec![curr_train_id, track_mut.lanes[lane][0]],
+                                                    );
+                                                    return Err(self
+                                                        .recorder
+                                                        .failure
+                                                        .take()
+                                                        .unwrap()
+                                                        .into_unnamed_error());
+                                                }
+                                                self.merge_waits.remove(&curr_train_id);
+                                                self.merge_from.insert(next_station_id, track);
+                                                track_mut.lanes[lane].pop_front();
+                                                self.recorder.moved(
+                                                    arrival,
+                                                    curr_train_id,
+                                                    next_station_id,
+                                                    true,
+                                                );
+                                                let next_station_mut = self
+                                                    .stations
+                                                    .get_mut(&next_station_id)
+                                                    .unwrap();
+                                                next_station_mut.train = Some(curr_train_id);
+                                                station_event = true;
+                                                let in_service = self.subway_map[track].revenue
+                                                    && !self.routes[&curr_train_mut.route].deadhead;
+                                                let stops = !self.routes[&curr_train_mut.route]
+                                                    .pass_through
+                                                    .contains(&next_station_id);
+                                                if time >= 0.0 && in_service && stops {
+                                                    record_arrival(
+                                                        &mut next_station_mut.arrival_times,
+                                                        &mut self.bunching,
+                                                        &self.config,
+                                                        next_station_id,
+                                                        curr_train_mut.route,
+                                                        time + time_step - time_left,
+                                                    );
+                                                }
+
+                                                curr_train_mut.distance_travelled +=
+                                                    self.tracks[&track].length as f64;
+                                                if time >= 0.0 {
+                                                    let route = &self.routes[&curr_train_mut.route];
+                                                    self.operating_distances
+                                                        .entry(
+                                                            route
+                                                                .parent
+                                                                .unwrap_or(curr_train_mut.route),
+                                                        )
+                                                        .or_default()
+                                                        .add(
+                                                            self.tracks[&track].length as f64,
+                                                            in_service,
+                                                        );
+                                                    add_energy(
+                                                        &mut self.energy,
+                                                        &self.config,
+                                                        route
+                                                            .parent
+                                                            .unwrap_or(curr_train_mut.route),
+                                                        time + time_step - time_left,
+                                                        self.tracks[&track].length as f64,
+                                                        stops,
+                                                        curr_train_mut.speed,
+                                                    );
+                                                }
+
+                                                curr_train_mut.curr_section =
+                                                    TrackStationId::Station(next_station_id);
+                                                curr_train_mut.pos = 0.0;
+
+                                                self.exchange_passengers(
+                                                    next_station_id,
+                                                    curr_train_id,
+                                                    time + time_step - time_left,
+                                                );
+                                                self.station_to_track(next_station_id, time_left);
+                                                // TODO: handle the fact that some station time may be wasted when the train could keep moving
+                                                // potentially some of this spaghetti code needs to get factored out into
+                                                // a separate function, tbd
+                                            }
+                                            Some(occupant) => {
+                                                self.recorder.fail(
+                                                    "a train arrived at an occupied station",
+                                                    time + time_step - time_left,
+                                                    TrackStationId::Station(next_station_id),
+                                                    vec![curr_train_id, occupant],
+                                                );
+                                                return Err(self
+                                                    .recorder
+                                                    .failure
+                                                    .take()
+                                                    .unwrap()
+                                                    .into_unnamed_error());
+                                            }
+                                        };
+                                    } else if bypassing
+                                        && curr_train_mut.pos >= track_mut.length as f64
+                                    {
+                                        track_mut.lanes[lane].remove(i);
+                                        self.merge_waits.remove(&curr_train_id);
+                                        let length = track_mut.length as f64;
+                                        curr_train_mut.distance_travelled += length;
+                                        curr_train_mut.curr_section =
+                                            TrackStationId::Station(next_station_id);
+                                        curr_train_mut.pos = 0.0;
+                                        let next_station_mut =
+                                            self.stations.get_mut(&next_station_id).unwrap();
+                                        next_station_mut.bypass = Some(curr_train_id);
+                                        if time >= 0.0 {
+                                            if let Some(overtaken) = next_station_mut.train {
+                                                self.overtakes.push(Overtake {
+                                                    station: next_station_id,
+                                                    train: curr_train_id,
+                                                    overtaken,
+                                                    time: time + time_step - time_left,
+                                                });
+                                            }
+                                            let route = &self.routes[&curr_train_mut.route];
+                                            self.operating_distances
+                                                .entry(route.parent.unwrap_or(curr_train_mut.route))
+                                                .or_default()
+                                                .add(
+                                                    length,
+                                                    self.subway_map[track].revenue
+                                                        && !route.deadhead,
+                                                );
+                                            add_energy(
+                                                &mut self.energy,
+                                                &self.config,
+                                                route.parent.unwrap_or(curr_train_mut.route),
+                                                time + time_step - time_left,
+                                                length,
+                                                false,
+                                                curr_train_mut.speed,
+                                            );
+                                        }
+                                        self.bypass_to_track(next_station_id, time_left);
+                                    } else {
+                                        // waiting at the end of the track to get into the station
+                                        if curr_train_mut.pos >= track_mut.length as f64 {
+                                            self.merge_waits
+                                                .entry(curr_train_id)
+                                                .or_insert(time + time_step - time_left);
+                                        }
+                                        limit = signal_behind(
+                                            curr_train_mut.tail(),
+                                            track_mut.block_length,
+                                        );
+                                        i += 1;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+
+                // trains are only dispatched on whole time units
+                if sub_step == 0 {
+                    routes.shuffle(&mut rng);
+                    let mut dispatched = Vec::new();
+                    for (id, route) in &routes {
+                        let start_station_mut =
+                            self.stations.get_mut(&route.start_station).unwrap();
+                        // TODO: do I need to handle the case where this is not true?
+                        let curr_train_id = TrainId {
+                            route_idx: id.0,
+                            count: self.curr_train_counts[id.0 as usize],
+                        };
+
+                        if frequencies[(t as i64 / SCHEDULE_GRANULARITY) as usize][&route.name] == 0
+                        {
+                            continue;
+                        }
+
+                        if start_station_mut.train.is_none() {
+                            let train = Train {
+                                id: curr_train_id,
+                                curr_section: TrackStationId::Station(start_station_mut.id),
+                                pos: 0.0,
+                                distance_travelled: 0.0,
+                                route: *id,
+                                passengers: Vec::new(),
+                                speed: 1.0,
+                                length: route.train_length,
+                            };
+
+                            // logic to handle when trying to schedule trains:
+                            // - should we wait if there's currently a train too close on the directly proceeding
+                            //   track? right now, will say no, but otherwise this would be the first check
+                            // - check if we've failed frequency requirements for last bin. if yes, report fail
+                            // - add an assertion to solver saying current train departure = time. if we get a sat
+                            //   model, proceed. otherwise, don't schedule a train
+                            // - the difficult thing to figure out is: what assertions can we keep permanently,
+                            //   and what can we get rid of? would be nice if we could maintain a list of permanent assumptions
+                            //   but otherwise do pop and push logic. a nice thing to assert then pop is depart_var = (or >=) curr time
+                            //   however when backtracking this could of course get invalidated, or could it? think about this
+
+                            let curr_train_z3 = curr_train_id.to_z3_departure(&z3_context);
+                            let curr_time_z3 = z3::ast::Int::from_i64(&z3_context, t.into());
+
+                            z3_solver.push();
+                            if !z3_solver.decide(&[curr_train_z3.ge(&curr_time_z3)], solve_time)? {
+                                // TODO attempt to allow a merge later? requires complex pruning of assertions
+                                return Ok(None);
+                            }
+                            // when smoothing, hold the train until the time the solver would prefer
+                            if let Some(preferred) = z3_solver.preferred_departure(&curr_train_z3) {
+                                if preferred > t.into() {
+                                    z3_solver.pop(1);
+                                    continue;
+                                }
+                            }
+                            let z3_departure_equality = curr_train_z3._eq(&curr_time_z3);
+                            z3_solver.assert(&z3_departure_equality);
+                            if !z3_solver.decide(&[], solve_time)? {
+                                z3_solver.pop(1);
+                                continue;
+                            }
+
+                            z3_solver.pop(1);
+                            z3_solver.assert(&z3_departure_equality);
+
+                            *frequencies[(t as i64 / SCHEDULE_GRANULARITY) as usize]
+                                .get_mut(&route.name)
+                                .unwrap() -= 1;
+
+                            start_station_mut.train = Some(curr_train_id);
+                            station_event = true;
+                            if t >= record_from && !route.deadhead {
+                                record_arrival(
+                                    &mut start_station_mut.arrival_times,
+                                    &mut self.bunching,
+                                    &self.config,
+                                    route.start_station,
+                                    *id,
+                                    (t - record_from) as f64,
+                                );
+                            }
+                            self.trains.insert(curr_train_id, train);
+                            train_to_route.insert(curr_train_id, *id);
+                            train_scheduled_at.insert(curr_train_id, t);
+                            self.curr_train_counts[id.0 as usize] += 1;
+                            dispatched.push((route.start_station, curr_train_id));
+                        }
+                    }
+                    for (station, train) in dispatched {
+                        self.start_trip(train);
+                        self.exchange_passengers(station, train, (t - record_from) as f64);
+                    }
+                }
+
+                if time >= 0.0 {
+                    self.peak_trains = self.peak_trains.max(self.trains.len());
+                    if sub_step == 0 {
+                        self.record_in_service(time);
+                    }
+                    self.record_crowding(time);
+                    self.record_bypass_use();
+                    let step = ((t - record_from) * self.steps_per_unit + sub_step) as u32;
+                    if self
+                        .config
+                        .position_recording
+                        .records(step, time, station_event)
+                    {
+                        train_positions.push(self.positions(time));
+                    }
+                }
+            }
+
+            t += 1;
+        }
+
+        let mut dispatch_deviations: HashMap<RouteId, (f64, usize)> = HashMap::new();
+        let mut departures: HashMap<RouteId, Vec<i64>> = HashMap::new();
+        for (train, scheduled_at) in &train_scheduled_at {
+            // entries for trains undone by backtracking may be left behind
+            if train.count >= self.curr_train_counts[train.route_idx as usize]
+                || *scheduled_at < record_from
+            {
+                continue;
+            }
+            let deviation = dispatch_deviations
+                .entry(RouteId(train.route_idx))
+                .or_default();
+            deviation.0 += (*scheduled_at as i64 - ideal_departures[train]).abs() as f64;
+            deviation.1 += 1;
+            departures
+                .entry(RouteId(train.route_idx))
+                .or_default()
+                .push((scheduled_at - record_from).into());
+        }
+        departures.values_mut().for_each(|times| times.sort());
+
+        Ok(Some((
+            SimulationResults {
+                train_positions,
+                train_to_route,
+                nominal_headways,
+                journey_times: self.journey_times.clone(),
+                journey_starts: self.journey_starts.clone(),
+                turned_away: Vec::new(),
+                journeys: self.traced_journeys(),
+                crowding_alerts: self.crowding_alerts(),
+                terminals: self.terminals(),
+                operating_distances: self.operating_distances.clone(),
+                energy: self.energy.clone(),
+                boardings: self.boardings.clone(),
+                route_loads: self.route_loads(),
+                bunching: self.bunching.clone(),
+                schedule_deviations: HashMap::new(),
+                gap_trains: Vec::new(),
+                fleet_waits: Vec::new(),
+                failure: None,
+                disruption_delays: self.disruption_delays.clone(),
+                short_turns: self.short_turns.clone(),
+                hold_times: self.hold_times.clone(),
+                coordinated_transfers: self.coordinated_transfers.clone(),
+                overtakes: self.overtakes.clone(),
+                skipped_stops: self.skipped_stops.clone(),
+                crowding_skips: self.crowding_skips,
+                crowding_detours: self.crowding_detours,
+                closure_effects: Vec::new(),
+                incidents: self.incidents.clone(),
+                bypass_use: self.bypass_use.clone(),
+                train_trips: self.recorded_trips(),
+                branch_headways: self.branch_headways(),
+                dispatches: self.curr_train_counts.iter().sum::<u32>() as usize,
+                peak_trains_in_service: self.peak_trains,
+                in_service: self.in_service.clone(),
+                padding: self.padding_use.clone(),
+                warm_up: 0,
+                time_unit: self.config.time_unit,
+                dispatch_deviations: dispatch_deviations
+                    .into_iter()
+                    .map(|(id, (total, count))| (id, total / count as f64))
+                    .collect(),
+                departures,
+                station_statistics: self
+                    .stations
+                    .iter()
+                    .map(|(id, s)| {
+                        (
+                            *id,
+                            StationStatistic {
+                                arrival_times: s.arrival_times.clone(),
+                                passenger_waits: s.passenger_waits.clone(),
+                                boarded_at: s.boarded_at.clone(),
+                            },
+                        )
+                    })
+                    .collect(),
+            },
+            new_conflicts,
+        )))
+    }
+
+    // Soft goals tying the departures of different routes together: feeders getting riders to
+    // timed transfers shortly before the trains they connect with leave, and trains merging at a
+    // station arriving spread out rather than together. Each train is paired with the other
+    // route's train due there closest to it, going by their ideal departures.
+    fn coordination_rules<'a>(
+        &self,
+        ctx: &'a z3::Context,
+        frequencies: &[HashMap<String, i64>],
+        ideal_departures: &HashMap<TrainId, i64>,
+        options: &OptimizeOptions,
+    ) -> Vec<(z3::ast::Bool<'a>, u32)> {
+        let weights = &options.weights;
+        let mut rules = Vec::new();
+        let mut trains: HashMap<RouteId, Vec<(TrainId, i64)>> = HashMap::new();
+        for (train, ideal) in ideal_departures {
+            trains
+                .entry(RouteId(train.route_idx))
+                .or_default()
+                .push((*train, *ideal));
+        }
+        let arrivals: HashMap<_, _> = trains
+            .keys()
+            .map(|id| (*id, self.nominal_arrivals(*id)))
+            .collect();
+        // the route's train due closest to at, reaching the station after minutes from dispatch
+        let closest = |route: RouteId, after: f64, at: f64| {
+            trains[&route]
+                .iter()
+                .min_by(|a, b| {
+                    (a.1 as f64 + after - at)
+                        .abs()
+                        .total_cmp(&(b.1 as f64 + after - at).abs())
+                })
+                .copied()
+        };
+        // time between a's and b's departures, less what it would take for them to be due at
+        // the same time
+        let separation = |a: TrainId, b: TrainId, due_apart: f64| {
+            z3::ast::Int::sub(
+                ctx,
+                &[
+                    &a.to_z3_departure(ctx),
+                    &b.to_z3_departure(ctx),
+                    &z3::ast::Int::from_i64(ctx, due_apart.round() as i64),
+                ],
+            )
+        };
+
+        if weights.merge_smoothness > 0 {
+            for (a, a_arrivals) in &arrivals {
+                for (b, b_arrivals) in &arrivals {
+                    if a == b {
+                        continue;
+                    }
+                    for &(station, a_track, a_time) in a_arrivals {
+                        let Some(&(_, b_track, b_time)) =
+                            b_arrivals.iter().find(|(at, ..)| *at == station)
+                        else {
+                            continue;
+                        };
+                        // coming in on the same track, they merged further back
+                        if a_track == b_track {
+                            continue;
+                        }
+                        for &(a_train, a_ideal) in &trains[a] {
+                            let Some((b_train, _)) = closest(*b, b_time, a_ideal as f64 + a_time)
+                            else {
+                                continue;
+                            };
+                            let period = &frequencies[(a_ideal / SCHEDULE_GRANULARITY) as usize];
+                            let merged =
+                                period[&self.routes[a].name] + period[&self.routes[b].name];
+                            let headway = SCHEDULE_GRANULARITY / merged.max(1);
+                            let separation = separation(a_train, b_train, b_time - a_time);
+                            for margin in [headway, headway / 2] {
+                                if margin == 0 {
+                                    continue;
+                                }
+                                rules.push((
+                                    z3::ast::Bool::or(
+                                        ctx,
+                                        &[
+                                            &separation.ge(&z3::ast::Int::from_i64(ctx, margin)),
+                                            &separation.le(&z3::ast::Int::from_i64(ctx, -margin)),
+                                        ],
+                                    ),
+                                    weights.merge_smoothness,
+                                ));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if weights.transfers > 0 {
+            let serves = |route: RouteId, station: StationId| {
+                let route = &self.routes[&route];
+                !route.deadhead && !route.pass_through.contains(&station)
+            };
+            for transfer in &options.timed_transfers {
+                for walk in self
+                    .subway_map
+                    .edges_directed(transfer.station, Direction::Incoming)
+                    .filter(|edge| edge.weight().ty == EdgeType::Walk)
+                {
+                    let feeder_station = walk.source();
+                    for (route, route_arrivals) in &arrivals {
+                        let Some(&(_, _, arrival)) = route_arrivals
+                            .iter()
+                            .find(|(at, ..)| *at == transfer.station)
+                        else {
+                            continue;
+                        };
+                        if !serves(*route, transfer.station) {
+                            continue;
+                        }
+                        let leaves = arrival + self.dwell_time(*route, transfer.station);
+                        for (feeder, feeder_arrivals) in &arrivals {
+                            let Some(&(_, _, feeder_arrival)) = feeder_arrivals
+                                .iter()
+                                .find(|(at, ..)| *at == feeder_station)
+                            else {
+                                continue;
+                            };
+                            if feeder == route || !serves(*feeder, feeder_station) {
+                                continue;
+                            }
+                            let ready = feeder_arrival + walk.weight().weight as f64;
+                            let max_hold = z3::ast::Int::from_i64(
+                                ctx,
+                                transfer.max_hold.round().max(1.) as i64,
+                            );
+                            for &(train, ideal) in &trains[route] {
+                                let Some((feeder_train, _)) =
+                                    closest(*feeder, ready, ideal as f64 + leaves)
+                                else {
+                                    continue;
+                                };
+                                // the feeder's riders get there no later than the train leaves,
+                                // and not so early they'd rather have taken the one before
+                                let separation = separation(train, feeder_train, ready - leaves);
+                                rules.push((
+                                    z3::ast::Bool::and(
+                                        ctx,
+                                        &[
+                                            &separation.ge(&z3::ast::Int::from_i64(ctx, 0)),
+                                            &separation.le(&max_hold),
+                                        ],
+                                    ),
+                                    weights.transfers,
+                                ));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        rules
+    }
+
+    // Stations a route's trains get to from their first one running at full speed, with the
+    // track they came in on and how long after dispatch they're due
+    fn nominal_arrivals(&self, id: RouteId) -> Vec<(StationId, Option<TrackId>, f64)> {
+        let route = &self.routes[&id];
+        let speed = self.rolling_stock[&id].speed;
+        let mut at = route.start_station;
+        let mut arrivals = vec![(at, None, 0.0)];
+        let mut visited = HashSet::from([at]);
+        let mut time = 0.0;
+        while let Some(track) = route.station_to.get(&at) {
+            time += self.dwell_time(id, at)
+                + self.tracks[track].length as f64 / self.run_speed(id, *track, speed);
+            at = self.subway_map.edge_endpoints(*track).unwrap().1;
+            if !visited.insert(at) {
+                break;
+            }
+            arrivals.push((at, Some(*track), time));
+        }
+        arrivals
+    }
+}
+
+/// Ground rules of the departure variables of each route, kept across schedule_trains calls.
+/// Raising one fragment's frequency only renumbers the trains of its own route, so the rules of
+/// the others are reused rather than rebuilt. Rules are asserted once, as they're built, into a
+/// solver kept with them, so one GroundRules is only for calls with the same options.
+#[derive(Default)]
+pub struct GroundRules<'ctx> {
+    routes: HashMap<RouteId, RouteGroundRules<'ctx>>,
+    solver: Option<DepartureSolver<'ctx>>,
+}
+
+struct RouteGroundRules<'ctx> {
+    /// Trains the route runs in each period, which the rules were built for
+    frequencies: Vec<i64>,
+    /// Implies the rules in the solver, where rules rebuilt since stay behind unused
+    guard: z3::ast::Bool<'ctx>,
+    hard: Vec<z3::ast::Bool<'ctx>>,
+    soft: Vec<(z3::ast::Bool<'ctx>, u32)>,
+    ideal_departures: Vec<(TrainId, i64)>,
+}
+
+impl<'ctx> GroundRules<'ctx> {
+    // The rules of a route running the given trains per period, rebuilt only if those changed.
+    // Specifically r_i+1 > r_i, and depending on frequencies time bounds, like r_0 >= 0 and
+    // r_0 < SCHEDULE_GRANULARITY when the first period runs a train.
+    #[allow(clippy::too_many_arguments)]
+    fn for_route(
+        &mut self,
+        ctx: &'ctx z3::Context,
+        id: RouteId,
+        route: &Route,
+        frequencies: Vec<i64>,
+        block_length: f64,
+        record_from: i32,
+        options: &OptimizeOptions,
+    ) -> &RouteGroundRules<'ctx> {
+        let cached = self
+            .routes
+            .get(&id)
+            .is_some_and(|rules| rules.frequencies == frequencies);
+        if !cached {
+            let rules = RouteGroundRules::new(
+                ctx,
+                id,
+                route,
+                frequencies,
+                block_length,
+                record_from,
+                options,
+            );
+            let solver = self.solver(ctx, options);
+            for rule in &rules.hard {
+                solver.assert(&rules.guard.implies(rule));
+            }
+            for (rule, weight) in &rules.soft {
+                solver.assert_soft(&rules.guard.implies(rule), *weight);
+            }
+            self.routes.insert(id, rules);
+        }
+        &self.routes[&id]
+    }
+
+    fn solver(
+        &mut self,
+        ctx: &'ctx z3::Context,
+        options: &OptimizeOptions,
+    ) -> &DepartureSolver<'ctx> {
+        self.solver
+            .get_or_insert_with(|| DepartureSolver::new(ctx, options.smooth_dispatches))
+    }
+}
+
+impl<'ctx> RouteGroundRules<'ctx> {
+    fn new(
+        ctx: &'ctx z3::Context,
+        id: RouteId,
+        route: &Route,
+        frequencies: Vec<i64>,
+        block_length: f64,
+        record_from: i32,
+        options: &OptimizeOptions,
+    ) -> Self {
+        let mut rules = RouteGroundRules {
+            frequencies: Vec::new(),
+            guard: z3::ast::Bool::fresh_const(ctx, "rules"),
+            hard: Vec::new(),
+            soft: Vec::new(),
+            ideal_departures: Vec::new(),
+        };
+        let mut start_time = 0;
+        let mut curr_idx = 0;
+        for &freq in &frequencies {
+            let end_time = start_time + SCHEDULE_GRANULARITY;
+
+            for i in 0..freq {
+                let curr_train_id = TrainId {
+                    route_idx: id.0,
+                    count: (i + curr_idx) as u32,
+                };
+                let curr_train = curr_train_id.to_z3_departure(ctx);
+
+                let spacing = SCHEDULE_GRANULARITY / freq;
+                let ideal_departure =
+                    start_time + i * SCHEDULE_GRANULARITY / freq + route.offset as i64 % spacing;
+                rules
+                    .ideal_departures
+                    .push((curr_train_id, ideal_departure));
+                let next_train = TrainId {
+                    route_idx: id.0,
+                    count: (i + curr_idx + 1) as u32,
+                }
+                .to_z3_departure(ctx);
+
+                if options.smooth_dispatches {
+                    let weights = &options.weights;
+                    rules.prefer_close(
+                        ctx,
+                        &curr_train,
+                        &z3::ast::Int::from_i64(ctx, ideal_departure),
+                        weights.offset_adherence,
+                    );
+                    rules.prefer_close(
+                        ctx,
+                        &curr_train,
+                        &z3::ast::Int::from_i64(ctx, start_time),
+                        weights.throughput,
+                    );
+                    if i + 1 < freq {
+                        rules.prefer_close(
+                            ctx,
+                            &z3::ast::Int::sub(ctx, &[&next_train, &curr_train]),
+                            &z3::ast::Int::from_i64(ctx, spacing),
+                            weights.regularity,
+                        );
+                    }
+                }
+
+                rules.hard.push(
+                    z3::ast::Int::add(
+                        ctx,
+                        &[
+                            &curr_train,
+                            &z3::ast::Int::from_u64(ctx, block_length.ceil() as u64),
+                        ],
+                    )
+                    .le(&next_train),
+                );
+                rules
+                    .hard
+                    .push(curr_train.ge(&z3::ast::Int::from_i64(ctx, start_time)));
+                rules
+                    .hard
+                    .push(curr_train.lt(&z3::ast::Int::from_i64(ctx, end_time)));
+            }
+
+            start_time = end_time;
+            curr_idx += freq;
+        }
+
+        if options.cyclic {
+            let trains_per_period = curr_idx / 2;
+            for i in 0..trains_per_period {
+                let first_pass_train = TrainId {
+                    route_idx: id.0,
+                    count: i as u32,
+                }
+                .to_z3_departure(ctx);
+                let second_pass_train = TrainId {
+                    route_idx: id.0,
+                    count: (i + trains_per_period) as u32,
+                }
+                .to_z3_departure(ctx);
+                rules.hard.push(second_pass_train._eq(&z3::ast::Int::add(
+                    ctx,
+                    &[
+                        &first_pass_train,
+                        &z3::ast::Int::from_i64(ctx, record_from.into()),
+                    ],
+                )));
+            }
+        }
+        rules.frequencies = frequencies;
+        rules
+    }
+
+    /// Softly prefers value to be close to target, more strongly the closer it is
+    fn prefer_close(
+        &mut self,
+        ctx: &'ctx z3::Context,
+        value: &z3::ast::Int<'ctx>,
+        target: &z3::ast::Int<'ctx>,
+        weight: u32,
+    ) {
+        if weight == 0 {
+            return;
+        }
+        for tolerance in [0, 1, 2, 4] {
+            let tolerance = z3::ast::Int::from_i64(ctx, tolerance);
+            self.soft.push((
+                z3::ast::Bool::and(
+                    ctx,
+                    &[
+                        &z3::ast::Int::sub(ctx, &[value, &tolerance]).le(target),
+                        &z3::ast::Int::add(ctx, &[value, &tolerance]).ge(target),
+                    ],
+                ),
+                weight,
+            ));
+        }
+    }
+}
+
+/// The solver used to pick departure times. Dispatch smoothing needs soft constraints, which
+/// only z3's Optimize supports, so the plain Solver is kept for the common case.
+struct DepartureSolver<'ctx> {
+    backend: SolverBackend<'ctx>,
+    /// Scopes pushed and not popped yet
+    scopes: Cell<u32>,
+}
 
-        'iteration: while t < iterations {
-            states.push((self.clone(), frequencies.clone()));
-            assert_eq!(states.len(), t as usize + 1);
-            z3_solver.push();
+enum SolverBackend<'ctx> {
+    Solver(z3::Solver<'ctx>),
+    Optimize(z3::Optimize<'ctx>),
+}
 
-            for track_station in &traversal_order {
-                match *track_station {
-                    TrackStationId::Station(station) => {
-                        self.station_to_track(station, TIME_STEP);
-                    }
-                    TrackStationId::Track(track) => {
-                        let mut i = 0;
-                        let mut last_train_pos = f64::INFINITY;
-                        let next_station_id = self.subway_map.edge_endpoints(track).unwrap().1;
-                        while i < self.tracks.get_mut(&track).unwrap().trains.len() {
-                            let track_mut = self.tracks.get_mut(&track).unwrap();
-                            if self.stations[&next_station_id].train.is_some() {
-                                last_train_pos = f64_min(
-                                    f64_max(track_mut.length as f64 - MIN_TRAIN_DISTANCE, 0.0),
-                                    last_train_pos,
-                                );
-                            }
-                            let curr_train_id = track_mut.trains[i];
-                            let curr_train_mut = self.trains.get_mut(&curr_train_id).unwrap();
-                            let mut time_left = TIME_STEP;
-                            
-                            if last_train_pos - MIN_TRAIN_DISTANCE <  curr_train_mut.pos + time_left {
-                                // MERGE CONFLICT
-                                
-                                let conflicting_train = if track_mut.trains.len() > i + 1 {
-                                    track_mut.trains[i+1]
-                                } else {
-                                    self.stations[&next_station_id].train.unwrap()
-                                };
-                                
-                                let scheduled_at = min(train_scheduled_at[&curr_train_id], train_scheduled_at[&conflicting_train]);
-                                t = scheduled_at;
-                                let num_states_removed =
-                                    states.len() - scheduled_at as usize;
-                                states.drain(scheduled_at as usize + 1..);
-                                let prev_state = states.pop().unwrap();
-
-                                *self = prev_state.0;
-                                frequencies = prev_state.1;
-
-                                train_positions.retain(|p: &TrainPositions| {
-                                    (p.time as i32) < scheduled_at
-                                });
-
-                                // restore solver state to the iteration we're returning to
-                                z3_solver.pop(num_states_removed as u32);
-
-                                // TODO quadratic performance, FIXME
-                                for assertion in &new_conflicts {
-                                    z3_solver.assert(assertion);
-                                }
-                                // encode conflict
-                                let conflicting_train_scheduled_at = conflicting_train
-                                    .to_z3_departure(&z3_context)
-                                    ._eq(&z3::ast::Int::from_i64(
-                                        &z3_context,
-                                        train_scheduled_at[&conflicting_train] as i64,
-                                    ));
-                                let assertion = conflicting_train_scheduled_at.implies(
-                                    &curr_train_id
-                                        .to_z3_departure(&z3_context)
-                                        ._eq(&z3::ast::Int::from_i64(
-                                            &z3_context,
-                                            train_scheduled_at[&curr_train_id] as i64,
-                                        ))
-                                        .not(),
-                                );
-                                z3_solver.assert(&assertion);
-                                new_conflicts.push(assertion);
+/// Scope of a DepartureSolver, popped along with any pushed inside it when dropped
+struct SolverScope<'s, 'ctx> {
+    solver: &'s DepartureSolver<'ctx>,
+    depth: u32,
+}
 
-                                continue 'iteration;
+impl Drop for SolverScope<'_, '_> {
+    fn drop(&mut self) {
+        self.solver.pop(self.solver.scopes.get() - self.depth);
+    }
+}
 
-                            }
+impl<'ctx> DepartureSolver<'ctx> {
+    fn new(ctx: &'ctx z3::Context, optimize: bool) -> Self {
+        DepartureSolver {
+            backend: if optimize {
+                SolverBackend::Optimize(z3::Optimize::new(ctx))
+            } else {
+                SolverBackend::Solver(z3::Solver::new(ctx))
+            },
+            scopes: Cell::new(0),
+        }
+    }
 
-                            let travel_distance = f64_min(
-                                f64_min(
-                                    time_left,
-                                    f64_max(track_mut.length as f64 - curr_train_mut.pos, 0.0),
-                                ),
-                                f64_max(
-                                    if curr_train_mut.pos + MIN_TRAIN_DISTANCE >= last_train_pos {
-                                        last_train_pos - MIN_TRAIN_DISTANCE - curr_train_mut.pos
-                                    } else {
-                                        last_train_pos - curr_train_mut.pos
-                                    },
-                                    0.0,
-                                ),
-                            );
-                            
-                            curr_train_mut.pos += travel_distance;
-                            time_left -= travel_distance;
-                            // we're done with the current track, and need to move into the station
-                            if curr_train_mut.pos >= track_mut.length as f64
-                                && self.stations[&next_station_id].train.is_none()
-                            {
-                                match self.stations[&next_station_id].train {
-                                    None => {
-                                        debug_assert_eq!(i, 0);
-                                        track_mut.trains.pop_front();
-                                        debug_assert!(
-                                            self.stations
-                                                .get_mut(&next_station_id)
-                                                .unwrap()
-                                                .train
-                                                .is_none(),
-                                            "travel distance is {travel_distance}"
-                                        );
-                                        let next_station_mut =
-                                            self.stations.get_mut(&next_station_id).unwrap();
-                                        next_station_mut.train = Some(curr_train_id);
-                                        if t >= 0 {
-                                            next_station_mut
-                                                .arrival_times
-                                                .entry(curr_train_mut.route)
-                                                .or_default()
-                                                .push(t as f64 + travel_distance);
-                                        }
+    fn assert(&self, ast: &z3::ast::Bool<'ctx>) {
+        match &self.backend {
+            SolverBackend::Solver(solver) => solver.assert(ast),
+            SolverBackend::Optimize(optimize) => optimize.assert(ast),
+        }
+    }
 
-                                        curr_train_mut.distance_travelled +=
-                                            self.tracks[&track].length as f64;
+    /// Soft constraints are ignored without an optimizer
+    fn assert_soft(&self, ast: &z3::ast::Bool<'ctx>, weight: u32) {
+        if let SolverBackend::Optimize(optimize) = &self.backend {
+            optimize.assert_soft(ast, weight, None);
+        }
+    }
 
-                                        curr_train_mut.curr_section =
-                                            TrackStationId::Station(next_station_id);
-                                        curr_train_mut.pos = 0.0;
+    fn push(&self) {
+        match &self.backend {
+            SolverBackend::Solver(solver) => solver.push(),
+            SolverBackend::Optimize(optimize) => optimize.push(),
+        }
+        self.scopes.set(self.scopes.get() + 1);
+    }
 
-                                        self.station_to_track(next_station_id, time_left);
-                                        // TODO: handle the fact that some station time may be wasted when the train could keep moving
-                                        // potentially some of this spaghetti code needs to get factored out into
-                                        // a separate function, tbd
-                                    }
-                                    Some(_) => {
-                                        panic!("wait how are we getting here?");
-                                    }
-                                };
-                            } else {
-                                last_train_pos = curr_train_mut.pos;
-                                i += 1;
-                            }
-                        }
-                    }
-                }
-            }
+    fn pop(&self, n: u32) {
+        match &self.backend {
+            SolverBackend::Solver(solver) => solver.pop(n),
+            SolverBackend::Optimize(optimize) => (0..n).for_each(|_| optimize.pop()),
+        }
+        self.scopes.set(self.scopes.get() - n);
+    }
 
-            routes.shuffle(&mut rng);
-            for (id, route) in &routes {
-                let start_station_mut = self.stations.get_mut(&route.start_station).unwrap();
-                // TODO: do I need to handle the case where this is not true?
-                let curr_train_id = TrainId {
-                    route_idx: id.0,
-                    count: self.curr_train_counts[id.0 as usize],
-                };
+    fn scope(&self) -> SolverScope<'_, 'ctx> {
+        let depth = self.scopes.get();
+        self.push();
+        SolverScope {
+            solver: self,
+            depth,
+        }
+    }
 
-                if frequencies[(t as i64 / SCHEDULE_GRANULARITY) as usize][&route.name] == 0 {
-                    continue;
-                }
+    fn check_assumptions(
+        &self,
+        assumptions: &[z3::ast::Bool<'ctx>],
+        solve_time: &mut Duration,
+    ) -> z3::SatResult {
+        let started = Instant::now();
+        let result = match &self.backend {
+            SolverBackend::Solver(solver) => solver.check_assumptions(assumptions),
+            SolverBackend::Optimize(optimize) => optimize.check(assumptions),
+        };
+        *solve_time += started.elapsed();
+        result
+    }
 
-                if start_station_mut.train.is_none() {
-                    let train = Train {
-                        id: curr_train_id,
-                        curr_section: TrackStationId::Station(start_station_mut.id),
-                        pos: 0.0,
-                        distance_travelled: 0.0,
-                        route: *id,
-                    };
+    /// Whether the assertions hold under the assumptions, failing when z3 can't tell
+    fn decide(
+        &self,
+        assumptions: &[z3::ast::Bool<'ctx>],
+        solve_time: &mut Duration,
+    ) -> Result<bool, SimError> {
+        match self.check_assumptions(assumptions, solve_time) {
+            z3::SatResult::Sat => Ok(true),
+            z3::SatResult::Unsat => Ok(false),
+            z3::SatResult::Unknown => Err(SimError::Solver(
+                "z3 couldn't decide whether the departures fit".to_string(),
+            )),
+        }
+    }
 
-                    // logic to handle when trying to schedule trains:
-                    // - should we wait if there's currently a train too close on the directly proceeding
-                    //   track? right now, will say no, but otherwise this would be the first check
-                    // - check if we've failed frequency requirements for last bin. if yes, report fail
-                    // - add an assertion to solver saying current train departure = time. if we get a sat
-                    //   model, proceed. otherwise, don't schedule a train
-                    // - the difficult thing to figure out is: what assertions can we keep permanently,
-                    //   and what can we get rid of? would be nice if we could maintain a list of permanent assumptions
-                    //   but otherwise do pop and push logic. a nice thing to assert then pop is depart_var = (or >=) curr time
-                    //   however when backtracking this could of course get invalidated, or could it? think about this
-
-                    let curr_train_z3 = curr_train_id.to_z3_departure(&z3_context);
-                    let curr_time_z3 = z3::ast::Int::from_i64(&z3_context, t.into());
-
-                    z3_solver.push();
-                    if z3_solver.check_assumptions(&[curr_train_z3.ge(&curr_time_z3)])
-                        != z3::SatResult::Sat
-                    {
-                        // TODO attempt to allow a merge later? requires complex pruning of assertions
-                        return None;
-                    }
-                    let z3_departure_equality = curr_train_z3._eq(&curr_time_z3);
-                    z3_solver.assert(&z3_departure_equality);
-                    if z3_solver.check_assumptions(&[]) != z3::SatResult::Sat {
-                        z3_solver.pop(1);
-                        continue;
-                    }
+    /// The departure time of a train in the optimal model of the last check, if optimizing
+    fn preferred_departure(&self, train: &z3::ast::Int<'ctx>) -> Option<i64> {
+        match &self.backend {
+            SolverBackend::Solver(_) => None,
+            SolverBackend::Optimize(optimize) => optimize
+                .get_model()
+                .and_then(|model| model.eval(train, true))
+                .and_then(|departure| departure.as_i64()),
+        }
+    }
+}
 
-                    z3_solver.pop(1);
-                    z3_solver.assert(&z3_departure_equality);
+// Sections in the order trains on them are moved each step, from the ends of the lines back, so
+// that trains ahead have made room before the ones behind them move. Only track in
+// all_route_edges is visited.
+fn traversal_order(
+    subway_map: &SubwayMap,
+    all_route_edges: &HashSet<TrackId>,
+) -> Vec<TrackStationId> {
+    let terminal_nodes = terminal_nodes(subway_map, all_route_edges);
+    let mut queue: VecDeque<TrackStationId> = terminal_nodes
+        .into_iter()
+        .map(TrackStationId::Station)
+        .collect();
+    let mut traversal_order: Vec<TrackStationId> = Vec::new();
+    let mut visited = HashSet::new();
+    let mut deferred = Vec::new();
 
-                    *frequencies[(t as i64 / SCHEDULE_GRANULARITY) as usize]
-                        .get_mut(&route.name)
-                        .unwrap() -= 1;
+    loop {
+        'bfs: while let Some(track_station) = queue.pop_front() {
+            if visited.contains(&track_station) {
+                continue;
+            };
 
-                    start_station_mut.train = Some(curr_train_id);
-                    if t >= 0 {
-                        start_station_mut
-                            .arrival_times
-                            .entry(*id)
-                            .or_default()
-                            .push(t as f64);
+            // If we get to a station where some of the edges it feeds into haven't been processed yet,
+            // skip processing now, with the assumption that we'll be returning later. Note that this
+            // requires all meaningful tracks to be assigned to a route.
+            if let TrackStationId::Station(station) = track_station {
+                for edge in subway_map
+                    .edges_directed(station, Direction::Outgoing)
+                    .filter(|e| all_route_edges.contains(&e.id()))
+                {
+                    if !visited.contains(&TrackStationId::Track(edge.id())) {
+                        deferred.push(station);
+                        continue 'bfs;
                     }
-                    self.trains.insert(curr_train_id, train);
-                    train_to_route.insert(curr_train_id, *id);
-                    train_scheduled_at.insert(curr_train_id, t);
-                    self.curr_train_counts[id.0 as usize] += 1;
                 }
             }
 
-            let mut curr_train_positions = Vec::new();
-            for (id, train) in &self.trains {
-                curr_train_positions.push(TrainPosition {
-                    id: *id,
-                    curr_section: train.curr_section,
-                    pos: train.pos,
-                    distance_travelled: train.distance_travelled,
-                })
-            }
-            if t >= 0 {
-                train_positions.push(TrainPositions {
-                    time: t as u32,
-                    trains: curr_train_positions,
-                });
+            visited.insert(track_station);
+            traversal_order.push(track_station);
+            match track_station {
+                TrackStationId::Track(track) => {
+                    let (source, _) = subway_map.edge_endpoints(track).unwrap();
+                    queue.push_back(TrackStationId::Station(source));
+                }
+                TrackStationId::Station(station) => subway_map
+                    .edges_directed(station, Direction::Incoming)
+                    .filter(|edge| all_route_edges.contains(&edge.id()))
+                    .for_each(|track| queue.push_back(TrackStationId::Track(track.id()))),
             }
-
-            t += 1;
         }
 
-        Some((SimulationResults {
-            train_positions,
-            train_to_route,
-            station_statistics: self
-                .stations
-                .iter()
-                .map(|(id, s)| {
-                    (
-                        *id,
-                        StationStatistic {
-                            arrival_times: s.arrival_times.clone(),
-                        },
-                    )
-                })
-                .collect(),
-        }, new_conflicts))
+        // Around a loop every station waits on the next one, so the search stops short of it.
+        // Break the loop at a track out of a station it got to, or any left if it got to none.
+        let unvisited = |track: &TrackId| !visited.contains(&TrackStationId::Track(*track));
+        let cut = deferred
+            .iter()
+            .flat_map(|station| subway_map.edges_directed(*station, Direction::Outgoing))
+            .map(|edge| edge.id())
+            .filter(|track| all_route_edges.contains(track))
+            .find(unvisited)
+            .or_else(|| all_route_edges.iter().copied().filter(unvisited).min());
+        match cut {
+            Some(track) => queue.push_back(TrackStationId::Track(track)),
+            None => break,
+        }
     }
+    traversal_order
 }
 
-/// Gets all nodes that have no out edges
-fn terminal_nodes(graph: &SubwayMap) -> Vec<NodeIndex> {
+/// Gets all nodes that have no out edges routes run over
+fn terminal_nodes(graph: &SubwayMap, all_route_edges: &HashSet<TrackId>) -> Vec<NodeIndex> {
     graph
         .node_indices()
         .filter(|&node| {
             graph
                 .edges_directed(node, Direction::Outgoing)
-                .filter(|e| e.weight().ty == EdgeType::Track)
+                .filter(|e| all_route_edges.contains(&e.id()))
                 .count()
                 == 0
         })
         .collect()
 }
 
+/// How the riders of a trip show up at its origin
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArrivalProcess {
+    /// Riders spread uniformly at random over the trip's time bucket
+    #[default]
+    Uniform,
+    /// Riders arrive as a Poisson process averaging the trip's riders per bucket, so how many
+    /// turn up varies too
+    Poisson,
+    /// Riders arrive at exactly these times, each trip taking those in its bucket up to its
+    /// riders
+    Scheduled(Vec<f64>),
+}
+
+impl ArrivalProcess {
+    /// Draws the arrival times of riders over [bucket_start, bucket_end). Uniform arrivals round
+    /// riders to a whole count, Poisson arrivals average it and scheduled ones come to at most it.
+    pub fn arrival_times(
+        &self,
+        riders: f64,
+        bucket_start: f64,
+        bucket_end: f64,
+        rng: &mut impl Rng,
+    ) -> Vec<f64> {
+        let mut times = match self {
+            ArrivalProcess::Uniform => (0..riders.round() as usize)
+                .map(|_| rng.gen_range(bucket_start..bucket_end))
+                .collect(),
+            ArrivalProcess::Poisson => {
+                let rate = riders / (bucket_end - bucket_start);
+                let mut times = Vec::new();
+                let mut time = bucket_start;
+                loop {
+                    // exponential inter-arrival times; 1 - u avoids ln(0)
+                    time -= (1. - rng.gen::<f64>()).ln() / rate;
+                    if time >= bucket_end {
+                        break times;
+                    }
+                    times.push(time);
+                }
+            }
+            ArrivalProcess::Scheduled(times) => times
+                .iter()
+                .copied()
+                .filter(|time| (bucket_start..bucket_end).contains(time))
+                .collect(),
+        };
+        times.sort_by(f64::total_cmp);
+        if let ArrivalProcess::Scheduled(_) = self {
+            times.truncate(riders.round() as usize);
+        }
+        times
+    }
+}
+
 pub struct Trip {
     pub start: NodeIndex,
     pub end: NodeIndex,
-    pub count: usize,
+    /// When each rider arrives at the origin, drawn from the trip's arrival process
+    pub arrival_times: Vec<f64>,
+}
+
+impl Trip {
+    pub fn new(
+        start: NodeIndex,
+        end: NodeIndex,
+        riders: f64,
+        bucket_start: f64,
+        bucket_end: f64,
+        arrival_process: &ArrivalProcess,
+        rng: &mut impl Rng,
+    ) -> Self {
+        Trip {
+            start,
+            end,
+            arrival_times: arrival_process.arrival_times(riders, bucket_start, bucket_end, rng),
+        }
+    }
 }
 
 pub type TripData = HashMap<i64, Vec<Trip>>;
 // A map from route ids to scheduled times for the trains to depart
-type Schedule = HashMap<String, Vec<i64>>;
+pub type Schedule = HashMap<String, Vec<i64>>;
 
 pub const SCHEDULE_GRANULARITY: i64 = 12;
 pub const SCHEDULE_PERIOD: i64 = 120;
 
 type Frequencies = Vec<HashMap<String, Cell<i64>>>;
 
+/// Relative weights of the soft goals balanced when smoothing dispatches. A weight of 0 drops
+/// the goal entirely.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ObjectiveWeights {
+    /// Keep consecutive departures of a route within a period evenly spaced
+    pub regularity: u32,
+    /// Dispatch trains early in their period, getting them into service sooner
+    pub throughput: u32,
+    /// Keep departures at the even spacing anchored on each route's requested offset
+    pub offset_adherence: u32,
+    /// Get feeders' riders to the timed transfers of OptimizeOptions shortly before the trains
+    /// they connect with leave
+    pub transfers: u32,
+    /// Spread out the trains of routes merging at a station
+    pub merge_smoothness: u32,
+}
+
+impl Default for ObjectiveWeights {
+    fn default() -> Self {
+        Self {
+            regularity: 0,
+            throughput: 0,
+            offset_adherence: 1,
+            transfers: 0,
+            merge_smoothness: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct OptimizeOptions {
+    /// Treat time modulo SCHEDULE_PERIOD, producing a timetable that repeats every period
+    pub cyclic: bool,
+    /// Softly prefer departures spread evenly across each period rather than clustered
+    pub smooth_dispatches: bool,
+    /// How dispatch smoothing and polishing trade off their goals
+    pub weights: ObjectiveWeights,
+    /// Once the frequencies are settled, run them again smoothing dispatches against all the
+    /// weights, to polish the departures the search happened to find
+    pub polish: bool,
+    /// How riders of the sampled trips arrive within their minute
+    pub arrival_process: ArrivalProcess,
+    /// Cost of running each train of the schedule, in the same units as riders' costs. A train
+    /// is only added if it saves riders more than this.
+    pub operator_cost: f64,
+    /// Times the optimizer is run in parallel, each from the next seed on, keeping the best
+    /// schedule. Once when 0 or 1.
+    pub restarts: u32,
+    /// Seed of the trips sampled and the order routes are scheduled in, set by the command
+    #[serde(skip)]
+    pub seed: u64,
+    /// Origins and destinations whose riders' costs are recorded at every increment, set by
+    /// the command
+    #[serde(skip)]
+    pub audit: Vec<(StationId, StationId)>,
+    /// Stations whose trains should meet their feeders, weighed by ObjectiveWeights::transfers,
+    /// set by the command
+    #[serde(skip)]
+    pub timed_transfers: Vec<TimedTransfer>,
+}
+
+/// How the cost model priced a rider of an audited trip
+#[derive(Debug, Clone)]
+pub struct AuditedRider {
+    pub origin: StationId,
+    pub destination: StationId,
+    pub arrival_time: f64,
+    /// Cheapest of the trip's paths, None if none of them has service
+    pub path: Option<PathCost>,
+}
+
+/// The riders of the audited trips as priced for an increment the optimizer picked, see
+/// OptimizeOptions::audit
+#[derive(Debug, Clone)]
+pub struct CostAudit {
+    /// Route and period given another train
+    pub route: String,
+    pub period: usize,
+    /// Cost of every rider with the increment, which made it the best candidate
+    pub cost: f64,
+    /// Whether the schedule still ran with the extra train and it was kept
+    pub accepted: bool,
+    pub riders: Vec<AuditedRider>,
+}
+
+/// Seconds spent on each part of an optimization run
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OptimizeProfile {
+    /// Rating candidate frequency increments
+    pub cost_estimation: f64,
+    /// Building the search map and finding riders' paths on it
+    pub search_map: f64,
+    /// Simulating schedules, not counting z3
+    pub simulation: f64,
+    pub solving: f64,
+    /// Turning the results into their form for the frontend
+    pub serialization: f64,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn optimize(
     subway_map: SubwayMap,
     routes: Vec<Route>,
     trip_data: &TripData,
     shortest_paths: &HashMap<(NodeIndex, NodeIndex), Vec<Vec<PathSegment>>>,
-) -> (Schedule, Option<SimulationResults>) {
+    config: SimulationConfig,
+    options: &OptimizeOptions,
+    cancelled: &AtomicBool,
+    audit: &mut Vec<CostAudit>,
+) -> Result<(Schedule, Option<SimulationResults>, OptimizeProfile), SimError> {
+    let mut profile = OptimizeProfile::default();
+    // time spent in z3 checks over all schedule_trains calls
+    let mut solve_time = Duration::ZERO;
     let mut frequencies: Frequencies =
         Vec::with_capacity((SCHEDULE_PERIOD / SCHEDULE_GRANULARITY) as usize);
+    // overlays have to earn their trains
+    let initial = |route: &Route| if route.overlay { 0 } else { 1 };
     for _ in 0..(SCHEDULE_PERIOD / SCHEDULE_GRANULARITY) {
         let mut map = HashMap::with_capacity(routes.len());
         for route in &routes {
-            map.insert(route.name.clone(), Cell::new(1));
+            map.insert(route.name.clone(), Cell::new(initial(route)));
         }
         frequencies.push(map);
     }
@@ -830,19 +5929,23 @@ pub fn optimize(
     for route in &routes {
         curr_schedule.insert(
             route.name.clone(),
-            vec![1; (SCHEDULE_PERIOD / SCHEDULE_GRANULARITY) as usize],
+            vec![initial(route); (SCHEDULE_PERIOD / SCHEDULE_GRANULARITY) as usize],
         );
     }
 
     let mut curr_simulation_results = None;
 
+    let headway_floors = HeadwayFloors::new(&subway_map, &routes);
+    let started = Instant::now();
     let mut search_map = SearchMap::generate(&subway_map, &routes);
+    profile.search_map += started.elapsed().as_secs_f64();
 
     let mut routes_vec = Vec::with_capacity(routes.len());
     for route in &routes {
         routes_vec.push(route.clone());
     }
-    let mut simulator = Simulator::new(subway_map, routes_vec);
+    let mut simulator = Simulator::new(subway_map, routes_vec, config);
+    simulator.add_demand(&search_map, trip_data, shortest_paths);
 
     // use z3 SMT to calculate train position bounds
     // details: each train is scheduled to depart at an integer time.
@@ -855,8 +5958,12 @@ pub fn optimize(
     
     // z3 conflict clauses learned over time
     let mut conflicts = Vec::new();
+    let mut ground_rules = GroundRules::default();
 
     loop {
+        if cancelled.load(Ordering::Relaxed) {
+            return Err(SimError::Cancelled);
+        }
         let mut best_fragment = None;
         let mut lowest_cost = f64::INFINITY;
 
@@ -868,14 +5975,24 @@ pub fn optimize(
                     continue;
                 }
                 frequency.set(frequency.get() + 1);
+                // the scheduler couldn't fit the trains through anyway
+                if headway_floors.violated(id, route_frequencies) {
+                    frequency.set(frequency.get() - 1);
+                    continue;
+                }
                 // calculate cost if frequency goes up by increment of 1
+                let started = Instant::now();
                 let estimated_cost = calculate_costs(
                     &mut search_map,
                     &frequencies,
                     &routes,
                     trip_data,
                     shortest_paths,
-                );
+                    options,
+                    None,
+                ) + options.operator_cost
+                    * scheduled_trains(&frequencies) as f64;
+                profile.cost_estimation += started.elapsed().as_secs_f64();
                 if estimated_cost < lowest_cost {
                     lowest_cost = estimated_cost;
                     best_fragment = Some((time, id.clone()));
@@ -888,7 +6005,32 @@ pub fn optimize(
             Some(best_fragment) => best_fragment,
             None => {
                 println!("Found with cost: {curr_cost}");
-                return (curr_schedule, curr_simulation_results);
+                if options.polish && curr_simulation_results.is_some() {
+                    let started = Instant::now();
+                    let polish_options = OptimizeOptions {
+                        smooth_dispatches: true,
+                        ..options.clone()
+                    };
+                    // the rules of the search were built without the soft goals
+                    let polished = simulator.schedule_trains(
+                        SCHEDULE_PERIOD as i32,
+                        &frequencies,
+                        &z3_context,
+                        &conflicts,
+                        &mut GroundRules::default(),
+                        &polish_options,
+                        &mut solve_time,
+                    )?;
+                    simulator.reset();
+                    profile.simulation += started.elapsed().as_secs_f64();
+                    // if polishing can't fit the trains, the departures found stand
+                    if let Some((simulation_results, _)) = polished {
+                        curr_simulation_results = Some(simulation_results);
+                    }
+                }
+                profile.solving = solve_time.as_secs_f64();
+                profile.simulation -= profile.solving;
+                return Ok((curr_schedule, curr_simulation_results, profile));
             }
         };
 
@@ -898,10 +6040,38 @@ pub fn optimize(
             .get_mut(&best_fragment.1)
             .unwrap()
             .get_mut() += 1;
-        let simulation_results =
-            simulator.schedule_trains(SCHEDULE_PERIOD as i32, &frequencies, &z3_context, &conflicts);
+        let mut audited = (!options.audit.is_empty()).then(|| {
+            let mut riders = Vec::new();
+            let cost = calculate_costs(
+                &mut search_map,
+                &frequencies,
+                &routes,
+                trip_data,
+                shortest_paths,
+                options,
+                Some(&mut riders),
+            );
+            CostAudit {
+                route: best_fragment.1.clone(),
+                period: best_fragment.0,
+                cost,
+                accepted: false,
+                riders,
+            }
+        });
+        let started = Instant::now();
+        let simulation_results = simulator.schedule_trains(
+            SCHEDULE_PERIOD as i32,
+            &frequencies,
+            &z3_context,
+            &conflicts,
+            &mut ground_rules,
+            options,
+            &mut solve_time,
+        )?;
         simulator.reset();
-        
+        profile.simulation += started.elapsed().as_secs_f64();
+
         // TODO should we get an actual cost estimate here?
         let cost = if let Some((simulation_results, mut new_conflicts)) = simulation_results {
             curr_simulation_results = Some(simulation_results);
@@ -911,6 +6081,10 @@ pub fn optimize(
             f64::INFINITY
         };
 
+        if let Some(mut audited) = audited.take() {
+            audited.accepted = cost < curr_cost;
+            audit.push(audited);
+        }
         if cost < curr_cost {
             curr_cost = cost;
             curr_schedule.get_mut(&best_fragment.1).unwrap()[best_fragment.0] += 1;
@@ -924,6 +6098,51 @@ pub fn optimize(
     }
 }
 
+/// The closest headway each track allows, with the routes sharing it
+struct HeadwayFloors {
+    tracks: Vec<(Vec<String>, f64)>,
+}
+
+impl HeadwayFloors {
+    fn new(subway_map: &SubwayMap, routes: &[Route]) -> Self {
+        let mut track_routes: HashMap<TrackId, Vec<String>> = HashMap::new();
+        for route in routes {
+            for track in route.station_to.values() {
+                track_routes
+                    .entry(*track)
+                    .or_default()
+                    .push(route.name.clone());
+            }
+        }
+        let tracks = track_routes
+            .into_iter()
+            .map(|(track, routes)| {
+                let edge = subway_map[track];
+                let (_, station) = subway_map.edge_endpoints(track).unwrap();
+                // trains follow a block apart on each parallel track, and still stop one at a
+                // time at the station it leads to
+                let floor = f64_max(
+                    edge.block_length / edge.tracks.max(1) as f64,
+                    subway_map[station].dwell_time(TimeUnit::Minutes),
+                );
+                (routes, floor)
+            })
+            .collect();
+        HeadwayFloors { tracks }
+    }
+
+    /// Whether trains of all routes in a period would run closer than a track route uses allows
+    fn violated(&self, route: &str, period: &HashMap<String, Cell<i64>>) -> bool {
+        self.tracks
+            .iter()
+            .filter(|(routes, _)| routes.iter().any(|r| r == route))
+            .any(|(routes, floor)| {
+                let trains: i64 = routes.iter().map(|r| period[r].get()).sum();
+                (SCHEDULE_GRANULARITY as f64) / (trains as f64) < *floor
+            })
+    }
+}
+
 // for search, modify graph? what we could do is duplicate each node and edge per route. then if
 // a route is no longer helpful for us, we dip
 
@@ -931,10 +6150,11 @@ pub fn optimize(
 // creates a map where each route has its own nodes and edges; if two routes share the same
 // nodes and edges, walk nodes of cost 0 connect them
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(Clone)]
 pub struct SearchNode {
     route: String,
     old_node: NodeIndex,
+    dwell_time: f64,
 }
 
 #[derive(Copy, Clone)]
@@ -966,6 +6186,10 @@ impl From<Edge> for SearchEdge {
 
 pub type SearchGraph = Graph<SearchNode, SearchEdge>;
 
+/// Version of what SearchMap::generate builds, hashed into the network hash of stored maps
+const SEARCH_MAP_VERSION: u32 = 3;
+
+#[derive(Clone)]
 pub struct SearchMap {
     map: SearchGraph,
     old_to_new_nodes: HashMap<NodeIndex, Vec<NodeIndex>>,
@@ -984,20 +6208,35 @@ impl SearchMap {
         let mut new_to_old_edges = HashMap::new();
 
         let mut route_old_to_new_nodes = HashMap::new();
+        let mut node_routes = HashMap::new();
 
         // For each route create nodes and edges for it
-        for route in routes {
+        for (route_id, route) in routes.iter().enumerate() {
             let mut create_node =
                 |old_node: NodeIndex, search_map: &mut SearchGraph| -> NodeIndex {
                     match route_old_to_new_nodes.get(&(&route.name, old_node)) {
                         Some(node) => *node,
                         None => {
-                            let new_nodes = old_to_new_nodes.entry(old_node).or_insert(Vec::new());
+                            // riders can't board or alight where the route doesn't stop, or
+                            // trains running empty, so those aren't linked to the station's
+                            // other nodes
+                            let pass_through = route.pass_through.contains(&old_node);
                             let route_node = search_map.add_node(SearchNode {
                                 route: route.name.clone(),
                                 old_node,
+                                dwell_time: if pass_through {
+                                    0.
+                                } else {
+                                    subway_map[old_node].dwell_time(TimeUnit::Minutes)
+                                },
                             });
-                            new_nodes.push(route_node);
+                            if !pass_through && !route.deadhead {
+                                old_to_new_nodes
+                                    .entry(old_node)
+                                    .or_insert(Vec::new())
+                                    .push(route_node);
+                            }
+                            node_routes.insert(route_node, RouteId(route_id as u32));
                             route_old_to_new_nodes.insert((&route.name, old_node), route_node);
                             route_node
                         }
@@ -1008,6 +6247,11 @@ impl SearchMap {
                 let (start, end) = subway_map.edge_endpoints(*edge).unwrap();
                 let new_start_node = create_node(start, &mut search_map);
                 let new_end_node = create_node(end, &mut search_map);
+                // trains run over non-revenue track and deadhead, but riders can't be routed
+                // along them
+                if route.deadhead || !subway_map[*edge].revenue {
+                    continue;
+                }
                 let new_edge =
                     search_map.add_edge(new_start_node, new_end_node, subway_map[*edge].into());
                 old_to_new_edges
@@ -1020,18 +6264,32 @@ impl SearchMap {
 
         // Connect virtual nodes that correspond to the same station together with walk edges. This
         // represents the transfer necessary to move between routes
-        for related_nodes in old_to_new_nodes.values() {
+        let transfer_time = |from: NodeIndex, to: NodeIndex, station: StationId| {
+            routes[node_routes[&from].0 as usize]
+                .transfer_times
+                .get(&(station, node_routes[&to]))
+                .copied()
+        };
+        for (station, related_nodes) in &old_to_new_nodes {
             for i in 0..related_nodes.len() - 1 {
                 for j in i + 1..related_nodes.len() {
-                    search_map.add_edge(
-                        related_nodes[i],
-                        related_nodes[j],
-                        SearchEdge {
-                            ty: crate::EdgeType::Walk,
-                            weight: 1,
-                            disabled: false,
-                        },
-                    );
+                    let (a, b) = (related_nodes[i], related_nodes[j]);
+                    // the search follows edges one way, so link the pair both ways; a time
+                    // measured only the other way stands in for one that wasn't
+                    for (from, to) in [(a, b), (b, a)] {
+                        let weight = transfer_time(from, to, *station)
+                            .or_else(|| transfer_time(to, from, *station))
+                            .map_or(1, |time| time.ceil() as u16);
+                        search_map.add_edge(
+                            from,
+                            to,
+                            SearchEdge {
+                                ty: crate::EdgeType::Walk,
+                                weight,
+                                disabled: false,
+                            },
+                        );
+                    }
                 }
             }
         }
@@ -1057,13 +6315,222 @@ impl SearchMap {
                 }
             }
         }
-
-        SearchMap {
-            map: search_map,
-            old_to_new_nodes,
-            old_to_new_edges,
-            new_to_old_edges,
-        }
+
+        SearchMap {
+            map: search_map,
+            old_to_new_nodes,
+            old_to_new_edges,
+            new_to_old_edges,
+        }
+    }
+
+    /// Hash of everything generate builds the search map from, so a stored one is only reused
+    /// for the same network and routes
+    pub fn network_hash(subway_map: &SubwayMap, routes: &[Route]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        // bumped whenever generate changes what it builds, so maps stored before aren't reused
+        SEARCH_MAP_VERSION.hash(&mut hasher);
+        for node in subway_map.node_indices() {
+            subway_map[node]
+                .dwell_time(TimeUnit::Minutes)
+                .to_bits()
+                .hash(&mut hasher);
+        }
+        for edge in subway_map.edge_references() {
+            let weight = edge.weight();
+            (
+                edge.source(),
+                edge.target(),
+                weight.ty,
+                weight.weight,
+                weight.revenue,
+            )
+                .hash(&mut hasher);
+        }
+        for route in routes {
+            (&route.name, route.deadhead).hash(&mut hasher);
+            let mut tracks: Vec<_> = route.station_to.values().collect();
+            tracks.sort();
+            tracks.hash(&mut hasher);
+            let mut pass_through: Vec<_> = route.pass_through.iter().collect();
+            pass_through.sort();
+            pass_through.hash(&mut hasher);
+            let mut transfer_times: Vec<_> = route
+                .transfer_times
+                .iter()
+                .map(|((station, to), time)| (*station, to.0, time.to_bits()))
+                .collect();
+            transfer_times.sort();
+            transfer_times.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    pub fn to_stored(&self) -> StoredSearchMap {
+        StoredSearchMap {
+            nodes: self
+                .map
+                .node_weights()
+                .map(|node| StoredSearchNode {
+                    route: node.route.clone(),
+                    old_node: node.old_node.index() as u32,
+                    dwell_time: node.dwell_time,
+                })
+                .collect(),
+            edges: self
+                .map
+                .edge_references()
+                .map(|edge| StoredSearchEdge {
+                    source: edge.source().index() as u32,
+                    target: edge.target().index() as u32,
+                    ty: edge.weight().ty,
+                    weight: edge.weight().weight,
+                })
+                .collect(),
+            old_to_new_nodes: stored_indices(&self.old_to_new_nodes),
+            old_to_new_edges: stored_indices(&self.old_to_new_edges),
+        }
+    }
+
+    /// The search map as it was stored, None if it refers to nodes or edges it doesn't have
+    pub fn from_stored(stored: StoredSearchMap) -> Option<Self> {
+        let mut map = SearchGraph::with_capacity(stored.nodes.len(), stored.edges.len());
+        for node in stored.nodes {
+            map.add_node(SearchNode {
+                route: node.route,
+                old_node: NodeIndex::new(node.old_node as usize),
+                dwell_time: node.dwell_time,
+            });
+        }
+        for edge in stored.edges {
+            let (source, target) = (
+                NodeIndex::new(edge.source as usize),
+                NodeIndex::new(edge.target as usize),
+            );
+            if map.node_weight(source).is_none() || map.node_weight(target).is_none() {
+                return None;
+            }
+            map.add_edge(
+                source,
+                target,
+                SearchEdge {
+                    ty: edge.ty,
+                    weight: edge.weight,
+                    disabled: false,
+                },
+            );
+        }
+        let old_to_new_nodes = stored
+            .old_to_new_nodes
+            .into_iter()
+            .map(|(old, new)| {
+                let new: Vec<_> = new
+                    .into_iter()
+                    .map(|i| NodeIndex::new(i as usize))
+                    .collect();
+                new.iter()
+                    .all(|node| map.node_weight(*node).is_some())
+                    .then_some((NodeIndex::new(old as usize), new))
+            })
+            .collect::<Option<HashMap<_, _>>>()?;
+        let old_to_new_edges = stored
+            .old_to_new_edges
+            .into_iter()
+            .map(|(old, new)| {
+                let new: Vec<_> = new
+                    .into_iter()
+                    .map(|i| EdgeIndex::new(i as usize))
+                    .collect();
+                new.iter()
+                    .all(|edge| map.edge_weight(*edge).is_some())
+                    .then_some((EdgeIndex::new(old as usize), new))
+            })
+            .collect::<Option<HashMap<_, _>>>()?;
+        let new_to_old_edges = old_to_new_edges
+            .iter()
+            .flat_map(|(old, new)| new.iter().map(|new| (*new, *old)))
+            .collect();
+        Some(SearchMap {
+            map,
+            old_to_new_nodes,
+            old_to_new_edges,
+            new_to_old_edges,
+        })
+    }
+}
+
+fn stored_indices<I: GraphIndex>(map: &HashMap<I, Vec<I>>) -> Vec<(u32, Vec<u32>)> {
+    map.iter()
+        .map(|(old, new)| {
+            let new = new.iter().map(|i| i.index() as u32).collect();
+            (old.index() as u32, new)
+        })
+        .collect()
+}
+
+/// A search map as written to disk, nodes and edges numbered as they were in the graph
+#[derive(Serialize, Deserialize)]
+pub struct StoredSearchMap {
+    nodes: Vec<StoredSearchNode>,
+    edges: Vec<StoredSearchEdge>,
+    old_to_new_nodes: Vec<(u32, Vec<u32>)>,
+    old_to_new_edges: Vec<(u32, Vec<u32>)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredSearchNode {
+    route: String,
+    old_node: u32,
+    dwell_time: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredSearchEdge {
+    source: u32,
+    target: u32,
+    ty: EdgeType,
+    weight: u16,
+}
+
+/// The quickest walking or transferring connection between two stations the router will use
+pub struct ConnectionTime {
+    pub from: StationId,
+    pub to: StationId,
+    pub walk_time: u16,
+    /// cost of the connection as weighed by the optimizer
+    pub cost: f64,
+}
+
+impl SearchMap {
+    /// Computes the minimum connection time between every pair of stations linked by walk and
+    /// transfer edges. A station paired with itself gives the time to transfer between routes
+    /// there.
+    pub fn connection_times(&self) -> Vec<ConnectionTime> {
+        let walks = EdgeFiltered::from_fn(&self.map, |edge| edge.weight().ty == EdgeType::Walk);
+        let mut times: HashMap<(StationId, StationId), u16> = HashMap::new();
+        for from in self.map.node_indices() {
+            let from_station = self.map[from].old_node;
+            for (to, walk_time) in
+                petgraph::algo::dijkstra(&walks, from, None, |e| e.weight().weight)
+            {
+                if to == from {
+                    continue;
+                }
+                let time = times
+                    .entry((from_station, self.map[to].old_node))
+                    .or_insert(u16::MAX);
+                *time = min(*time, walk_time);
+            }
+        }
+        times
+            .into_iter()
+            .map(|((from, to), walk_time)| ConnectionTime {
+                from,
+                to,
+                walk_time,
+                cost: walk_time as f64 * WALK_MULTIPLIER,
+            })
+            .collect()
     }
 }
 
@@ -1085,6 +6552,12 @@ pub fn shortest_paths(
     mut k: usize,
 ) -> Vec<Vec<PathSegment>> {
     assert!(k >= 1);
+    // stations no route stops at can't be reached
+    if !(search_map.old_to_new_nodes.contains_key(&start)
+        && search_map.old_to_new_nodes.contains_key(&end))
+    {
+        return Vec::new();
+    }
     let valid_end_nodes: HashSet<_> = search_map.old_to_new_nodes[&end]
         .clone()
         .into_iter()
@@ -1097,6 +6570,7 @@ pub fn shortest_paths(
     let virtual_start_node = search_map.map.add_node(SearchNode {
         route: String::new(),
         old_node: NodeIndex::new(0),
+        dwell_time: 0.,
     });
 
     for start_node in start_nodes {
@@ -1185,13 +6659,380 @@ pub fn shortest_paths(
 
         k -= 1;
     }
-    paths.push(path);
+    // the quickest comes first, riders taking it unless they weigh up their choices
+    paths.insert(0, path);
 
     search_map.map.remove_node(virtual_start_node);
 
     paths
 }
 
+/// Suggests route offsets spreading out the trains of routes that share track, when every
+/// route runs each frequency steps. Exhaustively tries offset combinations for the routes sharing
+/// track, minimizing the variance of the merged headways along shared tracks, based on nominal
+/// running times. Routes that share no track keep their offset. Returns None when there are too
+/// many combinations to search.
+pub fn suggest_offsets(
+    subway_map: &SubwayMap,
+    routes: &[Route],
+    frequency: u64,
+) -> Option<Vec<u64>> {
+    const MAX_COMBINATIONS: u64 = 1_000_000;
+
+    let frequency = frequency.max(1);
+    // time after dispatch each route's trains enter each of its tracks
+    let mut track_times: HashMap<TrackId, Vec<(usize, f64)>> = HashMap::new();
+    for (i, route) in routes.iter().enumerate() {
+        let mut time = 0.0;
+        let mut station = route.start_station;
+        let mut visited = HashSet::new();
+        while visited.insert(station) {
+            time += subway_map[station].dwell_time(TimeUnit::Minutes);
+            let Some(track) = route.station_to.get(&station) else {
+                break;
+            };
+            track_times.entry(*track).or_default().push((i, time));
+            time += subway_map[*track].weight as f64;
+            station = subway_map.edge_endpoints(*track).unwrap().1;
+        }
+    }
+    track_times.retain(|_, times| times.len() > 1);
+
+    let mut offsets: Vec<u64> = routes.iter().map(|route| route.offset).collect();
+    let mut searched: Vec<usize> = track_times
+        .values()
+        .flatten()
+        .map(|(i, _)| *i)
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    searched.sort();
+    // only relative offsets matter, so the first route stays put
+    let Some((_, free)) = searched.split_first() else {
+        return Some(offsets);
+    };
+    if frequency
+        .checked_pow(free.len() as u32)
+        .is_none_or(|combinations| combinations > MAX_COMBINATIONS)
+    {
+        return None;
+    }
+
+    let merged_variance = |offsets: &[u64]| -> f64 {
+        let mut variance = 0.0;
+        for times in track_times.values() {
+            let mut phases: Vec<f64> = times
+                .iter()
+                .map(|(i, time)| (offsets[*i] as f64 + time).rem_euclid(frequency as f64))
+                .collect();
+            phases.sort_by(f64::total_cmp);
+            let ideal_gap = frequency as f64 / phases.len() as f64;
+            for (j, phase) in phases.iter().enumerate() {
+                let next = phases
+                    .get(j + 1)
+                    .copied()
+                    .unwrap_or(phases[0] + frequency as f64);
+                variance += (next - phase - ideal_gap).powi(2) / phases.len() as f64;
+            }
+        }
+        variance
+    };
+
+    let mut current = offsets.clone();
+    current[searched[0]] %= frequency;
+    for i in free {
+        current[*i] = 0;
+    }
+    let mut best = f64::INFINITY;
+    loop {
+        let variance = merged_variance(&current);
+        if variance < best {
+            best = variance;
+            offsets.clone_from(&current);
+        }
+        // advance to the next combination, odometer style
+        let mut advanced = false;
+        for i in free {
+            current[*i] += 1;
+            if current[*i] < frequency {
+                advanced = true;
+                break;
+            }
+            current[*i] = 0;
+        }
+        if !advanced {
+            return Some(offsets);
+        }
+    }
+}
+
+/// The train which last left a station if its tail is still at the platform
+fn train_leaving(
+    subway_map: &SubwayMap,
+    tracks: &HashMap<TrackId, Track>,
+    trains: &HashMap<TrainId, Train>,
+    station: StationId,
+) -> Option<TrainId> {
+    subway_map
+        .edges_directed(station, Direction::Outgoing)
+        .filter_map(|edge| tracks.get(&edge.id()))
+        .flat_map(|track| track.lanes.iter().filter_map(|lane| lane.back()))
+        .find(|train| trains[train].tail() < 0.0)
+        .copied()
+}
+
+/// Which of a track's parallel lanes has the most room behind its last train, of the first open
+/// ones if only some are
+fn roomiest_lane(track: &Track, trains: &HashMap<TrainId, Train>, open: Option<usize>) -> usize {
+    let mut lane = 0;
+    let mut room = f64::NEG_INFINITY;
+    let open = open.unwrap_or(track.lanes.len()).max(1);
+    for (i, lane_trains) in track.lanes.iter().enumerate().take(open) {
+        let lane_room = lane_trains
+            .back()
+            .map_or(f64::INFINITY, |last_train| trains[last_train].tail());
+        if lane_room > room {
+            lane = i;
+            room = lane_room;
+        }
+    }
+    lane
+}
+
+/// Position of the signal protecting the block a train at pos occupies, the furthest a train
+/// behind it may go. A train stopped at a signal is still in the block before it.
+fn signal_behind(pos: f64, block_length: f64) -> f64 {
+    ((pos / block_length).ceil() - 1.0).max(0.0) * block_length
+}
+
+/// Which of a route and its variants takes the route's next departure, going round them in turn
+fn dispatch_turn(parent: RouteId, variants: &[RouteId], train_counts: &[u32]) -> RouteId {
+    let dispatches: u32 = variants
+        .iter()
+        .chain([&parent])
+        .map(|route| train_counts[route.0 as usize])
+        .sum();
+    match dispatches as usize % (variants.len() + 1) {
+        0 => parent,
+        turn => variants[turn - 1],
+    }
+}
+
+/// Appends pull-out and pull-in moves for the routes with a depot, as deadhead routes turning
+/// around onto and off the route. Routes whose depot can't be reached over track keep being
+/// dispatched at their start station.
+fn add_depot_moves(subway_map: &SubwayMap, mut routes: Vec<Route>) -> Vec<Route> {
+    let route_count = routes.len();
+    let mut moves = Vec::new();
+    for (i, route) in routes.iter_mut().enumerate() {
+        let Some(depot) = route.depot else {
+            continue;
+        };
+        let Some(pull_out) = track_path(subway_map, depot, route.start_station) else {
+            continue;
+        };
+        let parent = RouteId(i as u32);
+        route.dispatched = false;
+        moves.push(Route {
+            name: format!("{} pull-out", route.name),
+            start_station: depot,
+            station_to: pull_out,
+            offset: route.offset,
+            combine_short_stops: false,
+            deadhead: true,
+            pass_through: HashSet::new(),
+            turnaround: Some(parent),
+            depot: None,
+            parent: Some(parent),
+            dispatched: true,
+            variant_of: None,
+            train_length: route.train_length,
+            cars: route.cars,
+            rolling_stock: route.rolling_stock.clone(),
+            transfer_times: HashMap::new(),
+            timetable: None,
+            standby: None,
+            overlay: false,
+            padding: 0.0,
+            max_trains: None,
+        });
+
+        if route.turnaround.is_some() {
+            continue;
+        }
+        let mut terminal = route.start_station;
+        let mut visited = HashSet::new();
+        while visited.insert(terminal) {
+            let Some(track) = route.station_to.get(&terminal) else {
+                break;
+            };
+            terminal = subway_map.edge_endpoints(*track).unwrap().1;
+        }
+        if let Some(pull_in) = track_path(subway_map, terminal, depot) {
+            route.turnaround = Some(RouteId((route_count + moves.len()) as u32));
+            moves.push(Route {
+                name: format!("{} pull-in", route.name),
+                start_station: terminal,
+                station_to: pull_in,
+                offset: route.offset,
+                combine_short_stops: false,
+                deadhead: true,
+                pass_through: HashSet::new(),
+                turnaround: None,
+                depot: None,
+                parent: Some(parent),
+                dispatched: false,
+                variant_of: None,
+                train_length: route.train_length,
+                cars: route.cars,
+                rolling_stock: route.rolling_stock.clone(),
+                transfer_times: HashMap::new(),
+                timetable: None,
+                standby: None,
+                overlay: false,
+                padding: 0.0,
+                max_trains: None,
+            });
+        }
+    }
+    routes.extend(moves);
+    routes
+}
+
+/// Stations and edges nothing can get to: neither on a route, counting depot moves, nor a walk
+/// away from a station on one
+/// Routes the depot moves simulators add after the given routes are for, in the order of the
+/// RouteIds they get
+pub fn depot_move_parents(subway_map: &SubwayMap, routes: &[Route]) -> Vec<RouteId> {
+    add_depot_moves(subway_map, routes.to_vec())
+        .into_iter()
+        .skip(routes.len())
+        .filter_map(|route| route.parent)
+        .collect()
+}
+
+pub fn unused_elements(
+    subway_map: &SubwayMap,
+    routes: &[Route],
+) -> (HashSet<StationId>, HashSet<TrackId>) {
+    let mut used_tracks = HashSet::new();
+    let mut stack = Vec::new();
+    for route in add_depot_moves(subway_map, routes.to_vec()) {
+        stack.push(route.start_station);
+        stack.extend(route.pass_through.iter().copied());
+        stack.extend(route.depot);
+        for &track in route.station_to.values() {
+            let (start, end) = subway_map.edge_endpoints(track).unwrap();
+            stack.extend([start, end]);
+            used_tracks.insert(track);
+        }
+    }
+    let mut used_stations = HashSet::new();
+    while let Some(station) = stack.pop() {
+        if !used_stations.insert(station) {
+            continue;
+        }
+        for edge in subway_map.edges(station) {
+            if edge.weight().ty == EdgeType::Walk {
+                used_tracks.insert(edge.id());
+                stack.push(edge.target());
+            }
+        }
+    }
+    (
+        subway_map
+            .node_indices()
+            .filter(|station| !used_stations.contains(station))
+            .collect(),
+        subway_map
+            .edge_indices()
+            .filter(|track| !used_tracks.contains(track))
+            .collect(),
+    )
+}
+
+/// Shortest way from start to end over any track, as the track leaving each station
+fn track_path(
+    subway_map: &SubwayMap,
+    start: StationId,
+    end: StationId,
+) -> Option<HashMap<StationId, TrackId>> {
+    let tracks = EdgeFiltered::from_fn(subway_map, |edge| edge.weight().ty == EdgeType::Track);
+    let (_, nodes) = astar(
+        &tracks,
+        start,
+        |node| node == end,
+        |edge| edge.weight().weight as f64,
+        |_| 0.0,
+    )?;
+    let station_to = nodes
+        .windows(2)
+        .map(|w| {
+            let track = subway_map
+                .edges_connecting(w[0], w[1])
+                .filter(|edge| edge.weight().ty == EdgeType::Track)
+                .min_by_key(|edge| edge.weight().weight)
+                .unwrap()
+                .id();
+            (w[0], track)
+        })
+        .collect();
+    Some(station_to)
+}
+
+/// Finds up to k distinct paths over track between two stations to use as the basis of new
+/// routes. After each path is found its edges are made more expensive, so later searches favor
+/// reasonable alternatives over trivial variations of the shortest path.
+pub fn candidate_route_paths(
+    subway_map: &SubwayMap,
+    start: StationId,
+    end: StationId,
+    k: usize,
+) -> Vec<(Vec<StationId>, Vec<TrackId>)> {
+    const ALTERNATIVE_PENALTY: f64 = 2.0;
+
+    let tracks = EdgeFiltered::from_fn(subway_map, |edge| {
+        edge.weight().ty == EdgeType::Track && edge.weight().revenue
+    });
+    let mut penalties: HashMap<TrackId, f64> = HashMap::new();
+    let mut paths: Vec<(Vec<StationId>, Vec<TrackId>)> = Vec::new();
+
+    // a few extra attempts, since a search can rediscover a path despite penalties
+    for _ in 0..k * 3 {
+        if paths.len() >= k {
+            break;
+        }
+        let path = astar(
+            &tracks,
+            start,
+            |node| node == end,
+            |edge| edge.weight().weight as f64 * penalties.get(&edge.id()).unwrap_or(&1.0),
+            |_| 0.0,
+        );
+        let Some((_, nodes)) = path else {
+            break;
+        };
+        let edges: Vec<_> = nodes
+            .windows(2)
+            .map(|w| {
+                subway_map
+                    .edges_connecting(w[0], w[1])
+                    .filter(|edge| edge.weight().ty == EdgeType::Track && edge.weight().revenue)
+                    .min_by_key(|edge| edge.weight().weight)
+                    .unwrap()
+                    .id()
+            })
+            .collect();
+        for edge in &edges {
+            *penalties.entry(*edge).or_insert(1.0) *= ALTERNATIVE_PENALTY;
+        }
+        if !paths.iter().any(|(_, existing)| *existing == edges) {
+            paths.push((nodes, edges));
+        }
+    }
+    paths
+}
+
 #[derive(Debug)]
 pub struct PathSegment {
     routes: HashSet<String>,
@@ -1270,7 +7111,7 @@ fn calculate_time_to(search_map: &SearchMap, mut node: NodeIndex) -> f64 {
         let mut new_node = node;
         for edge in search_map.map.edges_directed(node, Direction::Incoming) {
             if edge.weight().ty == EdgeType::Track {
-                time += edge.weight().weight as f64 + STATION_DWELL_TIME;
+                time += edge.weight().weight as f64 + search_map.map[edge.source()].dwell_time;
                 new_node = edge.source();
                 break;
             }
@@ -1284,13 +7125,477 @@ fn calculate_time_to(search_map: &SearchMap, mut node: NodeIndex) -> f64 {
     }
 }
 
+/// Breakdown of the estimated cost of one segment of a path, ridden on any of `routes`
+#[derive(Debug, Clone)]
+pub struct SegmentCost {
+    pub routes: Vec<String>,
+    pub start_station: StationId,
+    pub end_station: StationId,
+    pub in_vehicle_time: f64,
+    /// Trains of the segment's routes in the period the rider reaches it
+    pub frequency: i64,
+    /// Headway implied by the combined frequency of the segment's routes
+    pub wait_time: f64,
+    /// Walk to the next segment, if any
+    pub walk_time: f64,
+}
+
+/// Breakdown of the estimated cost of a path, as used by the optimizer
+#[derive(Debug, Clone)]
+pub struct PathCost {
+    pub segments: Vec<SegmentCost>,
+    /// Total cost with waiting and walking weighted by their multipliers
+    pub cost: f64,
+}
+
+// Estimates the cost of travelling along a path starting at start_time. Returns None if a
+// segment of the path has no service.
+fn path_cost(
+    search_map: &SearchMap,
+    frequencies: &[HashMap<String, Cell<i64>>],
+    path: &[PathSegment],
+    start_time: f64,
+    cyclic: bool,
+    time_to_cache: &mut HashMap<String, HashMap<NodeIndex, f64>>,
+) -> Option<PathCost> {
+    let mut curr_time = start_time;
+    let mut cost = 0.;
+    let mut segments = Vec::with_capacity(path.len());
+    for segment in path {
+        let mut total_frequency = 0;
+        for route in &segment.routes {
+            let time_to_start = *time_to_cache
+                .entry(route.clone())
+                .or_default()
+                .entry(segment.start_node)
+                .or_insert_with(|| calculate_time_to(search_map, segment.start_node));
+            let since = curr_time as i64 - time_to_start as i64;
+            // around the cycle times before the start fall into the periods at its end
+            let curr_schedule = if cyclic {
+                since
+                    .div_euclid(SCHEDULE_GRANULARITY)
+                    .rem_euclid(frequencies.len() as i64)
+            } else {
+                since / SCHEDULE_GRANULARITY
+            };
+            if curr_schedule < 0 || curr_schedule >= frequencies.len() as i64 {
+                // if the journey runs overtime stop considering subsequent segments
+                continue;
+            }
+            total_frequency += frequencies[curr_schedule as usize]
+                .get(route)
+                .map(Cell::get)
+                .unwrap_or_default();
+        }
+
+        if total_frequency == 0 {
+            return None;
+        }
+
+        let wait_time = SCHEDULE_GRANULARITY as f64 / total_frequency as f64;
+        let total_segment_cost = segment.cost as f64 + wait_time * WAIT_MULTIPLIER;
+        cost += total_segment_cost;
+        curr_time += total_segment_cost;
+        let walk_time = segment
+            .edge_to_next
+            .and_then(|edge_idx| search_map.map.edge_weight(edge_idx))
+            .map(|e| e.weight)
+            .unwrap_or_default() as f64;
+        cost += walk_time * WALK_MULTIPLIER;
+        curr_time += walk_time * WALK_MULTIPLIER;
+
+        let mut routes: Vec<_> = segment.routes.iter().cloned().collect();
+        routes.sort();
+        segments.push(SegmentCost {
+            routes,
+            start_station: search_map.map[segment.start_node].old_node,
+            end_station: search_map.map[segment.end_node].old_node,
+            in_vehicle_time: segment.cost as f64,
+            frequency: total_frequency,
+            wait_time,
+            walk_time,
+        });
+    }
+    Some(PathCost { segments, cost })
+}
+
+/// Estimates the cost of a path under the per period frequencies of a schedule
+pub fn estimate_path_cost(
+    search_map: &SearchMap,
+    schedule: &Schedule,
+    path: &[PathSegment],
+    start_time: i64,
+) -> Option<PathCost> {
+    path_cost(
+        search_map,
+        &schedule_frequencies(schedule),
+        path,
+        start_time as f64,
+        false,
+        &mut HashMap::new(),
+    )
+}
+
+/// Cost of the sampled riders under a schedule, as the optimizer prices them
+pub fn passenger_cost(
+    search_map: &mut SearchMap,
+    schedule: &Schedule,
+    routes: &[Route],
+    trip_data: &TripData,
+    shortest_paths: &HashMap<(NodeIndex, NodeIndex), Vec<Vec<PathSegment>>>,
+    options: &OptimizeOptions,
+) -> f64 {
+    calculate_costs(
+        search_map,
+        &schedule_frequencies(schedule),
+        routes,
+        trip_data,
+        shortest_paths,
+        options,
+        None,
+    )
+}
+
+fn scheduled_trains(frequencies: &Frequencies) -> i64 {
+    frequencies
+        .iter()
+        .flat_map(|period| period.values())
+        .map(Cell::get)
+        .sum()
+}
+
+fn schedule_frequencies(schedule: &Schedule) -> Frequencies {
+    let periods = schedule.values().map(Vec::len).max().unwrap_or_default();
+    (0..periods)
+        .map(|period| {
+            schedule
+                .iter()
+                .map(|(route, counts)| {
+                    (
+                        route.clone(),
+                        Cell::new(counts.get(period).copied().unwrap_or_default()),
+                    )
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Counts each route's departures falling in every period. Departures must lie within the
+/// schedule period.
+pub fn departures_to_schedule(departures: &HashMap<String, Vec<i64>>) -> Schedule {
+    let periods = (SCHEDULE_PERIOD / SCHEDULE_GRANULARITY) as usize;
+    departures
+        .iter()
+        .map(|(route, times)| {
+            let mut counts = vec![0; periods];
+            for time in times {
+                counts[(time / SCHEDULE_GRANULARITY) as usize] += 1;
+            }
+            (route.clone(), counts)
+        })
+        .collect()
+}
+
+/// Spreads each period's trains evenly across it, shifted by the route's offset. These are the
+/// departures schedule_trains aims for, and always fall back into the same periods.
+pub fn schedule_to_departures(
+    schedule: &Schedule,
+    offsets: &HashMap<String, u64>,
+) -> HashMap<String, Vec<i64>> {
+    schedule
+        .iter()
+        .map(|(route, counts)| {
+            let offset = offsets.get(route).copied().unwrap_or_default() as i64;
+            let departures = counts
+                .iter()
+                .enumerate()
+                .filter(|(_, count)| **count > 0)
+                .flat_map(|(period, &count)| {
+                    let start = period as i64 * SCHEDULE_GRANULARITY;
+                    let spacing = (SCHEDULE_GRANULARITY / count).max(1);
+                    (0..count)
+                        .map(move |i| start + i * SCHEDULE_GRANULARITY / count + offset % spacing)
+                })
+                .collect();
+            (route.clone(), departures)
+        })
+        .collect()
+}
+
+// The cost of the cheapest of the paths for a rider starting at start_time, if any has service
+fn lowest_cost(
+    search_map: &SearchMap,
+    frequencies: &[HashMap<String, Cell<i64>>],
+    paths: &[Vec<PathSegment>],
+    start_time: f64,
+    cyclic: bool,
+    time_to_cache: &mut HashMap<String, HashMap<NodeIndex, f64>>,
+) -> Option<f64> {
+    cheapest_path(
+        search_map,
+        frequencies,
+        paths,
+        start_time,
+        cyclic,
+        time_to_cache,
+    )
+    .map(|path_cost| path_cost.cost)
+}
+
+fn cheapest_path(
+    search_map: &SearchMap,
+    frequencies: &[HashMap<String, Cell<i64>>],
+    paths: &[Vec<PathSegment>],
+    start_time: f64,
+    cyclic: bool,
+    time_to_cache: &mut HashMap<String, HashMap<NodeIndex, f64>>,
+) -> Option<PathCost> {
+    paths
+        .iter()
+        .filter_map(|path| {
+            path_cost(
+                search_map,
+                frequencies,
+                path,
+                start_time,
+                cyclic,
+                time_to_cache,
+            )
+        })
+        .min_by(|a, b| a.cost.total_cmp(&b.cost))
+}
+
+/// Estimated ridership of a route over one schedule period
+#[derive(Debug, Clone, Default)]
+pub struct PeriodLoad {
+    /// Riders boarding the route
+    pub riders: f64,
+    /// Riders per train over the route's busiest track, as a fraction of train capacity. None
+    /// if no trains run in the period.
+    pub load_factor: Option<f64>,
+    /// Mean wait of the riders boarding, None if nobody does
+    pub wait_time: Option<f64>,
+}
+
+#[derive(Default)]
+struct LoadTally {
+    riders: f64,
+    total_wait: f64,
+    track_riders: HashMap<TrackId, f64>,
+}
+
+/// Estimates the load and wait of each route in every period of a schedule with the
+/// optimizer's cost model, without simulating. Riders starting in a period take the cheapest
+/// of their paths then, and split between the routes of a segment by their frequencies.
+#[allow(clippy::too_many_arguments)]
+pub fn preview_loads(
+    search_map: &SearchMap,
+    subway_map: &SubwayMap,
+    routes: &[Route],
+    config: &SimulationConfig,
+    schedule: &Schedule,
+    trip_data: &TripData,
+    shortest_paths: &HashMap<(NodeIndex, NodeIndex), Vec<Vec<PathSegment>>>,
+    cyclic: bool,
+) -> HashMap<String, Vec<PeriodLoad>> {
+    let frequencies = schedule_frequencies(schedule);
+    let periods = (SCHEDULE_PERIOD / SCHEDULE_GRANULARITY) as usize;
+    let period_of = |time: f64| {
+        ((time / SCHEDULE_GRANULARITY as f64).floor() as i64).clamp(0, periods as i64 - 1) as usize
+    };
+    let frequency = |route: &String, period: usize| {
+        frequencies
+            .get(period)
+            .and_then(|frequencies| frequencies.get(route))
+            .map_or(0, Cell::get)
+    };
+    let routes_by_name: HashMap<_, _> = routes.iter().map(|route| (&route.name, route)).collect();
+    let mut tallies: HashMap<String, Vec<LoadTally>> = HashMap::new();
+    let mut time_to_cache = HashMap::new();
+
+    for trip in trip_data.values().flatten() {
+        let mut starting = vec![0; periods];
+        for &arrival_time in &trip.arrival_times {
+            starting[period_of(arrival_time)] += 1;
+        }
+        for (period, &count) in starting.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let Some(path) = cheapest_path(
+                search_map,
+                &frequencies,
+                &shortest_paths[&(trip.start, trip.end)],
+                (period as i64 * SCHEDULE_GRANULARITY) as f64,
+                cyclic,
+                &mut time_to_cache,
+            ) else {
+                continue;
+            };
+            for segment in &path.segments {
+                let total: i64 = segment.routes.iter().map(|r| frequency(r, period)).sum();
+                for name in &segment.routes {
+                    // a segment served later in the trip than the period it started may have
+                    // no trains yet, its routes then share the riders evenly
+                    let share = if total > 0 {
+                        frequency(name, period) as f64 / total as f64
+                    } else {
+                        1. / segment.routes.len() as f64
+                    };
+                    let riders = count as f64 * share;
+                    if riders == 0. {
+                        continue;
+                    }
+                    let tally = &mut tallies
+                        .entry(name.clone())
+                        .or_insert_with(|| (0..periods).map(|_| LoadTally::default()).collect())
+                        [period];
+                    tally.riders += riders;
+                    tally.total_wait += riders * segment.wait_time;
+                    let Some(route) = routes_by_name.get(name) else {
+                        continue;
+                    };
+                    let mut at = segment.start_station;
+                    while at != segment.end_station {
+                        let Some(track) = route.station_to.get(&at) else {
+                            break;
+                        };
+                        *tally.track_riders.entry(*track).or_default() += riders;
+                        at = subway_map.edge_endpoints(*track).unwrap().1;
+                    }
+                }
+            }
+        }
+    }
+
+    routes
+        .iter()
+        .filter(|route| schedule.contains_key(&route.name) || tallies.contains_key(&route.name))
+        .map(|route| {
+            let capacity = route
+                .rolling_stock
+                .as_ref()
+                .and_then(|name| config.rolling_stock.get(name))
+                .copied()
+                .unwrap_or_default()
+                .train_capacity(route.cars, config.train_capacity);
+            let route_tallies = tallies.get(&route.name).map_or(&[][..], Vec::as_slice);
+            let loads = (0..periods)
+                .map(|period| {
+                    let trains = frequency(&route.name, period);
+                    let Some(tally) = route_tallies.get(period) else {
+                        return PeriodLoad {
+                            load_factor: (trains > 0).then_some(0.),
+                            ..Default::default()
+                        };
+                    };
+                    let peak = tally.track_riders.values().copied().fold(0., f64::max);
+                    PeriodLoad {
+                        riders: tally.riders,
+                        load_factor: (trains > 0).then(|| peak / (trains as f64 * capacity as f64)),
+                        wait_time: (tally.riders > 0.).then(|| tally.total_wait / tally.riders),
+                    }
+                })
+                .collect();
+            (route.name.clone(), loads)
+        })
+        .collect()
+}
+
+/// Riders starting in each schedule period, before and after they respond to the schedule
+pub struct PeakSpreading {
+    pub riders_before: Vec<usize>,
+    pub riders_after: Vec<usize>,
+}
+
+/// A simple peak spreading model. Each rider compares the cost of their trip with leaving a
+/// period earlier or later, and moves to the cheaper alternative with a probability of the
+/// relative saving times sensitivity.
+pub fn spread_peaks(
+    search_map: &SearchMap,
+    schedule: &Schedule,
+    trip_data: &TripData,
+    shortest_paths: &HashMap<(NodeIndex, NodeIndex), Vec<Vec<PathSegment>>>,
+    sensitivity: f64,
+    cyclic: bool,
+    seed: u64,
+) -> PeakSpreading {
+    let frequencies = schedule_frequencies(schedule);
+    let periods = (SCHEDULE_PERIOD / SCHEDULE_GRANULARITY) as usize;
+    let period_of = |time: f64| {
+        ((time / SCHEDULE_GRANULARITY as f64).floor() as i64).clamp(0, periods as i64 - 1) as usize
+    };
+    let mut riders_before = vec![0; periods];
+    let mut riders_after = vec![0; periods];
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut time_to_cache = HashMap::new();
+
+    // iterate in a fixed order so the random draws are reproducible
+    let mut times: Vec<_> = trip_data.keys().collect();
+    times.sort();
+    for time in times {
+        for trip in &trip_data[time] {
+            let paths = &shortest_paths[&(trip.start, trip.end)];
+            for &arrival_time in &trip.arrival_times {
+                riders_before[period_of(arrival_time)] += 1;
+                let mut cost_at = |time: f64| {
+                    lowest_cost(
+                        search_map,
+                        &frequencies,
+                        paths,
+                        time,
+                        cyclic,
+                        &mut time_to_cache,
+                    )
+                };
+
+                let mut chosen = arrival_time;
+                if let Some(cost) = cost_at(arrival_time) {
+                    let alternatives = [-1., 1.].map(|shift| {
+                        let time = arrival_time + shift * SCHEDULE_GRANULARITY as f64;
+                        if cyclic {
+                            time.rem_euclid(SCHEDULE_PERIOD as f64)
+                        } else {
+                            time
+                        }
+                    });
+                    let best = alternatives
+                        .into_iter()
+                        .filter(|time| (0. ..SCHEDULE_PERIOD as f64).contains(time))
+                        .filter_map(|time| cost_at(time).map(|cost| (time, cost)))
+                        .min_by(|a, b| a.1.total_cmp(&b.1));
+                    if let Some((time, alternative_cost)) = best {
+                        if alternative_cost < cost
+                            && rng.gen::<f64>() < sensitivity * (cost - alternative_cost) / cost
+                        {
+                            chosen = time;
+                        }
+                    }
+                }
+                riders_after[period_of(chosen)] += 1;
+            }
+        }
+    }
+
+    PeakSpreading {
+        riders_before,
+        riders_after,
+    }
+}
+
+// Totals the cost of every rider's cheapest path. With audit, riders of the trips in
+// OptimizeOptions::audit are recorded along with how they were priced.
 fn calculate_costs(
     search_map: &mut SearchMap,
     frequencies: &[HashMap<String, Cell<i64>>],
     routes: &[Route],
     trip_data: &TripData,
     shortest_paths: &HashMap<(NodeIndex, NodeIndex), Vec<Vec<PathSegment>>>,
+    options: &OptimizeOptions,
+    mut audit: Option<&mut Vec<AuditedRider>>,
 ) -> f64 {
+    let cyclic = options.cyclic;
     let mut total_cost = 0.;
 
     let mut time_to_cache = HashMap::with_capacity(routes.len());
@@ -1298,57 +7603,286 @@ fn calculate_costs(
         time_to_cache.insert(route.name.clone(), HashMap::new());
     }
 
-    for (time, trips) in trip_data.iter() {
+    for trips in trip_data.values() {
         for trip in trips {
             let paths = &shortest_paths[&(trip.start, trip.end)];
             assert!(!paths.is_empty());
-            let mut lowest_cost = f64::INFINITY;
-            'path: for path in paths.iter() {
-                let mut curr_time = *time as f64;
-                let mut cost = 0.;
-                for segment in path {
-                    let mut total_frequency = 0;
-                    for route in &segment.routes {
-                        let time_to_start = *time_to_cache
-                            .get_mut(route)
-                            .unwrap()
-                            .entry(segment.start_node)
-                            .or_insert_with(|| calculate_time_to(&search_map, segment.start_node));
-                        let curr_schedule =
-                            (curr_time as i64 - time_to_start as i64) / SCHEDULE_GRANULARITY;
-                        if curr_schedule < 0 || curr_schedule >= frequencies.len() as i64 {
-                            // if the journey runs overtime stop considering subsequent segments
-                            continue;
-                        }
-                        total_frequency += frequencies[curr_schedule as usize][route].get();
-                    }
-
-                    if total_frequency == 0 {
-                        continue 'path;
-                    }
-
-                    let wait =
-                        SCHEDULE_GRANULARITY as f64 / total_frequency as f64 * WAIT_MULTIPLIER;
-                    let total_segment_cost = segment.cost as f64 + wait;
-                    cost += total_segment_cost;
-                    curr_time += total_segment_cost;
-                    if let Some(edge_idx) = segment.edge_to_next {
-                        let walk_time = search_map
-                            .map
-                            .edge_weight(edge_idx)
-                            .map(|e| e.weight)
-                            .unwrap_or_default() as f64
-                            * WALK_MULTIPLIER;
-                        cost += walk_time;
-                        curr_time += walk_time;
-                    }
+            let audited = audit.is_some() && options.audit.contains(&(trip.start, trip.end));
+            // each rider is costed from the moment they arrive at the origin
+            for &arrival_time in &trip.arrival_times {
+                if let (true, Some(riders)) = (audited, audit.as_deref_mut()) {
+                    let path = cheapest_path(
+                        search_map,
+                        frequencies,
+                        paths,
+                        arrival_time,
+                        cyclic,
+                        &mut time_to_cache,
+                    );
+                    total_cost += path.as_ref().map_or(0., |path| path.cost);
+                    riders.push(AuditedRider {
+                        origin: trip.start,
+                        destination: trip.end,
+                        arrival_time,
+                        path,
+                    });
+                } else if let Some(cost) = lowest_cost(
+                    search_map,
+                    frequencies,
+                    paths,
+                    arrival_time,
+                    cyclic,
+                    &mut time_to_cache,
+                ) {
+                    total_cost += cost;
                 }
-                lowest_cost = lowest_cost.min(cost);
-            }
-            if lowest_cost < f64::INFINITY {
-                total_cost += lowest_cost * trip.count as f64;
             }
         }
     }
     total_cost
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A route starting at the first node that changes none of the defaults
+    fn route(name: &str) -> Route {
+        Route {
+            name: name.to_string(),
+            start_station: NodeIndex::new(0),
+            station_to: HashMap::new(),
+            offset: 0,
+            combine_short_stops: false,
+            deadhead: false,
+            pass_through: HashSet::new(),
+            turnaround: None,
+            depot: None,
+            parent: None,
+            dispatched: true,
+            variant_of: None,
+            train_length: 0.0,
+            cars: None,
+            rolling_stock: None,
+            transfer_times: HashMap::new(),
+            timetable: None,
+            standby: None,
+            overlay: false,
+            padding: 0.0,
+            max_trains: None,
+        }
+    }
+
+    #[test]
+    fn solver_scopes_pop_whatever_was_pushed_in_them() {
+        let ctx = z3::Context::new(&z3::Config::new());
+        let solver = DepartureSolver::new(&ctx, false);
+        {
+            let _scope = solver.scope();
+            solver.push();
+            solver.push();
+            solver.pop(1);
+            assert_eq!(solver.scopes.get(), 2);
+        }
+        assert_eq!(solver.scopes.get(), 0);
+    }
+
+    #[test]
+    fn smoothing_spreads_each_periods_trains_evenly_from_the_offset() {
+        let ctx = z3::Context::new(&z3::Config::new());
+        let route = Route {
+            offset: 1,
+            ..route("r")
+        };
+        let mut options = OptimizeOptions::default();
+        let rules = RouteGroundRules::new(&ctx, RouteId(0), &route, vec![3, 2], 2.0, 0, &options);
+        // targets are only preferred when smoothing
+        assert!(rules.soft.is_empty());
+        options.smooth_dispatches = true;
+        let rules = RouteGroundRules::new(&ctx, RouteId(0), &route, vec![3, 2], 2.0, 0, &options);
+        assert!(!rules.soft.is_empty());
+        let ideal: Vec<_> = rules
+            .ideal_departures
+            .iter()
+            .map(|(train, ideal)| (train.count, *ideal))
+            .collect();
+        assert_eq!(ideal, [(0, 1), (1, 5), (2, 9), (3, 13), (4, 19)]);
+    }
+
+    #[test]
+    fn smoothing_weighs_its_preferences_as_asked() {
+        let ctx = z3::Context::new(&z3::Config::new());
+        let options = OptimizeOptions {
+            smooth_dispatches: true,
+            weights: ObjectiveWeights {
+                regularity: 5,
+                throughput: 0,
+                offset_adherence: 2,
+                ..ObjectiveWeights::default()
+            },
+            ..OptimizeOptions::default()
+        };
+        let rules = RouteGroundRules::new(&ctx, RouteId(0), &route("r"), vec![3], 2.0, 0, &options);
+        let weighted = |weight| rules.soft.iter().filter(|(_, w)| *w == weight).count();
+        // four tolerances for each of the three trains' targets and the two gaps between them,
+        // and none for the unweighted throughput
+        assert_eq!(weighted(2), 12);
+        assert_eq!(weighted(5), 8);
+        assert_eq!(rules.soft.len(), 20);
+    }
+
+    #[test]
+    fn headway_floors_count_every_route_sharing_the_track() {
+        let (js_graph, js_routes) = crate::tests::network(&serde_json::json!({
+            "nodes": ["a", "b", "c"],
+            "edges": [{ "id": "a-b", "block_length": 3.0 }, { "id": "b-c" }],
+            "routes": { "r": { "nodes": ["a", "b"] }, "s": { "nodes": ["a", "b", "c"] } },
+        }));
+        let (graph, map, _) = crate::js_graph_to_subway_map(js_graph).unwrap();
+        let (routes, _) = crate::js_routes_to_routes(js_routes, &graph, &map).unwrap();
+        let floors = HeadwayFloors::new(&graph, &routes);
+        let period = |r, s| {
+            HashMap::from([
+                ("r".to_string(), Cell::new(r)),
+                ("s".to_string(), Cell::new(s)),
+            ])
+        };
+        // four trains a period are three minutes apart, a block behind each other on a-b
+        assert!(!floors.violated("r", &period(2, 2)));
+        assert!(floors.violated("r", &period(2, 3)));
+        assert!(floors.violated("s", &period(2, 3)));
+        assert!(!floors.violated("s", &period(0, 4)));
+        assert!(floors.violated("s", &period(0, 5)));
+    }
+
+    #[test]
+    fn scheduled_arrivals_keep_to_their_bucket() {
+        let process = ArrivalProcess::Scheduled(vec![12.0, 1.0, 5.5, 9.0, 4.0, 10.0]);
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(
+            process.arrival_times(5.0, 0.0, 10.0, &mut rng),
+            [1.0, 4.0, 5.5, 9.0]
+        );
+        assert_eq!(process.arrival_times(2.0, 0.0, 10.0, &mut rng), [1.0, 4.0]);
+        assert_eq!(
+            process.arrival_times(5.0, 10.0, 20.0, &mut rng),
+            [10.0, 12.0]
+        );
+    }
+
+    #[test]
+    fn runs_take_longer_to_speed_up_and_brake_for_stops() {
+        let stock = RollingStock {
+            acceleration: Some(1.0),
+            deceleration: Some(0.5),
+            ..Default::default()
+        };
+        // speeding up takes a time unit and braking two, half of each lost against running at
+        // top speed
+        assert_eq!(stock.run_time(4.0, 1.0, true, true), 5.5);
+        assert_eq!(stock.run_time(4.0, 1.0, true, false), 4.5);
+        assert_eq!(stock.run_time(4.0, 1.0, false, false), 4.0);
+        // too short to get to top speed before braking
+        assert_eq!(stock.run_time(0.375, 1.0, true, true), 1.5);
+        assert_eq!(RollingStock::default().run_time(4.0, 2.0, true, true), 2.0);
+    }
+
+    #[test]
+    fn audited_trips_record_how_each_rider_was_priced() {
+        let (js_graph, js_routes) = crate::tests::network(&serde_json::json!({
+            "nodes": ["a", "b", "c"],
+            "edges": [{ "id": "a-b" }, { "id": "b-c" }],
+            "routes": { "r": { "nodes": ["a", "b", "c"] } },
+        }));
+        let (graph, map, _) = crate::js_graph_to_subway_map(js_graph).unwrap();
+        let (routes, _) = crate::js_routes_to_routes(js_routes, &graph, &map).unwrap();
+        let mut search_map = SearchMap::generate(&graph, &routes);
+        let (a, b, c) = (map["a"], map["b"], map["c"]);
+        let shortest_paths = HashMap::from([
+            ((a, b), shortest_paths(a, b, &mut search_map, 1)),
+            ((a, c), shortest_paths(a, c, &mut search_map, 1)),
+        ]);
+        let trip = |end, arrival_times| Trip {
+            start: a,
+            end,
+            arrival_times,
+        };
+        let trip_data = TripData::from([(0, vec![trip(b, vec![1.0]), trip(c, vec![2.0, 4.0])])]);
+        let frequencies = schedule_frequencies(&Schedule::from([("r".to_string(), vec![6; 10])]));
+        let options = OptimizeOptions {
+            audit: vec![(a, c)],
+            ..Default::default()
+        };
+        let mut costs = |audit: Option<&mut Vec<AuditedRider>>| {
+            calculate_costs(
+                &mut search_map,
+                &frequencies,
+                &routes,
+                &trip_data,
+                &shortest_paths,
+                &options,
+                audit,
+            )
+        };
+        let mut riders = Vec::new();
+        let audited = costs(Some(&mut riders));
+        // auditing only records the riders, it doesn't change what they cost
+        assert_eq!(audited, costs(None));
+        let priced: Vec<_> = riders
+            .iter()
+            .map(|rider| (rider.destination, rider.arrival_time))
+            .collect();
+        assert_eq!(priced, [(c, 2.0), (c, 4.0)]);
+        // six trains a period make for a two minute wait, on top of four minutes riding
+        let path = riders[0].path.as_ref().unwrap();
+        let segment = &path.segments[0];
+        assert_eq!(path.segments.len(), 1);
+        assert_eq!((segment.start_station, segment.end_station), (a, c));
+        assert_eq!(segment.routes, ["r"]);
+        assert_eq!(
+            (
+                segment.frequency,
+                segment.wait_time,
+                segment.in_vehicle_time
+            ),
+            (6, 2.0, 4.0)
+        );
+    }
+
+    #[test]
+    fn failed_checks_keep_the_first_failure_and_the_movements_before_it() {
+        let train = |count| TrainId {
+            route_idx: 0,
+            count,
+        };
+        let mut recorder = FlightRecorder::default();
+        for count in 0..RECENT_MOVEMENTS as u32 + 2 {
+            recorder.moved(
+                count as f64,
+                train(count),
+                NodeIndex::new(1),
+                count % 2 == 0,
+            );
+        }
+        let section = TrackStationId::Station(NodeIndex::new(1));
+        recorder.fail("trains overlap", 40.0, section, vec![train(3), train(4)]);
+        recorder.fail("station already occupied", 41.0, section, vec![]);
+
+        let error = recorder
+            .failure
+            .unwrap()
+            .into_error(|_| "b".to_string(), |train| format!("r_{}", train.count));
+        let error = serde_json::to_value(error).unwrap();
+        assert_eq!(error["code"], "internal");
+        assert_eq!(error["message"], "internal error: trains overlap");
+        let diagnostic = &error["diagnostic"];
+        assert_eq!(diagnostic["time"], 40.0);
+        assert_eq!(diagnostic["section"], "b");
+        assert_eq!(diagnostic["trains"], serde_json::json!(["r_3", "r_4"]));
+        // only the last movements are kept, oldest first
+        let recent = diagnostic["recent_events"].as_array().unwrap();
+        assert_eq!(recent.len(), RECENT_MOVEMENTS);
+        assert_eq!(recent[0], "2: r_2 arrived at b");
+        assert_eq!(recent[RECENT_MOVEMENTS - 1], "33: r_33 left b");
+    }
+}
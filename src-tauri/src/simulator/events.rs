@@ -0,0 +1,562 @@
+//! An event driven alternative to stepping every train a TIME_STEP at a time. Trains move at a
+//! constant speed between events, so nothing is simulated while they're just travelling, and
+//! arrivals and merges happen at their exact times instead of on step boundaries.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use super::{
+    add_energy, closed_through, record_arrival, route_track, signal_behind, track_closed,
+    Disruption, DisruptionResponse, HeadwayProfile, Holding, SimulationResults, Simulator,
+    StationId, TrackId, TrackStationId, TrainId,
+};
+
+/// Slack for times which should be equal but went through different arithmetic
+const EPSILON: f64 = 1e-6;
+
+#[derive(Debug, Clone, Copy)]
+enum Event {
+    /// A train's next move is due, leaving its station or reaching the end of its track. Stale
+    /// if the train has been scheduled again since.
+    Train { train: TrainId, token: u64 },
+    /// A station may have freed up for the trains waiting to enter it
+    StationFree(StationId),
+    /// Passenger releases, dispatches and recording of a step
+    Step(i32),
+}
+
+impl Event {
+    // trains move before a step at the same time dispatches and records
+    fn rank(&self) -> u8 {
+        match self {
+            Event::Train { .. } => 0,
+            Event::StationFree(_) => 1,
+            Event::Step(_) => 2,
+        }
+    }
+}
+
+struct Scheduled {
+    time: f64,
+    seq: u64,
+    event: Event,
+}
+
+// ordered so the BinaryHeap pops the earliest event first
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .time
+            .total_cmp(&self.time)
+            .then(other.event.rank().cmp(&self.event.rank()))
+            .then(other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scheduled {}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Motion {
+    /// When the train entered its current section. At a station its progress through the dwell
+    /// is the time since, so a layover puts it in the future.
+    entered: f64,
+    /// Time spent stopped on the current track by closures
+    held: f64,
+    /// Only the train's latest scheduled event is acted on
+    token: u64,
+}
+
+#[derive(Default)]
+struct Events {
+    queue: BinaryHeap<Scheduled>,
+    next_seq: u64,
+    motions: HashMap<TrainId, Motion>,
+    /// When the tail of the last train to leave each station clears the platform
+    platform_free_at: HashMap<StationId, f64>,
+    /// Trains at stations waiting for room on a track, woken when a train leaves it
+    waiting_for_track: HashMap<TrackId, Vec<TrainId>>,
+    /// Trains waiting to turn around into an occupied station
+    waiting_for_station: HashMap<StationId, Vec<TrainId>>,
+    /// Whether a train entered a station since the last step
+    station_event: bool,
+}
+
+impl Events {
+    fn push(&mut self, time: f64, event: Event) {
+        self.queue.push(Scheduled {
+            time,
+            seq: self.next_seq,
+            event,
+        });
+        self.next_seq += 1;
+    }
+
+    // Replaces whatever the train had scheduled with a move at time
+    fn schedule(&mut self, train: TrainId, time: f64) {
+        let Some(motion) = self.motions.get_mut(&train) else {
+            return;
+        };
+        motion.token += 1;
+        let token = motion.token;
+        self.push(time, Event::Train { train, token });
+    }
+
+    fn enter(&mut self, train: TrainId, time: f64) {
+        let motion = self.motions.entry(train).or_default();
+        motion.entered = time;
+        motion.held = 0.0;
+    }
+
+    fn wake(&mut self, trains: Option<Vec<TrainId>>, time: f64) {
+        for train in trains.unwrap_or_default() {
+            self.schedule(train, time);
+        }
+    }
+
+    fn platform_free(&self, station: StationId, time: f64) -> bool {
+        self.platform_free_at
+            .get(&station)
+            .is_none_or(|free_at| time >= free_at - EPSILON)
+    }
+}
+
+/// Time a track was closed between from and to, a slow zone counting as closed for the share of
+/// speed it takes away
+fn closed_time(disruptions: &[Disruption], track: TrackId, from: f64, to: f64) -> f64 {
+    disruptions
+        .iter()
+        .filter(|disruption| disruption.track == track)
+        .map(|disruption| {
+            (disruption.end.min(to) - disruption.start.max(from)).max(0.0)
+                * (1.0 - disruption.speed)
+        })
+        .sum()
+}
+
+/// When the closures of a track in effect at time end
+fn reopening(disruptions: &[Disruption], track: TrackId, time: f64) -> f64 {
+    disruptions
+        .iter()
+        .filter(|disruption| {
+            disruption.track == track
+                && disruption.speed <= 0.0
+                && disruption.start <= time
+                && time < disruption.end
+        })
+        .map(|disruption| disruption.end)
+        .fold(time, f64::max)
+}
+
+impl Simulator {
+    /// Runs the same model as run_with_profile, jumping from event to event instead of
+    /// stepping. Positions are still recorded and passengers released at whole steps.
+    pub(super) fn run_events(
+        mut self,
+        iterations: i32,
+        profile: &HeadwayProfile,
+        warm_up: i32,
+    ) -> SimulationResults {
+        self.noise_rng = self
+            .config
+            .travel_time_noise
+            .map(|noise| StdRng::seed_from_u64(noise.seed));
+        let mut events = Events::default();
+        let mut train_to_route = HashMap::new();
+        let mut train_positions = Vec::new();
+
+        events.push(-warm_up as f64, Event::Step(-warm_up));
+        while let Some(Scheduled { time, event, .. }) = events.queue.pop() {
+            self.current_time = time;
+            match event {
+                Event::Train { train, token } => {
+                    if events.motions.get(&train).is_none_or(|m| m.token != token) {
+                        continue;
+                    }
+                    match self.trains[&train].curr_section {
+                        TrackStationId::Station(station) => {
+                            self.leave_station(&mut events, train, station)
+                        }
+                        TrackStationId::Track(track) => {
+                            self.reach_station(&mut events, train, track)
+                        }
+                    }
+                }
+                Event::StationFree(station) => self.station_freed(&mut events, station),
+                Event::Step(t) => {
+                    if self.checkpoint_due(t) {
+                        self.update_positions(&events, time);
+                        self.checkpoint(t);
+                    }
+                    self.release_passengers(time);
+                    // the stepping engine dispatches after moving trains, so a train sent
+                    // out now first moves on the next step
+                    let start = time + self.time_step;
+                    for (station, train) in self.dispatch(t, profile, &mut train_to_route) {
+                        events.station_event = true;
+                        // a train sent round its loop again has had its dwell
+                        if events.motions.contains_key(&train) {
+                            self.exchange_passengers(station, train, time);
+                            events.schedule(train, start);
+                            continue;
+                        }
+                        events.enter(train, start);
+                        self.exchange_passengers(station, train, time);
+                        let dwell_time = self.dwell_time(self.trains[&train].route, station);
+                        events.schedule(train, start + dwell_time);
+                    }
+
+                    if t >= 0 {
+                        self.peak_trains = self.peak_trains.max(self.trains.len());
+                        self.record_in_service(time);
+                        self.record_crowding(time);
+                        if self.config.position_recording.records(
+                            t as u32,
+                            time,
+                            events.station_event,
+                        ) {
+                            self.update_positions(&events, time);
+                            train_positions.push(self.positions(time));
+                        }
+                    }
+                    events.station_event = false;
+
+                    if t + 1 >= iterations {
+                        break;
+                    }
+                    events.push((t + 1) as f64, Event::Step(t + 1));
+                }
+            }
+        }
+
+        self.into_results(
+            iterations,
+            profile,
+            train_positions,
+            train_to_route,
+            warm_up as u32,
+        )
+    }
+
+    // Positions of the trains on a lane, front first. Each runs freely apart from closures,
+    // stopping at the end of the track or the signal behind the train ahead.
+    fn lane_positions(
+        &self,
+        events: &Events,
+        track: TrackId,
+        lane: usize,
+        time: f64,
+    ) -> Vec<(TrainId, f64)> {
+        let track = &self.tracks[&track];
+        let mut limit = track.length as f64;
+        let mut positions = Vec::with_capacity(track.lanes[lane].len());
+        for train in &track.lanes[lane] {
+            let motion = events.motions[train];
+            let moving = time
+                - motion.entered
+                - closed_time(&self.disruptions, track.id, motion.entered, time);
+            let pos = (self.trains[train].speed * moving).clamp(0.0, limit.max(0.0));
+            limit = signal_behind(pos - self.trains[train].length, track.block_length);
+            positions.push((*train, pos));
+        }
+        positions
+    }
+
+    // Brings the positions of every train up to time, for recording
+    fn update_positions(&mut self, events: &Events, time: f64) {
+        let mut positions = Vec::new();
+        for (id, track) in &self.tracks {
+            for lane in 0..track.lanes.len() {
+                positions.extend(self.lane_positions(events, *id, lane, time));
+            }
+        }
+        for (train, pos) in positions {
+            self.trains.get_mut(&train).unwrap().pos = pos;
+        }
+        for station in self.stations.values() {
+            if let Some(train) = station.train {
+                self.trains.get_mut(&train).unwrap().pos = time - events.motions[&train].entered;
+            }
+        }
+    }
+
+    // Sends a train whose dwell is up onto its next track, or turns it around or out of service
+    // at the end of its route. Trains which can't leave yet wait at the station.
+    fn leave_station(&mut self, events: &mut Events, train: TrainId, station: StationId) {
+        let now = self.current_time;
+        let route_id = self.trains[&train].route;
+        let dwell_time = self.stop_dwell(train, station) + self.held(train, station);
+        let due = events.motions[&train].entered + dwell_time;
+        if now < due - EPSILON {
+            events.schedule(train, due);
+            return;
+        }
+
+        let Some(next_track_id) = route_track(
+            &self.routes[&route_id],
+            route_id,
+            &self.reroutes,
+            station,
+            now,
+        ) else {
+            match self.terminal_links.get(&(route_id, station)).copied() {
+                Some(next_route) => {
+                    let start_station = self.routes[&next_route].start_station;
+                    if start_station != station && self.stations[&start_station].train.is_some() {
+                        events
+                            .waiting_for_station
+                            .entry(start_station)
+                            .or_default()
+                            .push(train);
+                        return;
+                    }
+                    self.turn_around(train, station, next_route);
+                    if start_station != station {
+                        events.push(now, Event::StationFree(station));
+                    }
+                    let entered = now + self.config.layover_time();
+                    events.enter(train, entered);
+                    events.schedule(train, entered + self.dwell_time(next_route, start_station));
+                }
+                None => {
+                    self.end_trip(train);
+                    self.trains.remove(&train);
+                    events.motions.remove(&train);
+                    self.stations.get_mut(&station).unwrap().train = None;
+                    events.push(now, Event::StationFree(station));
+                }
+            }
+            return;
+        };
+
+        let gap_behind = matches!(self.config.holding, Holding::GapBehind { .. });
+        if gap_behind || self.timed_transfers.contains_key(&station) {
+            // gaps behind and feeders due are judged from where the trains are now
+            self.update_positions(events, now);
+        }
+        // a train back at the start of its loop waits for its next departure
+        if self.circling.contains(&train) && self.loop_stations.get(&route_id) == Some(&station) {
+            return;
+        }
+        let hold = self.hold(train, station, now);
+        if hold > 0.0 {
+            events.schedule(train, now + hold);
+            return;
+        }
+
+        if track_closed(&self.disruptions, next_track_id, now) {
+            match self.config.disruption_response {
+                DisruptionResponse::ShortTurn if self.subway_map[station].crossover => {
+                    self.short_turn(train, station);
+                    events.motions.remove(&train);
+                    events.push(now, Event::StationFree(station));
+                }
+                DisruptionResponse::ShortTurn | DisruptionResponse::Hold => {
+                    let reopens = reopening(&self.disruptions, next_track_id, now);
+                    *self.disruption_delays.entry(train).or_default() += reopens - now;
+                    events.schedule(train, reopens);
+                }
+            }
+            return;
+        }
+
+        // enter whichever parallel track has the most room
+        let next_track = &self.tracks[&next_track_id];
+        let mut lane = 0;
+        let mut room = f64::NEG_INFINITY;
+        let mut last_train = None;
+        for i in 0..next_track.lanes.len() {
+            let last = self.lane_positions(events, next_track_id, i, now).pop();
+            let lane_room = last.map_or(f64::INFINITY, |(train, pos)| {
+                pos - self.trains[&train].length
+            });
+            if lane_room > room {
+                lane = i;
+                room = lane_room;
+                last_train = last;
+            }
+        }
+
+        // only move the train off the station once the first block is clear
+        if let Some((last_train, pos)) = last_train {
+            if signal_behind(room, next_track.block_length) <= 0.0 {
+                events
+                    .waiting_for_track
+                    .entry(next_track_id)
+                    .or_default()
+                    .push(train);
+                // the block clears by itself unless the train in it is held up
+                let last = &self.trains[&last_train];
+                let motion = events.motions[&last_train];
+                let moving = now - motion.entered - motion.held;
+                let clear_pos = next_track.block_length + last.length;
+                if pos >= last.speed * moving - EPSILON && clear_pos < next_track.length as f64 {
+                    let clears = motion.entered + motion.held + clear_pos / last.speed;
+                    if clears > now {
+                        events.schedule(train, clears + EPSILON);
+                    }
+                }
+                return;
+            }
+        }
+
+        let noise = match (&mut self.noise_rng, &self.config.travel_time_noise) {
+            (Some(rng), Some(noise)) => noise.sample_speed(rng),
+            _ => 1.0,
+        };
+        let speed = self.run_speed(
+            route_id,
+            next_track_id,
+            noise * self.rolling_stock[&route_id].speed,
+        );
+        let length = next_track.length as f64;
+        self.stations.get_mut(&station).unwrap().train = None;
+        self.departed(train, station);
+        self.tracks.get_mut(&next_track_id).unwrap().lanes[lane].push_back(train);
+        let train_mut = self.trains.get_mut(&train).unwrap();
+        train_mut.pos = 0.0;
+        train_mut.speed = speed;
+        train_mut.distance_travelled += dwell_time;
+        train_mut.curr_section = TrackStationId::Track(next_track_id);
+        events.enter(train, now);
+        events.schedule(train, now + length / speed);
+
+        // the platform is taken until the train's tail has cleared it
+        let platform_free_at = now + train_mut.length / speed;
+        events.platform_free_at.insert(station, platform_free_at);
+        events.push(platform_free_at, Event::StationFree(station));
+    }
+
+    // Moves a train at the end of its track into the next station, if it's first in line and
+    // the station is free
+    fn reach_station(&mut self, events: &mut Events, train: TrainId, track: TrackId) {
+        let now = self.current_time;
+        // trains caught on a closed track stop where they are
+        if track_closed(&self.disruptions, track, now) {
+            events.schedule(train, reopening(&self.disruptions, track, now));
+            return;
+        }
+        let motion = events.motions[&train];
+        let held = closed_time(&self.disruptions, track, motion.entered, now);
+        if held > motion.held {
+            *self.disruption_delays.entry(train).or_default() += held - motion.held;
+            events.motions.get_mut(&train).unwrap().held = held;
+        }
+        let length = self.tracks[&track].length as f64;
+        let due = motion.entered + held + length / self.trains[&train].speed;
+        if now < due - EPSILON {
+            events.schedule(train, due);
+            return;
+        }
+
+        // trains behind wait for the one ahead, and everyone for the platform
+        let next_station_id = self.subway_map.edge_endpoints(track).unwrap().1;
+        let lane = self.tracks[&track]
+            .lanes
+            .iter()
+            .position(|lane| lane.front() == Some(&train));
+        let Some(lane) = lane else {
+            return;
+        };
+        self.merge_waits.entry(train).or_insert(now);
+        if self.stations[&next_station_id].train.is_some()
+            || !events.platform_free(next_station_id, now)
+            || self.gives_way(next_station_id, track, train, 0.0)
+        {
+            return;
+        }
+        self.merge_waits.remove(&train);
+        self.merge_from.insert(next_station_id, track);
+
+        let lane = &mut self.tracks.get_mut(&track).unwrap().lanes[lane];
+        lane.pop_front();
+        if let Some(next_front) = lane.front() {
+            events.schedule(*next_front, now);
+        }
+        let waiting = events.waiting_for_track.remove(&track);
+        events.wake(waiting, now);
+
+        let next_station_mut = self.stations.get_mut(&next_station_id).unwrap();
+        next_station_mut.train = Some(train);
+        events.station_event = true;
+        let train_mut = self.trains.get_mut(&train).unwrap();
+        let route_id = train_mut.route;
+        let route = &self.routes[&route_id];
+        let in_service = self.subway_map[track].revenue && !route.deadhead;
+        let stops = !route.pass_through.contains(&next_station_id)
+            && !self.skipping.contains_key(&(train, next_station_id))
+            && !closed_through(
+                &self.station_closures,
+                route,
+                route_id,
+                &self.reroutes,
+                next_station_id,
+                now,
+            );
+        if now >= 0.0 && in_service && stops {
+            record_arrival(
+                &mut next_station_mut.arrival_times,
+                &mut self.bunching,
+                &self.config,
+                next_station_id,
+                route_id,
+                now,
+            );
+        }
+        train_mut.distance_travelled += length;
+        if now >= 0.0 {
+            self.operating_distances
+                .entry(route.parent.unwrap_or(route_id))
+                .or_default()
+                .add(length, in_service);
+            add_energy(
+                &mut self.energy,
+                &self.config,
+                route.parent.unwrap_or(route_id),
+                now,
+                length,
+                stops,
+                train_mut.speed,
+            );
+        }
+        train_mut.curr_section = TrackStationId::Station(next_station_id);
+        train_mut.pos = 0.0;
+        events.enter(train, now);
+
+        self.exchange_passengers(next_station_id, train, now);
+        events.schedule(train, now + self.stop_dwell(train, next_station_id));
+    }
+
+    // Wakes the trains waiting to enter a station once it's free
+    fn station_freed(&mut self, events: &mut Events, station: StationId) {
+        let now = self.current_time;
+        if self.stations[&station].train.is_some() || !events.platform_free(station, now) {
+            return;
+        }
+        let waiting = events.waiting_for_station.remove(&station);
+        events.wake(waiting, now);
+        let fronts: Vec<_> = self
+            .subway_map
+            .edges_directed(station, Direction::Incoming)
+            .filter_map(|edge| self.tracks.get(&edge.id()))
+            .flat_map(|track| track.lanes.iter().filter_map(|lane| lane.front().copied()))
+            .collect();
+        events.wake(Some(fronts), now);
+    }
+}
@@ -8,26 +8,52 @@ import { Graph, GraphMode, SimulationResults } from "./Graph";
 import { Routes, SubwayGraph, defaultRoutes, defaultSubwayGraph } from "./subwayGraph";
 import "./App.css";
 
+// error returned by the backend commands, code being one of graph, route, invalid_argument,
+// solver, infeasible_schedule, cancelled, busy or internal
+export interface SimError {
+  code: string,
+  message: string,
+  // what the simulation was doing, for internal errors caught by a check on its state
+  diagnostic?: {
+    time: number,
+    section: string,
+    trains: string[],
+    recent_events: string[]
+  }
+}
+
+async function invokeCommand(command: string, args: Record<string, unknown>): Promise<unknown> {
+  try {
+    return await invoke(command, args);
+  } catch (error) {
+    const simError = error as SimError;
+    if (simError.code !== 'cancelled') {
+      alert(simError.message);
+    }
+    throw simError;
+  }
+}
+
 interface ShortestPath {
   length: number,
   path: string[]
 }
 
 async function shortestPath(graph: any, routes: any, source: string, target: string): Promise<ShortestPath | null> {
-  const result = await invoke('shortest_path', { jsGraph: graph, jsRoutes: routes, source, target });
+  const result = await invokeCommand('shortest_path', { jsGraph: graph, jsRoutes: routes, source, target });
   alert(JSON.stringify(result))
   return result as any;
 }
 
 
-async function runSimulation(graph: any, routes: any, frequency: number): Promise<SimulationResults> {
-  const result = await invoke('run_simulation', { jsGraph: graph, jsRoutes: routes, frequency });
+async function runSimulation(graph: any, routes: any, frequency: number, duration: number): Promise<SimulationResults> {
+  const result = await invokeCommand('run_simulation', { jsGraph: graph, jsRoutes: routes, frequency, duration });
   console.log(result);
   return result as any;
 }
 
 async function runOptimize(graph: any, routes: any): Promise<SimulationResults> {
-  const result = await invoke('run_optimize', { jsGraph: graph, jsRoutes: routes });
+  const result = await invokeCommand('run_optimize', { jsGraph: graph, jsRoutes: routes });
   console.log(result);
   return result as any;
 }
@@ -41,12 +67,19 @@ function App() {
   const getRoutes = useRef(defaultRoutes);
   const [initialRoutes, setInitialRoutes] = useState(defaultRoutes());
 
+  // minutes of service to simulate
+  const [duration, setDuration] = useState(60);
+  const simulate = useCallback(
+    (graph: any, routes: any, frequency: number) => runSimulation(graph, routes, frequency, duration),
+    [duration]
+  );
+
   const handleMode = useCallback((event: any) => {
     setMode(event.currentTarget.value)
   }, []);
   return (
     <div style={{ display: 'flex', flexDirection: 'column' }}>
-      <Graph mode={mode} initialSubwayGraph={initialSubwayGraph} initialRoutes={initialRoutes} onSimulate={runSimulation} onOptimize={runOptimize} onShortestPath={shortestPath} getCurrentSubwayGraph={getSubwayGraph} getCurrentRoutes={getRoutes} />
+      <Graph mode={mode} initialSubwayGraph={initialSubwayGraph} initialRoutes={initialRoutes} onSimulate={simulate} onOptimize={runOptimize} onShortestPath={shortestPath} getCurrentSubwayGraph={getSubwayGraph} getCurrentRoutes={getRoutes} />
       <div>
         <div>
           <input type="radio" value="display" checked={mode === "display"} onChange={handleMode} />
@@ -64,6 +97,10 @@ function App() {
           <input type="radio" value="route_edit" checked={mode === "route_edit"} onChange={handleMode} />
           <label htmlFor="route_edit">Route editing</label>
         </div>
+        <div>
+          <label htmlFor="duration">Duration (minutes) </label>
+          <input id="duration" type="number" min={1} max={24 * 60} value={duration} onChange={(event) => setDuration(Number(event.currentTarget.value))} />
+        </div>
       </div>
       <div>
         <div>
@@ -20,12 +20,17 @@ export interface TrainPosition {
     curr_section: string,
     pos: number,
     distance_travelled: number,
+    coordinates?: { x: number, y: number },
+    load: number,
 }
 
 export interface SimulationResults {
     train_positions: TrainPositions[],
     train_to_route: Record<string, string>,
     station_statistics: Record<string, StationStatistic>,
+    peak_loads: Record<string, number>,
+    passengers_delivered: number,
+    average_journey_time: number | null,
 }
 
 interface ArrivalTimes {
@@ -572,8 +577,8 @@ function renderTrainPositions(graph: cytoscape.Core, simulationResults: Simulati
 
 function serializeGraph(graph: cytoscape.Core): any {
     return {
-        nodes: graph.nodes().map(node => ({ id: node.id() })),
-        edges: graph.edges().map(edge => ({ id: edge.id(), source: edge.source().id(), target: edge.target().id(), weight: edge.data().weight, type: edge.data().type }))
+        nodes: graph.nodes().map(node => ({ id: node.id(), position: node.position(), dwell_time: node.data().dwell_time, waiting_capacity: node.data().waiting_capacity, bypass: node.data().bypass ?? false, crossover: node.data().crossover ?? false, skippable: node.data().skippable ?? false, transfer_times: node.data().transfer_times ?? [] })),
+        edges: graph.edges().map(edge => ({ id: edge.id(), source: edge.source().id(), target: edge.target().id(), weight: edge.data().weight, type: edge.data().type, non_revenue: edge.data().non_revenue, one_way: edge.data().one_way, tracks: edge.data().tracks, block_length: edge.data().block_length }))
     };
 }
 